@@ -4,7 +4,8 @@ use bollard::container::{
 };
 use bollard::image::{CreateImageOptions, RemoveImageOptions};
 use bollard::Docker;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
 use tempdir::TempDir;
 use walkdir::WalkDir;
 
@@ -14,12 +15,132 @@ use serde_json::json;
 use std::collections::HashMap;
 use std::default::Default;
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use tokio_async_drop::tokio_async_drop;
+use tokio_util::sync::CancellationToken;
+
+use rfs::fungi::meta::{FileType, Inode, Walk, WalkVisitor};
+use rfs::fungi::{Reader, Writer};
+use rfs::store::{self, batch::BatchStore, Route, Store};
+use rfs::Chunker;
+
+mod filter;
+mod layers;
+mod merge;
+mod platform;
+pub use filter::Filter;
+pub use platform::Platform;
+
+/// size of the chunks of an image archive streamed to docker load
+const LOAD_BUFFER_SIZE: usize = 64 * 1024;
+/// default number of blocks of a file that are uploaded at the same time
+pub const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+/// default number of blocks that are written to the store in one batch
+pub const DEFAULT_BATCH_SIZE: usize = store::batch::DEFAULT_BATCH_SIZE;
+
+/// Progress is an event reported while an image is converted to an flist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// a layer of the image is being downloaded, `current` and `total` are in bytes
+    Downloading {
+        layer: String,
+        current: u64,
+        total: u64,
+    },
+    /// a layer of the image is downloaded and extracted
+    Pulled { layer: String },
+    /// the image is exported, `files` is the number of files to pack
+    Exported { files: usize },
+    /// a file is packed, `packed` out of `files` files are done
+    Packed { packed: usize, files: usize },
+    /// a block of `size` bytes (compressed and encrypted) is uploaded to the store
+    Uploaded { size: u64 },
+    /// a block of `size` bytes is already in the store and is not uploaded again
+    Skipped { size: u64 },
+}
+
+/// Reporter receives the progress events of a conversion. The unit type is a
+/// reporter that ignores all events, and a channel sender forwards the events to
+/// its receiver.
+pub trait Reporter: Send + Sync + 'static {
+    fn report(&self, progress: Progress);
+}
 
-use rfs::fungi::Writer;
-use rfs::store::Store;
+impl Reporter for () {
+    fn report(&self, _progress: Progress) {}
+}
+
+impl Reporter for Sender<Progress> {
+    fn report(&self, progress: Progress) {
+        // a closed receiver means nobody is interested in the progress anymore
+        let _ = self.send(progress);
+    }
+}
+
+// ReportingStore reports the blocks uploaded to the wrapped store. With skip_existing
+// blocks that are already in the store are not uploaded again, it's safe since the
+// key of a block is the hash of its content. No block is uploaded once the conversion
+// is cancelled
+struct ReportingStore<S, R> {
+    store: S,
+    reporter: Arc<R>,
+    skip_existing: bool,
+    cancel: CancellationToken,
+}
+
+#[async_trait::async_trait]
+impl<S: Store, R: Reporter> Store for ReportingStore<S, R> {
+    async fn get(&self, key: &[u8]) -> store::Result<Vec<u8>> {
+        self.store.get(key).await
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> store::Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(store::Error::Other(anyhow::anyhow!(
+                "conversion is cancelled"
+            )));
+        }
+
+        if self.skip_existing {
+            match self.store.exists(key).await {
+                Ok(true) => {
+                    self.reporter.report(Progress::Skipped {
+                        size: blob.len() as u64,
+                    });
+                    return Ok(());
+                }
+                Ok(false) => {}
+                // the block is uploaded anyway, the set fails if the store is down
+                Err(err) => log::warn!("failed to check if block exists: {}", err),
+            }
+        }
+
+        self.store.set(key, blob).await?;
+        self.reporter.report(Progress::Uploaded {
+            size: blob.len() as u64,
+        });
+
+        Ok(())
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        self.store.routes()
+    }
+
+    async fn exists(&self, key: &[u8]) -> store::Result<bool> {
+        self.store.exists(key).await
+    }
+
+    async fn flush(&self) -> store::Result<()> {
+        self.store.flush().await
+    }
+
+    async fn health(&self) -> store::Result<()> {
+        self.store.health().await
+    }
+}
 
 struct DockerInfo {
     image_name: String,
@@ -44,11 +165,32 @@ impl Drop for DockerInfo {
     }
 }
 
+/// ImageSource is where the image to convert comes from
+#[derive(Debug, Clone, Default)]
+pub enum ImageSource {
+    /// pull the image from its registry
+    #[default]
+    Registry,
+    /// load the image from an archive, either a `docker save` tarball or an OCI
+    /// image layout (a directory or a tarball of it). The format is detected by
+    /// docker and no registry is involved
+    Archive(PathBuf),
+}
+
 pub struct DockerImageToFlist {
     meta: Writer,
     image_name: String,
     credentials: Option<DockerCredentials>,
     docker_tmp_dir: TempDir,
+    upload_concurrency: usize,
+    batch_size: usize,
+    chunker: Chunker,
+    source: ImageSource,
+    platform: Platform,
+    skip_existing: bool,
+    filter: Filter,
+    overlays: Vec<String>,
+    cancel: CancellationToken,
 }
 
 impl DockerImageToFlist {
@@ -63,17 +205,95 @@ impl DockerImageToFlist {
             image_name,
             credentials,
             docker_tmp_dir,
+            upload_concurrency: DEFAULT_UPLOAD_CONCURRENCY,
+            batch_size: DEFAULT_BATCH_SIZE,
+            chunker: Chunker::default(),
+            source: ImageSource::default(),
+            platform: Platform::host(),
+            skip_existing: true,
+            filter: Filter::default(),
+            overlays: Vec::default(),
+            cancel: CancellationToken::new(),
         }
     }
 
+    /// with_skip_existing sets if blocks that are already in the store are skipped
+    /// instead of uploaded again, it's enabled by default. Stores without a cheap
+    /// existence check get the block to know if it exists
+    pub fn with_skip_existing(mut self, skip_existing: bool) -> Self {
+        self.skip_existing = skip_existing;
+        self
+    }
+
+    /// with_filter sets the filter of the files of the image that are packed, the
+    /// excluded files are removed from the extracted image before it's packed
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// with_cancel sets a token that stops the conversion once it's cancelled, the
+    /// image pull is stopped and no block is uploaded anymore. The conversion then
+    /// fails and the partial flist must be removed by the caller
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// with_platform sets the platform that is pulled from a multi-arch image, the
+    /// default is the host platform. It's not used for images loaded from an archive
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// with_source sets where the image comes from. With an archive the loaded image
+    /// is tagged with the image name and the credentials are not used
+    pub fn with_source(mut self, source: ImageSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// with_upload_concurrency sets the number of blocks of a file that are uploaded
+    /// at the same time
+    pub fn with_upload_concurrency(mut self, upload_concurrency: usize) -> Self {
+        self.upload_concurrency = upload_concurrency.max(1);
+        self
+    }
+
+    /// with_batch_size sets the number of blocks that are buffered and written to
+    /// the store in one batch, stores like zdb write a batch in one round trip. A
+    /// batch size of 1 writes each block right away
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// with_chunker sets how the files are split into blocks, use `Chunker::sized`
+    /// for a custom block size
+    pub fn with_chunker(mut self, chunker: Chunker) -> Self {
+        self.chunker = chunker;
+        self
+    }
+
+    /// with_overlays sets images that are merged on top of the image, in order, into
+    /// the same flist. A later image wins over the earlier ones on path conflicts, a
+    /// directory in both is merged, and its whiteouts (`.wh.<name>` and the opaque
+    /// `.wh..wh..opq`) remove paths of the earlier ones. Overlays are
+    /// always pulled from the registry with the same credentials as the image, and
+    /// the startup command of the flist is the one of the last image
+    pub fn with_overlays(mut self, overlays: Vec<String>) -> Self {
+        self.overlays = overlays;
+        self
+    }
+
     pub fn files_count(&self) -> usize {
         WalkDir::new(self.docker_tmp_dir.path()).into_iter().count()
     }
 
-    pub async fn prepare(&mut self) -> Result<()> {
-        #[cfg(unix)]
-        let docker = Docker::connect_with_socket_defaults().context("failed to create docker")?;
-
+    /// prepare pulls the image (and its overlays) and extracts its content, the
+    /// pulled layers are reported to the reporter
+    pub async fn prepare<R: Reporter>(&mut self, reporter: &R) -> Result<()> {
         let container_file =
             Path::file_stem(self.docker_tmp_dir.path()).expect("failed to get directory name");
         let container_name = container_file
@@ -81,21 +301,88 @@ impl DockerImageToFlist {
             .expect("failed to get container name")
             .to_owned();
 
+        let root = self.docker_tmp_dir.path();
+        self.extract(
+            &self.image_name,
+            &self.source,
+            &container_name,
+            root,
+            reporter,
+        )
+        .await?;
+
+        for (index, image_name) in self.overlays.iter().enumerate() {
+            // each overlay is extracted on its own then merged into the image
+            let name = format!("{}-{}", container_name, index + 1);
+            let staging = TempDir::new(&name).context("failed to create tmp directory")?;
+            self.extract(
+                image_name,
+                &ImageSource::Registry,
+                &name,
+                staging.path(),
+                reporter,
+            )
+            .await?;
+
+            let stats = merge::merge(staging.path(), root)
+                .with_context(|| format!("failed to merge docker image '{}'", image_name))?;
+            log::info!(
+                "docker image '{}' is merged, {} paths replaced and {} removed",
+                image_name,
+                stats.replaced,
+                stats.removed
+            );
+        }
+
+        let removed = self
+            .filter
+            .prune(self.docker_tmp_dir.path())
+            .context("failed to filter the extracted image")?;
+        if removed > 0 {
+            log::info!("excluded {} files and directories of the image", removed);
+        }
+
+        reporter.report(Progress::Exported {
+            files: self.files_count(),
+        });
+
+        Ok(())
+    }
+
+    // extract pulls (or loads) an image and extracts its content to target
+    async fn extract<R: Reporter>(
+        &self,
+        image_name: &str,
+        source: &ImageSource,
+        container_name: &str,
+        target: &Path,
+        reporter: &R,
+    ) -> Result<()> {
+        #[cfg(unix)]
+        let docker = Docker::connect_with_socket_defaults().context("failed to create docker")?;
+
         let docker_info = DockerInfo {
-            image_name: self.image_name.to_owned(),
-            container_name,
+            image_name: image_name.to_owned(),
+            container_name: container_name.to_owned(),
             docker,
         };
 
-        extract_image(
+        let extract = extract_image(
             &docker_info.docker,
             &docker_info.image_name,
             &docker_info.container_name,
-            self.docker_tmp_dir.path(),
+            target,
+            source,
+            &self.platform,
             self.credentials.clone(),
-        )
-        .await
-        .context("failed to extract docker image to a directory")?;
+            reporter,
+        );
+
+        // the container and the image are removed when docker_info is dropped
+        tokio::select! {
+            result = extract => result.context("failed to extract docker image to a directory")?,
+            _ = self.cancel.cancelled() => anyhow::bail!("conversion is cancelled"),
+        }
         log::info!(
             "docker image '{}' is extracted successfully",
             docker_info.image_name
@@ -104,38 +391,152 @@ impl DockerImageToFlist {
         Ok(())
     }
 
-    pub async fn pack<S: Store>(&mut self, store: S, sender: Option<Sender<u32>>) -> Result<()> {
-        rfs::pack(
+    /// pack creates the flist from the extracted image, the packed files and the
+    /// uploaded blocks are reported to the reporter
+    pub async fn pack<S: Store, R: Reporter>(&mut self, store: S, reporter: R) -> Result<()> {
+        let options = rfs::PackOptions {
+            strip_password: true,
+            upload_concurrency: self.upload_concurrency,
+            chunker: self.chunker,
+            // images rely on xattrs like security.capability
+            xattrs: true,
+            ..Default::default()
+        };
+
+        let reporter = Arc::new(reporter);
+        let files = self.files_count();
+        let (sender, receiver) = mpsc::channel();
+        let packed_reporter = Arc::clone(&reporter);
+        // rfs reports each packed file on the channel, the thread exits once
+        // packing is done and the sender is dropped
+        std::thread::spawn(move || {
+            let mut packed = 0;
+            for step in receiver {
+                packed += step as usize;
+                packed_reporter.report(Progress::Packed { packed, files });
+            }
+        });
+
+        let pack = rfs::pack_with(
             self.meta.clone(),
-            store,
-            &self.docker_tmp_dir.path(),
-            true,
-            sender,
-        )
-        .await
-        .context("failed to pack flist")?;
+            ReportingStore {
+                store: BatchStore::new(store, self.batch_size),
+                reporter,
+                skip_existing: self.skip_existing,
+                cancel: self.cancel.clone(),
+            },
+            self.docker_tmp_dir.path(),
+            options,
+            Some(sender),
+        );
+
+        tokio::select! {
+            result = pack => result.context("failed to pack flist")?,
+            _ = self.cancel.cancelled() => anyhow::bail!("conversion is cancelled"),
+        }
 
         log::info!("flist has been created successfully");
         Ok(())
     }
 
-    pub async fn convert<S: Store>(&mut self, store: S, sender: Option<Sender<u32>>) -> Result<()> {
-        self.prepare().await?;
-        self.pack(store, sender).await?;
+    /// convert pulls the image and creates the flist, the progress of the
+    /// conversion is reported to the reporter. Use `()` to ignore the progress
+    pub async fn convert<S: Store, R: Reporter>(&mut self, store: S, reporter: R) -> Result<()> {
+        self.prepare(&reporter).await?;
+        self.pack(store, reporter).await?;
 
         Ok(())
     }
 }
 
-async fn extract_image(
+/// image_digest returns the content digest of the image from its registry without pulling it
+pub async fn image_digest(
+    image_name: &str,
+    credentials: Option<DockerCredentials>,
+) -> Result<String> {
+    let docker = Docker::connect_with_socket_defaults().context("failed to create docker")?;
+    let inspect = docker
+        .inspect_registry_image(image_name, credentials)
+        .await
+        .context("failed to inspect registry image")?;
+
+    inspect
+        .descriptor
+        .digest
+        .context("registry didn't return the image digest")
+}
+
+/// Summary of the content of a created flist
+#[derive(Debug, Default, serde::Serialize)]
+pub struct Summary {
+    pub files: u64,
+    pub directories: u64,
+    pub links: u64,
+    /// total size of regular files in bytes
+    pub size: u64,
+    pub blocks: u64,
+}
+
+#[async_trait::async_trait]
+impl WalkVisitor for Summary {
+    async fn visit(&mut self, _path: &Path, node: &Inode) -> rfs::fungi::meta::Result<Walk> {
+        match node.mode.file_type() {
+            FileType::Dir => self.directories += 1,
+            FileType::Link => self.links += 1,
+            FileType::Regular => {
+                self.files += 1;
+                self.size += node.size;
+            }
+            _ => {}
+        }
+
+        Ok(Walk::Continue)
+    }
+}
+
+/// summary walks the given flist and counts its entries
+pub async fn summary(meta: &Reader) -> Result<Summary> {
+    let mut summary = Summary::default();
+    meta.walk(&mut summary)
+        .await
+        .context("failed to walk flist")?;
+    summary.blocks = meta
+        .blocks_count()
+        .await
+        .context("failed to count flist blocks")?;
+
+    Ok(summary)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn extract_image<R: Reporter>(
     docker: &Docker,
     image_name: &str,
     container_name: &str,
     docker_tmp_dir_path: &Path,
+    source: &ImageSource,
+    platform: &Platform,
     credentials: Option<DockerCredentials>,
+    reporter: &R,
 ) -> Result<()> {
-    pull_image(docker, image_name, credentials).await?;
-    create_container(docker, image_name, container_name)
+    // a loaded image has a single platform, whatever it is. The layers of a pulled
+    // image are checked by docker against the digests of the image manifest, the
+    // layers of an archive are checked while the archive is loaded
+    let platform = match source {
+        ImageSource::Registry => {
+            let platform = platform.to_string();
+            pull_image(docker, image_name, &platform, credentials, reporter).await?;
+            Some(platform)
+        }
+        ImageSource::Archive(path) => {
+            let verifier = load_image(image_name, path)?;
+            verify_image(docker, image_name, verifier)
+                .await
+                .context("failed to validate docker image layers")?;
+            None
+        }
+    };
+    create_container(docker, image_name, container_name, platform.as_deref())
         .await
         .context("failed to create docker container")?;
     export_container(container_name, docker_tmp_dir_path)
@@ -147,27 +548,235 @@ async fn extract_image(
     Ok(())
 }
 
-async fn pull_image(
+async fn pull_image<R: Reporter>(
     docker: &Docker,
     image_name: &str,
+    platform: &str,
     credentials: Option<DockerCredentials>,
+    reporter: &R,
 ) -> Result<()> {
-    log::info!("pulling docker image {}", image_name);
+    check_platform(docker, image_name, platform, credentials.clone()).await?;
+    log::info!("pulling docker image {} ({})", image_name, platform);
 
     let options = Some(CreateImageOptions {
         from_image: image_name,
+        platform,
         ..Default::default()
     });
 
     let mut image_pull_stream = docker.create_image(options, None, credentials);
     while let Some(msg) = image_pull_stream.next().await {
-        msg.context("failed to pull docker image")?;
+        let info = msg.context("failed to pull docker image")?;
+        // messages without a layer id are about the image itself
+        let layer = match info.id {
+            Some(layer) => layer,
+            None => continue,
+        };
+
+        match (info.status.as_deref(), info.progress_detail) {
+            (Some("Downloading"), Some(detail)) => reporter.report(Progress::Downloading {
+                layer,
+                current: detail.current.unwrap_or_default() as u64,
+                total: detail.total.unwrap_or_default() as u64,
+            }),
+            (Some("Pull complete"), _) => reporter.report(Progress::Pulled { layer }),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// check_platform fails if the image in the registry doesn't have the requested
+// platform. If the registry can't be inspected the check is skipped and the pull
+// fails instead if the platform is missing
+async fn check_platform(
+    docker: &Docker,
+    image_name: &str,
+    platform: &str,
+    credentials: Option<DockerCredentials>,
+) -> Result<()> {
+    let inspect = match docker.inspect_registry_image(image_name, credentials).await {
+        Ok(inspect) => inspect,
+        Err(err) => {
+            log::warn!("failed to inspect registry image {}: {}", image_name, err);
+            return Ok(());
+        }
+    };
+
+    let requested: Platform = platform.parse()?;
+    let mut available = Vec::default();
+    for candidate in inspect.platforms.iter() {
+        let os = candidate.os.as_deref().unwrap_or_default();
+        let arch = candidate.architecture.as_deref().unwrap_or_default();
+        let variant = candidate.variant.as_deref();
+        if requested.matches(os, arch, variant) {
+            return Ok(());
+        }
+
+        available.push(match variant {
+            Some(variant) if !variant.is_empty() => format!("{}/{}/{}", os, arch, variant),
+            _ => format!("{}/{}", os, arch),
+        });
     }
 
+    anyhow::bail!(
+        "image {} is not available for platform {}, available platforms: {}",
+        image_name,
+        platform,
+        available.join(", ")
+    )
+}
+
+// load_image loads an image archive into docker and tags the loaded image with the
+// image name, so the rest of the conversion is the same as for a pulled image. The
+// archive is verified while it's streamed to docker, the returned verifier checks its
+// layers against the ones of the loaded image
+fn load_image(image_name: &str, path: &Path) -> Result<layers::Verifier> {
+    log::info!(
+        "loading docker image {} from {}",
+        image_name,
+        path.display()
+    );
+
+    // an OCI image layout directory is loaded as a tarball of its content
+    let mut tar = None;
+    let mut archive: Box<dyn Read> = if path.is_dir() {
+        let mut child = Command::new("tar")
+            .arg("-cf")
+            .arg("-")
+            .arg("-C")
+            .arg(path)
+            .arg(".")
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("failed to archive image layout directory")?;
+        let stdout = child.stdout.take().context("failed to get tar output")?;
+        tar = Some(child);
+        Box::new(stdout)
+    } else {
+        Box::new(fs::File::open(path).context("failed to open image archive")?)
+    };
+
+    let mut load = Command::new("docker")
+        .arg("load")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to run docker load")?;
+    let mut stdin = load
+        .stdin
+        .take()
+        .context("failed to get docker load input")?;
+
+    let mut verifier = layers::Verifier::default();
+    let mut buf = vec![0; LOAD_BUFFER_SIZE];
+    let streamed = loop {
+        let n = match archive.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(anyhow::Error::from(err).context("failed to read image archive")),
+        };
+
+        if let Err(err) = verifier.update(&buf[..n]) {
+            break Err(err.context("failed to validate docker image layers"));
+        }
+        if let Err(err) = stdin.write_all(&buf[..n]) {
+            break Err(anyhow::Error::from(err).context("failed to write image archive to docker"));
+        }
+    };
+    drop(stdin);
+
+    if let Err(err) = streamed {
+        // an invalid archive must not be loaded
+        let _ = load.kill();
+        let _ = load.wait();
+        if let Some(mut tar) = tar {
+            let _ = tar.kill();
+            let _ = tar.wait();
+        }
+        return Err(err);
+    }
+
+    if let Some(mut tar) = tar {
+        let status = tar
+            .wait()
+            .context("failed to archive image layout directory")?;
+        anyhow::ensure!(status.success(), "failed to archive image layout directory");
+    }
+    let output = load
+        .wait_with_output()
+        .context("failed to run docker load")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "failed to load docker image archive: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    // docker prints 'Loaded image: <name>' or 'Loaded image ID: <id>' for an
+    // image without a name
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let loaded = stdout
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("Loaded image: ")
+                .or_else(|| line.strip_prefix("Loaded image ID: "))
+        })
+        .map(str::trim)
+        .context("no image found in the archive")?;
+
+    if loaded != image_name {
+        let output = Command::new("docker")
+            .arg("tag")
+            .arg(loaded)
+            .arg(image_name)
+            .output()
+            .context("failed to run docker tag")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "failed to tag loaded image {}: {}",
+            loaded,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(verifier)
+}
+
+// verify_image checks the layers hashed while the image archive was loaded against
+// the layer digests of the loaded image config, so a corrupted layer fails the
+// conversion before anything is extracted
+async fn verify_image(docker: &Docker, image_name: &str, verifier: layers::Verifier) -> Result<()> {
+    log::debug!("Validating docker image layers {}", image_name);
+
+    let image = docker
+        .inspect_image(image_name)
+        .await
+        .context("failed to inspect docker image")?;
+    let layers = image
+        .root_fs
+        .and_then(|root_fs| root_fs.layers)
+        .context("failed to get docker image layers")?;
+
+    verifier.finish(&layers)?;
+    log::info!(
+        "validated {} layers of docker image {}",
+        layers.len(),
+        image_name
+    );
+
     Ok(())
 }
 
-async fn create_container(docker: &Docker, image_name: &str, container_name: &str) -> Result<()> {
+async fn create_container(
+    docker: &Docker,
+    image_name: &str,
+    container_name: &str,
+    platform: Option<&str>,
+) -> Result<()> {
     log::debug!("Inspecting docker image configurations {}", image_name);
 
     let image = docker
@@ -185,7 +794,7 @@ async fn create_container(docker: &Docker, image_name: &str, container_name: &st
 
     let options = Some(CreateContainerOptions {
         name: container_name,
-        platform: None,
+        platform,
     });
 
     let config = Config {
@@ -209,7 +818,7 @@ fn export_container(container_name: &str, docker_tmp_dir_path: &Path) -> Result<
     Command::new("sh")
         .arg("-c")
         .arg(format!(
-            "docker export {} | tar -xpf - -C {}",
+            "docker export {} | tar --xattrs --xattrs-include='*' -xpf - -C {}",
             container_name,
             docker_tmp_dir_path.display()
         ))
@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+/// Filter selects the files of the extracted image that are packed in the flist.
+///
+/// The patterns are globs matched against the path of a file from the root of the
+/// image, like `/var/cache/**`. A pattern without a `/` matches the file name at
+/// any depth, like `*.pyc`. `*` and `?` match within a path component, `**` matches
+/// across components and `[...]` matches a set of characters.
+///
+/// A file is excluded if it matches any exclude pattern and no include pattern, so
+/// an include always overrides an exclude whatever the order of the patterns. An
+/// excluded directory is pruned with all its content, unless some of its content is
+/// included, in which case only the included content (and the directories leading
+/// to it) is kept.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    includes: Vec<Regex>,
+    excludes: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new<I, E, S>(includes: I, excludes: E) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        E: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Ok(Filter {
+            includes: includes
+                .into_iter()
+                .map(|glob| pattern(glob.as_ref()))
+                .collect::<Result<_>>()?,
+            excludes: excludes
+                .into_iter()
+                .map(|glob| pattern(glob.as_ref()))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.excludes.is_empty()
+    }
+
+    // excluded checks if the file at the path (from the root of the image) is
+    // filtered out, everything in an excluded directory is excluded too
+    fn excluded<P: AsRef<Path>>(&self, path: P, in_excluded: bool) -> bool {
+        let path = format!("/{}", path.as_ref().to_string_lossy().trim_matches('/'));
+        (in_excluded || self.excludes.iter().any(|re| re.is_match(&path)))
+            && !self.includes.iter().any(|re| re.is_match(&path))
+    }
+
+    /// prune removes the excluded files from the extracted image at root and
+    /// returns the number of removed entries, a pruned directory counts as one
+    pub fn prune<P: AsRef<Path>>(&self, root: P) -> Result<usize> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+
+        self.prune_dir(root.as_ref(), Path::new(""), false)
+    }
+
+    fn prune_dir(&self, dir: &Path, relative: &Path, in_excluded: bool) -> Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(dir).with_context(|| format!("failed to read {:?}", dir))? {
+            let entry = entry?;
+            let path = entry.path();
+            let relative = relative.join(entry.file_name());
+            let excluded = self.excluded(&relative, in_excluded);
+
+            // symlinks are not followed, a link to a directory is removed as a file
+            if !entry.file_type()?.is_dir() {
+                if excluded {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("failed to remove {:?}", path))?;
+                    removed += 1;
+                }
+                continue;
+            }
+
+            // without include patterns nothing in an excluded directory is kept
+            if excluded && self.includes.is_empty() {
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("failed to remove {:?}", path))?;
+                removed += 1;
+                continue;
+            }
+
+            removed += self.prune_dir(&path, &relative, excluded)?;
+            if excluded && fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path).with_context(|| format!("failed to remove {:?}", path))?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+// pattern converts a glob to a regex that matches the whole path
+fn pattern(glob: &str) -> Result<Regex> {
+    let trimmed = glob.trim_end_matches('/');
+    // a pattern with a `/` is matched from the root, paths always start with `/`
+    let mut re = match trimmed.contains('/') {
+        true => String::from("^/"),
+        false => String::from("^(?:.*/)?"),
+    };
+
+    let mut chars = trimmed.trim_start_matches('/').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // a `**/` also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                let mut class = String::from("[");
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    class.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+                class.push(']');
+                re.push_str(&class);
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+
+    Regex::new(&re).with_context(|| format!("invalid pattern '{}'", glob))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_excluded() {
+        let filter = Filter::new(
+            ["/usr/share/doc/**/copyright"],
+            ["/var/cache", "*.pyc", "/usr/share/doc/**", "tmp/?.log"],
+        )
+        .unwrap();
+
+        assert!(filter.excluded("var/cache", false));
+        assert!(!filter.excluded("var/cache/apt", false));
+        assert!(filter.excluded("var/cache/apt", true));
+        assert!(!filter.excluded("var/cached", false));
+        assert!(filter.excluded("usr/lib/python3/x.pyc", false));
+        assert!(filter.excluded("x.pyc", false));
+        assert!(filter.excluded("usr/share/doc/bash/README", false));
+        assert!(!filter.excluded("usr/share/doc/bash/copyright", false));
+        assert!(!filter.excluded("usr/share/doc/bash/copyright", true));
+        assert!(filter.excluded("tmp/a.log", false));
+        assert!(!filter.excluded("tmp/ab.log", false));
+        assert!(!filter.excluded("var/tmp/a.log", false));
+
+        let filter = Filter::new(Vec::<&str>::new(), ["/etc/[!p]*", "**/*.md"]).unwrap();
+        assert!(filter.excluded("etc/hosts", false));
+        assert!(!filter.excluded("etc/passwd", false));
+        assert!(filter.excluded("README.md", false));
+        assert!(filter.excluded("usr/share/README.md", false));
+    }
+
+    fn tree(root: &Path) {
+        let _ = fs::remove_dir_all(root);
+        for dir in [
+            "etc",
+            "var/cache/apt/archives",
+            "usr/share/doc/bash",
+            "usr/lib/python3",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "etc/passwd",
+            "var/cache/apt/archives/bash.deb",
+            "usr/share/doc/bash/README",
+            "usr/share/doc/bash/copyright",
+            "usr/lib/python3/x.py",
+            "usr/lib/python3/x.pyc",
+        ] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        std::os::unix::fs::symlink("/var/cache", root.join("cache")).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let root = Path::new("/tmp/docker2fl-filter-test");
+        tree(root);
+
+        let filter = Filter::new(Vec::<&str>::new(), ["/var/cache", "*.pyc", "/cache"]).unwrap();
+        assert_eq!(filter.prune(root).unwrap(), 3);
+        assert!(root.join("var").exists());
+        assert!(!root.join("var/cache").exists());
+        assert!(!root.join("usr/lib/python3/x.pyc").exists());
+        assert!(root.join("usr/lib/python3/x.py").exists());
+        assert!(root.join("usr/share/doc/bash/README").exists());
+        assert!(!root.join("cache").exists());
+
+        // included content of an excluded directory is kept
+        tree(root);
+        let filter = Filter::new(
+            ["/usr/share/doc/**/copyright"],
+            ["/usr/share/doc", "/usr/share/doc/**", "/var/cache"],
+        )
+        .unwrap();
+        filter.prune(root).unwrap();
+        assert!(root.join("usr/share/doc/bash/copyright").exists());
+        assert!(!root.join("usr/share/doc/bash/README").exists());
+        assert!(!root.join("var/cache").exists());
+        assert!(root.join("etc/passwd").exists());
+    }
+}
@@ -0,0 +1,387 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+const BLOCK_SIZE: usize = 512;
+const DIGEST_PREFIX: &str = "sha256:";
+
+/// Verifier validates the layers of an image archive (the output of `docker save`)
+/// while it's streamed to docker. Content addressed blobs are checked against their
+/// names right away, the layers of the legacy format (`<id>/layer.tar`) are hashed
+/// and checked against the layer digests (diff ids) of the image config once it's
+/// loaded, and every entry must be complete so a truncated archive is detected.
+/// The verifier only keeps the current tar header, so its memory doesn't depend on
+/// the size of the layers.
+#[derive(Default)]
+pub struct Verifier {
+    // name and digest of the legacy layers
+    layers: Vec<(String, String)>,
+    header: Vec<u8>,
+    entry: Option<Entry>,
+    done: bool,
+}
+
+struct Entry {
+    name: String,
+    size: u64,
+    remaining: u64,
+    padding: u64,
+    hasher: Option<Sha256>,
+}
+
+impl Verifier {
+    /// update feeds the next chunk of the archive to the verifier
+    pub fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() && !self.done {
+            match self.entry {
+                Some(ref mut entry) => {
+                    if entry.remaining > 0 {
+                        let n = (entry.remaining as usize).min(data.len());
+                        if let Some(ref mut hasher) = entry.hasher {
+                            hasher.update(&data[..n]);
+                        }
+                        entry.remaining -= n as u64;
+                        data = &data[n..];
+                    }
+
+                    if entry.remaining == 0 {
+                        let n = (entry.padding as usize).min(data.len());
+                        entry.padding -= n as u64;
+                        data = &data[n..];
+                    }
+
+                    if entry.remaining == 0 && entry.padding == 0 {
+                        let entry = self.entry.take().unwrap();
+                        self.complete(entry)?;
+                    }
+                }
+                None => {
+                    let n = (BLOCK_SIZE - self.header.len()).min(data.len());
+                    self.header.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+
+                    if self.header.len() == BLOCK_SIZE {
+                        let header = std::mem::take(&mut self.header);
+                        self.begin(&header)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// finish makes sure the archive is complete and that its layers are the layers of
+    /// the image, given as digests in the form sha256:<hex>
+    pub fn finish<I, S>(self, layers: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        if let Some(entry) = self.entry {
+            anyhow::bail!(
+                "image archive is truncated, entry '{}' is missing {} of {} bytes",
+                entry.name,
+                entry.remaining,
+                entry.size
+            );
+        }
+
+        anyhow::ensure!(
+            self.header.is_empty() || self.done,
+            "image archive is truncated"
+        );
+
+        let expected: HashSet<String> = layers
+            .into_iter()
+            .map(|layer| {
+                layer
+                    .as_ref()
+                    .trim_start_matches(DIGEST_PREFIX)
+                    .to_lowercase()
+            })
+            .collect();
+        for (name, digest) in self.layers.iter() {
+            anyhow::ensure!(
+                expected.contains(digest),
+                "image layer {} digest {}{} doesn't match any of the image layers",
+                name,
+                DIGEST_PREFIX,
+                digest
+            );
+        }
+
+        // the layers of an OCI archive are blobs that are already checked, their diff
+        // ids are checked by docker when it decompresses them
+        if !self.layers.is_empty() {
+            let seen: HashSet<&String> = self.layers.iter().map(|(_, digest)| digest).collect();
+            for layer in expected.iter() {
+                anyhow::ensure!(
+                    seen.contains(layer),
+                    "layer {}{} is missing from the image archive",
+                    DIGEST_PREFIX,
+                    layer
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn begin(&mut self, header: &[u8]) -> Result<()> {
+        // the archive ends with zero blocks
+        if header.iter().all(|b| *b == 0) {
+            self.done = true;
+            return Ok(());
+        }
+
+        let name = entry_name(header);
+        let size = entry_size(&header[124..136])
+            .with_context(|| format!("invalid size of archive entry '{}'", name))?;
+        // only regular files have content that is verified, other entries (links,
+        // directories, extended headers) are skipped
+        let regular = matches!(header[156], b'0' | 0);
+        let hasher = match regular && (is_blob(&name) || is_layer(&name)) {
+            true => Some(Sha256::new()),
+            false => None,
+        };
+
+        let padding = (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64;
+        let entry = Entry {
+            name,
+            size,
+            remaining: size,
+            padding,
+            hasher,
+        };
+
+        if size == 0 {
+            return self.complete(entry);
+        }
+
+        self.entry = Some(entry);
+        Ok(())
+    }
+
+    fn complete(&mut self, entry: Entry) -> Result<()> {
+        let hasher = match entry.hasher {
+            Some(hasher) => hasher,
+            None => return Ok(()),
+        };
+
+        let digest = hex::encode(hasher.finalize());
+        if let Some(name) = entry.name.strip_prefix("blobs/sha256/") {
+            anyhow::ensure!(
+                name == digest,
+                "image blob {} digest mismatch got {}{}",
+                entry.name,
+                DIGEST_PREFIX,
+                digest
+            );
+            log::debug!("verified image archive entry {}", entry.name);
+        } else {
+            self.layers.push((entry.name, digest));
+        }
+
+        Ok(())
+    }
+}
+
+fn is_blob(name: &str) -> bool {
+    name.starts_with("blobs/sha256/")
+}
+
+fn is_layer(name: &str) -> bool {
+    name.ends_with("/layer.tar")
+}
+
+fn field(data: &[u8]) -> &[u8] {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    &data[..end]
+}
+
+fn entry_name(header: &[u8]) -> String {
+    let mut name = String::from_utf8_lossy(field(&header[0..100])).into_owned();
+    // ustar archives can split long names into a prefix and a name
+    if &header[257..262] == b"ustar" {
+        let prefix = String::from_utf8_lossy(field(&header[345..500]));
+        if !prefix.is_empty() {
+            name = format!("{}/{}", prefix, name);
+        }
+    }
+
+    // archives of a directory (tar -C <dir> .) have all the names prefixed by ./
+    match name.strip_prefix("./") {
+        Some(name) => name.into(),
+        None => name,
+    }
+}
+
+fn entry_size(data: &[u8]) -> Result<u64> {
+    // big sizes are stored as base-256 numbers with the high bit set
+    if data[0] & 0x80 != 0 {
+        let mut size: u64 = 0;
+        for b in data[1..].iter() {
+            size = (size << 8) | *b as u64;
+        }
+        return Ok(size);
+    }
+
+    let size = String::from_utf8_lossy(field(data));
+    let size = size.trim();
+    if size.is_empty() {
+        return Ok(0);
+    }
+
+    Ok(u64::from_str_radix(size, 8)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let mut header = vec![0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}", data.len());
+        header[124..124 + size.len()].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+        header[257..262].copy_from_slice(b"ustar");
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        archive.extend(std::iter::repeat_n(0, padding));
+    }
+
+    fn digest(data: &[u8]) -> String {
+        format!("{}{}", DIGEST_PREFIX, hex::encode(Sha256::digest(data)))
+    }
+
+    fn archive(layers: &[&[u8]]) -> Vec<u8> {
+        let mut archive = Vec::default();
+        entry(&mut archive, "manifest.json", b"[]");
+        for (index, layer) in layers.iter().enumerate() {
+            entry(&mut archive, &format!("{}/layer.tar", index), layer);
+            let name = format!("blobs/sha256/{}", hex::encode(Sha256::digest(layer)));
+            entry(&mut archive, &name, layer);
+        }
+        archive.extend(std::iter::repeat_n(0, 2 * BLOCK_SIZE));
+        archive
+    }
+
+    fn verify(layers: &[String], archive: &[u8], chunk: usize) -> Result<()> {
+        let mut verifier = Verifier::default();
+        for data in archive.chunks(chunk) {
+            verifier.update(data)?;
+        }
+        verifier.finish(layers)
+    }
+
+    #[test]
+    fn test_verify() {
+        let first = vec![1u8; 1000];
+        let second = vec![2u8; 3 * BLOCK_SIZE];
+        let archive = archive(&[&first, &second]);
+        let layers = vec![digest(&first), digest(&second)];
+
+        for chunk in [1, 100, 512, 4096] {
+            verify(&layers, &archive, chunk).unwrap();
+        }
+    }
+
+    // layer_chunk returns the chunk at index of a synthetic layer of 1M chunks
+    fn layer_chunk(index: u64) -> Vec<u8> {
+        (0..1024 * 1024u64)
+            .map(|i| (i.wrapping_mul(31) ^ index) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_large_layer() {
+        // the layer is generated and fed one chunk at a time, neither the layer nor
+        // the archive is ever held in memory
+        const CHUNKS: u64 = 128;
+        let mut hasher = Sha256::new();
+        for index in 0..CHUNKS {
+            hasher.update(layer_chunk(index));
+        }
+        let layer = format!("{}{}", DIGEST_PREFIX, hex::encode(hasher.finalize()));
+
+        let mut header = Vec::default();
+        entry(&mut header, "0/layer.tar", &[]);
+        // the size field of a layer bigger than 8G is a base-256 number
+        header[124..136].copy_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        header[132..136].copy_from_slice(&((CHUNKS << 20) as u32).to_be_bytes());
+
+        let mut verifier = Verifier::default();
+        verifier.update(&header).unwrap();
+        for index in 0..CHUNKS {
+            verifier.update(&layer_chunk(index)).unwrap();
+        }
+        verifier.update(&[0; 2 * BLOCK_SIZE]).unwrap();
+        verifier.finish([&layer]).unwrap();
+    }
+
+    #[test]
+    fn test_entry_size() {
+        assert_eq!(entry_size(b"00000001750\0").unwrap(), 1000);
+        let mut big = [0u8; 12];
+        big[0] = 0x80;
+        big[7] = 0x02;
+        big[8] = 0x80;
+        assert_eq!(entry_size(&big).unwrap(), 10 << 30);
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let layer = vec![1u8; 1000];
+        let archive = archive(&[&layer]);
+
+        let err = verify(&[digest(b"other layer")], &archive, 100).unwrap_err();
+        assert!(err.to_string().contains("doesn't match"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_blobs() {
+        // the layers of an OCI archive are only checked against their names
+        let layer = vec![1u8; 1000];
+        let mut archive = Vec::default();
+        let name = format!("blobs/sha256/{}", hex::encode(Sha256::digest(&layer)));
+        entry(&mut archive, &name, &layer);
+        archive.extend(std::iter::repeat_n(0, 2 * BLOCK_SIZE));
+        verify(&[digest(b"uncompressed layer")], &archive, 100).unwrap();
+
+        let mut archive = Vec::default();
+        entry(&mut archive, &name, b"corrupted layer");
+        let err = verify(&[digest(&layer)], &archive, 100).unwrap_err();
+        assert!(err.to_string().contains("mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_dir_blobs() {
+        // archives of an OCI layout directory have the names prefixed by ./
+        let layer = vec![1u8; 1000];
+        let name = format!("./blobs/sha256/{}", hex::encode(Sha256::digest(&layer)));
+        let mut archive = Vec::default();
+        entry(&mut archive, "./index.json", b"{}");
+        entry(&mut archive, &name, &layer);
+        archive.extend(std::iter::repeat_n(0, 2 * BLOCK_SIZE));
+        verify(&[digest(b"uncompressed layer")], &archive, 100).unwrap();
+
+        let mut archive = Vec::default();
+        entry(&mut archive, "./index.json", b"{}");
+        entry(&mut archive, &name, b"corrupted layer");
+        let err = verify(&[digest(&layer)], &archive, 100).unwrap_err();
+        assert!(err.to_string().contains("mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_truncated() {
+        let layer = vec![1u8; 4000];
+        let archive = archive(&[&layer]);
+
+        let err = verify(&[digest(&layer)], &archive[..2000], 100).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+}
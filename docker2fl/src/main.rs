@@ -1,13 +1,18 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bollard::auth::DockerCredentials;
 use clap::{ArgAction, Parser};
+use docker2fl::{Platform, Progress, Reporter};
 use rfs::fungi;
-use rfs::store::parse_router;
+use rfs::store::counting::CountingStore;
+use rfs::store::{parse_router_with, Sharding, Store};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use tokio::runtime::Builder;
 use uuid::Uuid;
 
-mod docker2fl;
-
 #[derive(Parser, Debug)]
 #[clap(name ="docker2fl", author, version = env!("GIT_VERSION"), about, long_about = None)]
 struct Options {
@@ -17,12 +22,33 @@ struct Options {
 
     /// store url for rfs in the format [xx-xx=]<url>. the range xx-xx is optional and used for
     /// sharding. the URL is per store type, please check docs for more information
-    #[clap(short, long, required = true, action=ArgAction::Append)]
+    #[clap(short, long, required_unless_present = "dry_run", action=ArgAction::Append)]
     store: Vec<String>,
 
-    /// name of the docker image to be converted to flist
-    #[clap(short, long, required = true)]
-    image_name: String,
+    /// how the blocks are spread over the stores: range (by the key range of each store)
+    /// or ring (consistent hashing, adding a store only moves a fraction of the blocks)
+    #[clap(long, default_value_t = Sharding::Range)]
+    sharding: Sharding,
+
+    /// name of the docker image to be converted to flist. can be repeated to merge several
+    /// images into one flist, a later image overrides the earlier ones on path conflicts
+    #[clap(short, long, required = true, action=ArgAction::Append)]
+    image_name: Vec<String>,
+
+    /// convert the image from a `docker save` tarball instead of pulling it from a registry,
+    /// the loaded image is tagged with the image name
+    #[clap(long, conflicts_with = "oci_archive")]
+    docker_archive: Option<PathBuf>,
+
+    /// convert the image from an OCI image layout (a directory or a tarball) instead of
+    /// pulling it from a registry, the loaded image is tagged with the image name
+    #[clap(long)]
+    oci_archive: Option<PathBuf>,
+
+    /// platform to pull from a multi-arch image in the format os/arch[/variant],
+    /// for example linux/arm64. defaults to the platform of the host
+    #[clap(long)]
+    platform: Option<Platform>,
 
     // docker credentials
     /// docker hub server username
@@ -52,9 +78,183 @@ struct Options {
     /// docker hub server registry token
     #[clap(long, required = false)]
     registry_token: Option<String>,
+
+    /// print the result as a single json object on stdout, logs are written to stderr
+    #[clap(long, default_value_t = false)]
+    json: bool,
+
+    /// run the full conversion without uploading any blocks or creating the flist,
+    /// only a summary of the conversion is reported
+    #[clap(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// number of blocks of a file that are uploaded to the store at the same time
+    #[clap(long, default_value_t = docker2fl::DEFAULT_UPLOAD_CONCURRENCY)]
+    upload_concurrency: usize,
+
+    /// number of blocks that are buffered and written to the store in one batch, zdb
+    /// stores write a batch in one round trip. 1 writes each block right away
+    #[clap(long, default_value_t = docker2fl::DEFAULT_BATCH_SIZE)]
+    batch_size: usize,
+
+    /// size in bytes of the blocks the files are split into. smaller blocks give better dedup
+    /// between images but more blocks to upload and a bigger fl, bigger blocks give a better
+    /// throughput. a block size other than the default can't be mounted by older rfs versions
+    #[clap(long, default_value_t = rfs::chunker::BLOCK_SIZE)]
+    block_size: usize,
+
+    /// chunking algorithm used to split files into blocks. cdc (content defined chunking) gives
+    /// better dedup between versions of the same image but the fl can't be mounted by older rfs
+    /// versions
+    #[clap(long, default_value = "fixed", value_parser = ["fixed", "cdc"])]
+    chunker: String,
+
+    /// minimum block size for cdc chunker
+    #[clap(long, default_value_t = rfs::chunker::CDC_MIN_SIZE)]
+    cdc_min: usize,
+
+    /// average block size for cdc chunker, must be a power of 2
+    #[clap(long, default_value_t = rfs::chunker::CDC_AVG_SIZE)]
+    cdc_avg: usize,
+
+    /// maximum block size for cdc chunker
+    #[clap(long, default_value_t = rfs::chunker::CDC_MAX_SIZE)]
+    cdc_max: usize,
+
+    /// exclude the files of the image matching the glob, like /var/cache/** or *.pyc (a
+    /// pattern without a / matches the file name at any depth). can be repeated
+    #[clap(long, action=ArgAction::Append)]
+    exclude: Vec<String>,
+
+    /// keep the files matching the glob even if they are excluded, an include always
+    /// overrides an exclude. can be repeated
+    #[clap(long, action=ArgAction::Append)]
+    include: Vec<String>,
+
+    /// skip the blocks that are already in the store instead of uploading them again
+    #[clap(long, default_value_t = true, action = ArgAction::Set)]
+    skip_existing: bool,
+
+    /// show the progress of the conversion on stderr
+    #[clap(long, default_value_t = false)]
+    progress: bool,
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+const BAR_WIDTH: usize = 30;
+
+/// ProgressBar renders the progress of a conversion on a single line of stderr
+#[derive(Default)]
+struct ProgressBar {
+    state: Mutex<BarState>,
+}
+
+#[derive(Default)]
+struct BarState {
+    // downloaded and total bytes per layer
+    layers: HashMap<String, (u64, u64)>,
+    pulled: usize,
+    packed: usize,
+    files: usize,
+    blocks: u64,
+    bytes: u64,
+    skipped: u64,
+}
+
+impl Reporter for ProgressBar {
+    fn report(&self, progress: Progress) {
+        let mut state = self.state.lock().unwrap();
+        match progress {
+            Progress::Downloading {
+                layer,
+                current,
+                total,
+            } => {
+                state.layers.insert(layer, (current, total));
+            }
+            Progress::Pulled { .. } => state.pulled += 1,
+            Progress::Exported { files } => {
+                state.files = files;
+                eprintln!();
+            }
+            Progress::Packed { packed, files } => {
+                state.packed = packed;
+                state.files = files;
+            }
+            Progress::Uploaded { size } => {
+                state.blocks += 1;
+                state.bytes += size;
+            }
+            Progress::Skipped { .. } => state.skipped += 1,
+        }
+
+        let line = match (state.packed * BAR_WIDTH).checked_div(state.files) {
+            // the files are only known once the image is extracted
+            None => {
+                let (current, total) = state
+                    .layers
+                    .values()
+                    .fold((0, 0), |(c, t), (current, total)| (c + current, t + total));
+                format!(
+                    "pulling: {} layers pulled, {:.1}/{:.1} MiB downloaded",
+                    state.pulled,
+                    current as f64 / MIB,
+                    total as f64 / MIB
+                )
+            }
+            Some(done) => {
+                let done = done.min(BAR_WIDTH);
+                format!(
+                    "packing: [{}{}] {}/{} files, {} blocks ({:.1} MiB) uploaded, {} skipped",
+                    "=".repeat(done),
+                    " ".repeat(BAR_WIDTH - done),
+                    state.packed,
+                    state.files,
+                    state.blocks,
+                    state.bytes as f64 / MIB,
+                    state.skipped
+                )
+            }
+        };
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        eprintln!();
+    }
+}
+
+/// StderrLogger is used in json mode to keep stdout for the json result only
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        // same as the module level set on the default logger
+        !(metadata.target().starts_with("sqlx") && metadata.level() > log::Level::Error)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{:<5} [{}] {}",
+                record.level(),
+                record.module_path().unwrap_or_default(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
 }
 
 fn main() -> Result<()> {
+    // no thread is started yet
+    unsafe { rfs::store::tls::install_system_ca() };
     let rt = Builder::new_multi_thread()
         .thread_stack_size(8 * 1024 * 1024)
         .enable_all()
@@ -66,23 +266,151 @@ fn main() -> Result<()> {
 async fn run() -> Result<()> {
     let opts = Options::parse();
 
-    simple_logger::SimpleLogger::new()
-        .with_utc_timestamps()
-        .with_level({
-            match opts.debug {
-                0 => log::LevelFilter::Info,
-                1 => log::LevelFilter::Debug,
-                _ => log::LevelFilter::Trace,
-            }
-        })
-        .with_module_level("sqlx", log::Level::Error.to_level_filter())
-        .init()?;
+    let level = match opts.debug {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
 
-    let mut docker_image = opts.image_name.to_string();
+    if opts.json {
+        static LOGGER: StderrLogger = StderrLogger;
+        log::set_logger(&LOGGER)?;
+        log::set_max_level(level);
+    } else {
+        simple_logger::SimpleLogger::new()
+            .with_utc_timestamps()
+            .with_level(level)
+            .with_module_level("sqlx", log::Level::Error.to_level_filter())
+            .init()?;
+    }
+
+    let json = opts.json;
+    let result = match opts.dry_run {
+        true => dry_run(opts).await,
+        false => create(opts).await,
+    };
+
+    let output = match result {
+        Ok(output) => output,
+        Err(err) if json => {
+            println!("{}", json!({ "error": format!("{:#}", err) }));
+            std::process::exit(1);
+        }
+        Err(err) => return Err(err),
+    };
+
+    if json {
+        println!("{}", output);
+    }
+
+    Ok(())
+}
+
+fn fl_name(image_name: &str) -> String {
+    image_name.replace([':', '/'], "-") + ".fl"
+}
+
+fn image_name(name: &str) -> String {
+    let mut docker_image = name.to_string();
     if !docker_image.contains(':') {
         docker_image.push_str(":latest");
     }
 
+    docker_image
+}
+
+// image_names returns the images to convert in order, the flist is named after the
+// last one
+fn image_names(opts: &Options) -> Vec<String> {
+    opts.image_name
+        .iter()
+        .map(|name| image_name(name))
+        .collect()
+}
+
+// create converts the docker image to an flist in the current directory
+async fn create(opts: Options) -> Result<serde_json::Value> {
+    let fl_name = fl_name(image_names(&opts).last().context("no image name")?);
+    let store = parse_router_with(&opts.store, opts.sharding).await?;
+
+    let res = convert(opts, &fl_name, store).await;
+
+    // remove the file created with the writer if fl creation failed
+    if let Err(err) = res {
+        tokio::fs::remove_file(fl_name).await?;
+        return Err(err);
+    }
+
+    report(&fl_name).await
+}
+
+// dry_run runs the full conversion but blocks are dropped instead of being uploaded
+// and the flist is created in a temporary directory that is removed afterwards
+async fn dry_run(opts: Options) -> Result<serde_json::Value> {
+    let tmp = tempdir::TempDir::new("docker2fl-dry-run")?;
+    let fl_name = tmp
+        .path()
+        .join(fl_name(image_names(&opts).last().context("no image name")?));
+    let store = CountingStore::default();
+    let chunker = chunker(&opts)?;
+
+    convert(opts, &fl_name, store.clone()).await?;
+
+    let meta = fungi::Reader::new(&fl_name).await?;
+    let summary = docker2fl::summary(&meta).await?;
+    let stats = store.stats();
+    let dedup = dedup(stats.blocks, stats.unique_blocks);
+    log::info!(
+        "dry run ({}): {} files, {} directories, {} links, {} bytes, {} blocks",
+        chunker,
+        summary.files,
+        summary.directories,
+        summary.links,
+        summary.size,
+        summary.blocks
+    );
+    log::info!(
+        "dry run: {} unique blocks ({:.1}% deduplicated), {} bytes to upload",
+        stats.unique_blocks,
+        dedup * 100.0,
+        stats.unique_bytes
+    );
+
+    Ok(json!({
+        "dry_run": true,
+        "chunker": chunker.to_string(),
+        "summary": summary,
+        "upload": {
+            "blocks": stats.unique_blocks,
+            "bytes": stats.unique_bytes,
+            "dedup": dedup,
+        },
+    }))
+}
+
+// dedup is the ratio of blocks that didn't need to be uploaded because they're duplicates
+fn dedup(blocks: u64, unique: u64) -> f64 {
+    if blocks == 0 {
+        return 0.0;
+    }
+
+    (blocks - unique) as f64 / blocks as f64
+}
+
+// chunker returns the chunker selected by the options
+fn chunker(opts: &Options) -> Result<rfs::Chunker> {
+    match opts.chunker.as_str() {
+        "cdc" => rfs::Chunker::cdc(opts.cdc_min, opts.cdc_avg, opts.cdc_max),
+        _ => rfs::Chunker::sized(opts.block_size),
+    }
+    .map_err(anyhow::Error::msg)
+}
+
+async fn convert<P: AsRef<Path>, S: Store>(opts: Options, fl_name: P, store: S) -> Result<()> {
+    let mut images = image_names(&opts).into_iter();
+    let docker_image = images.next().context("no image name")?;
+    let chunker = chunker(&opts)?;
+
     let credentials = Some(DockerCredentials {
         username: opts.username,
         password: opts.password,
@@ -93,23 +421,74 @@ async fn run() -> Result<()> {
         registrytoken: opts.registry_token,
     });
 
-    let fl_name = docker_image.replace([':', '/'], "-") + ".fl";
-    let meta = fungi::Writer::new(&fl_name, true).await?;
-    let store = parse_router(&opts.store).await?;
+    let meta = fungi::Writer::new(fl_name, true).await?;
 
     let container_name = Uuid::new_v4().to_string();
     let docker_tmp_dir =
         tempdir::TempDir::new(&container_name).expect("failed to create tmp directory");
 
+    // archives are loaded locally, credentials are only used to pull from a registry
+    let source = match opts.docker_archive.or(opts.oci_archive) {
+        Some(path) => docker2fl::ImageSource::Archive(path),
+        None => docker2fl::ImageSource::Registry,
+    };
+
+    let filter = docker2fl::Filter::new(&opts.include, &opts.exclude)?;
+    if opts.chunker == "cdc" && opts.block_size != rfs::chunker::BLOCK_SIZE {
+        log::warn!("block size is ignored by the cdc chunker, use the cdc sizes instead");
+    }
+
     let mut docker_to_fl =
-        docker2fl::DockerImageToFlist::new(meta, docker_image, credentials, docker_tmp_dir);
-    let res = docker_to_fl.convert(store, None).await;
+        docker2fl::DockerImageToFlist::new(meta, docker_image, credentials, docker_tmp_dir)
+            .with_upload_concurrency(opts.upload_concurrency)
+            .with_batch_size(opts.batch_size)
+            .with_source(source)
+            .with_platform(opts.platform.unwrap_or_else(Platform::host))
+            .with_skip_existing(opts.skip_existing)
+            .with_chunker(chunker)
+            .with_filter(filter)
+            .with_overlays(images.collect());
 
-    // remove the file created with the writer if fl creation failed
-    if res.is_err() {
-        tokio::fs::remove_file(fl_name).await?;
-        return res;
+    if opts.progress {
+        docker_to_fl.convert(store, ProgressBar::default()).await
+    } else {
+        docker_to_fl.convert(store, ()).await
     }
+}
 
-    Ok(())
+// report builds the json result of a created flist
+async fn report(fl_name: &str) -> Result<serde_json::Value> {
+    let bytes = tokio::fs::read(fl_name).await?;
+    let meta = fungi::Reader::new(fl_name).await?;
+    let summary = docker2fl::summary(&meta).await?;
+    log::info!(
+        "flist: {} files, {} directories, {} links, {} bytes, {} blocks",
+        summary.files,
+        summary.directories,
+        summary.links,
+        summary.size,
+        summary.blocks
+    );
+
+    let routes: Vec<serde_json::Value> = meta
+        .routes()
+        .await?
+        .into_iter()
+        .map(|route| {
+            json!({
+                "start": route.start,
+                "end": route.end,
+                "url": route.url,
+            })
+        })
+        .collect();
+
+    let path = std::fs::canonicalize(fl_name)?;
+
+    Ok(json!({
+        "flist": path,
+        "digest": format!("sha256:{}", sha256::digest(&bytes)),
+        "summary": summary,
+        "routes": routes,
+    }))
 }
@@ -0,0 +1,248 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+// whiteout entries as used by image layers
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// Stats of a merge
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// entries of the upper tree that replaced an entry of the lower tree
+    pub replaced: usize,
+    /// entries of the lower tree that were removed by a whiteout
+    pub removed: usize,
+}
+
+/// merge moves the content of the upper tree into the lower tree, so the lower tree
+/// becomes the union of both where the upper tree wins on conflicts:
+/// - directories that are in both trees are merged recursively, the directory gets
+///   the mode and owner of the upper tree
+/// - any other entry of the upper tree replaces the entry at the same path in the
+///   lower tree, a file replaces a whole directory and the other way around
+/// - a whiteout `.wh.<name>` in the upper tree removes `<name>` from the lower
+///   tree, and an opaque whiteout `.wh..wh..opq` removes all the lower content of
+///   its directory. Whiteouts are never kept in the merged tree
+///
+/// The upper tree is consumed, both trees must be on the same file system
+pub fn merge<P: AsRef<Path>, Q: AsRef<Path>>(upper: P, lower: Q) -> Result<Stats> {
+    let mut stats = Stats::default();
+    merge_dir(upper.as_ref(), lower.as_ref(), &mut stats)?;
+    Ok(stats)
+}
+
+fn merge_dir(upper: &Path, lower: &Path, stats: &mut Stats) -> Result<()> {
+    let mut entries = Vec::default();
+    for entry in fs::read_dir(upper).with_context(|| format!("failed to read {:?}", upper))? {
+        entries.push(entry?);
+    }
+
+    // whiteouts apply to the lower tree only, so they are handled before any entry
+    // of the upper directory is moved
+    for entry in entries.iter() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name == OPAQUE {
+            for lower_entry in fs::read_dir(lower)? {
+                remove(&lower_entry?.path())?;
+                stats.removed += 1;
+            }
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            // the hidden name must be an entry of this directory, `.wh..` would
+            // otherwise remove the parent of the lower directory
+            if hidden.is_empty() || hidden == "." || hidden == ".." || hidden.contains('/') {
+                bail!("invalid whiteout {:?} in {:?}", name, upper);
+            }
+            let path = lower.join(hidden);
+            if fs::symlink_metadata(&path).is_ok() {
+                remove(&path)?;
+                stats.removed += 1;
+            }
+        }
+    }
+
+    for entry in entries {
+        let source = entry.path();
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(WHITEOUT_PREFIX)
+        {
+            continue;
+        }
+
+        let target = lower.join(entry.file_name());
+        let existing = fs::symlink_metadata(&target).ok();
+        // symlinks are not followed, a link to a directory is not merged
+        if entry.file_type()?.is_dir() && existing.as_ref().is_some_and(|m| m.is_dir()) {
+            merge_dir(&source, &target, stats)?;
+            attributes(&source, &target)?;
+            continue;
+        }
+
+        if existing.is_some() {
+            remove(&target)?;
+            stats.replaced += 1;
+        }
+
+        fs::rename(&source, &target)
+            .with_context(|| format!("failed to move {:?} to {:?}", source, target))?;
+        if entry.file_type()?.is_dir() {
+            strip_whiteouts(&target)?;
+        }
+    }
+
+    Ok(())
+}
+
+// strip_whiteouts removes the whiteouts of a directory that has nothing to merge with
+fn strip_whiteouts(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(WHITEOUT_PREFIX)
+        {
+            remove(&entry.path())?;
+        } else if entry.file_type()?.is_dir() {
+            strip_whiteouts(&entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+// attributes copies the mode and the owner of a directory
+fn attributes(source: &Path, target: &Path) -> Result<()> {
+    let meta = fs::metadata(source)?;
+    fs::set_permissions(target, meta.permissions())?;
+    std::os::unix::fs::chown(target, Some(meta.uid()), Some(meta.gid()))
+        .with_context(|| format!("failed to set the owner of {:?}", target))
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+    .with_context(|| format!("failed to remove {:?}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    fn tree(root: &Path, dirs: &[&str], files: &[&str]) {
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        for dir in dirs {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in files {
+            fs::write(
+                root.join(file),
+                root.join(file).to_string_lossy().as_bytes(),
+            )
+            .unwrap();
+        }
+    }
+
+    fn content(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_merge() {
+        let lower = Path::new("/tmp/docker2fl-merge-test/lower");
+        let upper = Path::new("/tmp/docker2fl-merge-test/upper");
+        tree(
+            lower,
+            &["etc", "usr/lib", "opt/app", "data", "var/log"],
+            &[
+                "etc/passwd",
+                "etc/hosts",
+                "usr/lib/libc.so",
+                "opt/app/old",
+                "data/a",
+                "var/log/x.log",
+                "config",
+            ],
+        );
+        tree(
+            upper,
+            &["etc", "opt/app", "config", "var/log", "srv/www"],
+            &[
+                "etc/passwd",
+                "etc/.wh.hosts",
+                "opt/app/.wh..wh..opq",
+                "opt/app/new",
+                "config/app.toml",
+                "var/log/y.log",
+                ".wh.data",
+                "srv/www/.wh.index.html",
+            ],
+        );
+        symlink("/usr/lib", upper.join("lib")).unwrap();
+        fs::set_permissions(upper.join("var/log"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let stats = merge(upper, lower).unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                replaced: 2,
+                removed: 3,
+            }
+        );
+
+        // upper files win, lower files are kept
+        assert_eq!(
+            content(&lower.join("etc/passwd")),
+            upper.join("etc/passwd").to_string_lossy()
+        );
+        assert!(lower.join("usr/lib/libc.so").exists());
+        assert!(lower.join("var/log/x.log").exists());
+        assert!(lower.join("var/log/y.log").exists());
+        let mode = fs::metadata(lower.join("var/log"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // whiteouts remove lower entries and are not kept
+        assert!(!lower.join("etc/hosts").exists());
+        assert!(!lower.join("etc/.wh.hosts").exists());
+        assert!(!lower.join("data").exists());
+        assert!(!lower.join("opt/app/old").exists());
+        assert!(lower.join("opt/app/new").exists());
+        assert!(!lower.join("opt/app/.wh..wh..opq").exists());
+        assert!(lower.join("srv/www").exists());
+        assert!(!lower.join("srv/www/.wh.index.html").exists());
+
+        // a directory replaces a file and a symlink is moved as is
+        assert!(lower.join("config/app.toml").exists());
+        assert_eq!(
+            fs::read_link(lower.join("lib")).unwrap(),
+            Path::new("/usr/lib")
+        );
+    }
+
+    #[test]
+    fn test_merge_invalid_whiteout() {
+        for name in [".wh.", ".wh..", ".wh..."] {
+            let lower = Path::new("/tmp/docker2fl-merge-whiteout-test/lower");
+            let upper = Path::new("/tmp/docker2fl-merge-whiteout-test/upper");
+            tree(lower, &["etc"], &["etc/passwd"]);
+            tree(upper, &["etc"], &[]);
+            fs::write(upper.join("etc").join(name), "").unwrap();
+
+            assert!(merge(upper, lower).is_err(), "{}", name);
+            assert!(lower.join("etc/passwd").exists(), "{}", name);
+        }
+    }
+}
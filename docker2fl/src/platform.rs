@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Platform of an image in the form os/arch[/variant], for example linux/arm64
+/// or linux/arm/v7
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub arch: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// host returns the platform of the running binary, images are always linux
+    pub fn host() -> Self {
+        let arch = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            arch => arch,
+        };
+
+        Platform {
+            os: "linux".into(),
+            arch: arch.into(),
+            variant: None,
+        }
+    }
+
+    /// matches checks if this (requested) platform is satisfied by the given
+    /// platform of an image. The variant is only compared if it's requested, arm64
+    /// images without a variant are v8
+    pub fn matches(&self, os: &str, arch: &str, variant: Option<&str>) -> bool {
+        if self.os != os || self.arch != arch {
+            return false;
+        }
+
+        let variant = match variant {
+            None | Some("") if arch == "arm64" => Some("v8"),
+            Some("") => None,
+            variant => variant,
+        };
+
+        match self.variant {
+            Some(ref requested) => Some(requested.as_str()) == variant,
+            None => true,
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut parts = value.split('/');
+        let os = parts.next().filter(|os| !os.is_empty());
+        let arch = parts.next().filter(|arch| !arch.is_empty());
+        let variant = parts.next().map(String::from);
+
+        let (os, arch) = os
+            .zip(arch)
+            .filter(|_| parts.next().is_none())
+            .with_context(|| format!("invalid platform '{}', expected os/arch[/variant]", value))?;
+
+        Ok(Platform {
+            os: os.into(),
+            arch: arch.into(),
+            variant,
+        })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.arch)?;
+        if let Some(ref variant) = self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let platform: Platform = "linux/arm/v7".parse().unwrap();
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.arch, "arm");
+        assert_eq!(platform.variant.as_deref(), Some("v7"));
+        assert_eq!(platform.to_string(), "linux/arm/v7");
+
+        assert_eq!(
+            "linux/amd64".parse::<Platform>().unwrap().to_string(),
+            "linux/amd64"
+        );
+
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux/".parse::<Platform>().is_err());
+        assert!("linux/arm/v7/x".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let arm64: Platform = "linux/arm64".parse().unwrap();
+        assert!(arm64.matches("linux", "arm64", None));
+        assert!(arm64.matches("linux", "arm64", Some("v8")));
+        assert!(!arm64.matches("linux", "amd64", None));
+        assert!(!arm64.matches("windows", "arm64", None));
+
+        let v8: Platform = "linux/arm64/v8".parse().unwrap();
+        assert!(v8.matches("linux", "arm64", None));
+        assert!(v8.matches("linux", "arm64", Some("")));
+
+        let v7: Platform = "linux/arm/v7".parse().unwrap();
+        assert!(v7.matches("linux", "arm", Some("v7")));
+        assert!(!v7.matches("linux", "arm", Some("v6")));
+        assert!(!v7.matches("linux", "arm", None));
+    }
+}
@@ -5,10 +5,13 @@ use axum::{
     http::{self, StatusCode},
     middleware::Next,
     response::IntoResponse,
+    Extension,
 };
 use axum_macros::debug_handler;
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use jsonwebtoken::{
+    decode, encode, errors::ErrorKind, DecodingKey, EncodingKey, Header, TokenData, Validation,
+};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
@@ -77,6 +80,35 @@ pub async fn sign_in_handler(
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/api/refresh",
+    responses(
+        (status = 201, description = "Token refreshed successfully", body = SignInResponse),
+        (status = 401, description = "Token is expired"),
+        (status = 403, description = "Forbidden"),
+        (status = 500, description = "Internal server error"),
+    )
+)]
+#[debug_handler]
+pub async fn refresh_handler(
+    State(state): State<Arc<config::AppState>>,
+    Extension(username): Extension<String>,
+) -> impl IntoResponse {
+    // the token is already validated by the authorize middleware, an expired
+    // token can't be refreshed
+    let token = encode_jwt(
+        username,
+        state.config.jwt_secret.clone(),
+        state.config.jwt_expire_hours,
+    )
+    .map_err(|_| ResponseError::InternalServerError)?;
+
+    Ok::<_, ResponseError>(ResponseResult::SignedIn(SignInResponse {
+        access_token: token,
+    }))
+}
+
 pub fn encode_jwt(
     username: String,
     jwt_secret: String,
@@ -95,14 +127,21 @@ pub fn encode_jwt(
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-pub fn decode_jwt(jwt_token: String, jwt_secret: String) -> Result<TokenData<Claims>, StatusCode> {
-    let result: Result<TokenData<Claims>, StatusCode> = decode(
+/// decode_jwt validates the token, the expiry is checked with a leeway in seconds
+/// to tolerate the clock skew between the server and the clients
+pub fn decode_jwt(
+    jwt_token: String,
+    jwt_secret: String,
+    leeway: u64,
+) -> jsonwebtoken::errors::Result<TokenData<Claims>> {
+    let mut validation = Validation::default();
+    validation.leeway = leeway;
+
+    decode(
         &jwt_token,
         &DecodingKey::from_secret(jwt_secret.as_ref()),
-        &Validation::default(),
+        &validation,
     )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
-    result
 }
 
 pub async fn authorize(
@@ -131,8 +170,17 @@ pub async fn authorize(
         }
     };
 
-    let token_data = match decode_jwt(token_str, state.config.jwt_secret.clone()) {
+    let token_data = match decode_jwt(
+        token_str,
+        state.config.jwt_secret.clone(),
+        state.config.jwt_leeway_secs,
+    ) {
         Ok(data) => data,
+        Err(err) if matches!(err.kind(), ErrorKind::ExpiredSignature) => {
+            return Err(ResponseError::Unauthorized(
+                "JWT token is expired, please sign in again".to_string(),
+            ))
+        }
         Err(_) => {
             return Err(ResponseError::Forbidden(
                 "Unable to decode JWT token".to_string(),
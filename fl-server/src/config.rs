@@ -1,16 +1,22 @@
 use anyhow::{Context, Result};
+use axum::http::HeaderValue;
+use rfs::{
+    cache::Cache,
+    store::{Router, Stores},
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fs,
     path::PathBuf,
     sync::{Arc, Mutex},
+    time::SystemTime,
 };
 use utoipa::ToSchema;
 
 use crate::{
     db::{User, DB},
-    handlers,
+    handlers, history, limits, queue, webhook,
 };
 
 #[derive(Debug, ToSchema, Serialize, Clone)]
@@ -22,8 +28,15 @@ pub struct Job {
 pub struct AppState {
     pub jobs_state: Mutex<HashMap<String, handlers::FlistState>>,
     pub flists_progress: Mutex<HashMap<PathBuf, f32>>,
+    /// summaries of the flists and the modification time of the flist they are read from
+    pub flists_meta: Mutex<HashMap<PathBuf, (Option<SystemTime>, handlers::FlistMeta)>>,
+    /// cache of the blocks of the served flist contents, None if they are not served
+    pub content: Option<Arc<Cache<Router<Stores>>>>,
     pub db: Arc<dyn DB>,
     pub config: Config,
+    pub queue: queue::Queue,
+    pub history: history::History,
+    pub limiter: limits::RateLimiter,
 }
 
 #[derive(Debug, Default, Clone, Deserialize)]
@@ -35,7 +48,78 @@ pub struct Config {
 
     pub jwt_secret: String,
     pub jwt_expire_hours: i64,
+    /// seconds a token is still accepted after its expiry to tolerate clock skew
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub jwt_leeway_secs: u64,
     pub users: Vec<User>,
+
+    /// number of conversions that run at the same time
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// maximum number of conversions waiting in the queue
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    /// file where the queued and running jobs are recorded, so the jobs
+    /// interrupted by a restart can be reported
+    pub jobs_file: Option<String>,
+    /// enqueue the interrupted jobs again on start
+    #[serde(default)]
+    pub requeue_interrupted: bool,
+    /// file where the final states of the jobs are recorded, so they are still
+    /// reported after a restart
+    pub states_file: Option<String>,
+    /// seconds the final state of a job is kept after the job is done
+    #[serde(default = "default_jobs_retention_secs")]
+    pub jobs_retention_secs: u64,
+
+    /// maximum number of conversion requests of a user per minute
+    pub user_requests_per_minute: Option<usize>,
+    /// maximum number of queued and running conversions of a user
+    pub user_max_jobs: Option<usize>,
+
+    /// url notified once a conversion is done, a request can set its own
+    pub webhook_url: Option<String>,
+
+    /// maximum size in bytes of the body of a conversion request, bigger requests
+    /// are rejected with 413
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+
+    /// origins of the web frontends allowed to call the api, `*` allows any origin
+    /// but then browsers don't send credentials
+    #[serde(default = "default_cors_origins")]
+    pub cors_origins: Vec<String>,
+
+    /// directory where the blocks of the served flist contents are cached, the
+    /// contents of the flists are only served if it's set
+    pub content_cache: Option<String>,
+    /// maximum size of the content cache in bytes, the least recently used blocks
+    /// are evicted once it's full
+    pub content_cache_size: Option<u64>,
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+fn default_jobs_retention_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_cors_origins() -> Vec<String> {
+    vec!["*".into()]
+}
+
+fn default_workers() -> usize {
+    4
+}
+
+fn default_queue_size() -> usize {
+    100
+}
+
+fn default_max_body_size() -> usize {
+    16 * 1024
 }
 
 /// Parse the config file into Config struct.
@@ -59,5 +143,101 @@ pub async fn parse_config(filepath: &str) -> Result<Config> {
         ))
     }
 
+    // a leeway longer than the token life would keep tokens valid for twice as long
+    if c.jwt_leeway_secs > 3600 {
+        anyhow::bail!(
+            "jwt leeway in seconds '{}' is invalid, must be at most 3600",
+            c.jwt_leeway_secs
+        )
+    }
+
+    if c.workers < 1 {
+        anyhow::bail!("workers '{}' is invalid, must be at least 1", c.workers)
+    }
+
+    if let Some(ref url) = c.webhook_url {
+        webhook::validate(url).map_err(anyhow::Error::msg)?;
+    }
+
+    if c.user_requests_per_minute == Some(0) {
+        anyhow::bail!("user_requests_per_minute '0' is invalid, must be at least 1")
+    }
+
+    if c.user_max_jobs == Some(0) {
+        anyhow::bail!("user_max_jobs '0' is invalid, must be at least 1")
+    }
+
+    if c.max_body_size < 1 {
+        anyhow::bail!("max_body_size '0' is invalid, must be at least 1")
+    }
+
+    allowed_origins(&c.cors_origins)?;
+
+    if let Some(ref dir) = c.content_cache {
+        fs::create_dir_all(dir).context("failed to create content cache directory")?;
+    }
+
+    if c.content_cache_size == Some(0) {
+        anyhow::bail!("content_cache_size '0' is invalid, must be at least 1")
+    }
+
     Ok(c)
 }
+
+/// content_cache creates the cache the flist contents are served from, the blocks
+/// are downloaded from the configured stores
+pub async fn content_cache(c: &Config) -> Result<Option<Arc<Cache<Router<Stores>>>>> {
+    let dir = match c.content_cache {
+        Some(ref dir) => dir,
+        None => return Ok(None),
+    };
+
+    let router = rfs::store::parse_router(&c.store_url)
+        .await
+        .context("failed to parse store urls")?;
+    let cache = match c.content_cache_size {
+        Some(max) => Cache::with_limit(dir, router, max),
+        None => Cache::new(dir, router),
+    };
+
+    Ok(Some(Arc::new(cache)))
+}
+
+/// allowed_origins validates the cors origins of the config and returns them as
+/// header values, None is returned if any origin is allowed
+pub fn allowed_origins(origins: &[String]) -> Result<Option<Vec<HeaderValue>>> {
+    if origins.is_empty() {
+        anyhow::bail!("cors_origins is empty, use '*' to allow any origin")
+    }
+
+    if origins.iter().any(|origin| origin == "*") {
+        if origins.len() > 1 {
+            anyhow::bail!("cors_origins '*' can't be mixed with other origins")
+        }
+        return Ok(None);
+    }
+
+    let mut allowed = Vec::with_capacity(origins.len());
+    for origin in origins {
+        let u = reqwest::Url::parse(origin)
+            .with_context(|| format!("cors origin '{}' is invalid", origin))?;
+        if u.scheme() != "http" && u.scheme() != "https" {
+            anyhow::bail!("cors origin '{}' is invalid, scheme is not http(s)", origin)
+        }
+
+        // browsers send the origin as <scheme>://<host>[:<port>], anything else
+        // (a path or a trailing slash) would never match
+        let serialized = u.origin().ascii_serialization();
+        if serialized != *origin {
+            anyhow::bail!(
+                "cors origin '{}' is invalid, expected the form '{}'",
+                origin,
+                serialized
+            )
+        }
+
+        allowed.push(HeaderValue::from_str(origin)?);
+    }
+
+    Ok(Some(allowed))
+}
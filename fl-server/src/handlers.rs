@@ -1,7 +1,18 @@
 use anyhow::Error;
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    body::Body,
+    extract::{rejection::JsonRejection, Path, Query, State},
+    http::{
+        header::{
+            ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+            RANGE, X_CONTENT_TYPE_OPTIONS,
+        },
+        HeaderMap, StatusCode,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Extension, Json,
 };
 use axum_macros::debug_handler;
@@ -9,29 +20,38 @@ use std::{
     collections::HashMap,
     fs,
     path::PathBuf,
-    sync::{mpsc, Arc},
+    sync::{mpsc, Arc, OnceLock},
+    time::Duration,
 };
 
 use bollard::auth::DockerCredentials;
+use futures_util::stream;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    auth::{SignInBody, SignInResponse, __path_sign_in_handler},
+    auth::{SignInBody, SignInResponse, __path_refresh_handler, __path_sign_in_handler},
     response::{DirListTemplate, DirLister, ErrorTemplate, TemplateErr},
 };
 use crate::{
     config::{self, Job},
-    response::{FileInfo, ResponseError, ResponseResult},
+    precompress,
+    queue::{self, QueueStats},
+    response::{ErrorMessage, FieldError, FileInfo, InvalidBody, ResponseError, ResponseResult},
     serve_flists::visit_dir_one_level,
+    webhook,
 };
-use rfs::fungi::{Reader, Writer};
-use utoipa::{OpenApi, ToSchema};
+use rfs::fungi::{
+    meta::{FileType, Inode, Tag},
+    Reader, Writer,
+};
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 use uuid::Uuid;
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(health_check_handler, create_flist_handler, get_flist_state_handler, preview_flist_handler, list_flists_handler, sign_in_handler),
-    components(schemas(DirListTemplate, DirLister, FlistBody, Job, ResponseError, ErrorTemplate, TemplateErr, ResponseResult, FileInfo, SignInBody, FlistState, SignInResponse, FlistStateInfo, PreviewResponse)),
+    paths(health_check_handler, create_flist_handler, get_flist_state_handler, flist_progress_handler, preview_flist_handler, list_flists_handler, delete_flist_handler, flist_meta_handler, flist_content_handler, cancel_flist_handler, queue_stats_handler, sign_in_handler, refresh_handler),
+    components(schemas(DirListTemplate, DirLister, FlistBody, Job, ResponseError, FieldError, InvalidBody, ErrorMessage, ErrorTemplate, TemplateErr, ResponseResult, FileInfo, SignInBody, FlistState, SignInResponse, FlistStateInfo, FlistQueuedInfo, PreviewResponse, FlistMeta, QueueStats, FlistsPage, webhook::Notification, webhook::Status)),
     tags(
         (name = "fl-server", description = "Flist conversion API")
     )
@@ -50,6 +70,12 @@ pub struct FlistBody {
     pub server_address: Option<String>,
     pub identity_token: Option<String>,
     pub registry_token: Option<String>,
+
+    /// conversions with a higher priority are started first, default is 0
+    pub priority: Option<u8>,
+
+    /// url notified once the conversion is done, overrides the webhook of the config
+    pub webhook_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, ToSchema)]
@@ -59,19 +85,138 @@ pub struct PreviewResponse {
     pub checksum: String,
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq, ToSchema)]
+/// FlistMeta is a summary of a created flist
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FlistMeta {
+    pub username: String,
+    pub name: String,
+    /// size of the flist file in bytes
+    pub size: u64,
+    /// creation time of the flist in seconds since the epoch
+    pub created: i64,
+    /// number of files, directories and links of the flist
+    pub inodes: u64,
+    /// number of blocks of the files of the flist
+    pub blocks: u64,
+    /// total size of the files of the flist in bytes
+    pub content_size: u64,
+    /// docker image the flist is converted from, not known for the flists created
+    /// before it was recorded
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 pub enum FlistState {
     Accepted(String),
+    /// the job is waiting in the queue for a free worker, the state is reported
+    /// instead of Accepted while the job is queued
+    Queued(FlistQueuedInfo),
     Started(String),
     InProgress(FlistStateInfo),
     Created(String),
     Failed,
+    Interrupted(String),
+    /// the job is cancelled by its user
+    Cancelled(String),
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq, ToSchema)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 pub struct FlistStateInfo {
     msg: String,
     progress: f32,
+    /// number of files packed so far
+    packed: usize,
+    /// number of files of the image
+    files: usize,
+    /// number of image layers pulled so far
+    #[serde(default)]
+    pulled: usize,
+    /// bytes of the layers downloaded so far and their total size
+    #[serde(default)]
+    downloaded: u64,
+    #[serde(default)]
+    download_size: u64,
+    /// bytes of blocks uploaded to the store so far
+    #[serde(default)]
+    uploaded: u64,
+    /// number of blocks already in the store that are not uploaded again
+    #[serde(default)]
+    skipped: usize,
+}
+
+impl FlistStateInfo {
+    // apply updates the counters of the conversion with a progress event, downloads
+    // has the downloaded and total bytes of each layer
+    fn apply(
+        &mut self,
+        progress: docker2fl::Progress,
+        downloads: &mut HashMap<String, (u64, u64)>,
+    ) {
+        match progress {
+            docker2fl::Progress::Downloading {
+                layer,
+                current,
+                total,
+            } => {
+                downloads.insert(layer, (current, total));
+                self.downloaded = downloads.values().map(|(current, _)| current).sum();
+                self.download_size = downloads.values().map(|(_, total)| total).sum();
+            }
+            docker2fl::Progress::Pulled { .. } => self.pulled += 1,
+            docker2fl::Progress::Exported { files } => {
+                self.msg = "image is pulled, flist is in progress".into();
+                self.files = files;
+            }
+            docker2fl::Progress::Packed { packed, files } => {
+                self.msg = "flist is in progress".into();
+                self.packed = packed;
+                self.files = files;
+                if files > 0 {
+                    self.progress = packed as f32 / files as f32 * 100.0;
+                }
+            }
+            docker2fl::Progress::Uploaded { size } => self.uploaded += size,
+            docker2fl::Progress::Skipped { .. } => self.skipped += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct FlistQueuedInfo {
+    msg: String,
+    /// position of the job in the queue starting from 1, the job is started
+    /// once the jobs before it are started and a worker is free
+    position: usize,
+}
+
+impl FlistState {
+    /// is_final is true if the job is done, the state doesn't change anymore
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            FlistState::Created(_)
+                | FlistState::Failed
+                | FlistState::Interrupted(_)
+                | FlistState::Cancelled(_)
+        )
+    }
+
+    /// queued returns the Queued state of an accepted job that is still waiting in
+    /// the queue, other states are returned as is
+    fn queued(self, queue: &queue::Queue, id: &str) -> Self {
+        let msg = match self {
+            FlistState::Accepted(ref msg) => msg,
+            _ => return self,
+        };
+
+        match queue.position(id) {
+            Some(position) => FlistState::Queued(FlistQueuedInfo {
+                msg: msg.clone(),
+                position,
+            }),
+            None => self,
+        }
+    }
 }
 
 #[utoipa::path(
@@ -85,25 +230,84 @@ pub async fn health_check_handler() -> ResponseResult {
     ResponseResult::Health
 }
 
+// seconds a user with too many conversions is asked to wait, conversions take
+// minutes so there is no point in retrying sooner
+const USER_JOBS_RETRY_AFTER: u64 = 60;
+
 #[utoipa::path(
     post,
     path = "/v1/api/fl",
     request_body = FlistBody,
     responses(
+        (status = 200, description = "An identical flist already exists, the job is created right away", body = Job),
         (status = 201, description = "Flist conversion started", body = Job),
         (status = 401, description = "Unauthorized user"),
+        (status = 400, description = "Invalid request body, the invalid fields are listed", body = InvalidBody),
         (status = 403, description = "Forbidden"),
         (status = 409, description = "Conflict"),
+        (status = 413, description = "Request body is larger than the max_body_size of the config", body = ErrorMessage),
+        (status = 415, description = "Request body is not json"),
+        (status = 429, description = "Too many requests or running conversions of the user"),
         (status = 500, description = "Internal server error"),
+        (status = 503, description = "Conversion queue is full"),
     )
 )]
 #[debug_handler]
 pub async fn create_flist_handler(
     State(state): State<Arc<config::AppState>>,
     Extension(username): Extension<String>,
-    Json(body): Json<FlistBody>,
+    body: Result<Json<FlistBody>, JsonRejection>,
 ) -> impl IntoResponse {
     let cfg = state.config.clone();
+
+    let body = match body {
+        Ok(Json(body)) => body,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return Err(ResponseError::PayloadTooLarge(format!(
+                "request body is larger than {} bytes",
+                cfg.max_body_size
+            )));
+        }
+        Err(rejection) if rejection.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            return Err(ResponseError::UnsupportedMediaType(rejection.body_text()));
+        }
+        Err(rejection) => {
+            return Err(ResponseError::Invalid(vec![FieldError {
+                field: "body".into(),
+                msg: rejection.body_text(),
+            }]));
+        }
+    };
+
+    // the request is checked before anything is done so it fails right away instead
+    // of failing the conversion later
+    let errors = validate_body(&body);
+    if !errors.is_empty() {
+        return Err(ResponseError::Invalid(errors));
+    }
+
+    // the jobs are checked first so a rejected request doesn't count in the rate
+    if let Some(max_jobs) = cfg.user_max_jobs {
+        if state.queue.user_jobs(&username) >= max_jobs {
+            return Err(ResponseError::TooManyRequests(
+                format!("at most {} conversions can be queued or running", max_jobs),
+                USER_JOBS_RETRY_AFTER,
+            ));
+        }
+    }
+
+    if let Err(wait) = state.limiter.check(&username) {
+        return Err(ResponseError::TooManyRequests(
+            "too many conversion requests, try again later".to_string(),
+            // rounded up so a retry right after the wait is allowed
+            wait.as_secs() + u64::from(wait.subsec_nanos() > 0),
+        ));
+    }
+    let private = body.username.is_some()
+        || body.password.is_some()
+        || body.auth.is_some()
+        || body.identity_token.is_some()
+        || body.registry_token.is_some();
     let credentials = Some(DockerCredentials {
         username: body.username,
         password: body.password,
@@ -123,7 +327,31 @@ pub async fn create_flist_handler(
     let username_dir = std::path::Path::new(&cfg.flist_dir).join(&username);
     let fl_path = username_dir.join(&fl_name);
 
+    // the identity is only known if the registry can be reached, otherwise
+    // the flist is never considered the same
+    let identity = match docker2fl::image_digest(&docker_image, credentials.clone()).await {
+        Ok(digest) => Some(conversion_identity(&digest, &cfg.store_url)),
+        Err(err) => {
+            log::warn!(
+                "failed to get digest of image '{}': {:#}",
+                docker_image,
+                err
+            );
+            None
+        }
+    };
+
     if fl_path.exists() {
+        if identity.is_some() && flist_identity(&fl_path).await == identity {
+            let job = Job {
+                id: Uuid::new_v4().to_string(),
+            };
+
+            finish(&state, &job.id, created_state(&cfg, &username, &fl_name));
+
+            return Ok(ResponseResult::FlistCached(job));
+        }
+
         return Err(ResponseError::Conflict("flist already exists".to_string()));
     }
 
@@ -148,19 +376,10 @@ pub async fn create_flist_handler(
         }
     };
 
-    let store = match rfs::store::parse_router(&cfg.store_url).await {
-        Ok(s) => s,
-        Err(err) => {
-            log::error!("failed to parse router for store with error {}", err);
-            return Err(ResponseError::InternalServerError);
-        }
-    };
-
     // Create a new job id for the flist request
     let job: Job = Job {
         id: Uuid::new_v4().to_string(),
     };
-    let current_job = job.clone();
 
     state
         .jobs_state
@@ -171,98 +390,490 @@ pub async fn create_flist_handler(
             FlistState::Accepted(format!("flist '{}' is accepted", &fl_name)),
         );
 
-    let flist_download_url = std::path::Path::new(&format!("{}:{}", cfg.host, cfg.port))
-        .join(cfg.flist_dir)
-        .join(username)
-        .join(&fl_name);
+    let conversion = queue::Conversion {
+        record: queue::Record {
+            id: job.id.clone(),
+            username,
+            image: docker_image,
+            fl_name,
+            fl_path: fl_path.clone(),
+            priority: body.priority.unwrap_or_default(),
+            private,
+            webhook_url: body.webhook_url,
+        },
+        credentials,
+        meta,
+        identity,
+        cancel: CancellationToken::new(),
+    };
 
-    tokio::spawn(async move {
+    // the jobs of the user are counted again with the push, concurrent requests may
+    // have passed the first check
+    if let Err(rejected) = state.queue.push_user(conversion, cfg.user_max_jobs) {
+        log::warn!("failed to enqueue flist `{:?}`: {:?}", fl_path, rejected);
         state
             .jobs_state
             .lock()
             .expect("failed to lock state")
-            .insert(
-                job.id.clone(),
-                FlistState::Started(format!("flist '{}' is started", fl_name)),
-            );
+            .remove(&job.id);
+        let _ = tokio::fs::remove_file(&fl_path).await;
+        return Err(match rejected {
+            queue::Rejected::Full => {
+                ResponseError::ServiceUnavailable("conversion queue is full".into())
+            }
+            queue::Rejected::UserJobs(max_jobs) => ResponseError::TooManyRequests(
+                format!("at most {} conversions can be queued or running", max_jobs),
+                USER_JOBS_RETRY_AFTER,
+            ),
+        });
+    }
 
-        let container_name = Uuid::new_v4().to_string();
-        let docker_tmp_dir =
-            tempdir::TempDir::new(&container_name).expect("failed to create tmp dir for docker");
+    Ok(ResponseResult::FlistCreated(job))
+}
 
-        let (tx, rx) = mpsc::channel();
-        let mut docker_to_fl =
-            docker2fl::DockerImageToFlist::new(meta, docker_image, credentials, docker_tmp_dir);
+/// convert runs a queued conversion job and keeps its state up to date
+pub async fn convert(state: Arc<config::AppState>, conversion: queue::Conversion) {
+    let cfg = state.config.clone();
+    let queue::Conversion {
+        record,
+        credentials,
+        meta,
+        identity,
+        cancel,
+    } = conversion;
+    let queue::Record {
+        id: job_id,
+        username,
+        image: docker_image,
+        fl_name,
+        fl_path,
+        ..
+    } = record;
 
-        let res = docker_to_fl.prepare().await;
-        if res.is_err() {
+    let fail = |err: Error| {
+        let state = state.clone();
+        let fl_path = fl_path.clone();
+        let job_id = job_id.clone();
+        let cancelled = cancel.is_cancelled();
+        async move {
             let _ = tokio::fs::remove_file(&fl_path).await;
-            state
-                .jobs_state
-                .lock()
-                .expect("failed to lock state")
-                .insert(job.id.clone(), FlistState::Failed);
-            return;
-        }
-
-        let files_count = docker_to_fl.files_count();
-        let st = state.clone();
-        let job_id = job.id.clone();
-        let cloned_fl_path = fl_path.clone();
-        tokio::spawn(async move {
-            let mut progress: f32 = 0.0;
-
-            for _ in 0..files_count - 1 {
-                let step = rx.recv().expect("failed to receive progress") as f32;
-                progress += step;
-                let progress_percentage = progress / files_count as f32 * 100.0;
-                st.jobs_state.lock().expect("failed to lock state").insert(
-                    job_id.clone(),
-                    FlistState::InProgress(FlistStateInfo {
-                        msg: "flist is in progress".to_string(),
-                        progress: progress_percentage,
-                    }),
+            let _ = precompress::remove(&fl_path).await;
+            // the state of a cancelled job is set by cancel_flist_handler
+            if cancelled {
+                log::info!(
+                    job_id = job_id.as_str();
+                    "flist `{:?}` creation is cancelled",
+                    fl_path
                 );
+                state
+                    .flists_progress
+                    .lock()
+                    .expect("failed to lock state")
+                    .remove(&fl_path);
+                return;
+            }
+
+            log::error!(
+                job_id = job_id.as_str();
+                "flist `{:?}` creation failed with error {:#}",
+                fl_path,
+                err
+            );
+            finish(&state, &job_id, FlistState::Failed);
+        }
+    };
+
+    let store = match rfs::store::parse_router(&cfg.store_url).await {
+        Ok(s) => s,
+        Err(err) => return fail(err.context("failed to parse router for store")).await,
+    };
+
+    update(
+        &state,
+        &job_id,
+        FlistState::Started(format!("flist '{}' is started", fl_name)),
+    );
+
+    let container_name = Uuid::new_v4().to_string();
+    let docker_tmp_dir =
+        tempdir::TempDir::new(&container_name).expect("failed to create tmp dir for docker");
+
+    let (tx, rx) = mpsc::channel();
+    let mut docker_to_fl =
+        docker2fl::DockerImageToFlist::new(meta, docker_image.clone(), credentials, docker_tmp_dir)
+            .with_cancel(cancel.clone());
+
+    let st = state.clone();
+    let progress_job_id = job_id.clone();
+    let cloned_fl_path = fl_path.clone();
+    // every progress event of the pull and of the packing updates the state, the
+    // receiver is closed once packing is done
+    tokio::task::spawn_blocking(move || {
+        let mut info = FlistStateInfo {
+            msg: "image is being pulled".to_string(),
+            ..FlistStateInfo::default()
+        };
+        let mut downloads = HashMap::new();
+        for progress in rx {
+            let packed = matches!(progress, docker2fl::Progress::Packed { .. });
+            info.apply(progress, &mut downloads);
+
+            let mut jobs = st.jobs_state.lock().expect("failed to lock state");
+            // late events must not override the final state of the job
+            if !matches!(
+                jobs.get(&progress_job_id),
+                Some(FlistState::Started(_)) | Some(FlistState::InProgress(_))
+            ) {
+                continue;
+            }
+
+            jobs.insert(
+                progress_job_id.clone(),
+                FlistState::InProgress(info.clone()),
+            );
+            if packed {
                 st.flists_progress
                     .lock()
                     .expect("failed to lock state")
-                    .insert(cloned_fl_path.clone(), progress_percentage);
+                    .insert(cloned_fl_path.clone(), info.progress);
             }
+        }
+    });
+
+    if let Err(err) = docker_to_fl.prepare(&tx).await {
+        return fail(err).await;
+    }
+
+    // remove the file created with the writer if fl creation failed
+    if let Err(err) = docker_to_fl.pack(store, tx).await {
+        return fail(err).await;
+    }
+
+    // the job can be cancelled right after the flist is packed
+    if cancel.is_cancelled() {
+        return fail(anyhow::anyhow!("conversion is cancelled")).await;
+    }
+
+    // the tags are recorded only once the flist is complete, an flist with an
+    // identity is not converted again
+    let tags = [(SOURCE_TAG, Some(docker_image)), (IDENTITY_TAG, identity)];
+    for (tag, value) in tags {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if let Err(err) = write_tag(&fl_path, tag, &value).await {
+            log::error!(
+                "failed to write {} of flist `{:?}` with error {}",
+                tag,
+                fl_path,
+                err
+            );
+        }
+    }
+
+    // the compressed copies are written before the flist is reported as created
+    if let Err(err) = precompress::precompress(fl_path.clone()).await {
+        log::error!("{:#}", err);
+    }
+
+    finish(&state, &job_id, created_state(&cfg, &username, &fl_name));
+    state
+        .flists_progress
+        .lock()
+        .expect("failed to lock state")
+        .insert(fl_path, 100.0);
+}
+
+// flist_url is the download url of a flist
+fn flist_url(cfg: &config::Config, username: &str, fl_name: &str) -> PathBuf {
+    std::path::Path::new(&format!("{}:{}", cfg.host, cfg.port))
+        .join(&cfg.flist_dir)
+        .join(username)
+        .join(fl_name)
+}
+
+// created_state is the state of a created flist, it has the download url of the flist
+fn created_state(cfg: &config::Config, username: &str, fl_name: &str) -> FlistState {
+    FlistState::Created(format!(
+        "flist {:?} is created successfully",
+        flist_url(cfg, username, fl_name)
+    ))
+}
+
+// maximum length of an image reference, the name alone is limited to 255
+const MAX_IMAGE_LEN: usize = 512;
+
+// docker image reference: [registry[:port]/]path[:tag][@digest], the path is made of
+// lowercase components separated by `/`
+const IMAGE_PATTERN: &str = concat!(
+    r"^(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?",
+    r"(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?)*(?::[0-9]+)?/)?",
+    r"[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*",
+    r"(?:/[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*)*",
+    r"(?::[a-zA-Z0-9_][a-zA-Z0-9_.-]{0,127})?",
+    r"(?:@[a-z0-9]+(?:[+._-][a-z0-9]+)*:[a-fA-F0-9]{32,})?$",
+);
+
+static IMAGE_REFERENCE: OnceLock<regex::Regex> = OnceLock::new();
+
+// validate_body returns the invalid fields of a conversion request
+fn validate_body(body: &FlistBody) -> Vec<FieldError> {
+    let mut errors = Vec::default();
+    if let Err(msg) = validate_image(&body.image_name) {
+        errors.push(FieldError {
+            field: "image_name".into(),
+            msg,
         });
+    }
 
-        let res = docker_to_fl.pack(store, Some(tx)).await;
+    if let Some(ref url) = body.webhook_url {
+        if let Err(msg) = webhook::validate_public(url) {
+            errors.push(FieldError {
+                field: "webhook_url".into(),
+                msg,
+            });
+        }
+    }
 
-        // remove the file created with the writer if fl creation failed
-        if res.is_err() {
-            log::error!("failed creation failed with error {:?}", res.err());
-            let _ = tokio::fs::remove_file(&fl_path).await;
-            state
-                .jobs_state
-                .lock()
-                .expect("failed to lock state")
-                .insert(job.id.clone(), FlistState::Failed);
-            return;
+    errors
+}
+
+fn validate_image(image: &str) -> Result<(), String> {
+    if image.is_empty() {
+        return Err("image name is empty".into());
+    }
+
+    if image.len() > MAX_IMAGE_LEN {
+        return Err(format!(
+            "image name is longer than {} characters",
+            MAX_IMAGE_LEN
+        ));
+    }
+
+    let re = IMAGE_REFERENCE
+        .get_or_init(|| regex::Regex::new(IMAGE_PATTERN).expect("image pattern is valid"));
+    if !re.is_match(image) {
+        return Err(format!(
+            "invalid image name '{}', expected [registry/]name[:tag][@digest]",
+            image
+        ));
+    }
+
+    Ok(())
+}
+
+/// notify posts the final state of a done conversion to its webhook (or the one of
+/// the config) in the background, nothing is done if no webhook is set
+pub fn notify(state: &config::AppState, record: &queue::Record) {
+    let url = match record
+        .webhook_url
+        .as_ref()
+        .or(state.config.webhook_url.as_ref())
+    {
+        Some(url) => url.clone(),
+        None => return,
+    };
+
+    let job_state = state
+        .jobs_state
+        .lock()
+        .expect("failed to lock state")
+        .get(&record.id)
+        .cloned();
+
+    let (status, url_of_flist) = match job_state {
+        Some(FlistState::Created(_)) => (
+            webhook::Status::Created,
+            Some(
+                flist_url(&state.config, &record.username, &record.fl_name)
+                    .display()
+                    .to_string(),
+            ),
+        ),
+        Some(FlistState::Cancelled(_)) => (webhook::Status::Cancelled, None),
+        _ => (webhook::Status::Failed, None),
+    };
+
+    webhook::notify(
+        url,
+        webhook::Notification {
+            id: record.id.clone(),
+            username: record.username.clone(),
+            fl_name: record.fl_name.clone(),
+            url: url_of_flist,
+            status,
+        },
+        // the webhook of the config is trusted, the one of a user is not
+        record.webhook_url.is_some(),
+    );
+}
+
+/// update sets the state of a running job, it's ignored if the job is already done
+/// (it's cancelled)
+fn update(state: &config::AppState, id: &str, job_state: FlistState) {
+    let mut jobs = state.jobs_state.lock().expect("failed to lock state");
+    if jobs.get(id).map(FlistState::is_final).unwrap_or(false) {
+        return;
+    }
+
+    jobs.insert(id.into(), job_state);
+}
+
+/// finish sets the final state of a job, the state is kept for the retention window
+/// of the config
+pub fn finish(state: &config::AppState, id: &str, job_state: FlistState) {
+    state.history.record(id, &job_state);
+    state
+        .jobs_state
+        .lock()
+        .expect("failed to lock state")
+        .insert(id.into(), job_state);
+}
+
+/// recover handles the jobs interrupted by a restart of the server. The jobs are
+/// enqueued again if enabled in the config (except the ones that need registry
+/// credentials), otherwise they are reported as interrupted
+pub async fn recover(state: &Arc<config::AppState>, records: Vec<queue::Record>) {
+    for record in records {
+        let id = record.id.clone();
+        let fl_path = record.fl_path.clone();
+
+        if state.config.requeue_interrupted && !record.private {
+            let meta = match Writer::new(&fl_path, true).await {
+                Ok(writer) => Some(writer),
+                Err(err) => {
+                    log::error!("failed to recreate flist `{:?}`: {}", fl_path, err);
+                    None
+                }
+            };
+
+            if let Some(meta) = meta {
+                state
+                    .jobs_state
+                    .lock()
+                    .expect("failed to lock state")
+                    .insert(
+                        id.clone(),
+                        FlistState::Accepted(format!("flist '{}' is accepted", record.fl_name)),
+                    );
+
+                let conversion = queue::Conversion {
+                    record,
+                    credentials: None,
+                    meta,
+                    identity: None,
+                    cancel: CancellationToken::new(),
+                };
+
+                match state.queue.push(conversion) {
+                    Ok(_) => {
+                        log::info!(
+                            job_id = id.as_str();
+                            "interrupted job {} is enqueued again",
+                            id
+                        );
+                        continue;
+                    }
+                    Err(err) => log::error!("failed to enqueue interrupted job {}: {}", id, err),
+                }
+            }
         }
 
-        state
+        log::warn!(job_id = id.as_str(); "job {} for flist `{:?}` was interrupted", id, fl_path);
+        let _ = tokio::fs::remove_file(&fl_path).await;
+        finish(
+            state,
+            &id,
+            FlistState::Interrupted("flist conversion was interrupted by a server restart".into()),
+        );
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/api/fl/{job_id}/cancel",
+    responses(
+        (status = 200, description = "Job cancelled", body = FlistState),
+        (status = 401, description = "Unauthorized user"),
+        (status = 403, description = "Forbidden"),
+        (status = 404, description = "Job not found"),
+        (status = 409, description = "Job is already done"),
+    ),
+    params(
+        ("job_id" = String, Path, description = "flist job id")
+    )
+)]
+#[debug_handler]
+pub async fn cancel_flist_handler(
+    State(state): State<Arc<config::AppState>>,
+    Extension(current_user): Extension<String>,
+    Path(job_id): Path<String>,
+) -> impl IntoResponse {
+    let done = || ResponseError::Conflict(format!("job '{}' is already done", job_id));
+    let record = match state.queue.record(&job_id) {
+        Some(record) => record,
+        None if state
             .jobs_state
             .lock()
             .expect("failed to lock state")
-            .insert(
-                job.id.clone(),
-                FlistState::Created(format!(
-                    "flist {:?} is created successfully",
-                    flist_download_url
-                )),
-            );
-        state
-            .flists_progress
-            .lock()
-            .expect("failed to lock state")
-            .insert(fl_path, 100.0);
-    });
+            .contains_key(&job_id) =>
+        {
+            return Err(done())
+        }
+        None => return Err(ResponseError::NotFound("job doesn't exist".to_string())),
+    };
+
+    if record.username != current_user {
+        return Err(ResponseError::Forbidden(
+            "You can only cancel your own jobs".to_string(),
+        ));
+    }
+
+    // a done job is still running until its worker is released
+    let finished = state
+        .jobs_state
+        .lock()
+        .expect("failed to lock state")
+        .get(&job_id)
+        .map(FlistState::is_final)
+        .unwrap_or(false);
+    if finished {
+        return Err(done());
+    }
+
+    // the job can be done since its record was read
+    let cancelled = match state.queue.cancel(&job_id) {
+        Some(cancelled) => cancelled,
+        None => return Err(done()),
+    };
+
+    let job_state = FlistState::Cancelled(format!("flist '{}' is cancelled", record.fl_name));
+    finish(&state, &job_id, job_state.clone());
+
+    // a running job removes its partial flist once its conversion stops, a queued job
+    // is never started so it's cleaned up here
+    if let queue::Cancelled::Queued(conversion) = cancelled {
+        drop(conversion);
+        let _ = tokio::fs::remove_file(&record.fl_path).await;
+        notify(&state, &record);
+    }
 
-    Ok(ResponseResult::FlistCreated(current_job))
+    log::info!(job_id = job_id.as_str(); "job {} is cancelled by {}", job_id, current_user);
+    Ok(ResponseResult::FlistState(job_state))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/api/queue",
+    responses(
+        (status = 200, description = "Conversion queue stats", body = QueueStats),
+    )
+)]
+#[debug_handler]
+pub async fn queue_stats_handler(State(state): State<Arc<config::AppState>>) -> impl IntoResponse {
+    ResponseResult::Queue(state.queue.stats())
 }
 
 #[utoipa::path(
@@ -299,51 +910,133 @@ pub async fn get_flist_state_handler(
         .expect("failed to lock state")
         .get(&flist_job_id.clone())
         .expect("failed to get from state")
-        .to_owned();
+        .to_owned()
+        .queued(&state.queue, &flist_job_id);
 
+    // the final states are kept until the retention window of the config expires
     match res_state {
-        FlistState::Accepted(_) => Ok(ResponseResult::FlistState(res_state)),
-        FlistState::Started(_) => Ok(ResponseResult::FlistState(res_state)),
-        FlistState::InProgress(_) => Ok(ResponseResult::FlistState(res_state)),
-        FlistState::Created(_) => {
-            state
-                .jobs_state
-                .lock()
-                .expect("failed to lock state")
-                .remove(&flist_job_id.clone());
+        FlistState::Failed => Err(ResponseError::InternalServerError),
+        _ => Ok(ResponseResult::FlistState(res_state)),
+    }
+}
 
-            Ok(ResponseResult::FlistState(res_state))
-        }
-        FlistState::Failed => {
-            state
-                .jobs_state
-                .lock()
-                .expect("failed to lock state")
-                .remove(&flist_job_id.clone());
+const DEFAULT_PER_PAGE: usize = 100;
+const MAX_PER_PAGE: usize = 1000;
 
-            Err(ResponseError::InternalServerError)
-        }
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Pagination {
+    /// page number starting from 1, default is 1
+    pub page: Option<usize>,
+    /// number of flists per page, default is 100 and max is 1000
+    pub per_page: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FlistsPage {
+    /// flists of the page grouped by username
+    pub flists: HashMap<String, Vec<FileInfo>>,
+    pub page: usize,
+    pub per_page: usize,
+    /// total number of flists of all users
+    pub total: usize,
+    /// next page number if any
+    pub next: Option<usize>,
+    /// previous page number if any
+    pub prev: Option<usize>,
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[utoipa::path(
+    get,
+    path = "/v1/api/fl/{job_id}/progress",
+    responses(
+        (status = 200, description = "Stream (server-sent events) of the flist state, an event is sent each time the state changes", content_type = "text/event-stream", body = FlistState),
+        (status = 404, description = "Flist not found"),
+        (status = 401, description = "Unauthorized user"),
+        (status = 403, description = "Forbidden"),
+    ),
+    params(
+        ("job_id" = String, Path, description = "flist job id")
+    )
+)]
+#[debug_handler]
+pub async fn flist_progress_handler(
+    Path(flist_job_id): Path<String>,
+    State(state): State<Arc<config::AppState>>,
+) -> impl IntoResponse {
+    if !state
+        .jobs_state
+        .lock()
+        .expect("failed to lock state")
+        .contains_key(&flist_job_id)
+    {
+        return Err(ResponseError::NotFound("flist doesn't exist".to_string()));
     }
+
+    // the state is checked periodically and sent whenever it changes, the stream
+    // ends with the final state
+    let events = stream::unfold(
+        (state, flist_job_id, None::<FlistState>),
+        |(state, id, last)| async move {
+            if last.as_ref().map(FlistState::is_final).unwrap_or(false) {
+                return None;
+            }
+
+            loop {
+                let current = state
+                    .jobs_state
+                    .lock()
+                    .expect("failed to lock state")
+                    .get(&id)?
+                    .clone();
+                // the position of a queued job changes while it waits
+                let current = current.queued(&state.queue, &id);
+
+                if last.as_ref() != Some(&current) {
+                    let event = Event::default().event("state").json_data(&current);
+                    return Some((event, (state, id, Some(current))));
+                }
+
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
+            }
+        },
+    );
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
 
 #[utoipa::path(
 	get,
 	path = "/v1/api/fl",
 	responses(
-        (status = 200, description = "Listing flists", body = HashMap<String, Vec<FileInfo>>),
+        (status = 200, description = "Listing flists", body = FlistsPage),
+        (status = 400, description = "Bad request"),
         (status = 401, description = "Unauthorized user"),
         (status = 403, description = "Forbidden"),
         (status = 500, description = "Internal server error"),
-	)
+	),
+    params(Pagination)
 )]
 #[debug_handler]
-pub async fn list_flists_handler(State(state): State<Arc<config::AppState>>) -> impl IntoResponse {
-    let mut flists: HashMap<String, Vec<FileInfo>> = HashMap::new();
+pub async fn list_flists_handler(
+    State(state): State<Arc<config::AppState>>,
+    Query(pagination): Query<Pagination>,
+) -> impl IntoResponse {
+    let page = pagination.page.unwrap_or(1);
+    let per_page = pagination.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    if page == 0 || per_page == 0 || per_page > MAX_PER_PAGE {
+        return Err(ResponseError::BadRequest(format!(
+            "page must be at least 1 and per_page between 1 and {}",
+            MAX_PER_PAGE
+        )));
+    }
 
     let rs: Result<Vec<FileInfo>, std::io::Error> =
         visit_dir_one_level(&state.config.flist_dir, &state).await;
 
-    let files = match rs {
+    let mut users = match rs {
         Ok(files) => files,
         Err(e) => {
             log::error!("failed to list flists directory with error: {}", e);
@@ -351,20 +1044,443 @@ pub async fn list_flists_handler(State(state): State<Arc<config::AppState>>) ->
         }
     };
 
-    for file in files {
-        if !file.is_file {
-            let flists_per_username = visit_dir_one_level(&file.path_uri, &state).await;
-            match flists_per_username {
-                Ok(files) => flists.insert(file.name, files),
+    // flists are sorted by username then name so the pages are stable
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut all: Vec<(String, FileInfo)> = Vec::new();
+    for user in users {
+        if !user.is_file {
+            let flists_per_username = visit_dir_one_level(&user.path_uri, &state).await;
+            let mut files = match flists_per_username {
+                Ok(files) => files,
                 Err(e) => {
                     log::error!("failed to list flists per username with error: {}", e);
                     return Err(ResponseError::InternalServerError);
                 }
             };
+
+            files.sort_by(|a, b| a.name.cmp(&b.name));
+            all.extend(files.into_iter().map(|file| (user.name.clone(), file)));
         };
     }
 
-    Ok(ResponseResult::Flists(flists))
+    let total = all.len();
+    let mut flists: HashMap<String, Vec<FileInfo>> = HashMap::new();
+    let start = page_start(page, per_page);
+    for (username, file) in all.into_iter().skip(start).take(per_page) {
+        flists.entry(username).or_default().push(file);
+    }
+
+    Ok(ResponseResult::Flists(FlistsPage {
+        flists,
+        page,
+        per_page,
+        total,
+        next: (start.saturating_add(per_page) < total).then_some(page + 1),
+        prev: (page > 1).then_some(page - 1),
+    }))
+}
+
+// page_start is the index of the first flist of a page, a page past usize::MAX is just
+// past the end of the list
+fn page_start(page: usize, per_page: usize) -> usize {
+    (page - 1).saturating_mul(per_page)
+}
+
+#[utoipa::path(
+	delete,
+	path = "/v1/api/fl/{flist_name}",
+	responses(
+        (status = 204, description = "Flist deleted"),
+        (status = 400, description = "Bad request"),
+        (status = 401, description = "Unauthorized user"),
+        (status = 403, description = "Forbidden"),
+        (status = 404, description = "Flist not found"),
+        (status = 409, description = "Flist is being converted"),
+        (status = 500, description = "Internal server error"),
+	),
+    params(
+        ("flist_name" = String, Path, description = "flist file name, or '<username>/<flist file name>' (url encoded)")
+    )
+)]
+#[debug_handler]
+pub async fn delete_flist_handler(
+    State(state): State<Arc<config::AppState>>,
+    Extension(current_user): Extension<String>,
+    Path(flist_name): Path<String>,
+) -> impl IntoResponse {
+    let (username, flist_name) = match flist_name.split_once('/') {
+        Some((username, name)) => (username.to_string(), name.to_string()),
+        None => (current_user.clone(), flist_name),
+    };
+
+    if username != current_user {
+        return Err(ResponseError::Forbidden(
+            "You can only delete your own flists".to_string(),
+        ));
+    }
+
+    if flist_name.contains('/') || flist_name.starts_with('.') || !flist_name.ends_with(".fl") {
+        return Err(ResponseError::BadRequest(format!(
+            "invalid flist name '{}'",
+            flist_name
+        )));
+    }
+
+    let fl_path = std::path::Path::new(&state.config.flist_dir)
+        .join(&username)
+        .join(&flist_name);
+    if !fl_path.is_file() {
+        return Err(ResponseError::NotFound(format!(
+            "flist '{}' doesn't exist",
+            flist_name
+        )));
+    }
+
+    // the conversion would fail (or create the flist again) if the file is removed
+    if let Some(id) = state.queue.job(&fl_path) {
+        return Err(ResponseError::Conflict(format!(
+            "flist '{}' is being converted by job {}",
+            flist_name, id
+        )));
+    }
+
+    if let Err(err) = tokio::fs::remove_file(&fl_path).await {
+        log::error!("failed to remove flist `{:?}` with error {}", fl_path, err);
+        return Err(ResponseError::InternalServerError);
+    }
+
+    if let Err(err) = precompress::remove(&fl_path).await {
+        log::error!(
+            "failed to remove compressed copies of flist `{:?}` with error {}",
+            fl_path,
+            err
+        );
+    }
+
+    state
+        .flists_meta
+        .lock()
+        .expect("failed to lock state")
+        .remove(&fl_path);
+
+    let created = created_state(&state.config, &username, &flist_name);
+    let forgotten: Vec<String> = {
+        let mut jobs = state.jobs_state.lock().expect("failed to lock state");
+        let forgotten = jobs
+            .iter()
+            .filter(|(_, job_state)| **job_state == created)
+            .map(|(id, _)| id.clone())
+            .collect();
+        jobs.retain(|_, job_state| *job_state != created);
+        forgotten
+    };
+    state.history.remove(&forgotten);
+    state
+        .flists_progress
+        .lock()
+        .expect("failed to lock state")
+        .remove(&fl_path);
+
+    Ok(ResponseResult::FlistDeleted)
+}
+
+#[utoipa::path(
+	get,
+	path = "/v1/api/fl/{flist_name}/meta",
+	responses(
+        (status = 200, description = "Flist summary", body = FlistMeta),
+        (status = 400, description = "Bad request"),
+        (status = 404, description = "Flist not found"),
+        (status = 409, description = "Flist is being converted"),
+        (status = 500, description = "Internal server error"),
+	),
+    params(
+        ("flist_name" = String, Path, description = "'<username>/<flist file name>' (url encoded)")
+    )
+)]
+#[debug_handler]
+pub async fn flist_meta_handler(
+    State(state): State<Arc<config::AppState>>,
+    Path(flist_name): Path<String>,
+) -> impl IntoResponse {
+    let (username, name) = split_flist_name(&flist_name)?;
+
+    let fl_path = std::path::Path::new(&state.config.flist_dir)
+        .join(&username)
+        .join(&name);
+
+    // the flist is only complete once the conversion is done
+    if let Some(id) = state.queue.job(&fl_path) {
+        return Err(ResponseError::Conflict(format!(
+            "flist '{}' is being converted by job {}",
+            flist_name, id
+        )));
+    }
+
+    let modified = match tokio::fs::metadata(&fl_path).await {
+        Ok(metadata) if metadata.is_file() => metadata.modified().ok(),
+        _ => {
+            return Err(ResponseError::NotFound(format!(
+                "flist '{}' doesn't exist",
+                flist_name
+            )))
+        }
+    };
+
+    // the summary is kept until the flist file changes
+    if let Some((cached, meta)) = state
+        .flists_meta
+        .lock()
+        .expect("failed to lock state")
+        .get(&fl_path)
+    {
+        if modified.is_some() && *cached == modified {
+            return Ok(ResponseResult::FlistMeta(meta.clone()));
+        }
+    }
+
+    let meta = match flist_meta(&fl_path, username, name).await {
+        Ok(meta) => meta,
+        Err(err) => {
+            log::error!(
+                "failed to read metadata of flist `{:?}` with error {:#}",
+                fl_path,
+                err
+            );
+            return Err(ResponseError::InternalServerError);
+        }
+    };
+
+    state
+        .flists_meta
+        .lock()
+        .expect("failed to lock state")
+        .insert(fl_path, (modified, meta.clone()));
+
+    Ok(ResponseResult::FlistMeta(meta))
+}
+
+// split_flist_name splits '<username>/<flist name>' into the username and the flist name
+fn split_flist_name(flist_name: &str) -> Result<(String, String), ResponseError> {
+    match flist_name.split_once('/') {
+        Some((username, name))
+            if !username.is_empty()
+                && !username.starts_with('.')
+                && !name.contains('/')
+                && !name.starts_with('.')
+                && name.ends_with(".fl") =>
+        {
+            Ok((username.to_string(), name.to_string()))
+        }
+        _ => Err(ResponseError::BadRequest(format!(
+            "invalid flist name '{}', should be '<username>/<flist name>'",
+            flist_name
+        ))),
+    }
+}
+
+async fn flist_meta(
+    fl_path: &std::path::Path,
+    username: String,
+    name: String,
+) -> Result<FlistMeta, Error> {
+    let metadata = tokio::fs::metadata(fl_path).await?;
+    let created = metadata.created().or_else(|_| metadata.modified())?;
+
+    let reader = Reader::new(fl_path).await?;
+    Ok(FlistMeta {
+        username,
+        name,
+        size: metadata.len(),
+        created: created
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64,
+        inodes: reader.inodes_count().await?,
+        blocks: reader.blocks_count().await?,
+        content_size: reader.files_size().await?,
+        source: reader.tag(Tag::Custom(SOURCE_TAG)).await?,
+    })
+}
+
+// size of the buffer between the task that downloads the blocks of a served file
+// and the response body
+const CONTENT_BUFFER: usize = 64 * 1024;
+
+#[utoipa::path(
+	get,
+	path = "/v1/api/fl/{flist_name}/content/{path}",
+	responses(
+        (status = 200, description = "Content of the file"),
+        (status = 206, description = "Requested range of the content of the file"),
+        (status = 400, description = "Bad request"),
+        (status = 404, description = "Flist or file not found"),
+        (status = 409, description = "Flist is being converted"),
+        (status = 416, description = "Range not satisfiable"),
+        (status = 500, description = "Internal server error"),
+	),
+    params(
+        ("flist_name" = String, Path, description = "'<username>/<flist file name>' (url encoded)"),
+        ("path" = String, Path, description = "path of the file in the flist")
+    )
+)]
+#[debug_handler]
+pub async fn flist_content_handler(
+    State(state): State<Arc<config::AppState>>,
+    Path((flist_name, path)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, ResponseError> {
+    let cache = match state.content {
+        Some(ref cache) => Arc::clone(cache),
+        None => {
+            return Err(ResponseError::NotFound(
+                "flist contents are not served".to_string(),
+            ))
+        }
+    };
+
+    let (username, name) = split_flist_name(&flist_name)?;
+    let fl_path = std::path::Path::new(&state.config.flist_dir)
+        .join(&username)
+        .join(&name);
+
+    if let Some(id) = state.queue.job(&fl_path) {
+        return Err(ResponseError::Conflict(format!(
+            "flist '{}' is being converted by job {}",
+            flist_name, id
+        )));
+    }
+
+    if !fl_path.is_file() {
+        return Err(ResponseError::NotFound(format!(
+            "flist '{}' doesn't exist",
+            flist_name
+        )));
+    }
+
+    let (meta, node) = match flist_file(&fl_path, &path).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            return Err(ResponseError::NotFound(format!(
+                "file '{}' doesn't exist in flist '{}'",
+                path, flist_name
+            )))
+        }
+        Err(err) => {
+            log::error!(
+                "failed to find file '{}' of flist `{:?}` with error {:#}",
+                path,
+                fl_path,
+                err
+            );
+            return Err(ResponseError::InternalServerError);
+        }
+    };
+
+    let size = node.size;
+    let (status, start, len) = match headers.get(RANGE) {
+        None => (StatusCode::OK, 0, size),
+        Some(range) => match range.to_str().ok().and_then(|r| byte_range(r, size)) {
+            Some((first, last)) => (StatusCode::PARTIAL_CONTENT, first, last - first + 1),
+            None => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(CONTENT_RANGE, format!("bytes */{}", size))],
+                )
+                    .into_response())
+            }
+        },
+    };
+
+    let mut response = axum::http::Response::builder()
+        .status(status)
+        .header(
+            CONTENT_TYPE,
+            mime_guess::from_path(&node.name)
+                .first_or_octet_stream()
+                .as_ref(),
+        )
+        .header(CONTENT_LENGTH, len)
+        .header(ACCEPT_RANGES, "bytes")
+        // the files come from any converted image and are served on the api origin, the
+        // sandbox keeps their html and scripts away from the api and its users
+        .header(CONTENT_SECURITY_POLICY, "sandbox")
+        .header(X_CONTENT_TYPE_OPTIONS, "nosniff");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, start + len - 1, size),
+        );
+    }
+
+    // the blocks are written to the body as they are downloaded
+    let (writer, reader) = tokio::io::duplex(CONTENT_BUFFER);
+    tokio::spawn(async move {
+        let served = rfs::content::read_range(&meta, &cache, &node, start, len, writer).await;
+        if let Err(err) = served {
+            log::error!(
+                "failed to serve file '{}' of flist `{:?}` with error {:#}",
+                path,
+                fl_path,
+                err
+            );
+        }
+    });
+
+    response
+        .body(Body::from_stream(ReaderStream::new(reader)))
+        .map_err(|_| ResponseError::InternalServerError)
+}
+
+// flist_file returns the flist reader and the regular file at the path, a directory
+// is served by its index.html
+async fn flist_file(
+    fl_path: &std::path::Path,
+    path: &str,
+) -> Result<Option<(Reader, Inode)>, Error> {
+    let meta = Reader::new(fl_path).await?;
+    let mut node = match rfs::content::resolve(&meta, path).await? {
+        Some(node) => node,
+        None => return Ok(None),
+    };
+
+    if node.mode.is(FileType::Dir) {
+        let index = std::path::Path::new(path).join("index.html");
+        node = match rfs::content::resolve(&meta, index).await? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+    }
+
+    if !node.mode.is(FileType::Regular) {
+        return Ok(None);
+    }
+
+    Ok(Some((meta, node)))
+}
+
+// byte_range returns the first and last byte of a range header of the form
+// `bytes=<first>-[<last>]` or `bytes=-<suffix length>`, None if the range is not
+// satisfiable. Multi-range requests are not supported
+fn byte_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    if size == 0 {
+        return None;
+    }
+
+    let (first, last) = match (first.trim(), last.trim()) {
+        ("", suffix) => match suffix.parse::<u64>().ok()? {
+            0 => return None,
+            suffix => (size.saturating_sub(suffix), size - 1),
+        },
+        (first, "") => (first.parse().ok()?, size - 1),
+        (first, last) => (first.parse().ok()?, last.parse::<u64>().ok()?.min(size - 1)),
+    };
+
+    if first > last {
+        return None;
+    }
+
+    Some((first, last))
 }
 
 #[utoipa::path(
@@ -417,6 +1533,28 @@ pub async fn preview_flist_handler(
     }))
 }
 
+const IDENTITY_TAG: &str = "identity";
+// docker image of the flist
+const SOURCE_TAG: &str = "source";
+
+// conversion_identity is a digest of everything that affects the conversion result
+fn conversion_identity(image_digest: &str, store_url: &[String]) -> String {
+    sha256::digest(format!("{}\n{}", image_digest, store_url.join("\n")))
+}
+
+// flist_identity returns the identity recorded in a complete flist
+async fn flist_identity(fl_path: &std::path::Path) -> Option<String> {
+    let meta = Reader::new(fl_path).await.ok()?;
+    meta.tag(Tag::Custom(IDENTITY_TAG)).await.ok().flatten()
+}
+
+async fn write_tag(fl_path: &std::path::Path, tag: &str, value: &str) -> Result<(), Error> {
+    let meta = Writer::new(fl_path, false).await?;
+    meta.tag(Tag::Custom(tag), value).await?;
+
+    Ok(())
+}
+
 async fn validate_flist_path(state: &Arc<config::AppState>, fl_path: &String) -> Result<(), Error> {
     // validate path starting with `/`
     if fl_path.starts_with("/") {
@@ -529,3 +1667,188 @@ impl rfs::fungi::meta::WalkVisitor for ReadVisitor {
         Ok(rfs::fungi::meta::Walk::Continue)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_byte_range() {
+        // first and last
+        assert_eq!(byte_range("bytes=0-9", 100), Some((0, 9)));
+        assert_eq!(byte_range("bytes=10-200", 100), Some((10, 99)));
+        // open ended
+        assert_eq!(byte_range("bytes=90-", 100), Some((90, 99)));
+        assert_eq!(byte_range("bytes=0-", 1), Some((0, 0)));
+        // suffix
+        assert_eq!(byte_range("bytes=-10", 100), Some((90, 99)));
+        assert_eq!(byte_range("bytes=-200", 100), Some((0, 99)));
+        assert_eq!(byte_range("bytes=-0", 100), None);
+        // past the end of the file
+        assert_eq!(byte_range("bytes=100-", 100), None);
+        assert_eq!(byte_range("bytes=100-200", 100), None);
+        assert_eq!(byte_range("bytes=0-", 0), None);
+        assert_eq!(byte_range("bytes=-10", 0), None);
+        // malformed
+        for range in [
+            "",
+            "bytes=",
+            "bytes=-",
+            "bytes=10",
+            "bytes=9-5",
+            "bytes=a-b",
+            "bytes=0-9,20-29",
+            "items=0-9",
+        ] {
+            assert_eq!(byte_range(range, 100), None, "{}", range);
+        }
+    }
+
+    #[test]
+    fn test_page_start() {
+        assert_eq!(page_start(1, 100), 0);
+        assert_eq!(page_start(3, 100), 200);
+        assert_eq!(page_start(usize::MAX, 100), usize::MAX);
+        assert_eq!(page_start(usize::MAX / 2, MAX_PER_PAGE), usize::MAX);
+    }
+
+    #[test]
+    fn test_progress() {
+        use docker2fl::Progress;
+
+        let mut info = FlistStateInfo::default();
+        let mut downloads = HashMap::new();
+        for progress in [
+            Progress::Downloading {
+                layer: "a".into(),
+                current: 10,
+                total: 100,
+            },
+            Progress::Downloading {
+                layer: "b".into(),
+                current: 5,
+                total: 50,
+            },
+            Progress::Downloading {
+                layer: "a".into(),
+                current: 100,
+                total: 100,
+            },
+            Progress::Pulled { layer: "a".into() },
+            Progress::Exported { files: 4 },
+            Progress::Packed {
+                packed: 1,
+                files: 4,
+            },
+            Progress::Uploaded { size: 1024 },
+            Progress::Uploaded { size: 512 },
+            Progress::Skipped { size: 2048 },
+        ] {
+            info.apply(progress, &mut downloads);
+        }
+
+        assert_eq!(info.downloaded, 105);
+        assert_eq!(info.download_size, 150);
+        assert_eq!(info.pulled, 1);
+        assert_eq!((info.packed, info.files), (1, 4));
+        assert_eq!(info.progress, 25.0);
+        assert_eq!(info.uploaded, 1536);
+        assert_eq!(info.skipped, 1);
+    }
+
+    #[test]
+    fn test_validate_image() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        for image in [
+            "redis".to_string(),
+            "redis:7".into(),
+            "library/redis:latest".into(),
+            "localhost:5000/redis:7".into(),
+            "registry.example.com:443/org/app:v1.2".into(),
+            format!("redis@{}", digest),
+            format!("ghcr.io/org/app:1.0@{}", digest),
+        ] {
+            assert!(validate_image(&image).is_ok(), "{}", image);
+        }
+
+        for image in [
+            "".to_string(),
+            "Redis".into(),
+            "redis:".into(),
+            "-redis".into(),
+            "/redis".into(),
+            "redis/".into(),
+            "redis:tag with space".into(),
+            "localhost:port/redis".into(),
+            "redis@sha256:short".into(),
+            "a".repeat(MAX_IMAGE_LEN + 1),
+        ] {
+            assert!(validate_image(&image).is_err(), "{}", image);
+        }
+    }
+
+    async fn create_flist(
+        max_body_size: usize,
+        content_type: Option<&str>,
+        body: String,
+    ) -> StatusCode {
+        use axum::{extract::DefaultBodyLimit, http::Request, routing::post, Router};
+        use tower::ServiceExt;
+
+        let config = config::Config {
+            max_body_size,
+            ..config::Config::default()
+        };
+        let state = Arc::new(config::AppState {
+            jobs_state: std::sync::Mutex::new(HashMap::new()),
+            flists_progress: std::sync::Mutex::new(HashMap::new()),
+            flists_meta: std::sync::Mutex::new(HashMap::new()),
+            content: None,
+            db: Arc::new(crate::db::MapDB::new(&[])),
+            queue: queue::Queue::new(1, 10, None),
+            history: crate::history::History::new(Duration::from_secs(60), None),
+            limiter: crate::limits::RateLimiter::new(None),
+            config,
+        });
+
+        let app = Router::new()
+            .route(
+                "/",
+                post(create_flist_handler).layer(DefaultBodyLimit::max(max_body_size)),
+            )
+            .layer(Extension(String::from("user")))
+            .with_state(state);
+
+        let mut request = Request::post("/");
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        let response = app
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn test_create_flist_body() {
+        const JSON: Option<&str> = Some("application/json");
+
+        let oversized = format!(r#"{{"image_name": "{}"}}"#, "a".repeat(1024));
+        assert_eq!(
+            create_flist(512, JSON, oversized).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let body = r#"{"image_name": "Redis"}"#.to_string();
+        assert_eq!(
+            create_flist(512, None, body.clone()).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(create_flist(512, JSON, body).await, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            create_flist(512, JSON, "{".into()).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
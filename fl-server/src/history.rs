@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::{config, handlers::FlistState};
+
+/// interval of the removal of the expired job states
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    state: FlistState,
+    /// time the job reached its final state in seconds since the epoch
+    finished: u64,
+}
+
+/// History keeps the final states of the jobs for the retention window, the states
+/// are persisted so they are still reported after a restart
+pub struct History {
+    entries: Mutex<HashMap<String, Entry>>,
+    retention: Duration,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    pub fn new(retention: Duration, path: Option<PathBuf>) -> Self {
+        History {
+            entries: Mutex::default(),
+            retention,
+            path,
+        }
+    }
+
+    /// load returns the states recorded in the states file that are not expired yet,
+    /// it must be called before any state is recorded
+    pub fn load(&self) -> Result<Vec<(String, FlistState)>> {
+        let path = match self.path {
+            Some(ref path) if path.exists() => path,
+            _ => return Ok(Vec::default()),
+        };
+
+        let content = std::fs::read(path).context("failed to read states file")?;
+        let mut loaded: HashMap<String, Entry> =
+            serde_json::from_slice(&content).context("failed to parse states file")?;
+
+        let deadline = now().saturating_sub(self.retention.as_secs());
+        loaded.retain(|_, entry| entry.finished >= deadline);
+
+        let states = loaded
+            .iter()
+            .map(|(id, entry)| (id.clone(), entry.state.clone()))
+            .collect();
+
+        let mut entries = self.entries.lock().expect("failed to lock history");
+        *entries = loaded;
+        self.persist(&entries);
+
+        Ok(states)
+    }
+
+    /// record records the final state of a job
+    pub fn record(&self, id: &str, state: &FlistState) {
+        let mut entries = self.entries.lock().expect("failed to lock history");
+        entries.insert(
+            id.into(),
+            Entry {
+                state: state.clone(),
+                finished: now(),
+            },
+        );
+        self.persist(&entries);
+    }
+
+    /// remove forgets the states of the given jobs
+    pub fn remove(&self, ids: &[String]) {
+        let mut entries = self.entries.lock().expect("failed to lock history");
+        let count = entries.len();
+        entries.retain(|id, _| !ids.contains(id));
+        if entries.len() != count {
+            self.persist(&entries);
+        }
+    }
+
+    /// expire removes the states older than the retention window, it returns the
+    /// ids of the removed jobs
+    pub fn expire(&self) -> Vec<String> {
+        let mut entries = self.entries.lock().expect("failed to lock history");
+        let deadline = now().saturating_sub(self.retention.as_secs());
+        let expired: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| entry.finished < deadline)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        if !expired.is_empty() {
+            entries.retain(|id, _| !expired.contains(id));
+            self.persist(&entries);
+        }
+
+        expired
+    }
+
+    // persist writes the recorded states to the states file
+    fn persist(&self, entries: &HashMap<String, Entry>) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let result = serde_json::to_vec(entries)
+            .context("failed to serialize job states")
+            .and_then(|content| {
+                let tmp = path.with_extension("tmp");
+                std::fs::write(&tmp, content)?;
+                std::fs::rename(&tmp, path)?;
+                Ok(())
+            });
+
+        if let Err(err) = result {
+            log::error!("failed to persist job states to {:?}: {:#}", path, err);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// start spawns the removal of the expired job states
+pub fn start(state: Arc<config::AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let expired = state.history.expire();
+            if expired.is_empty() {
+                continue;
+            }
+
+            log::debug!("removing {} expired job states", expired.len());
+            state
+                .jobs_state
+                .lock()
+                .expect("failed to lock state")
+                .retain(|id, _| !expired.contains(id));
+        }
+    });
+}
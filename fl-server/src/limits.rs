@@ -0,0 +1,103 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// RateLimiter limits the number of requests of each user in a sliding window of
+/// one minute, no limit is applied if the rate is not set
+pub struct RateLimiter {
+    per_minute: Option<usize>,
+    requests: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: Option<usize>) -> Self {
+        RateLimiter {
+            per_minute,
+            requests: Mutex::default(),
+        }
+    }
+
+    /// check records a request of the user if it's allowed, otherwise it returns
+    /// the time after which the user can try again
+    pub fn check(&self, username: &str) -> Result<(), Duration> {
+        let per_minute = match self.per_minute {
+            Some(per_minute) => per_minute,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut requests = self.requests.lock().expect("failed to lock rate limiter");
+        // users that didn't send a request in the last window are forgotten
+        requests.retain(|_, times| {
+            times
+                .back()
+                .is_some_and(|last| now.duration_since(*last) < WINDOW)
+        });
+
+        let times = requests.entry(username.into()).or_default();
+        while let Some(first) = times.front() {
+            if now.duration_since(*first) < WINDOW {
+                break;
+            }
+            times.pop_front();
+        }
+
+        if times.len() >= per_minute {
+            let first = times.front().expect("a full window is not empty");
+            return Err(WINDOW - now.duration_since(*first));
+        }
+
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(None);
+        for _ in 0..1000 {
+            assert!(limiter.check("user").is_ok());
+        }
+    }
+
+    #[test]
+    fn test_limit() {
+        let limiter = RateLimiter::new(Some(2));
+        assert!(limiter.check("user").is_ok());
+        assert!(limiter.check("user").is_ok());
+
+        // the oldest request leaves the window in a minute at most
+        let wait = limiter.check("user").unwrap_err();
+        assert!(wait > Duration::default() && wait <= WINDOW);
+        assert!(limiter.check("user").is_err());
+
+        // a rejected request is not recorded and users are limited separately
+        assert_eq!(limiter.requests.lock().unwrap()["user"].len(), 2);
+        assert!(limiter.check("other").is_ok());
+    }
+
+    #[test]
+    fn test_window() {
+        let limiter = RateLimiter::new(Some(1));
+        assert!(limiter.check("user").is_ok());
+        assert!(limiter.check("user").is_err());
+
+        // move the request out of the window
+        let past = Instant::now() - WINDOW;
+        limiter
+            .requests
+            .lock()
+            .unwrap()
+            .insert("user".into(), VecDeque::from([past]));
+        assert!(limiter.check("user").is_ok());
+        assert!(limiter.check("user").is_err());
+    }
+}
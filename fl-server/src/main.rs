@@ -2,16 +2,22 @@ mod auth;
 mod config;
 mod db;
 mod handlers;
+mod history;
+mod limits;
+mod precompress;
+mod queue;
 mod response;
 mod serve_flists;
+mod webhook;
 
 use anyhow::{Context, Result};
 use axum::{
     error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
     http::StatusCode,
     middleware,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, post},
     BoxError, Router,
 };
 use clap::{ArgAction, Parser};
@@ -43,9 +49,15 @@ struct Options {
     /// config file path
     #[clap(short, long)]
     config_path: String,
+
+    /// format of the logs, json writes one json object per line
+    #[clap(long, default_value = "text", value_parser = ["text", "json"])]
+    log_format: String,
 }
 
 fn main() -> Result<()> {
+    // no thread is started yet
+    unsafe { rfs::store::tls::install_system_ca() };
     let rt = Builder::new_multi_thread()
         .thread_stack_size(8 * 1024 * 1024)
         .enable_all()
@@ -56,17 +68,22 @@ fn main() -> Result<()> {
 
 async fn app() -> Result<()> {
     let opts = Options::parse();
-    simple_logger::SimpleLogger::new()
-        .with_utc_timestamps()
-        .with_level({
-            match opts.debug {
-                0 => log::LevelFilter::Info,
-                1 => log::LevelFilter::Debug,
-                _ => log::LevelFilter::Trace,
-            }
-        })
-        .with_module_level("sqlx", log::Level::Error.to_level_filter())
-        .init()?;
+    let level = match opts.debug {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    match opts.log_format.as_str() {
+        "json" => rfs::logger::JsonLogger::new()
+            .with_level(level)
+            .with_module_level("sqlx", log::Level::Error.to_level_filter())
+            .init()?,
+        _ => simple_logger::SimpleLogger::new()
+            .with_utc_timestamps()
+            .with_level(level)
+            .with_module_level("sqlx", log::Level::Error.to_level_filter())
+            .init()?,
+    }
 
     let config = config::parse_config(&opts.config_path)
         .await
@@ -74,40 +91,108 @@ async fn app() -> Result<()> {
 
     let db = Arc::new(db::MapDB::new(&config.users.clone()));
 
+    let queue = queue::Queue::new(
+        config.workers,
+        config.queue_size,
+        config.jobs_file.as_ref().map(|path| path.into()),
+    );
+    let interrupted = queue
+        .interrupted()
+        .context("failed to load interrupted jobs")?;
+
+    let history = history::History::new(
+        Duration::from_secs(config.jobs_retention_secs),
+        config.states_file.as_ref().map(|path| path.into()),
+    );
+    let jobs_state = history
+        .load()
+        .context("failed to load job states")?
+        .into_iter()
+        .collect();
+
     let app_state = Arc::new(config::AppState {
-        jobs_state: Mutex::new(HashMap::new()),
+        jobs_state: Mutex::new(jobs_state),
         flists_progress: Mutex::new(HashMap::new()),
+        flists_meta: Mutex::new(HashMap::new()),
+        content: config::content_cache(&config).await?,
         db,
+        limiter: limits::RateLimiter::new(config.user_requests_per_minute),
         config,
+        queue,
+        history,
     });
 
+    handlers::recover(&app_state, interrupted).await;
+    queue::start(Arc::clone(&app_state));
+    history::start(Arc::clone(&app_state));
+
     let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
         .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
+    // credentials can't be allowed for any origin
+    let cors = match config::allowed_origins(&app_state.config.cors_origins)? {
+        Some(origins) => cors.allow_origin(origins).allow_credentials(true),
+        None => cors.allow_origin(Any),
+    };
 
     let v1_routes = Router::new()
         .route("/v1/api", get(handlers::health_check_handler))
         .route("/v1/api/signin", post(auth::sign_in_handler))
         .route(
-            "/v1/api/fl",
-            post(handlers::create_flist_handler).layer(middleware::from_fn_with_state(
+            "/v1/api/refresh",
+            post(auth::refresh_handler).layer(middleware::from_fn_with_state(
                 app_state.clone(),
                 auth::authorize,
             )),
         )
         .route(
-            "/v1/api/fl/:job_id",
+            "/v1/api/fl",
+            post(handlers::create_flist_handler)
+                .layer(DefaultBodyLimit::max(app_state.config.max_body_size))
+                .layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    auth::authorize,
+                )),
+        )
+        .route(
+            "/v1/api/fl/:id",
             get(handlers::get_flist_state_handler).layer(middleware::from_fn_with_state(
                 app_state.clone(),
                 auth::authorize,
             )),
         )
+        .route(
+            "/v1/api/fl/:id",
+            delete(handlers::delete_flist_handler).layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::authorize,
+            )),
+        )
+        .route(
+            "/v1/api/fl/:id/cancel",
+            post(handlers::cancel_flist_handler).layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::authorize,
+            )),
+        )
+        .route(
+            "/v1/api/fl/:id/progress",
+            get(handlers::flist_progress_handler).layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::authorize,
+            )),
+        )
+        .route("/v1/api/fl/:id/meta", get(handlers::flist_meta_handler))
+        .route(
+            "/v1/api/fl/:id/content/*path",
+            get(handlers::flist_content_handler),
+        )
         .route(
             "/v1/api/fl/preview/:flist_path",
             get(handlers::preview_flist_handler),
         )
         .route("/v1/api/fl", get(handlers::list_flists_handler))
+        .route("/v1/api/queue", get(handlers::queue_stats_handler))
         .route("/*path", get(serve_flists::serve_flists));
 
     let app = Router::new()
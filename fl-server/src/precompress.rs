@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// zstd level of the compressed flists, they are compressed once and downloaded
+/// many times so a high level is worth it
+const ZSTD_LEVEL: i32 = 19;
+
+/// a temporary file older than this is left over by a compression that didn't
+/// finish (the server was stopped) and is removed
+const STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Encoding of a pre-compressed copy of an flist
+#[derive(Debug, Clone, Copy)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 2] = [Encoding::Zstd, Encoding::Gzip];
+
+    /// extension that is added to the flist name, it's the one expected by
+    /// ServeDir precompressed_gzip and precompressed_zstd
+    fn ext(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Zstd => "zst",
+        }
+    }
+}
+
+/// sidecar is the path of the copy of the flist compressed with the encoding
+pub fn sidecar(fl_path: &Path, encoding: Encoding) -> PathBuf {
+    let mut path = OsString::from(fl_path.as_os_str());
+    path.push(".");
+    path.push(encoding.ext());
+    path.into()
+}
+
+/// is_fresh checks that all the compressed copies of the flist exist and are
+/// not older than the flist
+pub fn is_fresh(fl_path: &Path) -> bool {
+    let modified = match fs::metadata(fl_path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+
+    Encoding::ALL.iter().all(|encoding| {
+        matches!(
+            fs::metadata(sidecar(fl_path, *encoding)).and_then(|m| m.modified()),
+            Ok(compressed) if compressed >= modified
+        )
+    })
+}
+
+/// remove removes the compressed copies of the flist, missing copies are ignored
+pub async fn remove(fl_path: &Path) -> io::Result<()> {
+    for encoding in Encoding::ALL {
+        match tokio::fs::remove_file(sidecar(fl_path, encoding)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// precompress writes the compressed copies of the flist next to it so they are
+/// served to the clients that accept the encoding without compressing the flist
+/// on each request
+pub async fn precompress(fl_path: PathBuf) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        for encoding in Encoding::ALL {
+            compress(&fl_path, encoding).with_context(|| {
+                format!("failed to compress flist {:?} with {:?}", fl_path, encoding)
+            })?;
+        }
+
+        Ok(())
+    })
+    .await?
+}
+
+fn compress(fl_path: &Path, encoding: Encoding) -> Result<()> {
+    let target = sidecar(fl_path, encoding);
+    let mut tmp_path = OsString::from(target.as_os_str());
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let tmp = match create(&tmp_path)? {
+        Some(tmp) => tmp,
+        // another request is already compressing the flist
+        None => return Ok(()),
+    };
+
+    let written = File::open(fl_path).and_then(|mut source| match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(tmp, Compression::best());
+            io::copy(&mut source, &mut encoder)?;
+            encoder.finish()?.sync_all()
+        }
+        Encoding::Zstd => zstd::stream::copy_encode(&mut source, tmp, ZSTD_LEVEL),
+    });
+
+    // the copy is renamed only once it's complete so a partial copy is never served
+    if let Err(err) = written.and_then(|_| fs::rename(&tmp_path, &target)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+/// create creates the temporary file of a compression, None is returned if the
+/// file is used by a running compression
+fn create(tmp_path: &Path) -> io::Result<Option<File>> {
+    let open = || {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(tmp_path)
+    };
+    match open() {
+        Ok(tmp) => return Ok(Some(tmp)),
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+        Err(_) => {}
+    }
+
+    let modified = fs::metadata(tmp_path)?.modified()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    if age < STALE_AFTER {
+        return Ok(None);
+    }
+
+    fs::remove_file(tmp_path)?;
+    match open() {
+        Ok(tmp) => Ok(Some(tmp)),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,496 @@
+use anyhow::{Context, Result};
+use bollard::auth::DockerCredentials;
+use rfs::fungi::Writer;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
+
+use crate::{config, handlers, precompress};
+
+/// Record of a conversion job, records of queued and running jobs are persisted
+/// so the jobs interrupted by a restart are known
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub id: String,
+    pub username: String,
+    pub image: String,
+    pub fl_name: String,
+    pub fl_path: PathBuf,
+    pub priority: u8,
+    /// the job uses registry credentials, credentials are never persisted so
+    /// private jobs can't be enqueued again after a restart
+    pub private: bool,
+    /// webhook notified once the job is done, overrides the one of the config
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+}
+
+/// Conversion is a job waiting in the queue
+pub struct Conversion {
+    pub record: Record,
+    pub credentials: Option<DockerCredentials>,
+    pub meta: Writer,
+    pub identity: Option<String>,
+    /// cancelled if the job is cancelled while it's running
+    pub cancel: CancellationToken,
+}
+
+/// Cancelled is a cancelled job
+pub enum Cancelled {
+    /// the job was still queued, it's removed from the queue and never started
+    Queued(Box<Conversion>),
+    /// the job is running, its conversion is stopped
+    Running,
+}
+
+/// Rejected is the reason a conversion is not added to the queue
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejected {
+    /// the queue is full
+    Full,
+    /// the user already has the maximum number of queued and running conversions
+    UserJobs(usize),
+}
+
+/// Stats of the conversion queue
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct QueueStats {
+    pub workers: usize,
+    pub capacity: usize,
+    pub queued: usize,
+    pub running: usize,
+}
+
+struct Item {
+    seq: u64,
+    conversion: Conversion,
+}
+
+impl Item {
+    // higher priority first, then the oldest job
+    fn key(&self) -> (u8, std::cmp::Reverse<u64>) {
+        (self.conversion.record.priority, std::cmp::Reverse(self.seq))
+    }
+}
+
+impl PartialEq for Item {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Item {}
+
+impl PartialOrd for Item {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Item {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    queued: BinaryHeap<Item>,
+    running: HashMap<String, Record>,
+    cancels: HashMap<String, CancellationToken>,
+    seq: u64,
+}
+
+impl Inner {
+    fn user_jobs(&self, username: &str) -> usize {
+        self.running
+            .values()
+            .chain(self.queued.iter().map(|item| &item.conversion.record))
+            .filter(|record| record.username == username)
+            .count()
+    }
+}
+
+/// Queue is a bounded priority queue of conversion jobs processed by a fixed
+/// number of workers
+pub struct Queue {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    workers: usize,
+    capacity: usize,
+    path: Option<PathBuf>,
+}
+
+impl Queue {
+    pub fn new(workers: usize, capacity: usize, path: Option<PathBuf>) -> Self {
+        Queue {
+            inner: Mutex::default(),
+            notify: Notify::new(),
+            workers: workers.max(1),
+            capacity,
+            path,
+        }
+    }
+
+    /// interrupted returns the jobs that were queued or running when the server
+    /// stopped, it must be called before any job is pushed
+    pub fn interrupted(&self) -> Result<Vec<Record>> {
+        let path = match self.path {
+            Some(ref path) if path.exists() => path,
+            _ => return Ok(Vec::default()),
+        };
+
+        let content = std::fs::read(path).context("failed to read jobs file")?;
+        let records: Vec<Record> =
+            serde_json::from_slice(&content).context("failed to parse jobs file")?;
+
+        Ok(records)
+    }
+
+    /// push adds a conversion to the queue, it fails if the queue is full
+    pub fn push(&self, conversion: Conversion) -> Result<()> {
+        match self.push_user(conversion, None) {
+            Err(Rejected::Full) => anyhow::bail!("conversion queue is full"),
+            _ => Ok(()),
+        }
+    }
+
+    /// push_user adds a conversion to the queue like push but it also fails if the user
+    /// already has max_jobs queued and running conversions. The jobs are counted under
+    /// the same lock so concurrent requests of a user can't go over the limit
+    pub fn push_user(
+        &self,
+        conversion: Conversion,
+        max_jobs: Option<usize>,
+    ) -> std::result::Result<(), Rejected> {
+        {
+            let mut inner = self.lock();
+            if inner.queued.len() >= self.capacity {
+                return Err(Rejected::Full);
+            }
+
+            if let Some(max_jobs) = max_jobs {
+                if inner.user_jobs(&conversion.record.username) >= max_jobs {
+                    return Err(Rejected::UserJobs(max_jobs));
+                }
+            }
+
+            inner.seq += 1;
+            let seq = inner.seq;
+            inner.queued.push(Item { seq, conversion });
+            self.persist(&inner);
+        }
+
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// pop waits for the next conversion, the job is considered running until done is called
+    pub async fn pop(&self) -> Conversion {
+        loop {
+            {
+                let mut inner = self.lock();
+                if let Some(item) = inner.queued.pop() {
+                    let record = item.conversion.record.clone();
+                    inner
+                        .cancels
+                        .insert(record.id.clone(), item.conversion.cancel.clone());
+                    inner.running.insert(record.id.clone(), record);
+                    self.persist(&inner);
+                    return item.conversion;
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    /// done marks the job as finished
+    pub fn done(&self, id: &str) {
+        let mut inner = self.lock();
+        inner.running.remove(id);
+        inner.cancels.remove(id);
+        self.persist(&inner);
+    }
+
+    /// record returns the record of a queued or running job
+    pub fn record(&self, id: &str) -> Option<Record> {
+        let inner = self.lock();
+        inner
+            .running
+            .get(id)
+            .or_else(|| {
+                inner
+                    .queued
+                    .iter()
+                    .map(|item| &item.conversion.record)
+                    .find(|record| record.id == id)
+            })
+            .cloned()
+    }
+
+    /// cancel removes a queued job from the queue or stops a running job, None is
+    /// returned if the job is not queued nor running
+    pub fn cancel(&self, id: &str) -> Option<Cancelled> {
+        let mut inner = self.lock();
+        if let Some(cancel) = inner.cancels.get(id) {
+            cancel.cancel();
+            return Some(Cancelled::Running);
+        }
+
+        let (cancelled, queued): (Vec<Item>, Vec<Item>) = std::mem::take(&mut inner.queued)
+            .into_vec()
+            .into_iter()
+            .partition(|item| item.conversion.record.id == id);
+        inner.queued = queued.into();
+
+        let item = cancelled.into_iter().next()?;
+        self.persist(&inner);
+        Some(Cancelled::Queued(Box::new(item.conversion)))
+    }
+
+    /// job returns the id of the queued or running conversion of the given flist
+    pub fn job(&self, fl_path: &Path) -> Option<String> {
+        let inner = self.lock();
+        inner
+            .running
+            .values()
+            .chain(inner.queued.iter().map(|item| &item.conversion.record))
+            .find(|record| record.fl_path == fl_path)
+            .map(|record| record.id.clone())
+    }
+
+    /// position returns the position (starting from 1) of a queued job in the queue,
+    /// it's the number of jobs that are started before it plus one
+    pub fn position(&self, id: &str) -> Option<usize> {
+        let inner = self.lock();
+        let key = inner
+            .queued
+            .iter()
+            .find(|item| item.conversion.record.id == id)?
+            .key();
+
+        Some(inner.queued.iter().filter(|item| item.key() > key).count() + 1)
+    }
+
+    /// user_jobs returns the number of queued and running conversions of the user
+    pub fn user_jobs(&self, username: &str) -> usize {
+        self.lock().user_jobs(username)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let inner = self.lock();
+        QueueStats {
+            workers: self.workers,
+            capacity: self.capacity,
+            queued: inner.queued.len(),
+            running: inner.running.len(),
+        }
+    }
+
+    // lock locks the state of the queue. The state is never left half updated, so
+    // it's still used if a thread panicked while holding the lock
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // persist writes the records of the queued and running jobs to the jobs file
+    fn persist(&self, inner: &Inner) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let records: Vec<&Record> = inner
+            .running
+            .values()
+            .chain(inner.queued.iter().map(|item| &item.conversion.record))
+            .collect();
+
+        let result = serde_json::to_vec(&records)
+            .context("failed to serialize jobs")
+            .and_then(|content| {
+                let tmp = path.with_extension("tmp");
+                std::fs::write(&tmp, content)?;
+                std::fs::rename(&tmp, path)?;
+                Ok(())
+            });
+
+        if let Err(err) = result {
+            log::error!("failed to persist jobs to {:?}: {:#}", path, err);
+        }
+    }
+}
+
+/// start spawns the conversion workers
+pub fn start(state: Arc<config::AppState>) {
+    for _ in 0..state.queue.workers {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                let conversion = state.queue.pop().await;
+                let record = conversion.record.clone();
+                // the job fails if its conversion panics, the worker goes on
+                let job = tokio::spawn(handlers::convert(Arc::clone(&state), conversion));
+                if let Err(err) = job.await {
+                    log::error!(
+                        job_id = record.id.as_str();
+                        "flist `{:?}` creation panicked: {}",
+                        record.fl_path,
+                        err
+                    );
+                    let _ = tokio::fs::remove_file(&record.fl_path).await;
+                    let _ = precompress::remove(&record.fl_path).await;
+                    handlers::finish(&state, &record.id, handlers::FlistState::Failed);
+                }
+                state.queue.done(&record.id);
+                handlers::notify(&state, &record);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn conversion(id: &str, priority: u8) -> Conversion {
+        let fl_path = PathBuf::from(format!("/tmp/fl-server-queue-test-{}.fl", id));
+        Conversion {
+            record: Record {
+                id: id.into(),
+                username: "user".into(),
+                image: "alpine".into(),
+                fl_name: format!("{}.fl", id),
+                fl_path: fl_path.clone(),
+                priority,
+                private: false,
+                webhook_url: None,
+            },
+            credentials: None,
+            meta: Writer::new(&fl_path, true).await.unwrap(),
+            identity: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let queue = Queue::new(1, 10, None);
+        for (id, priority) in [("a", 1), ("b", 5), ("c", 5), ("d", 0)] {
+            queue.push(conversion(id, priority).await).unwrap();
+        }
+
+        assert_eq!(queue.position("b"), Some(1));
+        assert_eq!(queue.position("c"), Some(2));
+        assert_eq!(queue.position("d"), Some(4));
+
+        // higher priority first, then the oldest job
+        for id in ["b", "c", "a", "d"] {
+            assert_eq!(queue.pop().await.record.id, id);
+        }
+        assert_eq!(queue.stats().running, 4);
+    }
+
+    #[tokio::test]
+    async fn test_full() {
+        let queue = Queue::new(1, 2, None);
+        queue.push(conversion("full-a", 0).await).unwrap();
+        queue.push(conversion("full-b", 0).await).unwrap();
+        assert!(queue.push(conversion("full-c", 0).await).is_err());
+        assert_eq!(queue.stats().queued, 2);
+
+        // the running jobs don't take a place in the queue
+        queue.pop().await;
+        queue.push(conversion("full-c", 0).await).unwrap();
+        assert_eq!(queue.stats().queued, 2);
+        assert_eq!(queue.stats().running, 1);
+    }
+
+    #[tokio::test]
+    async fn test_user_jobs() {
+        let queue = Queue::new(1, 10, None);
+        queue
+            .push_user(conversion("user-a", 0).await, Some(2))
+            .unwrap();
+        queue
+            .push_user(conversion("user-b", 0).await, Some(2))
+            .unwrap();
+        assert_eq!(
+            queue.push_user(conversion("user-c", 0).await, Some(2)),
+            Err(Rejected::UserJobs(2))
+        );
+        assert_eq!(queue.user_jobs("user"), 2);
+
+        // running jobs count until they are done
+        queue.pop().await;
+        assert_eq!(
+            queue.push_user(conversion("user-c", 0).await, Some(2)),
+            Err(Rejected::UserJobs(2))
+        );
+        queue.done("user-a");
+        queue
+            .push_user(conversion("user-c", 0).await, Some(2))
+            .unwrap();
+        assert_eq!(queue.user_jobs("user"), 2);
+        assert_eq!(queue.user_jobs("other"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let queue = Queue::new(1, 10, None);
+        queue.push(conversion("cancel-a", 0).await).unwrap();
+        queue.push(conversion("cancel-b", 0).await).unwrap();
+
+        match queue.cancel("cancel-b") {
+            Some(Cancelled::Queued(conversion)) => assert_eq!(conversion.record.id, "cancel-b"),
+            _ => panic!("queued job is not cancelled"),
+        }
+        assert!(queue.record("cancel-b").is_none());
+        assert!(queue.cancel("cancel-b").is_none());
+
+        let running = queue.pop().await;
+        assert!(matches!(queue.cancel("cancel-a"), Some(Cancelled::Running)));
+        assert!(running.cancel.is_cancelled());
+
+        queue.done("cancel-a");
+        assert!(queue.cancel("cancel-a").is_none());
+        assert_eq!(queue.stats().queued, 0);
+        assert_eq!(queue.stats().running, 0);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted() {
+        let path = PathBuf::from("/tmp/fl-server-queue-test-jobs.json");
+        let _ = std::fs::remove_file(&path);
+
+        let queue = Queue::new(1, 10, Some(path.clone()));
+        assert!(queue.interrupted().unwrap().is_empty());
+        queue.push(conversion("interrupted-a", 0).await).unwrap();
+        queue.push(conversion("interrupted-b", 0).await).unwrap();
+        queue.push(conversion("interrupted-c", 0).await).unwrap();
+        queue.pop().await;
+        queue.done("interrupted-a");
+        queue.pop().await;
+
+        // the running and the queued jobs are reloaded, the done ones are not
+        let queue = Queue::new(1, 10, Some(path));
+        let mut ids: Vec<String> = queue
+            .interrupted()
+            .unwrap()
+            .into_iter()
+            .map(|record| record.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["interrupted-b", "interrupted-c"]);
+    }
+}
@@ -1,9 +1,7 @@
-use std::collections::HashMap;
-
 use askama::Template;
 use axum::{
     body::Body,
-    http::StatusCode,
+    http::{header::RETRY_AFTER, StatusCode},
     response::{Html, IntoResponse, Response},
     Json,
 };
@@ -13,7 +11,8 @@ use utoipa::ToSchema;
 use crate::{
     auth::SignInResponse,
     config::Job,
-    handlers::{FlistState, PreviewResponse},
+    handlers::{FlistMeta, FlistState, FlistsPage, PreviewResponse},
+    queue::QueueStats,
 };
 
 #[derive(Serialize, ToSchema)]
@@ -24,9 +23,37 @@ pub enum ResponseError {
     Unauthorized(String),
     BadRequest(String),
     Forbidden(String),
+    ServiceUnavailable(String),
+    /// the message and the number of seconds to wait before retrying
+    TooManyRequests(String, u64),
+    /// the invalid fields of the request body
+    Invalid(Vec<FieldError>),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
     TemplateError(ErrorTemplate),
 }
 
+/// FieldError is an invalid field of a request body
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    /// name of the field, `body` if the body can't be parsed at all
+    pub field: String,
+    pub msg: String,
+}
+
+/// ErrorMessage is the json response to a request that can't be handled
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ErrorMessage {
+    pub msg: String,
+}
+
+/// InvalidBody is the response to a request with invalid fields
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct InvalidBody {
+    pub msg: String,
+    pub errors: Vec<FieldError>,
+}
+
 impl IntoResponse for ResponseError {
     fn into_response(self) -> Response<Body> {
         match self {
@@ -38,6 +65,29 @@ impl IntoResponse for ResponseError {
             ResponseError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg).into_response(),
             ResponseError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
             ResponseError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg).into_response(),
+            ResponseError::ServiceUnavailable(msg) => {
+                (StatusCode::SERVICE_UNAVAILABLE, msg).into_response()
+            }
+            ResponseError::Invalid(errors) => (
+                StatusCode::BAD_REQUEST,
+                Json(InvalidBody {
+                    msg: "invalid request body".into(),
+                    errors,
+                }),
+            )
+                .into_response(),
+            ResponseError::PayloadTooLarge(msg) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, Json(ErrorMessage { msg })).into_response()
+            }
+            ResponseError::UnsupportedMediaType(msg) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg).into_response()
+            }
+            ResponseError::TooManyRequests(msg, retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+                msg,
+            )
+                .into_response(),
             ResponseError::TemplateError(t) => match t.render() {
                 Ok(html) => {
                     let mut resp = Html(html).into_response();
@@ -77,9 +127,13 @@ impl IntoResponse for ResponseError {
 pub enum ResponseResult {
     Health,
     FlistCreated(Job),
+    FlistCached(Job),
+    FlistDeleted,
     FlistState(FlistState),
-    Flists(HashMap<String, Vec<FileInfo>>),
+    FlistMeta(FlistMeta),
+    Flists(FlistsPage),
     PreviewFlist(PreviewResponse),
+    Queue(QueueStats),
     SignedIn(SignInResponse),
     DirTemplate(DirListTemplate),
     Res(hyper::Response<tower_http::services::fs::ServeFileSystemResponseBody>),
@@ -95,6 +149,14 @@ impl IntoResponse for ResponseResult {
                 .into_response(),
             ResponseResult::SignedIn(token) => (StatusCode::CREATED, Json(token)).into_response(),
             ResponseResult::FlistCreated(job) => (StatusCode::CREATED, Json(job)).into_response(),
+            ResponseResult::FlistCached(job) => (
+                StatusCode::OK,
+                Json(serde_json::json!({
+                    "id": job.id,
+                    "cached": true
+                })),
+            )
+                .into_response(),
             ResponseResult::FlistState(flist_state) => (
                 StatusCode::OK,
                 Json(serde_json::json!({
@@ -102,7 +164,10 @@ impl IntoResponse for ResponseResult {
                 })),
             )
                 .into_response(),
+            ResponseResult::FlistDeleted => StatusCode::NO_CONTENT.into_response(),
+            ResponseResult::FlistMeta(meta) => (StatusCode::OK, Json(meta)).into_response(),
             ResponseResult::Flists(flists) => (StatusCode::OK, Json(flists)).into_response(),
+            ResponseResult::Queue(stats) => (StatusCode::OK, Json(stats)).into_response(),
             ResponseResult::PreviewFlist(content) => {
                 (StatusCode::OK, Json(content)).into_response()
             }
@@ -6,14 +6,17 @@ use tower_http::services::ServeDir;
 
 use axum::{
     body::Body,
-    http::{Request, StatusCode},
+    http::{
+        header::{IF_RANGE, LAST_MODIFIED, RANGE},
+        Request, StatusCode,
+    },
     response::IntoResponse,
 };
 use axum_macros::debug_handler;
 use percent_encoding::percent_decode;
 
 use crate::{
-    config,
+    config, precompress,
     response::{
         DirListTemplate, DirLister, ErrorTemplate, FileInfo, ResponseError, ResponseResult,
         TemplateErr,
@@ -27,7 +30,40 @@ pub async fn serve_flists(
 ) -> impl IntoResponse {
     let path = req.uri().path().to_string();
 
-    return match ServeDir::new("").oneshot(req).await {
+    if path.ends_with(".fl") {
+        if let Ok(fl_path) = validate_path(&path) {
+            refresh_compressed(&state, fl_path).await;
+        }
+    }
+
+    // the compressed copy of an flist is served if the client accepts its encoding,
+    // range requests are still supported for the uncompressed flist
+    let serve = ServeDir::new("").precompressed_zstd().precompressed_gzip();
+
+    let if_range = req.headers().get(IF_RANGE).cloned();
+    let full = without_range(&req);
+    let mut served = serve.clone().oneshot(req).await;
+
+    // ServeDir answers the range requests (206, or 416 for unsatisfiable and
+    // multi-range requests) but doesn't check If-Range. A client resuming the
+    // download of an flist that changed since gets the whole new flist instead
+    if let (Some(if_range), Some(full)) = (if_range, full) {
+        let stale = match &served {
+            Ok(res) => {
+                let ranged = matches!(
+                    res.status(),
+                    StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE
+                );
+                ranged && res.headers().get(LAST_MODIFIED) != Some(&if_range)
+            }
+            Err(_) => false,
+        };
+        if stale {
+            served = serve.oneshot(full).await;
+        }
+    }
+
+    match served {
         Ok(res) => {
             let status = res.status();
             match status {
@@ -75,7 +111,63 @@ pub async fn serve_flists(
             cur_path: path.to_string(),
             message: format!("Unhandled error: {}", err),
         })),
-    };
+    }
+}
+
+/// refresh_compressed compresses the flists that were created without compressed
+/// copies (or were changed after they were compressed) in the background. The
+/// flist is served uncompressed until its copies are written
+async fn refresh_compressed(state: &Arc<config::AppState>, fl_path: PathBuf) {
+    if !fl_path.is_file() || precompress::is_fresh(&fl_path) {
+        return;
+    }
+
+    let in_progress = matches!(
+        state
+            .flists_progress
+            .lock()
+            .expect("failed to lock state")
+            .get(&fl_path),
+        Some(progress) if *progress < 100.0
+    );
+    if in_progress {
+        return;
+    }
+
+    // stale copies must not be served
+    if let Err(err) = precompress::remove(&fl_path).await {
+        log::error!(
+            "failed to remove compressed copies of flist `{:?}` with error {}",
+            fl_path,
+            err
+        );
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Err(err) = precompress::precompress(fl_path).await {
+            log::error!("{:#}", err);
+        }
+    });
+}
+
+/// without_range returns a copy of a conditional range request that asks for the
+/// whole file, the request is expected to be a GET (or HEAD) without a body
+fn without_range(req: &Request<Body>) -> Option<Request<Body>> {
+    let headers = req.headers();
+    if !headers.contains_key(RANGE) || !headers.contains_key(IF_RANGE) {
+        return None;
+    }
+
+    let mut full = Request::new(Body::empty());
+    *full.method_mut() = req.method().clone();
+    *full.uri_mut() = req.uri().clone();
+    *full.version_mut() = req.version();
+    *full.headers_mut() = headers.clone();
+    full.headers_mut().remove(RANGE);
+    full.headers_mut().remove(IF_RANGE);
+
+    Some(full)
 }
 
 fn validate_path(path: &str) -> io::Result<PathBuf> {
@@ -0,0 +1,233 @@
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Failed,
+    Cancelled,
+}
+
+/// Notification is the body posted to the webhook once a conversion is done
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Notification {
+    pub id: String,
+    pub username: String,
+    pub fl_name: String,
+    /// download url of the flist, only set if the flist is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub status: Status,
+}
+
+/// validate checks that a webhook url can be posted to
+pub fn validate(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(u) if u.scheme() == "http" || u.scheme() == "https" => Ok(()),
+        Ok(u) => Err(format!(
+            "webhook url scheme '{}' is not http(s)",
+            u.scheme()
+        )),
+        Err(err) => Err(format!("invalid webhook url '{}': {}", url, err)),
+    }
+}
+
+/// validate_public checks that a webhook url given by a user can be posted to, the
+/// host can't be localhost or an address of a private network. Host names are
+/// checked once they are resolved, when the notification is posted
+pub fn validate_public(url: &str) -> Result<(), String> {
+    validate(url)?;
+    let u = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+    // an ipv6 host is kept in brackets by the url
+    let host = u.host_str().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let public = match host.parse::<IpAddr>() {
+        Ok(ip) => is_public(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_ascii_lowercase();
+            !domain.is_empty() && domain != "localhost" && !domain.ends_with(".localhost")
+        }
+    };
+
+    if !public {
+        return Err(format!("webhook url '{}' is not a public address", url));
+    }
+
+    Ok(())
+}
+
+// is_public is false for the loopback, private, link local and other reserved
+// addresses that the server must not be made to post to
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || first == 0
+                // shared address space of carrier grade nat, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local fc00::/7 and link local fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+// public_addr resolves the host of the url and returns its address, it fails if the
+// host has any address that is not public
+async fn public_addr(url: &reqwest::Url) -> Result<(String, SocketAddr), String> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("webhook url '{}' has no host", url))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| format!("webhook url '{}' has no port", url))?;
+
+    // an ipv6 host is kept in brackets by the url
+    let name = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name, port))
+        .await
+        .map_err(|err| format!("failed to resolve webhook host '{}': {}", host, err))?
+        .collect();
+
+    match addrs.first() {
+        Some(addr) if addrs.iter().all(|addr| is_public(addr.ip())) => {
+            Ok((host.to_string(), *addr))
+        }
+        Some(_) => Err(format!("webhook host '{}' is not a public address", host)),
+        None => Err(format!("webhook host '{}' has no address", host)),
+    }
+}
+
+/// notify posts the notification to the webhook in the background, the post is
+/// retried a few times if it fails. The webhook of a user (public_only) is only
+/// posted to if its host resolves to public addresses, the post goes to the checked
+/// address and redirects are not followed so the server can't be made to reach its
+/// own network
+pub fn notify(url: String, notification: Notification, public_only: bool) {
+    tokio::spawn(async move {
+        let mut builder = reqwest::Client::builder().timeout(TIMEOUT);
+        if public_only {
+            let resolved = match reqwest::Url::parse(&url) {
+                Ok(u) => public_addr(&u).await,
+                Err(err) => Err(err.to_string()),
+            };
+
+            let (host, addr) = match resolved {
+                Ok(resolved) => resolved,
+                Err(err) => {
+                    log::error!(
+                        "webhook {} of job {} refused: {}",
+                        url,
+                        notification.id,
+                        err
+                    );
+                    return;
+                }
+            };
+
+            builder = builder
+                .resolve(&host, addr)
+                .redirect(reqwest::redirect::Policy::none());
+        }
+
+        let client = match builder.build() {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("failed to create webhook client: {}", err);
+                return;
+            }
+        };
+
+        let body = match serde_json::to_vec(&notification) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("failed to serialize webhook notification: {}", err);
+                return;
+            }
+        };
+
+        for attempt in 1..=ATTEMPTS {
+            let result = client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => return,
+                Err(err) if attempt < ATTEMPTS => {
+                    log::warn!(
+                        "webhook {} of job {} failed (attempt {}/{}): {}",
+                        url,
+                        notification.id,
+                        attempt,
+                        ATTEMPTS,
+                        err
+                    );
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                }
+                Err(err) => {
+                    log::error!("webhook {} of job {} failed: {}", url, notification.id, err)
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate_public() {
+        for url in [
+            "https://ci.example.com/hooks/flist",
+            "http://8.8.8.8:8080/hook",
+            "http://[2001:4860::8888]/hook",
+        ] {
+            assert!(validate_public(url).is_ok(), "{}", url);
+        }
+
+        for url in [
+            "ftp://ci.example.com/hook",
+            "http://localhost:8080/hook",
+            "http://api.localhost/hook",
+            "http://127.0.0.1/hook",
+            "http://10.1.2.3/hook",
+            "http://192.168.1.1/hook",
+            "http://172.16.0.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(validate_public(url).is_err(), "{}", url);
+        }
+    }
+}
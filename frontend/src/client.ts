@@ -1,4 +1,5 @@
 import axios from "axios";
+import { FlistsPage, FlistsResponseInterface } from "./types/Flist.ts";
 
 
 
@@ -9,3 +10,19 @@ export const api = axios.create({
     Authorization: "Bearer " + sessionStorage.getItem("token"),
   },
 });
+
+// listFlists gets all the pages of the flists listing
+export const listFlists = async (): Promise<FlistsResponseInterface> => {
+  const flists: FlistsResponseInterface = {};
+  let page: number | null = 1;
+  while (page !== null) {
+    const res: FlistsPage = (
+      await api.get<FlistsPage>("/v1/api/fl", { params: { page, per_page: 1000 } })
+    ).data;
+    for (const username in res.flists) {
+      flists[username] = (flists[username] || []).concat(res.flists[username]);
+    }
+    page = res.next;
+  }
+  return flists;
+};
@@ -23,6 +23,15 @@ export interface FlistsResponseInterface {
   [key: string]: FlistBody[];
 }
 
+export interface FlistsPage {
+  flists: FlistsResponseInterface;
+  page: number;
+  per_page: number;
+  total: number;
+  next: number | null;
+  prev: number | null;
+}
+
 export interface FlistPreview{
   checksum: string;
   content: string[];
@@ -22,14 +22,25 @@ CREATE TABLE IF NOT EXISTS extra (
 );
 
 -- blocks per file, order of insertion is important
+-- size is only set for files chunked with a content defined chunker, otherwise
+-- all blocks are of the fixed block size (512K) except the last one
 CREATE TABLE IF NOT EXISTS block (
     ino INTEGER,
     id VARCHAR(32),
-    key VARCHAR(32)
+    key VARCHAR(32),
+    size INTEGER
 );
 
 CREATE INDEX IF NOT EXISTS block_ino ON block (ino);
 
+-- extended attributes per inode, flists created by older versions don't have this table
+CREATE TABLE IF NOT EXISTS xattr (
+    ino INTEGER,
+    key VARCHAR(255),
+    value BLOB,
+    PRIMARY KEY (ino, key)
+);
+
 -- global flist tags, this can include values like `version`, `description`, `block-size`, etc..
 -- it can also hold extra user-defined tags for extensions
 CREATE TABLE IF NOT EXISTS tag (
@@ -0,0 +1,196 @@
+use crate::store::Store;
+use futures::stream::{self, StreamExt};
+use rand::RngCore;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+/// Stats of a benchmark run of one store operation
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// number of successful operations
+    pub count: usize,
+    /// number of failed operations
+    pub errors: usize,
+    /// total bytes transferred by the successful operations
+    pub bytes: u64,
+    /// wall time of the whole run
+    pub elapsed: Duration,
+    // sorted latencies of successful operations
+    latencies: Vec<Duration>,
+}
+
+impl Stats {
+    fn new(results: Vec<Option<(Duration, u64)>>, elapsed: Duration) -> Self {
+        let mut stats = Stats {
+            elapsed,
+            ..Default::default()
+        };
+
+        for result in results {
+            match result {
+                Some((latency, bytes)) => {
+                    stats.count += 1;
+                    stats.bytes += bytes;
+                    stats.latencies.push(latency);
+                }
+                None => stats.errors += 1,
+            }
+        }
+
+        stats.latencies.sort();
+        stats
+    }
+
+    /// throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// percentile returns the latency at the given percentile (0.0 to 1.0)
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let index = ((self.latencies.len() - 1) as f64 * p.clamp(0.0, 1.0)).round() as usize;
+        self.latencies[index]
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ops: {}, errors: {}, throughput: {:.2} MiB/s, p50: {:?}, p90: {:?}, p99: {:?}, max: {:?}",
+            self.count,
+            self.errors,
+            self.throughput() / (1024.0 * 1024.0),
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.percentile(1.0),
+        )
+    }
+}
+
+/// keys generates count random keys that can be used for a benchmark
+pub fn keys(count: usize) -> Vec<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    (0..count)
+        .map(|_| {
+            let mut key = vec![0; 32];
+            rng.fill_bytes(&mut key);
+            key
+        })
+        .collect()
+}
+
+/// set writes a random blob of the given size for each key, with up to concurrency
+/// operations in flight
+pub async fn set<S: Store>(store: &S, keys: &[Vec<u8>], size: usize, concurrency: usize) -> Stats {
+    let mut blob = vec![0; size];
+    rand::thread_rng().fill_bytes(&mut blob);
+    let blob = &blob;
+
+    let start = Instant::now();
+    let results: Vec<Option<(Duration, u64)>> = stream::iter(keys)
+        .map(|key| async move {
+            // make every blob unique so stores can't dedup them
+            let mut blob = blob.clone();
+            let prefix = blob.len().min(key.len());
+            blob[..prefix].copy_from_slice(&key[..prefix]);
+
+            let start = Instant::now();
+            match store.set(key, &blob).await {
+                Ok(_) => Some((start.elapsed(), blob.len() as u64)),
+                Err(err) => {
+                    log::debug!("failed to set key {}: {}", hex::encode(key), err);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    Stats::new(results, start.elapsed())
+}
+
+/// get reads all the keys from the store, with up to concurrency operations in flight
+pub async fn get<S: Store>(store: &S, keys: &[Vec<u8>], concurrency: usize) -> Stats {
+    let start = Instant::now();
+    let results: Vec<Option<(Duration, u64)>> = stream::iter(keys)
+        .map(|key| async move {
+            let start = Instant::now();
+            match store.get(key).await {
+                Ok(blob) => Some((start.elapsed(), blob.len() as u64)),
+                Err(err) => {
+                    log::debug!("failed to get key {}: {}", hex::encode(key), err);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    Stats::new(results, start.elapsed())
+}
+
+/// delete removes all the keys from the store, with up to concurrency operations in
+/// flight. No bytes are transferred so only the counts and latencies are set
+pub async fn delete<S: Store>(store: &S, keys: &[Vec<u8>], concurrency: usize) -> Stats {
+    let start = Instant::now();
+    let results: Vec<Option<(Duration, u64)>> = stream::iter(keys)
+        .map(|key| async move {
+            let start = Instant::now();
+            match store.delete(key).await {
+                Ok(_) => Some((start.elapsed(), 0)),
+                Err(err) => {
+                    log::debug!("failed to delete key {}: {}", hex::encode(key), err);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    Stats::new(results, start.elapsed())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::mem::MemStore;
+
+    #[tokio::test]
+    async fn test_bench() {
+        let store = MemStore::default();
+        let keys = keys(100);
+
+        let stats = set(&store, &keys, 1024, 10).await;
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.bytes, 100 * 1024);
+
+        let stats = get(&store, &keys, 10).await;
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.bytes, 100 * 1024);
+        assert!(stats.percentile(0.5) <= stats.percentile(0.99));
+
+        let stats = get(&store, &super::keys(10), 10).await;
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.errors, 10);
+
+        let stats = delete(&store, &keys, 10).await;
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.bytes, 0);
+        let stats = get(&store, &keys, 10).await;
+        assert_eq!(stats.errors, 100);
+    }
+}
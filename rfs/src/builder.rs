@@ -0,0 +1,313 @@
+use crate::fungi::meta::{FileType, Ino, Inode, Mode};
+use crate::fungi::{Error, Result, Writer};
+use crate::pack::{read_full, write_routes, BLOB_SIZE};
+use crate::store::{BlockStore, Store};
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncRead;
+
+/// Attributes of an entry added to the FlistBuilder
+#[derive(Debug, Clone)]
+pub struct Attributes {
+    pub uid: u32,
+    pub gid: u32,
+    /// permission bits of the entry, the file type bits are ignored
+    pub perm: u32,
+    pub ctime: i64,
+    pub mtime: i64,
+}
+
+impl Attributes {
+    /// default attributes for a directory (root owned 0755)
+    pub fn dir() -> Self {
+        Self::with_perm(0o755)
+    }
+
+    /// default attributes for a file (root owned 0644)
+    pub fn file() -> Self {
+        Self::with_perm(0o644)
+    }
+
+    fn with_perm(perm: u32) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+
+        Self {
+            uid: 0,
+            gid: 0,
+            perm,
+            ctime: now,
+            mtime: now,
+        }
+    }
+}
+
+/// FlistBuilder creates an FL from entries provided by the caller instead of
+/// walking a local directory. File content is chunked and uploaded to the store
+/// exactly the same way `pack` does.
+///
+/// Entries can be added in any order. Missing parent directories are created
+/// automatically with default attributes, adding a directory that was implicitly
+/// created before is an error, so add directories before their children if you
+/// care about their attributes.
+pub struct FlistBuilder<S: Store> {
+    writer: Writer,
+    store: BlockStore<S>,
+    dirs: HashMap<PathBuf, Ino>,
+}
+
+impl<S> FlistBuilder<S>
+where
+    S: Store,
+{
+    /// create a new builder, the store routes are written to the FL right away
+    pub async fn new(writer: Writer, store: S, strip_password: bool) -> Result<Self> {
+        write_routes(&writer, &store, strip_password).await?;
+
+        let root = writer
+            .inode(Inode {
+                name: "/".into(),
+                mode: Mode::new(FileType::Dir, 0o755),
+                ..Inode::default()
+            })
+            .await?;
+
+        let mut dirs = HashMap::default();
+        dirs.insert(PathBuf::from("/"), root);
+
+        Ok(Self {
+            writer,
+            store: store.into(),
+            dirs,
+        })
+    }
+
+    /// add a directory entry
+    pub async fn dir<P: AsRef<Path>>(&mut self, path: P, attr: Attributes) -> Result<Ino> {
+        let path = normalize(path.as_ref())?;
+        if self.dirs.contains_key(&path) {
+            return Err(Error::Anyhow(anyhow::anyhow!(
+                "directory '{}' already exists",
+                path.display()
+            )));
+        }
+
+        let (parent, name) = self.parent(&path).await?;
+        let ino = self
+            .entry(parent, name, FileType::Dir, &attr, 0, None)
+            .await?;
+        self.dirs.insert(path, ino);
+
+        Ok(ino)
+    }
+
+    /// add a file entry, the content is read until EOF, chunked and uploaded to the store
+    pub async fn file<P, R>(&mut self, path: P, mut content: R, attr: Attributes) -> Result<Ino>
+    where
+        P: AsRef<Path>,
+        R: AsyncRead + Unpin,
+    {
+        let path = normalize(path.as_ref())?;
+        let (parent, name) = self.parent(&path).await?;
+
+        // blocks are uploaded first since the inode needs the total size
+        let mut buffer = vec![0; BLOB_SIZE];
+        let mut blocks = Vec::default();
+        let mut size = 0;
+        loop {
+            let read = read_full(&mut content, &mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            size += read as u64;
+            blocks.push(self.store.set(&buffer[..read]).await?);
+        }
+
+        let ino = self
+            .entry(parent, name, FileType::Regular, &attr, size, None)
+            .await?;
+
+        for block in blocks {
+            self.writer.block(ino, &block.id, &block.key).await?;
+        }
+
+        Ok(ino)
+    }
+
+    /// add a symlink entry pointing to target
+    pub async fn link<P, T>(&mut self, path: P, target: T, attr: Attributes) -> Result<Ino>
+    where
+        P: AsRef<Path>,
+        T: AsRef<Path>,
+    {
+        let path = normalize(path.as_ref())?;
+        let (parent, name) = self.parent(&path).await?;
+        let target = target.as_ref().as_os_str().as_bytes();
+
+        self.entry(
+            parent,
+            name,
+            FileType::Link,
+            &attr,
+            target.len() as u64,
+            Some(target.into()),
+        )
+        .await
+    }
+
+    /// finish building the FL and return the writer
+    pub fn finish(self) -> Writer {
+        self.writer
+    }
+
+    async fn entry(
+        &self,
+        parent: Ino,
+        name: String,
+        typ: FileType,
+        attr: &Attributes,
+        size: u64,
+        data: Option<Vec<u8>>,
+    ) -> Result<Ino> {
+        self.writer
+            .inode(Inode {
+                ino: 0,
+                parent,
+                name,
+                size,
+                uid: attr.uid,
+                gid: attr.gid,
+                mode: Mode::new(typ, attr.perm),
+                rdev: 0,
+                ctime: attr.ctime,
+                mtime: attr.mtime,
+                data,
+            })
+            .await
+    }
+
+    // parent returns the inode of the parent directory of path (creating missing
+    // directories on the way) and the entry name
+    async fn parent(&mut self, path: &Path) -> Result<(Ino, String)> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("invalid entry path '{}'", path.display()))?;
+        let name = String::from_utf8_lossy(name.as_bytes()).into_owned();
+
+        let mut current = PathBuf::from("/");
+        let mut ino = self.dirs[&current];
+        if let Some(parent) = path.parent() {
+            for component in parent.components().skip(1) {
+                current.push(component);
+                ino = match self.dirs.get(&current) {
+                    Some(ino) => *ino,
+                    None => {
+                        let dir = String::from_utf8_lossy(component.as_os_str().as_bytes());
+                        let dir = self
+                            .entry(
+                                ino,
+                                dir.into_owned(),
+                                FileType::Dir,
+                                &Attributes::dir(),
+                                0,
+                                None,
+                            )
+                            .await?;
+                        self.dirs.insert(current.clone(), dir);
+                        dir
+                    }
+                };
+            }
+        }
+
+        Ok((ino, name))
+    }
+}
+
+// normalize makes the path absolute and rejects paths that escape the root
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => {
+                return Err(Error::Anyhow(anyhow::anyhow!(
+                    "invalid entry path '{}'",
+                    path.display()
+                )))
+            }
+        }
+    }
+
+    if normalized == Path::new("/") {
+        return Err(Error::Anyhow(anyhow::anyhow!("root entry can't be added")));
+    }
+
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::fungi::Reader;
+    use crate::store::dir::DirStore;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_builder() {
+        const ROOT: &str = "/tmp/builder-test";
+        let _ = tokio::fs::remove_dir_all(ROOT).await;
+        tokio::fs::create_dir_all(ROOT).await.unwrap();
+        let root = PathBuf::from(ROOT);
+
+        let writer = Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let mut builder = FlistBuilder::new(writer, store, true).await.unwrap();
+
+        // parent directory is created implicitly
+        let content: Vec<u8> = (0..BLOB_SIZE * 2 + 10).map(|i| i as u8).collect();
+        builder
+            .file("etc/config/data", content.as_slice(), Attributes::file())
+            .await
+            .unwrap();
+        builder
+            .link("/etc/link", "config/data", Attributes::file())
+            .await
+            .unwrap();
+        builder.dir("/var", Attributes::dir()).await.unwrap();
+        assert!(builder.dir("/etc", Attributes::dir()).await.is_err());
+        assert!(builder
+            .file("../escape", "".as_bytes(), Attributes::file())
+            .await
+            .is_err());
+        builder.finish();
+
+        let reader = Reader::new(root.join("meta.fl")).await.unwrap();
+        let etc = reader.lookup(1, "etc").await.unwrap().unwrap();
+        assert!(etc.mode.is(FileType::Dir));
+        let link = reader.lookup(etc.ino, "link").await.unwrap().unwrap();
+        assert_eq!(link.data.unwrap(), b"config/data");
+        let config = reader.lookup(etc.ino, "config").await.unwrap().unwrap();
+        let data = reader.lookup(config.ino, "data").await.unwrap().unwrap();
+        assert_eq!(data.size, content.len() as u64);
+
+        let blocks = reader.blocks(data.ino).await.unwrap();
+        assert_eq!(blocks.len(), 3);
+
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), store);
+        let mut received = Vec::default();
+        for block in blocks {
+            let (_, mut file) = cache.get(&block).await.unwrap();
+            file.read_to_end(&mut received).await.unwrap();
+        }
+
+        assert_eq!(received, content);
+    }
+}
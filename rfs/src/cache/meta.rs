@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+const DIGEST_EXT: &str = "sha256";
+
+/// is_remote checks if the metadata location is a url that need to be fetched
+pub fn is_remote(meta: &str) -> bool {
+    meta.starts_with("http://") || meta.starts_with("https://")
+}
+
+/// fetch downloads the flist at the given url into the `meta` directory under the cache
+/// root and returns the path of the local copy. The url can carry the expected sha256
+/// digest of the flist as a fragment (`https://host/file.fl#sha256=<hex>`), in that case
+/// the download is verified against it.
+///
+/// A cached copy is used as is (after its integrity is verified) unless refresh is set.
+pub async fn fetch<P: AsRef<Path>>(root: P, url: &str, refresh: bool) -> Result<PathBuf> {
+    let mut u = url::Url::parse(url).context("invalid metadata url")?;
+    let expected = match u.fragment() {
+        Some(fragment) => Some(
+            fragment
+                .strip_prefix("sha256=")
+                .context("invalid metadata url fragment, expected sha256=<hex>")?
+                .to_lowercase(),
+        ),
+        None => None,
+    };
+    u.set_fragment(None);
+
+    // the flist is keyed by its digest if known, otherwise by the digest of the url
+    let key = match expected {
+        Some(ref digest) => digest.clone(),
+        None => hex::encode(Sha256::digest(u.as_str())),
+    };
+
+    let dir = root.as_ref().join("meta");
+    fs::create_dir_all(&dir)
+        .await
+        .context("failed to create metadata cache directory")?;
+
+    let path = dir.join(format!("{}.fl", key));
+    let digest_path = path.with_extension(DIGEST_EXT);
+
+    if !refresh && path.exists() {
+        let recorded = match expected {
+            Some(ref digest) => Some(digest.clone()),
+            None => fs::read_to_string(&digest_path).await.ok(),
+        };
+
+        match recorded {
+            Some(recorded) if recorded == digest(&path).await? => {
+                log::debug!("using cached metadata {}", path.display());
+                return Ok(path);
+            }
+            _ => log::warn!("cached metadata {} is corrupted", path.display()),
+        }
+    }
+
+    log::info!("downloading metadata from {}", u);
+    let content = reqwest::get(u.clone())
+        .await
+        .context("failed to download metadata")?
+        .error_for_status()
+        .context("failed to download metadata")?
+        .bytes()
+        .await
+        .context("failed to download metadata")?;
+
+    let actual = hex::encode(Sha256::digest(&content));
+    if let Some(ref digest) = expected {
+        anyhow::ensure!(
+            digest == &actual,
+            "metadata digest mismatch expected '{}' got '{}'",
+            digest,
+            actual
+        );
+    }
+
+    // files are written to a temporary file first then renamed so concurrent
+    // mounts of the same flist never see a partially written file
+    atomic_write(&digest_path, actual.as_bytes()).await?;
+    atomic_write(&path, &content).await?;
+
+    Ok(path)
+}
+
+/// fetch_sidecar downloads a sidecar file of the flist at the given url (like the
+/// signature at `<url>.sig`) next to the local copy of the flist at path
+pub async fn fetch_sidecar(url: &str, path: &Path, ext: &str) -> Result<()> {
+    let mut u = url::Url::parse(url).context("invalid metadata url")?;
+    u.set_fragment(None);
+    let sidecar_path = format!("{}.{}", u.path(), ext);
+    u.set_path(&sidecar_path);
+
+    let content = reqwest::get(u.clone())
+        .await
+        .with_context(|| format!("failed to download {}", u))?
+        .error_for_status()
+        .with_context(|| format!("failed to download {}", u))?
+        .bytes()
+        .await
+        .with_context(|| format!("failed to download {}", u))?;
+
+    atomic_write(&crate::fungi::sign::sidecar(path, ext), &content).await
+}
+
+async fn digest(path: &Path) -> Result<String> {
+    let content = fs::read(path).await?;
+    Ok(hex::encode(Sha256::digest(&content)))
+}
+
+async fn atomic_write(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+    fs::write(&tmp, content).await?;
+    if let Err(err) = fs::rename(&tmp, path).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_remote() {
+        assert!(is_remote("https://hub.grid.tf/user/image.fl"));
+        assert!(is_remote("http://localhost:3000/image.fl"));
+        assert!(!is_remote("/tmp/image.fl"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_invalid_fragment() {
+        let err = fetch(
+            "/tmp/meta-cache-test",
+            "http://localhost/a.fl#md5=abc",
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("fragment"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cached() {
+        const ROOT: &str = "/tmp/meta-cache-test-cached";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let content = b"some flist content";
+        let digest = hex::encode(Sha256::digest(content));
+        fs::create_dir_all(Path::new(ROOT).join("meta"))
+            .await
+            .unwrap();
+        let path = Path::new(ROOT).join("meta").join(format!("{}.fl", digest));
+        fs::write(&path, content).await.unwrap();
+
+        // the url is not reachable, so the cached copy must be used
+        let url = format!("http://localhost:1/image.fl#sha256={}", digest);
+        let fetched = fetch(ROOT, &url, false).await.unwrap();
+        assert_eq!(fetched, path);
+
+        // refresh forces a download
+        assert!(fetch(ROOT, &url, true).await.is_err());
+    }
+}
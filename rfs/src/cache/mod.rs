@@ -1,17 +1,79 @@
+pub mod meta;
+mod policy;
+pub use policy::{EvictionPolicy, Policy};
+
 use crate::fungi::meta::Block;
-use crate::store::{BlockStore, Store};
+use crate::pack::BLOB_SIZE;
+use crate::store::{verify_block, BlockStore, Store};
 use anyhow::{Context, Result};
 
+use std::fmt;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
 use tokio::fs::{self, File, OpenOptions};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 /// Cache implements a caching layer on top of a block store
 //#[derive(Clone)]
 pub struct Cache<S: Store> {
     store: BlockStore<S>,
     root: PathBuf,
+    evictor: Option<Mutex<Evictor>>,
+    verify: bool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    served: AtomicU64,
+    downloaded: AtomicU64,
+}
+
+/// Stats of the cache usage since it was created
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Stats {
+    /// number of blocks served from the cache directory
+    pub hits: u64,
+    /// number of blocks downloaded from the store
+    pub misses: u64,
+    /// total size of the blocks served from the cache directory
+    pub served: u64,
+    /// total size of the downloaded blocks
+    pub downloaded: u64,
+}
+
+impl Stats {
+    /// hit_rate returns the ratio of the blocks served from the cache directory
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits ({} bytes), {} misses ({} bytes downloaded), {:.1}% hit rate",
+            self.hits,
+            self.served,
+            self.misses,
+            self.downloaded,
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+/// Evictor keeps the cache directory under a size budget by removing
+/// the blocks selected by the eviction policy.
+struct Evictor {
+    max: u64,
+    size: u64,
+    policy: Box<dyn EvictionPolicy>,
 }
 
 impl<S> Cache<S>
@@ -22,74 +84,316 @@ where
     where
         P: Into<PathBuf>,
     {
-        Cache {
+        let cache = Cache {
             store: store.into(),
             root: root.into(),
-        }
+            evictor: None,
+            verify: false,
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            served: AtomicU64::default(),
+            downloaded: AtomicU64::default(),
+        };
+
+        cache.load();
+        cache
     }
 
-    // download given an open file, writes the content of the chunk to the file
-    async fn download(&self, file: &mut File, block: &Block) -> Result<u64> {
-        let data = self.store.get(block).await?;
-        file.write_all(&data).await?;
+    /// create a cache that never grows over max bytes, the least recently used
+    /// blocks are removed first once the limit is exceeded
+    pub fn with_limit<P>(root: P, store: S, max: u64) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self::with_policy(root, store, max, Policy::Lru)
+    }
+
+    /// create a cache that never grows over max bytes. Once the limit is exceeded
+    /// blocks are removed in the order decided by the given policy. The blocks that
+    /// are already in the cache directory count toward the limit
+    pub fn with_policy<P>(root: P, store: S, max: u64, policy: Policy) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        let cache = Cache {
+            store: store.into(),
+            root: root.into(),
+            evictor: Some(Mutex::new(Evictor {
+                max,
+                size: 0,
+                policy: policy.build(),
+            })),
+            verify: false,
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            served: AtomicU64::default(),
+            downloaded: AtomicU64::default(),
+        };
+
+        cache.load();
+        cache
+    }
 
-        Ok(data.len() as u64)
+    /// with_verification makes the cache check the content of a cached block
+    /// against the block hash on each hit, a corrupted block is downloaded again.
+    /// Downloaded blocks are always authenticated when they are decrypted
+    pub fn with_verification(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
     }
 
-    async fn prepare(&self, id: &[u8]) -> Result<File> {
+    /// store returns the block store the cache downloads the blocks from
+    pub fn store(&self) -> &BlockStore<S> {
+        &self.store
+    }
+
+    /// stats returns the cache usage since the cache was created
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            served: self.served.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+        }
+    }
+
+    fn path(&self, id: &[u8]) -> Result<PathBuf> {
         let name = id.hex();
         if name.len() < 4 {
             anyhow::bail!("invalid chunk hash");
         }
-        let path = self.root.join(&name[0..2]).join(&name[2..4]);
-        fs::create_dir_all(&path).await?;
-        let path = path.join(name);
 
+        Ok(self.root.join(&name[0..2]).join(&name[2..4]).join(name))
+    }
+
+    fn hit(&self, id: &[u8], size: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.served.fetch_add(size, Ordering::Relaxed);
+        if let Some(ref evictor) = self.evictor {
+            evictor.lock().unwrap().policy.touch(id);
+        }
+    }
+
+    // load removes the partial blocks left in the cache directory by a previous run
+    // and tracks its blocks, from the least to the most recently used, then evicts
+    // blocks if they are over the budget
+    fn load(&self) {
+        let mut blocks = match scan(&self.root) {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                warn!("failed to scan cache {}: {:#}", self.root.display(), err);
+                return;
+            }
+        };
+
+        let evictor = match self.evictor {
+            Some(ref evictor) => evictor,
+            None => return,
+        };
+
+        blocks.sort_by_key(|(_, _, used)| *used);
+
+        let mut evictor = evictor.lock().unwrap();
+        for (id, size, _) in blocks.iter() {
+            evictor.policy.insert(id, *size);
+            evictor.size += size;
+        }
+
+        debug!(
+            "loaded {} cached blocks ({} bytes)",
+            blocks.len(),
+            evictor.size
+        );
+        self.shrink(&mut evictor);
+    }
+
+    // track a newly downloaded block and evict blocks until the
+    // cache is back under its budget
+    fn track(&self, id: &[u8], size: u64) {
+        let evictor = match self.evictor {
+            Some(ref evictor) => evictor,
+            None => return,
+        };
+
+        let mut evictor = evictor.lock().unwrap();
+        evictor.policy.insert(id, size);
+        evictor.size += size;
+        self.shrink(&mut evictor);
+    }
+
+    // shrink evicts blocks until the cache is under its budget. It's safe to remove
+    // a block file while it's still open by a reader since the data stays available
+    // to open descriptors
+    fn shrink(&self, evictor: &mut Evictor) {
+        while evictor.size > evictor.max {
+            let (victim, size) = match evictor.policy.evict() {
+                Some(victim) => victim,
+                None => break,
+            };
+
+            evictor.size = evictor.size.saturating_sub(size);
+            let path = match self.path(&victim) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+
+            debug!("evicting block: {}", victim.as_slice().hex());
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!("failed to evict block {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    // download fetches the block into a temporary file that is renamed to the
+    // block path once it's complete and verified, so a crash never leaves a partial
+    // block behind. The temporary file is locked so the same block is downloaded
+    // only once, false is returned if another download of the block completed first
+    async fn download(&self, block: &Block, path: &Path) -> Result<bool> {
+        let tmp = tmp_path(path);
+        loop {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(false)
+                .open(&tmp)
+                .await?;
+
+            let locker = Locker::new(&file);
+            locker.lock().await?;
+            let result = self.download_locked(&mut file, block, path, &tmp).await;
+            locker.unlock().await?;
+
+            // a failed download leaves the temporary file as is, it's truncated by
+            // the next download and never served
+            match result? {
+                // the temporary file was renamed or removed while waiting for the
+                // lock, the download is tried again with a new one
+                Downloaded::Stale => continue,
+                Downloaded::Exists => return Ok(false),
+                Downloaded::Done => return Ok(true),
+            }
+        }
+    }
+
+    async fn download_locked(
+        &self,
+        file: &mut File,
+        block: &Block,
+        path: &Path,
+        tmp: &Path,
+    ) -> Result<Downloaded> {
+        if is_cached(path).await {
+            return Ok(Downloaded::Exists);
+        }
+
+        let current = match fs::metadata(tmp).await {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Downloaded::Stale),
+            Err(err) => return Err(err.into()),
+        };
+        if current.ino() != file.metadata().await?.ino() {
+            return Ok(Downloaded::Stale);
+        }
+
+        // the block store authenticates the blob when it's decrypted, the plain
+        // content is checked against the block hash too
+        let data = self.store.get(block).await?;
+        if !verify_block(block, &data) {
+            anyhow::bail!("downloaded block doesn't match its hash");
+        }
+
+        file.set_len(0).await?;
+        file.rewind().await?;
+        file.write_all(&data).await?;
+        file.sync_data().await?;
+        fs::rename(tmp, path).await?;
+
+        Ok(Downloaded::Done)
+    }
+
+    // verify_cached checks the content of a cached block file, the file is
+    // rewinded after it's read
+    async fn verify_cached(&self, file: &mut File, block: &Block) -> Result<bool> {
+        let mut data = Vec::default();
+        file.read_to_end(&mut data)
+            .await
+            .context("failed to read cached block")?;
+        file.rewind().await?;
+
+        Ok(verify_block(block, &data))
+    }
+
+    // hole returns a (sparse) file of zeros with the size of a full block
+    async fn hole(&self) -> Result<(u64, File)> {
+        fs::create_dir_all(&self.root).await?;
         let file = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
             .truncate(false)
-            .open(path)
+            .open(self.root.join("hole"))
             .await?;
 
-        Ok(file)
+        let size = BLOB_SIZE as u64;
+        if file.metadata().await?.len() != size {
+            file.set_len(size).await?;
+        }
+
+        Ok((size, file))
     }
 
     /// get a file block either from cache or from remote if it's already
     /// not cached
     pub async fn get(&self, block: &Block) -> Result<(u64, File)> {
-        let mut file = self
-            .prepare(&block.id)
-            .await
-            .context("failed to prepare cache block")?;
-        // TODO: locking must happen here so no
-        // other processes start downloading the same chunk
-        let locker = Locker::new(&file);
-        locker.lock().await?;
-
-        let meta = file
-            .metadata()
-            .await
-            .context("failed to get block metadata")?;
-        if meta.len() > 0 {
-            // chunk is already downloaded
-            debug!("block cache hit: {}", block.id.as_slice().hex());
-            locker.unlock().await?;
-            return Ok((meta.len(), file));
+        if block.is_hole() {
+            return self.hole().await;
+        }
+
+        let path = self.path(&block.id)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .context("failed to prepare cache block")?;
+        }
+
+        if is_cached(&path).await {
+            let mut file = File::open(&path)
+                .await
+                .context("failed to open cached block")?;
+            if !self.verify || self.verify_cached(&mut file, block).await? {
+                let size = file.metadata().await?.len();
+                debug!("block cache hit: {}", block.id.as_slice().hex());
+                self.hit(&block.id, size);
+                return Ok((size, file));
+            }
+
+            warn!(
+                "cached block {} is corrupted, downloading it again",
+                block.id.as_slice().hex()
+            );
+            fs::remove_file(&path).await?;
         }
 
         debug!("downloading block with key: {}", block.id.as_slice().hex());
-        let size = self
-            .download(&mut file, block)
+        let downloaded = self
+            .download(block, &path)
             .await
             .context("failed to download block")?;
 
-        // if file is just downloaded, we need
-        // to seek to beginning of the file.
-        file.rewind().await?;
+        let file = File::open(&path)
+            .await
+            .context("failed to open cached block")?;
+        let size = file.metadata().await?.len();
+        if downloaded {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.downloaded.fetch_add(size, Ordering::Relaxed);
+            self.track(&block.id, size);
+        } else {
+            // downloaded by another reader of the block while waiting for it
+            self.hit(&block.id, size);
+        }
 
-        locker.unlock().await?;
         Ok((size, file))
     }
 
@@ -98,6 +402,12 @@ where
     pub async fn direct(&self, blocks: &[Block], out: &mut File) -> Result<()> {
         use tokio::io::copy;
         for (index, block) in blocks.iter().enumerate() {
+            if block.is_hole() {
+                // skip over the hole, caller must set the file size at the end
+                out.seek(SeekFrom::Current(BLOB_SIZE as i64)).await?;
+                continue;
+            }
+
             let (_, mut chunk) = self.get(block).await?;
             copy(&mut chunk, out)
                 .await
@@ -108,6 +418,107 @@ where
     }
 }
 
+// scan returns the id, size and last use time of the blocks in the cache directory,
+// the temporary files left by the downloads that didn't finish are removed
+fn scan(root: &Path) -> Result<Vec<(Vec<u8>, u64, SystemTime)>> {
+    let mut blocks = Vec::default();
+    if !root.exists() {
+        return Ok(blocks);
+    }
+
+    // blocks are stored as root/ab/cd/abcd..., other entries (like the hole
+    // file and the meta directory) are skipped
+    let is_prefix = |entry: &std::fs::DirEntry| -> std::io::Result<bool> {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        Ok(entry.file_type()?.is_dir() && name.len() == 2 && hex::decode(name.as_ref()).is_ok())
+    };
+
+    for first in std::fs::read_dir(root)? {
+        let first = first?;
+        if !is_prefix(&first)? {
+            continue;
+        }
+
+        for second in std::fs::read_dir(first.path())? {
+            let second = second?;
+            if !is_prefix(&second)? {
+                continue;
+            }
+
+            for entry in std::fs::read_dir(second.path())? {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().ends_with(TMP_SUFFIX) {
+                    if let Err(err) = remove_stale(&entry.path()) {
+                        warn!("failed to remove {}: {:#}", entry.path().display(), err);
+                    }
+                    continue;
+                }
+
+                let id = match hex::decode(entry.file_name().to_string_lossy().as_ref()) {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                };
+
+                let meta = entry.metadata()?;
+                if !meta.is_file() || meta.len() == 0 {
+                    continue;
+                }
+
+                let used = meta
+                    .accessed()
+                    .into_iter()
+                    .chain(meta.modified())
+                    .max()
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                blocks.push((id, meta.len(), used));
+            }
+        }
+    }
+
+    Ok(blocks)
+}
+
+// Downloaded is the outcome of a download done while holding the lock of the
+// temporary file of the block
+enum Downloaded {
+    Done,
+    Exists,
+    Stale,
+}
+
+const TMP_SUFFIX: &str = ".tmp";
+
+// tmp_path is the path of the temporary file a block is downloaded to
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(TMP_SUFFIX);
+    tmp.into()
+}
+
+// is_cached checks if the block file exists, only complete blocks are renamed
+// to the block path but an empty file is still left by older versions
+async fn is_cached(path: &Path) -> bool {
+    matches!(fs::metadata(path).await, Ok(meta) if meta.len() > 0)
+}
+
+// remove_stale removes a temporary file left by a download that didn't finish,
+// the file is kept if a download still holds its lock
+fn remove_stale(path: &Path) -> Result<()> {
+    let file = std::fs::File::open(path)?;
+    let lock = nix::fcntl::FlockArg::LockExclusiveNonblock;
+    match nix::fcntl::flock(file.as_raw_fd(), lock) {
+        Ok(_) => {
+            debug!("removing partial block {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        Err(nix::errno::Errno::EWOULDBLOCK) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
 pub struct Locker {
     fd: std::os::unix::io::RawFd,
 }
@@ -149,3 +560,115 @@ impl Hex for &[u8] {
         hex::encode(self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::mem::MemStore;
+
+    #[tokio::test]
+    async fn test_with_limit() {
+        const ROOT: &str = "/tmp/cache-limit-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let store = MemStore::default();
+        let blocks = BlockStore::from(store.clone());
+        let mut ids = Vec::default();
+        for i in 0..4u8 {
+            ids.push(blocks.set(&[i; 1000]).await.unwrap());
+        }
+
+        let cache = Cache::new(ROOT, store.clone());
+        let mut size = 0;
+        for block in ids.iter() {
+            size = cache.get(block).await.unwrap().0;
+        }
+
+        // the blocks of the previous run count toward the limit
+        let cache = Cache::with_limit(ROOT, store.clone(), 2 * size);
+        let cached: Vec<&Block> = ids
+            .iter()
+            .filter(|block| cache.path(&block.id).unwrap().exists())
+            .collect();
+        assert_eq!(cached.len(), 2);
+
+        // a block that is still open by a reader survives its eviction
+        let (_, mut file) = cache.get(cached[0]).await.unwrap();
+        for block in ids.iter() {
+            cache.get(block).await.unwrap();
+        }
+
+        let mut data = Vec::default();
+        file.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data.len() as u64, size);
+    }
+
+    #[tokio::test]
+    async fn test_verification() {
+        const ROOT: &str = "/tmp/cache-verify-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let store = MemStore::default();
+        let block = BlockStore::from(store.clone())
+            .set(b"block content")
+            .await
+            .unwrap();
+
+        let cache = Cache::new(ROOT, store).with_verification(true);
+        cache.get(&block).await.unwrap();
+
+        // corrupt the cached block
+        let path = cache.path(&block.id).unwrap();
+        fs::write(&path, b"garbage content").await.unwrap();
+
+        let (size, mut file) = cache.get(&block).await.unwrap();
+        let mut data = Vec::default();
+        file.read_to_end(&mut data).await.unwrap();
+        assert_eq!(size, 13);
+        assert_eq!(data, b"block content");
+        assert_eq!(cache.stats().misses, 2);
+
+        // the block was fixed so it's a hit now
+        cache.get(&block).await.unwrap();
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().served, 13);
+        assert_eq!(cache.stats().hit_rate(), 1.0 / 3.0);
+    }
+
+    #[tokio::test]
+    async fn test_partial_download() {
+        const ROOT: &str = "/tmp/cache-partial-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let store = MemStore::default();
+        let block = BlockStore::from(store.clone())
+            .set(b"block content")
+            .await
+            .unwrap();
+
+        // a download interrupted by a crash leaves its temporary file
+        let cache = Cache::new(ROOT, store.clone());
+        let path = cache.path(&block.id).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(tmp_path(&path), b"block").await.unwrap();
+
+        // it's removed when the cache is loaded again and never served
+        let cache = Cache::new(ROOT, store);
+        assert!(!tmp_path(&path).exists());
+        assert!(!path.exists());
+
+        let (size, mut file) = cache.get(&block).await.unwrap();
+        let mut data = Vec::default();
+        file.read_to_end(&mut data).await.unwrap();
+        assert_eq!(size, 13);
+        assert_eq!(data, b"block content");
+
+        // a failed download never leaves a block behind
+        let missing = Block {
+            id: [0xab; 32],
+            key: [0xcd; 32],
+        };
+        assert!(cache.get(&missing).await.is_err());
+        assert!(!cache.path(&missing.id).unwrap().exists());
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// EvictionPolicy keeps track of the cached blocks and decides which block
+/// must be removed first once the cache grows over its size limit.
+pub trait EvictionPolicy: Send + Sync {
+    /// insert is called after a block is written to the cache
+    fn insert(&mut self, id: &[u8], size: u64);
+    /// touch is called on each cache hit of a block
+    fn touch(&mut self, id: &[u8]);
+    /// evict removes the next victim from the index and returns its id and size
+    fn evict(&mut self) -> Option<(Vec<u8>, u64)>;
+}
+
+/// available eviction policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// least recently used
+    #[default]
+    Lru,
+    /// least frequently used
+    Lfu,
+    /// first in first out
+    Fifo,
+}
+
+impl Policy {
+    pub fn build(&self) -> Box<dyn EvictionPolicy> {
+        match self {
+            Self::Lru => Box::<Lru>::default(),
+            Self::Lfu => Box::<Lfu>::default(),
+            Self::Fifo => Box::<Fifo>::default(),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            "fifo" => Ok(Self::Fifo),
+            _ => Err(format!(
+                "unknown cache policy '{}' expected one of [lru, lfu, fifo]",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Lru => "lru",
+            Self::Lfu => "lfu",
+            Self::Fifo => "fifo",
+        };
+
+        f.write_str(name)
+    }
+}
+
+pub struct Lru {
+    index: lru::LruCache<Vec<u8>, u64>,
+}
+
+impl Default for Lru {
+    fn default() -> Self {
+        Self {
+            index: lru::LruCache::unbounded(),
+        }
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn insert(&mut self, id: &[u8], size: u64) {
+        self.index.put(id.into(), size);
+    }
+
+    fn touch(&mut self, id: &[u8]) {
+        // get promotes the entry to the most recently used
+        let _ = self.index.get(&id.to_vec());
+    }
+
+    fn evict(&mut self) -> Option<(Vec<u8>, u64)> {
+        self.index.pop_lru()
+    }
+}
+
+/// Lfu evicts the block with the lowest hit count, ties are broken
+/// by evicting the least recently accessed block first.
+#[derive(Default)]
+pub struct Lfu {
+    // id -> (hits, seq, size)
+    entries: HashMap<Vec<u8>, (u64, u64, u64)>,
+    // ordered set of (hits, seq, id) the first item is the next victim
+    order: BTreeSet<(u64, u64, Vec<u8>)>,
+    seq: u64,
+}
+
+impl Lfu {
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+}
+
+impl EvictionPolicy for Lfu {
+    fn insert(&mut self, id: &[u8], size: u64) {
+        let seq = self.next_seq();
+        let hits = match self.entries.remove(id) {
+            Some((hits, old, _)) => {
+                self.order.remove(&(hits, old, id.to_vec()));
+                hits
+            }
+            None => 1,
+        };
+
+        self.entries.insert(id.into(), (hits, seq, size));
+        self.order.insert((hits, seq, id.into()));
+    }
+
+    fn touch(&mut self, id: &[u8]) {
+        let seq = self.next_seq();
+        if let Some((hits, old, _)) = self.entries.get_mut(id) {
+            self.order.remove(&(*hits, *old, id.to_vec()));
+            *hits += 1;
+            *old = seq;
+            self.order.insert((*hits, seq, id.into()));
+        }
+    }
+
+    fn evict(&mut self) -> Option<(Vec<u8>, u64)> {
+        let first = self.order.iter().next().cloned()?;
+        self.order.remove(&first);
+        let (_, _, id) = first;
+        let (_, _, size) = self.entries.remove(&id)?;
+
+        Some((id, size))
+    }
+}
+
+/// Fifo evicts blocks in the same order they were added to the cache
+/// regardless of how often they are accessed.
+#[derive(Default)]
+pub struct Fifo {
+    queue: VecDeque<Vec<u8>>,
+    sizes: HashMap<Vec<u8>, u64>,
+}
+
+impl EvictionPolicy for Fifo {
+    fn insert(&mut self, id: &[u8], size: u64) {
+        if self.sizes.insert(id.into(), size).is_none() {
+            self.queue.push_back(id.into());
+        }
+    }
+
+    fn touch(&mut self, _id: &[u8]) {}
+
+    fn evict(&mut self) -> Option<(Vec<u8>, u64)> {
+        let id = self.queue.pop_front()?;
+        let size = self.sizes.remove(&id)?;
+
+        Some((id, size))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // scripted access: insert a, b, c then hit a twice and b once
+    fn script(policy: &mut dyn EvictionPolicy) -> Vec<Vec<u8>> {
+        policy.insert(b"a", 1);
+        policy.insert(b"b", 1);
+        policy.insert(b"c", 1);
+
+        policy.touch(b"a");
+        policy.touch(b"a");
+        policy.touch(b"b");
+
+        let mut victims = Vec::default();
+        while let Some((id, _)) = policy.evict() {
+            victims.push(id);
+        }
+
+        victims
+    }
+
+    #[test]
+    fn test_lru() {
+        let victims = script(Policy::Lru.build().as_mut());
+        assert_eq!(victims, vec![b"c".to_vec(), b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn test_lfu() {
+        let victims = script(Policy::Lfu.build().as_mut());
+        assert_eq!(victims, vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+    }
+
+    #[test]
+    fn test_fifo() {
+        let victims = script(Policy::Fifo.build().as_mut());
+        assert_eq!(victims, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("lfu".parse::<Policy>().unwrap(), Policy::Lfu);
+        assert_eq!(Policy::Fifo.to_string(), "fifo");
+        assert!("mru".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::pack::BLOB_SIZE;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// default sizes of content defined chunks
+pub const CDC_MIN_SIZE: usize = 128 * 1024;
+pub const CDC_AVG_SIZE: usize = 512 * 1024;
+pub const CDC_MAX_SIZE: usize = 2 * 1024 * 1024;
+
+/// size of the blocks of the fixed chunker
+pub const BLOCK_SIZE: usize = BLOB_SIZE;
+
+/// bounds of the size of fixed size blocks, smaller blocks make the flist
+/// too big and bigger blocks use too much memory while packing
+pub const MIN_BLOCK_SIZE: usize = 4 * 1024;
+pub const MAX_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// maximum number of blocks of a file. A mount loads the whole block list of a file
+/// to read it, so a file with more blocks can't be served, it's refused while
+/// packing instead of producing an flist that can't be read
+pub const MAX_FILE_BLOCKS: u64 = 1024 * 1024;
+
+/// Chunker decides where the content of a file is split into blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Chunker {
+    /// fixed size blocks of 512K
+    #[default]
+    Fixed,
+    /// content defined chunking (FastCDC), block boundaries depend on the content
+    /// so an insertion in a file only changes the blocks around it.
+    Cdc { min: usize, avg: usize, max: usize },
+    /// fixed size blocks of a custom size, the size of each block is recorded in the
+    /// FL so it can't be mounted by older rfs versions
+    Sized { size: usize },
+}
+
+impl Chunker {
+    /// cdc creates a content defined chunker with the given sizes
+    pub fn cdc(min: usize, avg: usize, max: usize) -> Result<Self, String> {
+        if min == 0 || !(min <= avg && avg <= max) {
+            return Err(format!(
+                "invalid chunk sizes min: {}, avg: {}, max: {} expected 0 < min <= avg <= max",
+                min, avg, max
+            ));
+        }
+
+        if !avg.is_power_of_two() {
+            return Err(format!("average chunk size {} must be a power of 2", avg));
+        }
+
+        Ok(Self::Cdc { min, avg, max })
+    }
+
+    /// sized creates a chunker of fixed size blocks of the given size. The default
+    /// block size is the fixed chunker
+    pub fn sized(size: usize) -> Result<Self, String> {
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size) {
+            return Err(format!(
+                "invalid block size {} expected {} to {}",
+                size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            ));
+        }
+
+        match size {
+            BLOB_SIZE => Ok(Self::Fixed),
+            size => Ok(Self::Sized { size }),
+        }
+    }
+
+    /// max_size is the size of the biggest block the chunker can produce
+    pub fn max_size(&self) -> usize {
+        match self {
+            Self::Fixed => BLOB_SIZE,
+            Self::Cdc { max, .. } => *max,
+            Self::Sized { size } => *size,
+        }
+    }
+
+    /// blocks returns the number of blocks a file of the given size is split into,
+    /// it's an estimate based on the average block size for content defined chunks
+    pub fn blocks(&self, size: u64) -> u64 {
+        let block = match self {
+            Self::Fixed => BLOB_SIZE,
+            Self::Cdc { avg, .. } => *avg,
+            Self::Sized { size } => *size,
+        } as u64;
+
+        size.div_ceil(block)
+    }
+
+    /// check_size fails if a file of the given size is split into more than
+    /// MAX_FILE_BLOCKS blocks, the error suggests the smallest block size that fits
+    pub fn check_size(&self, size: u64) -> Result<(), String> {
+        let blocks = self.blocks(size);
+        if blocks <= MAX_FILE_BLOCKS {
+            return Ok(());
+        }
+
+        let needed = size.div_ceil(MAX_FILE_BLOCKS).next_power_of_two();
+        let needed = needed.max(MIN_BLOCK_SIZE as u64);
+        if needed > MAX_BLOCK_SIZE as u64 {
+            return Err(format!(
+                "file of {} bytes needs {} blocks which is over the limit of {} blocks \
+                 even with the biggest block size {}",
+                size, blocks, MAX_FILE_BLOCKS, MAX_BLOCK_SIZE
+            ));
+        }
+
+        Err(format!(
+            "file of {} bytes needs {} blocks with the {} chunker which is over the \
+             limit of {} blocks, use a block size of at least {}",
+            size, blocks, self, MAX_FILE_BLOCKS, needed
+        ))
+    }
+
+    /// cut returns the size of the first block of data. If eof is not set and
+    /// data is shorter than the max block size, more data is needed before a cut
+    /// point can be decided, in that case None is returned
+    pub fn cut(&self, data: &[u8], eof: bool) -> Option<usize> {
+        if data.is_empty() {
+            return None;
+        }
+
+        let max = self.max_size();
+        if !eof && data.len() < max {
+            return None;
+        }
+
+        match self {
+            Self::Fixed | Self::Sized { .. } => Some(data.len().min(max)),
+            Self::Cdc { min, avg, max } => Some(fastcdc(data, *min, *avg, *max)),
+        }
+    }
+}
+
+impl FromStr for Chunker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Self::Fixed),
+            "cdc" => Self::cdc(CDC_MIN_SIZE, CDC_AVG_SIZE, CDC_MAX_SIZE),
+            _ => Err(format!(
+                "unknown chunker '{}' expected one of [fixed, cdc]",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Chunker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fixed => write!(f, "fixed"),
+            Self::Cdc { min, avg, max } => write!(f, "cdc min={} avg={} max={}", min, avg, max),
+            Self::Sized { size } => write!(f, "fixed size={}", size),
+        }
+    }
+}
+
+// random values (splitmix64) for each byte value used by the gear rolling hash
+const GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+}
+
+// fastcdc finds the first cut point in data. A stricter mask is used before the
+// average size is reached and a looser one after it, so chunk sizes are normalized
+// around the average.
+fn fastcdc(data: &[u8], min: usize, avg: usize, max: usize) -> usize {
+    let len = data.len().min(max);
+    if len <= min {
+        return len;
+    }
+
+    let bits = avg.trailing_zeros();
+    // the gear hash mixes the most recent bytes into the high bits
+    let mask = |bits: u32| -> u64 { ((1u64 << bits) - 1) << (64 - bits) };
+    let strict = mask(bits + 1);
+    let loose = mask(bits.saturating_sub(1).max(1));
+
+    let normal = avg.min(len);
+    let mut hash: u64 = 0;
+    let mut i = min;
+    while i < normal {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & strict == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+
+    while i < len {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & loose == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+
+    len
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::RngCore;
+    use std::collections::HashSet;
+
+    fn chunks(chunker: &Chunker, mut data: &[u8]) -> HashSet<Vec<u8>> {
+        let mut hashes = HashSet::default();
+        while let Some(size) = chunker.cut(data, true) {
+            let hash = blake2b_simd::Params::new()
+                .hash_length(32)
+                .hash(&data[..size]);
+            hashes.insert(hash.as_bytes().to_vec());
+            data = &data[size..];
+        }
+
+        hashes
+    }
+
+    // shared returns the ratio of the chunks of shifted that also exist in original
+    fn shared(chunker: &Chunker, original: &[u8], shifted: &[u8]) -> f64 {
+        let original = chunks(chunker, original);
+        let shifted = chunks(chunker, shifted);
+
+        original.intersection(&shifted).count() as f64 / shifted.len() as f64
+    }
+
+    #[test]
+    fn test_cut_sizes() {
+        let chunker = Chunker::cdc(1024, 4096, 16384).unwrap();
+        let mut data = vec![0u8; 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let mut rest = data.as_slice();
+        let mut total = 0;
+        while let Some(size) = chunker.cut(rest, true) {
+            assert!(size <= 16384);
+            if size < rest.len() {
+                assert!(size >= 1024);
+            }
+            total += size;
+            rest = &rest[size..];
+        }
+
+        assert_eq!(total, data.len());
+        // not enough data to decide without eof
+        assert_eq!(chunker.cut(&data[..100], false), None);
+    }
+
+    #[test]
+    fn test_cdc_dedup() {
+        let mut original = vec![0u8; 8 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut original);
+
+        // insert a few bytes at the start of the content
+        let mut shifted = b"some inserted bytes".to_vec();
+        shifted.extend_from_slice(&original);
+
+        let fixed = shared(&Chunker::Fixed, &original, &shifted);
+        let cdc = shared(&"cdc".parse().unwrap(), &original, &shifted);
+
+        assert_eq!(fixed, 0.0);
+        assert!(cdc > 0.5, "cdc shared chunks ratio {}", cdc);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("fixed".parse::<Chunker>().unwrap(), Chunker::Fixed);
+        assert!(matches!(
+            "cdc".parse::<Chunker>().unwrap(),
+            Chunker::Cdc { .. }
+        ));
+        assert!("rabin".parse::<Chunker>().is_err());
+        assert!(Chunker::cdc(1024, 3000, 4096).is_err());
+        assert!(Chunker::cdc(4096, 1024, 8192).is_err());
+    }
+
+    #[test]
+    fn test_check_size() {
+        let chunker = Chunker::sized(MIN_BLOCK_SIZE).unwrap();
+        assert_eq!(chunker.blocks(0), 0);
+        assert_eq!(chunker.blocks(MIN_BLOCK_SIZE as u64 + 1), 2);
+        assert!(chunker.check_size(4 << 30).is_ok());
+
+        let err = chunker.check_size(16 << 30).unwrap_err();
+        assert!(err.contains("at least 16384"), "{}", err);
+        assert!(Chunker::Fixed.check_size(16 << 30).is_ok());
+        assert!(Chunker::Fixed.check_size(16 << 40).is_err());
+
+        // the biggest sizes don't overflow
+        assert_eq!(
+            Chunker::Fixed.blocks(u64::MAX),
+            u64::MAX / BLOB_SIZE as u64 + 1
+        );
+        assert!(Chunker::Fixed.check_size(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_sized() {
+        assert_eq!(Chunker::sized(BLOB_SIZE).unwrap(), Chunker::Fixed);
+        assert!(Chunker::sized(0).is_err());
+        assert!(Chunker::sized(MAX_BLOCK_SIZE + 1).is_err());
+
+        let chunker = Chunker::sized(64 * 1024).unwrap();
+        assert_eq!(chunker.to_string(), "fixed size=65536");
+
+        let data = vec![1u8; 100 * 1024];
+        assert_eq!(chunker.cut(&data, false), Some(64 * 1024));
+        assert_eq!(chunker.cut(&data[64 * 1024..], false), None);
+        assert_eq!(chunker.cut(&data[64 * 1024..], true), Some(36 * 1024));
+    }
+}
@@ -5,31 +5,59 @@ use crate::{
 };
 use anyhow::Error;
 use futures::lock::Mutex;
+use futures::stream::{self, Stream, StreamExt};
 use hex::ToHex;
+use std::collections::{HashSet, VecDeque};
 use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 
 const WORKERS: usize = 10;
+const PAGE: u32 = 1000;
+
+/// blocks lists the blocks of the flist that are in the store one page at a time.
+/// Holes are not stored and blocks shared by files are listed once
+pub(crate) fn blocks(reader: &Reader) -> impl Stream<Item = Result<Block>> + '_ {
+    // the current page, the offset of the next one, whether it's the last page and
+    // the ids of the listed blocks
+    let state: (VecDeque<Block>, u64, bool, HashSet<[u8; 32]>) =
+        (VecDeque::new(), 0, false, HashSet::new());
+    stream::try_unfold(
+        state,
+        move |(mut page, mut offset, mut last, mut seen)| async move {
+            loop {
+                while let Some(block) = page.pop_front() {
+                    if block.is_hole() || !seen.insert(block.id) {
+                        continue;
+                    }
+                    return Ok(Some((block, (page, offset, last, seen))));
+                }
+
+                if last {
+                    return Ok::<_, crate::fungi::Error>(None);
+                }
+
+                let blocks = reader.all_blocks(PAGE, offset).await?;
+                offset += blocks.len() as u64;
+                last = blocks.len() < PAGE as usize;
+                page = blocks.into();
+            }
+        },
+    )
+}
 
 pub async fn clone<S: Store>(reader: Reader, store: S, cache: Cache<S>) -> Result<()> {
     let failures = Arc::new(Mutex::new(Vec::new()));
     let cloner = BlobCloner::new(cache, store.into(), failures.clone());
     let mut workers = workers::WorkerPool::new(cloner, WORKERS);
 
-    let mut offset = 0;
-    loop {
+    let blocks = blocks(&reader);
+    futures::pin_mut!(blocks);
+    while let Some(block) = blocks.next().await {
         if !failures.lock().await.is_empty() {
             break;
         }
-        let blocks = reader.all_blocks(1000, offset).await?;
-        if blocks.is_empty() {
-            break;
-        }
-        for block in blocks {
-            offset += 1;
-            let worker = workers.get().await;
-            worker.send(block)?;
-        }
+        let worker = workers.get().await;
+        worker.send(block?)?;
     }
 
     workers.close().await;
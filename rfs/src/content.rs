@@ -0,0 +1,219 @@
+use crate::cache::Cache;
+use crate::fungi::{
+    meta::{FileType, Inode, Result},
+    Reader,
+};
+use crate::pack::BLOB_SIZE;
+use crate::store::Store;
+use anyhow::Context;
+use std::ffi::OsStr;
+use std::io::SeekFrom;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+// maximum number of symlinks followed to resolve a path, like the linux limit
+pub(crate) const MAX_LINKS: usize = 40;
+
+/// resolve returns the inode at the path (from the root of the FL), or None if the
+/// path doesn't exist. Symlinks are followed the same way a mount does, relative
+/// targets from the directory of the link and absolute targets from the root of
+/// the FL, so a path never resolves outside of the FL
+pub async fn resolve<P: AsRef<Path>>(meta: &Reader, path: P) -> Result<Option<Inode>> {
+    // the directories leading to the current inode, starting with the root
+    let mut parents = vec![meta.inode(1).await?];
+    let mut pending: Vec<Vec<u8>> = components(path.as_ref());
+    pending.reverse();
+
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == b".." {
+            if parents.len() > 1 {
+                parents.pop();
+            }
+            continue;
+        }
+
+        let dir = parents.last().expect("root is always kept");
+        if !dir.mode.is(FileType::Dir) {
+            return Ok(None);
+        }
+
+        let name = String::from_utf8_lossy(&name);
+        let node = match meta.lookup(dir.ino, name).await? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+
+        if !node.mode.is(FileType::Link) {
+            parents.push(node);
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_LINKS {
+            return Ok(None);
+        }
+
+        let target = node.data.unwrap_or_default();
+        if target.starts_with(b"/") {
+            parents.truncate(1);
+        }
+        let target = components(Path::new(OsStr::from_bytes(&target)));
+        pending.extend(target.into_iter().rev());
+    }
+
+    Ok(parents.pop())
+}
+
+// components returns the names of the path with `..` kept as is, `.` and the root
+// are dropped
+pub(crate) fn components(path: &Path) -> Vec<Vec<u8>> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.as_bytes().to_vec()),
+            Component::ParentDir => Some(b"..".to_vec()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// read_range writes len bytes of the content of the file node starting at offset to
+/// out, the range is cut at the end of the file. Only the blocks that cover the range
+/// are downloaded, and they are written as they are downloaded so only one block is
+/// held in memory at a time
+pub async fn read_range<S, W>(
+    meta: &Reader,
+    cache: &Cache<S>,
+    node: &Inode,
+    offset: u64,
+    len: u64,
+    mut out: W,
+) -> Result<()>
+where
+    S: Store,
+    W: AsyncWrite + Unpin + Send,
+{
+    let end = node.size.min(offset.saturating_add(len));
+    if offset >= end {
+        return Ok(());
+    }
+
+    let mut remaining = end - offset;
+    let blocks = meta.blocks(node.ino).await?;
+    let sizes = meta.block_sizes(node.ino).await?;
+    let (index, mut skip) = locate(&sizes, offset);
+    for block in blocks.iter().skip(index) {
+        if remaining == 0 {
+            break;
+        }
+
+        let (_, mut chunk) = cache
+            .get(block)
+            .await
+            .with_context(|| format!("failed to download file '{}'", node.name))?;
+        chunk.seek(SeekFrom::Start(skip)).await?;
+        remaining -= tokio::io::copy(&mut chunk.take(remaining), &mut out).await?;
+        skip = 0;
+    }
+
+    // files ending with a hole are not covered by their blocks
+    tokio::io::copy(&mut tokio::io::repeat(0).take(remaining), &mut out).await?;
+    out.flush().await?;
+    Ok(())
+}
+
+/// locate returns the index of the block that has the given file offset and the
+/// offset inside that block. blocks without a size are of the fixed BLOB_SIZE
+pub(crate) fn locate(sizes: &[Option<u64>], offset: u64) -> (usize, u64) {
+    let fixed = BLOB_SIZE as u64;
+    if sizes.iter().all(|size| size.is_none()) {
+        return ((offset / fixed) as usize, offset % fixed);
+    }
+
+    let mut start = 0;
+    for (index, size) in sizes.iter().enumerate() {
+        let size = size.unwrap_or(fixed);
+        if offset < start + size {
+            return (index, offset - start);
+        }
+        start += size;
+    }
+
+    (sizes.len(), 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::Writer;
+    use crate::store::dir::DirStore;
+    use crate::{Attributes, FlistBuilder};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_locate() {
+        let fixed = BLOB_SIZE as u64;
+        assert_eq!(locate(&[None, None], 0), (0, 0));
+        assert_eq!(locate(&[None, None], fixed + 10), (1, 10));
+        assert_eq!(locate(&[Some(10), Some(20)], 15), (1, 5));
+        assert_eq!(locate(&[Some(10), Some(20)], 30), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn test_read_range() {
+        const ROOT: &str = "/tmp/content-test";
+        let _ = tokio::fs::remove_dir_all(ROOT).await;
+        tokio::fs::create_dir_all(ROOT).await.unwrap();
+        let root = PathBuf::from(ROOT);
+
+        let writer = Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let mut builder = FlistBuilder::new(writer, store, true).await.unwrap();
+
+        let content: Vec<u8> = (0..BLOB_SIZE * 2 + 10).map(|i| i as u8).collect();
+        builder
+            .file("/srv/www/data", content.as_slice(), Attributes::file())
+            .await
+            .unwrap();
+        builder
+            .link("/srv/current", "www", Attributes::file())
+            .await
+            .unwrap();
+        builder
+            .link("/srv/www/loop", "../current/loop", Attributes::file())
+            .await
+            .unwrap();
+        builder
+            .link("/srv/www/root", "/srv/../..", Attributes::file())
+            .await
+            .unwrap();
+        builder.finish();
+
+        let meta = Reader::new(root.join("meta.fl")).await.unwrap();
+        let data = resolve(&meta, "/srv/current/data").await.unwrap().unwrap();
+        assert_eq!(data.size, content.len() as u64);
+        let root_dir = resolve(&meta, "srv/www/root").await.unwrap().unwrap();
+        assert_eq!(root_dir.ino, 1);
+        assert!(resolve(&meta, "/srv/missing").await.unwrap().is_none());
+        assert!(resolve(&meta, "/srv/www/data/x").await.unwrap().is_none());
+        assert!(resolve(&meta, "/srv/www/loop").await.unwrap().is_none());
+
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), store);
+        for (offset, len) in [
+            (0, u64::MAX),
+            (BLOB_SIZE as u64 - 5, 10),
+            (BLOB_SIZE as u64 * 2, 100),
+            (content.len() as u64, 10),
+        ] {
+            let mut out = Vec::default();
+            read_range(&meta, &cache, &data, offset, len, &mut out)
+                .await
+                .unwrap();
+            let start = (offset as usize).min(content.len());
+            let end = (offset.saturating_add(len) as usize).min(content.len());
+            assert_eq!(out, &content[start..end]);
+        }
+    }
+}
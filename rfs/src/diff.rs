@@ -0,0 +1,225 @@
+use crate::fungi::{
+    meta::{FileType, Inode, Walk, WalkVisitor, DEFAULT_WALK_CONCURRENCY},
+    Reader, Result,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// Change is a path that differs between two flists
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Change {
+    pub path: PathBuf,
+    pub kind: Kind,
+    /// what changed in a modified path: type, size, content, target, mode,
+    /// owner or device
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<&'static str>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::Added => write!(f, "+ {}", self.path.display()),
+            Kind::Removed => write!(f, "- {}", self.path.display()),
+            Kind::Modified => write!(f, "~ {} ({})", self.path.display(), self.fields.join(", ")),
+        }
+    }
+}
+
+struct Collector(BTreeMap<PathBuf, Inode>);
+
+#[async_trait::async_trait]
+impl WalkVisitor for Collector {
+    async fn visit(&mut self, path: &Path, node: &Inode) -> Result<Walk> {
+        self.0.insert(path.into(), node.clone());
+        Ok(Walk::Continue)
+    }
+}
+
+async fn collect(meta: &Reader) -> Result<BTreeMap<PathBuf, Inode>> {
+    let mut collector = Collector(BTreeMap::default());
+    meta.walk_concurrent(&mut collector, DEFAULT_WALK_CONCURRENCY)
+        .await?;
+    Ok(collector.0)
+}
+
+/// diff returns the paths that are added, removed or modified in `new` compared
+/// to `old` sorted by path. Times are ignored since they change on each build
+pub async fn diff(old: &Reader, new: &Reader) -> Result<Vec<Change>> {
+    let (old_nodes, mut new_nodes) = futures::try_join!(collect(old), collect(new))?;
+
+    let mut changes = Vec::default();
+    for (path, old_node) in old_nodes {
+        let change = match new_nodes.remove(&path) {
+            None => Change {
+                path,
+                kind: Kind::Removed,
+                fields: Vec::default(),
+            },
+            Some(new_node) => {
+                let fields = compare(old, &old_node, new, &new_node).await?;
+                if fields.is_empty() {
+                    continue;
+                }
+
+                Change {
+                    path,
+                    kind: Kind::Modified,
+                    fields,
+                }
+            }
+        };
+
+        changes.push(change);
+    }
+
+    changes.extend(new_nodes.into_keys().map(|path| Change {
+        path,
+        kind: Kind::Added,
+        fields: Vec::default(),
+    }));
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(changes)
+}
+
+async fn compare(
+    old: &Reader,
+    old_node: &Inode,
+    new: &Reader,
+    new_node: &Inode,
+) -> Result<Vec<&'static str>> {
+    let typ = old_node.mode.file_type();
+    if typ != new_node.mode.file_type() {
+        return Ok(vec!["type"]);
+    }
+
+    let mut fields = Vec::default();
+    match typ {
+        FileType::Regular if old_node.size != new_node.size => fields.push("size"),
+        FileType::Regular => {
+            let old_blocks = old.blocks(old_node.ino).await?;
+            let new_blocks = new.blocks(new_node.ino).await?;
+            if !old_blocks
+                .iter()
+                .map(|b| b.id)
+                .eq(new_blocks.iter().map(|b| b.id))
+            {
+                fields.push("content");
+            }
+        }
+        FileType::Link if old_node.data != new_node.data => fields.push("target"),
+        FileType::Block | FileType::Char if old_node.rdev != new_node.rdev => fields.push("device"),
+        _ => {}
+    }
+
+    if old_node.mode.permissions() != new_node.mode.permissions() {
+        fields.push("mode");
+    }
+
+    if old_node.uid != new_node.uid || old_node.gid != new_node.gid {
+        fields.push("owner");
+    }
+
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::{meta::Mode, Writer};
+
+    async fn flist(path: &str, files: &[(&str, FileType, u32, u64, &str)]) -> Reader {
+        let meta = Writer::new(path, true).await.unwrap();
+        let root = meta
+            .inode(Inode {
+                name: "/".into(),
+                mode: Mode::new(FileType::Dir, 0o755),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+
+        for (name, typ, perm, size, data) in files {
+            let ino = meta
+                .inode(Inode {
+                    parent: root,
+                    name: name.to_string(),
+                    mode: Mode::new(typ.clone(), *perm),
+                    size: *size,
+                    data: Some(data.as_bytes().to_vec()),
+                    ..Inode::default()
+                })
+                .await
+                .unwrap();
+
+            if *typ == FileType::Regular {
+                let mut id = [0; 32];
+                id[..data.len()].copy_from_slice(data.as_bytes());
+                meta.block(ino, &id, &[1; 32]).await.unwrap();
+            }
+        }
+
+        Reader::new(path).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_diff() {
+        use FileType::*;
+        let old = flist(
+            "/tmp/diff-old.fl",
+            &[
+                ("same", Regular, 0o644, 10, "a"),
+                ("removed", Regular, 0o644, 10, "b"),
+                ("content", Regular, 0o644, 10, "c"),
+                ("size", Regular, 0o644, 10, "d"),
+                ("mode", Regular, 0o644, 10, "e"),
+                ("link", Link, 0o777, 0, "same"),
+                ("type", Regular, 0o644, 10, "f"),
+            ],
+        )
+        .await;
+        let new = flist(
+            "/tmp/diff-new.fl",
+            &[
+                ("same", Regular, 0o644, 10, "a"),
+                ("added", Regular, 0o644, 10, "b"),
+                ("content", Regular, 0o644, 10, "cc"),
+                ("size", Regular, 0o644, 20, "d"),
+                ("mode", Regular, 0o755, 10, "e"),
+                ("link", Link, 0o777, 0, "size"),
+                ("type", Link, 0o777, 0, "same"),
+            ],
+        )
+        .await;
+
+        let changes: Vec<String> = diff(&old, &new)
+            .await
+            .unwrap()
+            .iter()
+            .map(|change| change.to_string())
+            .collect();
+
+        assert_eq!(
+            changes,
+            vec![
+                "+ /added",
+                "~ /content (content)",
+                "~ /link (target)",
+                "~ /mode (mode)",
+                "- /removed",
+                "~ /size (size)",
+                "~ /type (type)",
+            ]
+        );
+    }
+}
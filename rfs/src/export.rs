@@ -0,0 +1,211 @@
+use crate::cache::Cache;
+use crate::fungi::{
+    meta::{FileType, Inode, Result, Walk, WalkVisitor},
+    Reader,
+};
+use crate::store::Store;
+use anyhow::Context;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tar::{EntryType, Header};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// size of a tar record, headers and file contents are padded to it
+const RECORD_SIZE: u64 = 512;
+// names and link targets longer than this are written as gnu long name entries
+const NAME_LEN: usize = 100;
+
+/// export writes the content of an FL to out as a tar archive (gnu format) without
+/// mounting it. The files are written block by block as they are downloaded, so
+/// only one block is held in memory at a time. Directories, regular files and
+/// symlinks are exported with their mode, owner and modification time, the other
+/// file types are skipped
+pub async fn export<S, W>(meta: &Reader, cache: &Cache<S>, out: W) -> Result<()>
+where
+    S: Store,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut visitor = TarVisitor { meta, cache, out };
+    meta.walk(&mut visitor).await?;
+
+    // the end of the archive is marked by 2 empty records
+    visitor
+        .out
+        .write_all(&[0; 2 * RECORD_SIZE as usize])
+        .await?;
+    visitor.out.flush().await?;
+    Ok(())
+}
+
+struct TarVisitor<'a, S, W>
+where
+    S: Store,
+{
+    meta: &'a Reader,
+    cache: &'a Cache<S>,
+    out: W,
+}
+
+impl<'a, S, W> TarVisitor<'a, S, W>
+where
+    S: Store,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn content(&mut self, path: &Path, node: &Inode) -> Result<()> {
+        let mut remaining = node.size;
+        for block in self.meta.blocks(node.ino).await? {
+            if remaining == 0 {
+                break;
+            }
+
+            let (_, chunk) = self
+                .cache
+                .get(&block)
+                .await
+                .with_context(|| format!("failed to download file '{:?}'", path))?;
+            remaining -= tokio::io::copy(&mut chunk.take(remaining), &mut self.out).await?;
+        }
+
+        // files ending with a hole are not covered by their blocks
+        zeros(&mut self.out, remaining).await?;
+        zeros(&mut self.out, padding(node.size)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<'a, S, W> WalkVisitor for TarVisitor<'a, S, W>
+where
+    S: Store,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn visit(&mut self, path: &Path, node: &Inode) -> Result<Walk> {
+        // entries are relative to the root of the archive
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let mut name = Path::new(".")
+            .join(relative)
+            .as_os_str()
+            .as_bytes()
+            .to_vec();
+
+        let mut header = Header::new_gnu();
+        header.set_mode(node.mode.permissions());
+        header.set_uid(node.uid.into());
+        header.set_gid(node.gid.into());
+        header.set_mtime(node.mtime.max(0) as u64);
+
+        let mut target: &[u8] = &[];
+        match node.mode.file_type() {
+            FileType::Dir => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                // the root is already `./`
+                if name.last() != Some(&b'/') {
+                    name.push(b'/');
+                }
+            }
+            FileType::Regular => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(node.size);
+            }
+            FileType::Link => {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                target = node
+                    .data
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("link has no target path"))?;
+            }
+            kind => {
+                warn!("skipping '{:?}' of unsupported file kind: {:?}", path, kind);
+                return Ok(Walk::Continue);
+            }
+        }
+
+        let mut entry = Vec::default();
+        if name.len() > NAME_LEN {
+            entry.extend(long_name(EntryType::GNULongName, &name));
+        }
+        if target.len() > NAME_LEN {
+            entry.extend(long_name(EntryType::GNULongLink, target));
+        }
+
+        let gnu = header.as_gnu_mut().expect("header is a gnu header");
+        copy_name(&mut gnu.name, &name);
+        copy_name(&mut gnu.linkname, target);
+        header.set_cksum();
+        entry.extend_from_slice(header.as_bytes());
+        self.out.write_all(&entry).await?;
+
+        if node.mode.is(FileType::Regular) {
+            self.content(path, node).await?;
+        }
+
+        Ok(Walk::Continue)
+    }
+}
+
+// copy_name copies the name to a header field, a name that doesn't fit is truncated
+// since it's written in full by a long name entry before the header
+fn copy_name(field: &mut [u8; NAME_LEN], name: &[u8]) {
+    let len = name.len().min(NAME_LEN);
+    field[..len].copy_from_slice(&name[..len]);
+}
+
+// long_name returns a gnu long name (or long link name) entry for the name
+fn long_name(kind: EntryType, name: &[u8]) -> Vec<u8> {
+    let mut header = Header::new_gnu();
+    let size = name.len() as u64 + 1;
+    copy_name(
+        &mut header.as_gnu_mut().expect("header is a gnu header").name,
+        b"././@LongLink",
+    );
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(size);
+    header.set_entry_type(kind);
+    header.set_cksum();
+
+    let mut entry = header.as_bytes().to_vec();
+    entry.extend_from_slice(name);
+    entry.resize(entry.len() + 1 + padding(size) as usize, 0);
+    entry
+}
+
+// padding returns the number of bytes needed to fill the last record of the content
+fn padding(size: u64) -> u64 {
+    (RECORD_SIZE - size % RECORD_SIZE) % RECORD_SIZE
+}
+
+async fn zeros<W: AsyncWrite + Unpin>(out: &mut W, len: u64) -> std::io::Result<()> {
+    tokio::io::copy(&mut tokio::io::repeat(0).take(len), out).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_long_name() {
+        let name = vec![b'a'; 150];
+        let entry = long_name(EntryType::GNULongName, &name);
+        assert_eq!(entry.len(), 1024);
+        assert_eq!(&entry[512..662], name.as_slice());
+        assert_eq!(entry[662], 0);
+
+        let header = Header::from_byte_slice(&entry[..512]);
+        assert_eq!(header.entry_type(), EntryType::GNULongName);
+        assert_eq!(header.entry_size().unwrap(), 151);
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(padding(0), 0);
+        assert_eq!(padding(1), 511);
+        assert_eq!(padding(512), 0);
+        assert_eq!(padding(513), 511);
+    }
+}
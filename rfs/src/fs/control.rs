@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::cache;
+
+/// Status of a running mount as reported on the control socket
+#[derive(Debug, Serialize)]
+pub struct Status {
+    /// path of the mounted flist
+    pub flist: String,
+    /// sha256 digest of the mounted flist
+    pub digest: String,
+    pub mountpoint: PathBuf,
+    /// seconds since the filesystem was mounted
+    pub uptime: u64,
+    /// number of read requests served
+    pub reads: u64,
+    /// bytes returned to read requests
+    pub bytes_read: u64,
+    /// number of failed requests
+    pub errors: u64,
+    /// number of requests in progress
+    pub inflight: usize,
+    pub cache: cache::Stats,
+}
+
+/// Control identifies the mounted flist and where the control socket is created
+#[derive(Debug, Clone)]
+pub struct Control {
+    pub path: PathBuf,
+    pub flist: String,
+    pub digest: String,
+}
+
+/// Handler is implemented by the filesystem to answer the control socket commands
+#[async_trait::async_trait]
+pub trait Handler: Send + Sync + 'static {
+    fn status(&self) -> Status;
+    async fn unmount(&self) -> Result<()>;
+}
+
+/// bind creates the control socket, the socket is only accessible by the owner
+/// of the process
+pub fn bind<P: AsRef<Path>>(path: P) -> Result<UnixListener> {
+    let path = path.as_ref();
+    // a socket left behind by a previous run, anything else at the path is kept
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            std::fs::remove_file(path).context("failed to remove old control socket")?;
+        }
+        Ok(_) => anyhow::bail!("'{}' exists and is not a socket", path.display()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).context("failed to check control socket path"),
+    }
+
+    let listener = UnixListener::bind(path).context("failed to bind control socket")?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .context("failed to set control socket permissions")?;
+
+    Ok(listener)
+}
+
+/// serve accepts connections on the control socket. The status is sent as a json
+/// line once a client connects, after that the client can send one command per line
+/// - status: sends the status again
+/// - unmount: unmounts the filesystem
+pub async fn serve<H: Handler + Clone>(listener: UnixListener, handler: H) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("failed to accept control connection: {}", err);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, handler).await {
+                debug!("control connection error: {:#}", err);
+            }
+        });
+    }
+}
+
+async fn handle<H: Handler>(stream: UnixStream, handler: H) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    reply(&mut writer, &serde_json::to_value(handler.status())?).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match line.trim() {
+            "" => continue,
+            "status" => serde_json::to_value(handler.status())?,
+            "unmount" => match handler.unmount().await {
+                Ok(_) => serde_json::json!({"ok": true}),
+                Err(err) => serde_json::json!({"error": format!("{:#}", err)}),
+            },
+            cmd => serde_json::json!({"error": format!("unknown command '{}'", cmd)}),
+        };
+
+        reply(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
+
+async fn reply<W: AsyncWriteExt + Unpin>(writer: &mut W, value: &serde_json::Value) -> Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind() {
+        const PATH: &str = "/tmp/rfs-control-test.sock";
+        let _ = std::fs::remove_file(PATH);
+
+        std::fs::write(PATH, "not a socket").unwrap();
+        let err = bind(PATH).unwrap_err();
+        assert!(err.to_string().contains("not a socket"), "{}", err);
+        assert_eq!(std::fs::read_to_string(PATH).unwrap(), "not a socket");
+        std::fs::remove_file(PATH).unwrap();
+
+        // a socket left behind is replaced
+        drop(bind(PATH).unwrap());
+        drop(bind(PATH).unwrap());
+        std::fs::remove_file(PATH).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+use crate::fungi::Reader;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// name of the virtual directory added to the root of the mount
+pub const INFO_DIR: &str = ".rfs";
+/// name of the virtual info file inside the INFO_DIR
+pub const INFO_FILE: &str = "info";
+
+/// build generates the content of the virtual info file, a json document that
+/// identifies the mounted flist
+pub async fn build<P: AsRef<Path>>(path: P, meta: &Reader) -> Result<Vec<u8>> {
+    let digest = digest(path).await?;
+
+    let mut routes = Vec::default();
+    for route in meta.routes().await? {
+        routes.push(serde_json::json!({
+            "start": route.start,
+            "end": route.end,
+            "url": redact(&route.url),
+        }));
+    }
+
+    let info = serde_json::json!({
+        "digest": format!("sha256:{}", digest),
+        "routes": routes,
+        "blocks": meta.blocks_count().await?,
+        "size": meta.files_size().await?,
+        "version": env!("GIT_VERSION"),
+    });
+
+    let mut content = serde_json::to_vec_pretty(&info)?;
+    content.push(b'\n');
+
+    Ok(content)
+}
+
+/// digest returns the hex encoded sha256 digest of the flist file
+pub async fn digest<P: AsRef<Path>>(path: P) -> Result<String> {
+    let content = tokio::fs::read(path.as_ref())
+        .await
+        .context("failed to read flist")?;
+
+    Ok(hex::encode(Sha256::digest(&content)))
+}
+
+// redact removes the credentials from the route url
+fn redact(u: &str) -> String {
+    match url::Url::parse(u) {
+        Ok(mut url) => {
+            if url.password().is_some() {
+                let _ = url.set_password(Some("***"));
+            }
+            url.to_string()
+        }
+        Err(_) => u.into(),
+    }
+}
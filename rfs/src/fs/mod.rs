@@ -2,37 +2,202 @@
 #![deny(clippy::unimplemented, clippy::todo)]
 
 use crate::cache;
+use crate::content::{components, locate, MAX_LINKS};
 use crate::fungi::{
-    meta::{FileType, Inode},
-    Reader,
+    meta::{Block, FileType, Ino, Inode, Mode},
+    Reader, Writer,
 };
-use crate::store::Store;
+use crate::store::{self, Store};
 
 use anyhow::{ensure, Context, Result};
 use polyfuse::reply::FileAttr;
 use polyfuse::{
     op,
-    reply::{AttrOut, EntryOut, ReaddirOut, StatfsOut},
+    reply::{AttrOut, EntryOut, OpenOut, ReaddirOut, StatfsOut, WriteOut, XattrOut},
     KernelConfig, Operation, Request, Session,
 };
+use std::ffi::OsStr;
 use std::io::SeekFrom;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use std::{io, path::PathBuf, time::Duration};
 use tokio::fs::File;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tokio::{
     io::{unix::AsyncFd, AsyncReadExt, AsyncSeekExt, Interest},
     task::{self, JoinHandle},
 };
 
-const CHUNK_SIZE: usize = 512 * 1024; // 512k and is hardcoded in the hub. the block_size value is not used
 const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+const RW_TTL: Duration = Duration::from_secs(1); // entries of a writable mount change
 const LRU_CAP: usize = 5; // Least Recently Used File Capacity
+pub const ATTR_CACHE_CAP: usize = 10 * 1024; // max number of cached inodes
+const READAHEAD_CAP: usize = 1024; // max number of files tracked for readahead
+const STATS_INTERVAL: Duration = Duration::from_secs(60); // cache stats debug log period
 const FS_BLOCK_SIZE: u32 = 4 * 1024;
+const NAME_MAX: u32 = 255;
+/// subtype of rfs mounts, they are listed with the fuse.rfs type
+pub const SUBTYPE: &str = "rfs";
+// default source of the mount, shown as the device by mount
+const FSNAME: &str = "rfs";
+// a path can't be deeper than this since each level takes at least 2 bytes of PATH_MAX
+const MAX_DEPTH: usize = 2048;
+// inodes of the virtual info entries, they are out of the range of the flist inodes
+const INFO_DIR_INO: Ino = u64::MAX - 1;
+const INFO_FILE_INO: Ino = u64::MAX - 2;
+
+pub mod control;
+pub mod info;
+pub mod options;
+pub mod overlay;
 
 type FHash = [u8; 32];
 type BlockSize = u64;
 
+/// AttrCache keeps the inodes of the recently listed directories entries
+/// so a stat of each entry after listing a directory (ls -l) is served from
+/// memory. Entries are invalidated by the writes of a writable mount, and
+/// expire after the ttl if it's set.
+struct AttrCache {
+    nodes: lru::LruCache<Ino, (Inode, Instant)>,
+    names: lru::LruCache<(Ino, String), Ino>,
+    ttl: Option<Duration>,
+}
+
+impl AttrCache {
+    fn new(cap: usize, ttl: Option<Duration>) -> Self {
+        Self {
+            nodes: lru::LruCache::new(cap),
+            names: lru::LruCache::new(cap),
+            ttl,
+        }
+    }
+
+    fn insert(&mut self, node: &Inode) {
+        self.names.put((node.parent, node.name.clone()), node.ino);
+        self.nodes.put(node.ino, (node.clone(), Instant::now()));
+    }
+
+    fn get(&mut self, ino: Ino) -> Option<Inode> {
+        let (node, cached) = self.nodes.get(&ino)?;
+        match self.ttl {
+            Some(ttl) if cached.elapsed() >= ttl => {
+                self.nodes.pop(&ino);
+                None
+            }
+            _ => Some(node.clone()),
+        }
+    }
+
+    fn lookup(&mut self, parent: Ino, name: &str) -> Option<Inode> {
+        let ino = *self.names.get(&(parent, name.to_owned()))?;
+        self.get(ino)
+    }
+
+    fn forget(&mut self, node: &Inode) {
+        self.names.pop(&(node.parent, node.name.clone()));
+        self.nodes.pop(&node.ino);
+    }
+}
+
+/// Usage of the flist reported by statfs, it's computed once at mount
+#[derive(Debug, Default, Clone, Copy)]
+struct Usage {
+    // total size of the regular files
+    size: u64,
+    inodes: u64,
+}
+
+/// Activity tracks the time of the last fuse request and the number
+/// of requests that are still in progress
+struct Activity {
+    start: Instant,
+    // milliseconds since start
+    last: AtomicU64,
+    inflight: AtomicUsize,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            inflight: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// begin starts a request, it's in progress until the returned guard is dropped
+    fn begin(self: &Arc<Self>) -> ActivityGuard {
+        self.inflight.fetch_add(1, Ordering::SeqCst);
+        self.touch();
+        ActivityGuard(Arc::clone(self))
+    }
+
+    fn end(&self) {
+        self.touch();
+        self.inflight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// idle returns the time since the last request if no requests are in progress
+    fn idle(&self) -> Option<Duration> {
+        if self.inflight.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        Some(self.start.elapsed().saturating_sub(last))
+    }
+}
+
+/// ActivityGuard ends its request when dropped, so a request that returns early
+/// or panics is never left in progress
+struct ActivityGuard(Arc<Activity>);
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        self.0.end();
+    }
+}
+
+/// AbortOnDrop aborts its background task when dropped, so the task doesn't outlive
+/// the mount if serving it fails
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Counters of the served requests
+#[derive(Default)]
+struct Counters {
+    reads: AtomicU64,
+    bytes_read: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counters {
+    fn read(&self, size: usize) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 pub struct Filesystem<S>
 where
     S: Store,
@@ -40,6 +205,26 @@ where
     meta: Reader,
     cache: Arc<cache::Cache<S>>,
     lru: Arc<Mutex<lru::LruCache<FHash, (File, BlockSize)>>>,
+    attrs: Arc<Mutex<AttrCache>>,
+    // overrides the default ttl of the entries and attributes
+    attr_ttl: Option<Duration>,
+    info: Option<Arc<Vec<u8>>>,
+    idle_timeout: Option<Duration>,
+    activity: Arc<Activity>,
+    inflight: Option<Arc<Semaphore>>,
+    counters: Arc<Counters>,
+    usage: Usage,
+    control: Option<control::Control>,
+    readahead: usize,
+    confine_links: bool,
+    mount_options: Vec<options::MountOption>,
+    fsname: String,
+    // last block read and the end of the prefetched blocks per file
+    sequential: Arc<std::sync::Mutex<lru::LruCache<Ino, (usize, usize)>>>,
+    // only set if the mount is writable
+    overlay: Option<Arc<overlay::Overlay>>,
+    // set once mounted, used by the control socket unmount command
+    mounted: Option<(PathBuf, PathBuf)>,
 }
 
 impl<S> Clone for Filesystem<S>
@@ -51,6 +236,22 @@ where
             meta: self.meta.clone(),
             cache: Arc::clone(&self.cache),
             lru: Arc::clone(&self.lru),
+            attrs: Arc::clone(&self.attrs),
+            attr_ttl: self.attr_ttl,
+            info: self.info.clone(),
+            idle_timeout: self.idle_timeout,
+            activity: Arc::clone(&self.activity),
+            inflight: self.inflight.clone(),
+            counters: Arc::clone(&self.counters),
+            usage: self.usage,
+            control: self.control.clone(),
+            readahead: self.readahead,
+            confine_links: self.confine_links,
+            mount_options: self.mount_options.clone(),
+            fsname: self.fsname.clone(),
+            sequential: Arc::clone(&self.sequential),
+            overlay: self.overlay.clone(),
+            mounted: self.mounted.clone(),
         }
     }
 }
@@ -64,33 +265,333 @@ where
             meta,
             cache: Arc::new(cache),
             lru: Arc::new(Mutex::new(lru::LruCache::new(LRU_CAP))),
+            attrs: Arc::new(Mutex::new(AttrCache::new(ATTR_CACHE_CAP, None))),
+            attr_ttl: None,
+            info: None,
+            idle_timeout: None,
+            activity: Arc::default(),
+            inflight: None,
+            counters: Arc::default(),
+            usage: Usage::default(),
+            control: None,
+            readahead: 0,
+            confine_links: false,
+            mount_options: Vec::default(),
+            fsname: FSNAME.into(),
+            sequential: Arc::new(std::sync::Mutex::new(lru::LruCache::new(READAHEAD_CAP))),
+            overlay: None,
+            mounted: None,
         }
     }
 
+    /// with_control_socket serves the status of the mount on a unix socket at the
+    /// given path and accepts an unmount command. flist and digest identify the
+    /// mounted flist in the status
+    pub fn with_control_socket<P: Into<PathBuf>>(
+        mut self,
+        path: P,
+        flist: String,
+        digest: String,
+    ) -> Self {
+        self.control = Some(control::Control {
+            path: path.into(),
+            flist,
+            digest,
+        });
+        self
+    }
+
+    /// with_max_inflight limits the number of reads that are processed at the
+    /// same time, extra reads wait until a running read is done
+    pub fn with_max_inflight(mut self, max: usize) -> Self {
+        self.inflight = Some(Arc::new(Semaphore::new(max.max(1))));
+        self
+    }
+
+    /// with_attr_cache sets the ttl and the maximum number of inodes of the attributes
+    /// cache. The ttl is also used by the kernel to cache the entries and attributes.
+    /// Without a ttl, the attributes of a read-only mount are cached forever since the
+    /// flist doesn't change, and those of a writable mount for a second
+    pub fn with_attr_cache(mut self, ttl: Option<Duration>, size: usize) -> Self {
+        self.attrs = Arc::new(Mutex::new(AttrCache::new(size.max(1), ttl)));
+        self.attr_ttl = ttl;
+        self
+    }
+
+    /// with_readahead downloads up to the given number of blocks after the block
+    /// being read in the background once a file is read sequentially
+    pub fn with_readahead(mut self, blocks: usize) -> Self {
+        self.readahead = blocks;
+        self
+    }
+
+    /// with_confined_links rewrites the targets of the links that point outside of the
+    /// mount (absolute targets or too many `..`) so they're resolved inside the mount
+    /// instead. It's meant for mounting untrusted flists
+    pub fn with_confined_links(mut self) -> Self {
+        self.confine_links = true;
+        self
+    }
+
+    /// with_mount_options adds options to the fuse mount, an option replaces the
+    /// default option with the same name. The uid and gid options are applied by
+    /// the filesystem to the owner of all entries
+    pub fn with_mount_options(mut self, options: Vec<options::MountOption>) -> Self {
+        self.mount_options = options;
+        self
+    }
+
+    /// with_fsname sets the source of the mount shown by `mount` and in /proc/mounts,
+    /// usually the flist that is mounted. The mount type is always `fuse.rfs`, an
+    /// fsname mount option replaces the name. Commas are replaced by `_` since they
+    /// separate the mount options
+    pub fn with_fsname<N: AsRef<str>>(mut self, name: N) -> Self {
+        self.fsname = name.as_ref().replace(',', "_");
+        self
+    }
+
+    /// with_overlay makes the mount writable, new and changed files are uploaded
+    /// to the store and recorded in the flist using the given writer. root is the
+    /// directory where files are kept while they are written
+    pub async fn with_overlay<P: Into<PathBuf>>(mut self, meta: Writer, root: P) -> Result<Self> {
+        self.overlay = Some(Arc::new(overlay::Overlay::new(meta, root).await?));
+        Ok(self)
+    }
+
+    /// with_idle_timeout unmounts the filesystem once no requests are received
+    /// for the given duration
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// with_info exposes the given content as a read-only virtual file /.rfs/info.
+    /// the file is not exposed if the flist already has a /.rfs entry
+    pub async fn with_info(mut self, content: Vec<u8>) -> Result<Self> {
+        if self.meta.lookup(1, info::INFO_DIR).await?.is_some() {
+            warn!(
+                "flist has a /{} entry, info file will not be exposed",
+                info::INFO_DIR
+            );
+            return Ok(self);
+        }
+
+        self.info = Some(Arc::new(content));
+        Ok(self)
+    }
+
+    // prefetch downloads the next blocks of a file to the cache in the background if the
+    // file is read sequentially. Prefetch errors are only logged, the foreground read
+    // downloads the block again if it's still missing. A prefetch counts as a read in
+    // progress so the mount is not idle, and it takes a permit of max_inflight like the
+    // foreground reads
+    fn prefetch(&self, ino: Ino, blocks: &[Block], index: usize) {
+        let (start, end) = {
+            let mut sequential = self.sequential.lock().unwrap();
+            let (last, until) = sequential.get(&ino).copied().unwrap_or_default();
+            if index != last && index != last + 1 {
+                // a random access, the readahead starts over
+                sequential.put(ino, (index, index + 1));
+                return;
+            }
+
+            let start = until.max(index + 1);
+            let end = (index + 1 + self.readahead).min(blocks.len());
+            sequential.put(ino, (index, end.max(until)));
+            (start, end)
+        };
+
+        for block in blocks.iter().take(end).skip(start) {
+            if block.is_hole() {
+                continue;
+            }
+
+            let cache = Arc::clone(&self.cache);
+            let inflight = self.inflight.clone();
+            let activity = self.activity.begin();
+            let block = block.clone();
+            tokio::spawn(async move {
+                let _activity = activity;
+                let _permit = match inflight {
+                    Some(ref inflight) => match inflight.acquire().await {
+                        Ok(permit) => Some(permit),
+                        Err(_) => return,
+                    },
+                    None => None,
+                };
+
+                debug!("prefetching block {}", hex::encode(block.id));
+                if let Err(err) = cache.get(&block).await {
+                    debug!(
+                        "failed to prefetch block {}: {:#}",
+                        hex::encode(block.id),
+                        err
+                    );
+                }
+            });
+        }
+    }
+
+    // virtual_inode returns the inode of a virtual entry if the info file is exposed
+    fn virtual_inode(&self, ino: Ino) -> Option<Inode> {
+        let info = self.info.as_ref()?;
+
+        let node = match ino {
+            INFO_DIR_INO => Inode {
+                ino,
+                parent: 1,
+                name: info::INFO_DIR.into(),
+                mode: Mode::new(FileType::Dir, 0o555),
+                ..Inode::default()
+            },
+            INFO_FILE_INO => Inode {
+                ino,
+                parent: INFO_DIR_INO,
+                name: info::INFO_FILE.into(),
+                size: info.len() as u64,
+                mode: Mode::new(FileType::Regular, 0o444),
+                ..Inode::default()
+            },
+            _ => return None,
+        };
+
+        Some(node)
+    }
+
+    async fn inode(&self, ino: Ino) -> Result<Inode> {
+        if let Some(node) = self.virtual_inode(ino) {
+            return Ok(node);
+        }
+
+        let cached = self.attrs.lock().await.get(ino);
+        let mut node = match cached {
+            Some(node) => node,
+            None => self.meta.inode(ino).await?,
+        };
+
+        // the size of a file that is being written is only known by the overlay
+        if let Some(ref overlay) = self.overlay {
+            if let Some(size) = overlay.size(ino).await {
+                node.size = size;
+            }
+        }
+
+        Ok(node)
+    }
+
+    // fill sets the attributes of the node, the owner is overridden by the uid
+    // and gid mount options
+    fn fill(&self, node: &Inode, attr: &mut FileAttr) {
+        node.fill(attr);
+        for option in self.mount_options.iter() {
+            match option {
+                options::MountOption::Uid(uid) => attr.uid(*uid),
+                options::MountOption::Gid(gid) => attr.gid(*gid),
+                options::MountOption::Kernel(_) => {}
+            }
+        }
+    }
+
+    fn ttl(&self) -> Duration {
+        match (self.attr_ttl, &self.overlay) {
+            (Some(ttl), _) => ttl,
+            (None, Some(_)) => RW_TTL,
+            (None, None) => TTL,
+        }
+    }
+
+    /// mount mounts the filesystem and serves it until it's unmounted
     pub async fn mount<P>(&self, mnt: P) -> Result<()>
+    where
+        P: Into<PathBuf>,
+    {
+        self.spawn(mnt).await?.await
+    }
+
+    /// spawn mounts the filesystem and serves it in a background task, it returns once
+    /// the filesystem is mounted. The returned handle is used to unmount the filesystem
+    /// or to wait for it to be unmounted, so many filesystems can be mounted by the
+    /// same process
+    pub async fn spawn<P>(&self, mnt: P) -> Result<MountHandle>
     where
         P: Into<PathBuf>,
     {
         let mountpoint: PathBuf = mnt.into();
         ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
+        let defaults = [
+            if self.overlay.is_some() { "rw" } else { "ro" }.to_string(),
+            "allow_other".to_string(),
+            format!("fsname={}", self.fsname),
+            format!("subtype={}", SUBTYPE),
+            "default_permissions".to_string(),
+        ];
+        let mount_options = options::join(&defaults, &self.mount_options);
         let mut options = KernelConfig::default();
-        options.mount_option(&format!(
-            "ro,allow_other,fsname={},subtype=g8ufs,default_permissions",
-            std::process::id()
-        ));
+        options.mount_option(&mount_options);
 
         // polyfuse assumes an absolute path, see https://github.com/ubnt-intrepid/polyfuse/issues/83
         let fusermount_path =
             which::which("fusermount").context("looking up 'fusermount' in PATH")?;
-        options.fusermount_path(fusermount_path);
+        options.fusermount_path(fusermount_path.clone());
 
-        let session = AsyncSession::mount(mountpoint, options).await?;
+        let listener = match self.control {
+            Some(ref ctl) => Some(control::bind(&ctl.path)?),
+            None => None,
+        };
+
+        let usage = Usage {
+            size: self.meta.files_size().await?,
+            inodes: self.meta.inodes_count().await?,
+        };
+
+        let session = AsyncSession::mount(mountpoint.clone(), options).await?;
+
+        let mut fs = self.clone();
+        fs.mounted = Some((fusermount_path.clone(), mountpoint.clone()));
+        fs.usage = usage;
+
+        if let Some(listener) = listener {
+            task::spawn(control::serve(listener, fs.clone()));
+        }
+
+        if let Some(timeout) = self.idle_timeout {
+            self.activity.touch();
+            task::spawn(unmount_on_idle(
+                Arc::clone(&self.activity),
+                timeout,
+                fusermount_path.clone(),
+                mountpoint.clone(),
+            ));
+        }
+
+        Ok(MountHandle {
+            fusermount: fusermount_path,
+            mountpoint,
+            task: task::spawn(fs.serve(session)),
+        })
+    }
+
+    // serve handles the requests of the mounted filesystem until it's unmounted
+    async fn serve(self, session: AsyncSession) -> Result<()> {
+        let _stats = log::log_enabled!(log::Level::Debug).then(|| {
+            let cache = Arc::clone(&self.cache);
+            AbortOnDrop(task::spawn(async move {
+                let mut interval = tokio::time::interval(STATS_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    debug!("cache: {}", cache.stats());
+                }
+            }))
+        });
 
         // release here
         while let Some(req) = session.next_request().await? {
             let fs = self.clone();
+            let activity = fs.activity.begin();
 
             let handler: JoinHandle<Result<()>> = task::spawn(async move {
+                let _activity = activity;
                 let result = match req.operation()? {
                     Operation::Lookup(op) => fs.lookup(&req, op).await,
                     Operation::Getattr(op) => fs.getattr(&req, op).await,
@@ -98,6 +599,24 @@ where
                     Operation::Readdir(op) => fs.readdir(&req, op).await,
                     Operation::Readlink(op) => fs.readlink(&req, op).await,
                     Operation::Statfs(op) => fs.statfs(&req, op).await,
+                    Operation::Getxattr(op) => fs.getxattr(&req, op).await,
+                    Operation::Listxattr(op) => fs.listxattr(&req, op).await,
+                    Operation::Setxattr(op) => fs.setxattr(&req, op).await,
+                    // create is not implemented so the kernel creates files with mknod
+                    Operation::Mknod(op) => fs.mknod(&req, op).await,
+                    Operation::Mkdir(op) => fs.mkdir(&req, op).await,
+                    Operation::Unlink(op) => fs.unlink(&req, op).await,
+                    Operation::Rmdir(op) => fs.rmdir(&req, op).await,
+                    Operation::Write(op, mut data) => {
+                        let mut buf = Vec::with_capacity(op.size() as usize);
+                        std::io::Read::read_to_end(&mut data, &mut buf)?;
+                        fs.write(&req, op, buf).await
+                    }
+                    Operation::Setattr(op) => fs.setattr(&req, op).await,
+                    Operation::Flush(op) => fs.sync(&req, op.ino()).await,
+                    Operation::Fsync(op) => fs.sync(&req, op.ino()).await,
+                    Operation::Open(op) => fs.open(&req, op.ino()).await,
+                    Operation::Release(op) => fs.release(&req, op.ino()).await,
                     op => {
                         debug!("function is not implemented: {:?}", op);
                         Ok(req.reply_error(libc::ENOSYS)?)
@@ -105,6 +624,7 @@ where
                 };
 
                 if result.is_err() {
+                    fs.counters.error();
                     req.reply_error(libc::ENOENT)?;
                 }
 
@@ -114,13 +634,45 @@ where
             drop(handler);
         }
 
+        if let Some(ref ctl) = self.control {
+            let _ = std::fs::remove_file(&ctl.path);
+        }
+
+        info!("cache: {}", self.cache.stats());
+
         Ok(())
     }
 
+    /// cache_stats returns the usage of the blocks cache since the filesystem was created
+    pub fn cache_stats(&self) -> cache::Stats {
+        self.cache.stats()
+    }
+
     async fn statfs(&self, req: &Request, _op: op::Statfs<'_>) -> Result<()> {
+        // a read-only mount is always full, a writable mount has the free space of
+        // the overlay directory where written files are kept
+        let (free, free_inodes) = match self.overlay {
+            Some(ref overlay) => overlay.available().unwrap_or_else(|err| {
+                warn!("{:#}", err);
+                (0, 0)
+            }),
+            None => (0, 0),
+        };
+
+        let block_size = FS_BLOCK_SIZE as u64;
+        let used = self.usage.size.div_ceil(block_size);
+        let free = free / block_size;
+
         let mut out = StatfsOut::default();
         let stats = out.statfs();
         stats.bsize(FS_BLOCK_SIZE);
+        stats.frsize(FS_BLOCK_SIZE);
+        stats.blocks(used + free);
+        stats.bfree(free);
+        stats.bavail(free);
+        stats.files(self.usage.inodes + free_inodes);
+        stats.ffree(free_inodes);
+        stats.namelen(NAME_MAX);
         req.reply(out)?;
         Ok(())
     }
@@ -131,27 +683,73 @@ where
             return Ok(req.reply_error(libc::ENOLINK)?);
         }
 
-        if let Some(target) = link.data {
-            req.reply(target)?;
-            return Ok(());
+        let target = match link.data.as_deref() {
+            Some(target) => target,
+            None => return Ok(req.reply_error(libc::ENOLINK)?),
+        };
+
+        // the kernel resolves the target, it fails with ELOOP once too many links are
+        // followed so link loops don't need to be detected here
+        if !self.confine_links {
+            return Ok(req.reply(target)?);
+        }
+
+        let parents = self.parents(link.parent).await?;
+        Ok(req.reply(confine(&self.meta, &parents, &link, target).await?)?)
+    }
+
+    // parents returns the directories (inode and name) from the root of the flist down
+    // to the given directory, the root itself is not included
+    async fn parents(&self, mut ino: Ino) -> Result<Vec<(Ino, String)>> {
+        let mut dirs = Vec::default();
+        while ino != 1 {
+            ensure!(dirs.len() < MAX_DEPTH, "inode {} is too deep", ino);
+            let node = self.inode(ino).await?;
+            dirs.push((ino, node.name));
+            ino = node.parent;
         }
 
-        Ok(req.reply_error(libc::ENOLINK)?)
+        dirs.reverse();
+        Ok(dirs)
     }
 
     async fn read(&self, req: &Request, op: op::Read<'_>) -> Result<()> {
-        let entry = self.meta.inode(op.ino()).await?;
+        let entry = self.inode(op.ino()).await?;
 
         if !entry.mode.is(FileType::Regular) {
             return Ok(req.reply_error(libc::EISDIR)?);
         };
 
-        let offset = op.offset() as usize;
+        if let (INFO_FILE_INO, Some(info)) = (op.ino(), &self.info) {
+            let start = (op.offset() as usize).min(info.len());
+            let end = (start + op.size() as usize).min(info.len());
+            self.counters.read(end - start);
+            return Ok(req.reply(&info[start..end])?);
+        }
+
+        // the permit is held until the read is done so the number of open
+        // blocks and store connections used by reads is bounded
+        let _permit = match self.inflight {
+            Some(ref inflight) => Some(inflight.acquire().await?),
+            None => None,
+        };
+
+        if let Some(ref overlay) = self.overlay {
+            if let Some(data) = overlay
+                .read(op.ino(), op.offset(), op.size() as usize)
+                .await?
+            {
+                self.counters.read(data.len());
+                return Ok(req.reply(data)?);
+            }
+        }
+
         let size = op.size() as usize;
-        let chunk_size = CHUNK_SIZE; // file.block_size as usize;
-        let chunk_index = offset / chunk_size;
 
         let blocks = self.meta.blocks(op.ino()).await?;
+        let sizes = self.meta.block_sizes(op.ino()).await?;
+        let (chunk_index, offset) = locate(&sizes, op.offset());
+        let mut offset = offset as usize;
 
         if chunk_index >= blocks.len() || op.size() == 0 {
             // reading after the end of the file
@@ -159,8 +757,10 @@ where
             return Ok(req.reply(data)?);
         }
 
-        // offset inside the file
-        let mut offset = offset - (chunk_index * chunk_size);
+        if self.readahead > 0 {
+            self.prefetch(op.ino(), &blocks, chunk_index);
+        }
+
         let mut buf: Vec<u8> = vec![0; size];
         let mut total = 0;
 
@@ -180,7 +780,15 @@ where
                     let (bsize, descriptor) = match self.cache.get(block).await {
                         Ok(out) => out,
                         Err(err) => {
-                            error!("io cache error: {:#}", err);
+                            match err.downcast_ref::<store::Error>() {
+                                Some(store::Error::KeyNotRoutable(prefix)) => error!(
+                                    "no route for block prefix {:02x} of file '{}' (inode {}), \
+                                     the flist routes don't cover this prefix",
+                                    prefix, entry.name, entry.ino
+                                ),
+                                _ => error!("io cache error: {:#}", err),
+                            }
+                            self.counters.error();
                             return Ok(req.reply_error(libc::EIO)?);
                         }
                     };
@@ -199,6 +807,7 @@ where
                     Ok(n) => n,
                     Err(err) => {
                         error!("read error: {:#}", err);
+                        self.counters.error();
                         return Ok(req.reply_error(libc::EIO)?);
                     }
                 };
@@ -227,18 +836,18 @@ where
             offset = 0;
         }
 
+        self.counters.read(size);
         Ok(req.reply(&buf[..size])?)
     }
 
     async fn getattr(&self, req: &Request, op: op::Getattr<'_>) -> Result<()> {
         log::debug!("getattr({})", op.ino());
 
-        let entry = self.meta.inode(op.ino()).await?;
+        let entry = self.inode(op.ino()).await?;
 
         let mut attr = AttrOut::default();
 
-        let fill = attr.attr();
-        entry.fill(fill);
+        self.fill(&entry, attr.attr());
 
         req.reply(attr)?;
 
@@ -247,7 +856,7 @@ where
 
     async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> Result<()> {
         log::debug!("readdir({})", op.ino());
-        let root = self.meta.inode(op.ino()).await?;
+        let root = self.inode(op.ino()).await?;
 
         if !root.mode.is(FileType::Dir) {
             req.reply_error(libc::ENOTDIR)?;
@@ -257,6 +866,25 @@ where
         let mut out = ReaddirOut::new(op.size() as usize);
         let mut offset = op.offset();
 
+        if root.ino == INFO_DIR_INO {
+            if offset == 0 {
+                out.entry(".".as_ref(), INFO_DIR_INO, libc::DT_DIR as u32, 1);
+                out.entry("..".as_ref(), 1, libc::DT_DIR as u32, 2);
+                out.entry(
+                    info::INFO_FILE.as_ref(),
+                    INFO_FILE_INO,
+                    libc::DT_REG as u32,
+                    3,
+                );
+            }
+
+            return Ok(req.reply(out)?);
+        }
+
+        // the virtual info directory is listed in the root directory right after ..
+        let with_info = root.ino == 1 && self.info.is_some();
+        let base = if with_info { 3 } else { 2 };
+
         let mut query_offset = offset;
         if offset == 0 {
             out.entry(".".as_ref(), op.ino(), libc::DT_DIR as u32, 1);
@@ -269,14 +897,30 @@ where
                 libc::DT_DIR as u32,
                 2,
             );
-            offset = 2;
+            if with_info {
+                out.entry(
+                    info::INFO_DIR.as_ref(),
+                    INFO_DIR_INO,
+                    libc::DT_DIR as u32,
+                    3,
+                );
+            }
+            offset = base;
         } else {
             // we don't add the . and .. but
             // we also need to change the offset to
-            query_offset -= 2;
+            query_offset -= base;
         }
 
         let children = self.meta.children(root.ino, 10, query_offset).await?;
+        {
+            // the entries are usually stat-ed right after listing
+            let mut attrs = self.attrs.lock().await;
+            for entry in children.iter() {
+                attrs.insert(entry);
+            }
+        }
+
         for entry in children.iter() {
             offset += 1;
 
@@ -315,7 +959,18 @@ where
             }
         };
 
-        let node = self.meta.lookup(op.parent(), name).await?;
+        let node = match (op.parent(), name) {
+            (1, info::INFO_DIR) if self.info.is_some() => self.virtual_inode(INFO_DIR_INO),
+            (INFO_DIR_INO, info::INFO_FILE) => self.virtual_inode(INFO_FILE_INO),
+            (INFO_DIR_INO, _) => None,
+            _ => {
+                let cached = self.attrs.lock().await.lookup(op.parent(), name);
+                match cached {
+                    Some(node) => Some(node),
+                    None => self.meta.lookup(op.parent(), name).await?,
+                }
+            }
+        };
 
         let node = match node {
             Some(node) => node,
@@ -326,13 +981,547 @@ where
         };
         let mut out = EntryOut::default();
 
-        node.fill(out.attr());
+        self.fill(&node, out.attr());
+        out.ino(node.ino);
+        out.ttl_attr(self.ttl());
+        out.ttl_entry(self.ttl());
+
+        Ok(req.reply(out)?)
+    }
+
+    async fn getxattr(&self, req: &Request, op: op::Getxattr<'_>) -> Result<()> {
+        log::debug!("getxattr({}, {:?})", op.ino(), op.name());
+        // flists created by older versions have no xattrs at all
+        let value = match (self.virtual_inode(op.ino()), op.name().to_str()) {
+            (None, Some(name)) => self.meta.xattr(op.ino(), name).await?,
+            _ => None,
+        };
+
+        match value {
+            Some(value) => reply_xattr(req, op.size(), &value),
+            None => Ok(req.reply_error(libc::ENODATA)?),
+        }
+    }
+
+    async fn listxattr(&self, req: &Request, op: op::Listxattr<'_>) -> Result<()> {
+        log::debug!("listxattr({})", op.ino());
+        // the names are returned as a list of null terminated strings
+        let mut names = Vec::default();
+        if self.virtual_inode(op.ino()).is_none() {
+            for (key, _) in self.meta.xattrs(op.ino()).await? {
+                names.extend_from_slice(key.as_bytes());
+                names.push(0);
+            }
+        }
+
+        reply_xattr(req, op.size(), &names)
+    }
+
+    // writable returns the overlay of a writable mount, the request is answered
+    // with EROFS if the mount is read-only or the inode is a virtual entry
+    fn writable(&self, req: &Request, ino: Ino) -> Result<Option<&overlay::Overlay>> {
+        match self.overlay {
+            Some(ref overlay) if self.virtual_inode(ino).is_none() => Ok(Some(overlay)),
+            _ => {
+                req.reply_error(libc::EROFS)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // make adds a new inode to a directory and replies with its entry
+    async fn make(&self, req: &Request, parent: Ino, name: &OsStr, mode: Mode) -> Result<()> {
+        let overlay = match self.writable(req, parent)? {
+            Some(overlay) => overlay,
+            None => return Ok(()),
+        };
+
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return Ok(req.reply_error(libc::EINVAL)?),
+        };
+
+        if !self.inode(parent).await?.mode.is(FileType::Dir) {
+            return Ok(req.reply_error(libc::ENOTDIR)?);
+        }
+
+        let reserved = parent == 1 && name == info::INFO_DIR && self.info.is_some();
+        if reserved || self.meta.lookup(parent, name).await?.is_some() {
+            return Ok(req.reply_error(libc::EEXIST)?);
+        }
+
+        let now = overlay::now();
+        let mut node = Inode {
+            parent,
+            name: name.into(),
+            mode,
+            uid: req.uid(),
+            gid: req.gid(),
+            ctime: now,
+            mtime: now,
+            ..Inode::default()
+        };
+        node.ino = overlay.meta().inode(node.clone()).await?;
+
+        let mut out = EntryOut::default();
+        self.fill(&node, out.attr());
         out.ino(node.ino);
-        out.ttl_attr(TTL);
-        out.ttl_entry(TTL);
+        out.ttl_attr(self.ttl());
+        out.ttl_entry(self.ttl());
 
         Ok(req.reply(out)?)
     }
+
+    async fn mknod(&self, req: &Request, op: op::Mknod<'_>) -> Result<()> {
+        log::debug!("mknod(parent: {}, name: {:?})", op.parent(), op.name());
+        let mode = Mode::from(op.mode());
+        if !mode.is(FileType::Regular) {
+            return Ok(req.reply_error(libc::EPERM)?);
+        }
+
+        self.make(req, op.parent(), op.name(), mode).await
+    }
+
+    async fn mkdir(&self, req: &Request, op: op::Mkdir<'_>) -> Result<()> {
+        log::debug!("mkdir(parent: {}, name: {:?})", op.parent(), op.name());
+        let mode = Mode::new(FileType::Dir, op.mode());
+        self.make(req, op.parent(), op.name(), mode).await
+    }
+
+    // remove removes an entry from a directory, dir tells if the entry must be
+    // an empty directory (rmdir) or must not be a directory (unlink)
+    async fn remove(&self, req: &Request, parent: Ino, name: &OsStr, dir: bool) -> Result<()> {
+        let overlay = match self.writable(req, parent)? {
+            Some(overlay) => overlay,
+            None => return Ok(()),
+        };
+
+        let node = match name.to_str() {
+            Some(name) => self.meta.lookup(parent, name).await?,
+            None => None,
+        };
+        let node = match node {
+            Some(node) => node,
+            None => return Ok(req.reply_error(libc::ENOENT)?),
+        };
+
+        match (node.mode.is(FileType::Dir), dir) {
+            (true, false) => return Ok(req.reply_error(libc::EISDIR)?),
+            (false, true) => return Ok(req.reply_error(libc::ENOTDIR)?),
+            (true, true) if !self.meta.children(node.ino, 1, 0).await?.is_empty() => {
+                return Ok(req.reply_error(libc::ENOTEMPTY)?)
+            }
+            _ => {}
+        }
+
+        overlay.forget(node.ino).await;
+        overlay.meta().delete_inode(node.ino).await?;
+        self.attrs.lock().await.forget(&node);
+
+        Ok(req.reply(())?)
+    }
+
+    async fn unlink(&self, req: &Request, op: op::Unlink<'_>) -> Result<()> {
+        log::debug!("unlink(parent: {}, name: {:?})", op.parent(), op.name());
+        self.remove(req, op.parent(), op.name(), false).await
+    }
+
+    async fn rmdir(&self, req: &Request, op: op::Rmdir<'_>) -> Result<()> {
+        log::debug!("rmdir(parent: {}, name: {:?})", op.parent(), op.name());
+        self.remove(req, op.parent(), op.name(), true).await
+    }
+
+    async fn write(&self, req: &Request, op: op::Write<'_>, data: Vec<u8>) -> Result<()> {
+        let overlay = match self.writable(req, op.ino())? {
+            Some(overlay) => overlay,
+            None => return Ok(()),
+        };
+
+        let node = self.inode(op.ino()).await?;
+        if !node.mode.is(FileType::Regular) {
+            return Ok(req.reply_error(libc::EISDIR)?);
+        }
+
+        if let Err(err) = overlay
+            .write(&self.cache, &self.meta, op.ino(), op.offset(), &data)
+            .await
+        {
+            error!("failed to write to file '{}': {:#}", node.name, err);
+            self.counters.error();
+            return Ok(req.reply_error(libc::EIO)?);
+        }
+        self.attrs.lock().await.forget(&node);
+
+        let mut out = WriteOut::default();
+        out.size(data.len() as u32);
+        Ok(req.reply(out)?)
+    }
+
+    async fn setattr(&self, req: &Request, op: op::Setattr<'_>) -> Result<()> {
+        let overlay = match self.writable(req, op.ino())? {
+            Some(overlay) => overlay,
+            None => return Ok(()),
+        };
+
+        let mut node = self.inode(op.ino()).await?;
+        self.attrs.lock().await.forget(&node);
+
+        if let Some(size) = op.size() {
+            if !node.mode.is(FileType::Regular) {
+                return Ok(req.reply_error(libc::EISDIR)?);
+            }
+
+            if let Err(err) = overlay
+                .truncate(&self.cache, &self.meta, op.ino(), size)
+                .await
+            {
+                error!("failed to truncate file '{}': {:#}", node.name, err);
+                self.counters.error();
+                return Ok(req.reply_error(libc::EIO)?);
+            }
+            node.size = size;
+        }
+
+        // access and modification times can't be set, the mtime is updated when
+        // a written file is synced
+        if op.mode().is_some() || op.uid().is_some() || op.gid().is_some() {
+            if let Some(mode) = op.mode() {
+                node.mode = Mode::new(node.mode.file_type(), mode);
+            }
+            node.uid = op.uid().unwrap_or(node.uid);
+            node.gid = op.gid().unwrap_or(node.gid);
+            node.ctime = overlay::now();
+
+            // the size of a file that is being written is only recorded once it's synced
+            let stored = self.meta.inode(op.ino()).await?;
+            overlay
+                .meta()
+                .update_inode(&Inode {
+                    size: stored.size,
+                    ..node.clone()
+                })
+                .await?;
+        }
+
+        let mut out = AttrOut::default();
+        self.fill(&node, out.attr());
+        out.ttl(self.ttl());
+        Ok(req.reply(out)?)
+    }
+
+    // sync uploads the written blocks of a file and records them in the flist, it
+    // runs on fsync and on each close of the file
+    async fn sync(&self, req: &Request, ino: Ino) -> Result<()> {
+        if let Some(ref overlay) = self.overlay {
+            if let Err(err) = overlay.sync(&self.cache, &self.meta, ino).await {
+                error!("failed to sync inode {}: {:#}", ino, err);
+                self.counters.error();
+                return Ok(req.reply_error(libc::EIO)?);
+            }
+        }
+
+        Ok(req.reply(())?)
+    }
+
+    async fn setxattr(&self, req: &Request, op: op::Setxattr<'_>) -> Result<()> {
+        log::debug!("setxattr({}, {:?})", op.ino(), op.name());
+        let overlay = match self.writable(req, op.ino())? {
+            Some(overlay) => overlay,
+            None => return Ok(()),
+        };
+
+        let name = match op.name().to_str() {
+            Some(name) => name,
+            None => return Ok(req.reply_error(libc::EINVAL)?),
+        };
+
+        let exists = self.meta.xattr(op.ino(), name).await?.is_some();
+        let flags = op.flags() as i32;
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Ok(req.reply_error(libc::EEXIST)?);
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Ok(req.reply_error(libc::ENODATA)?);
+        }
+
+        overlay.meta().xattr(op.ino(), name, op.value()).await?;
+        Ok(req.reply(())?)
+    }
+
+    async fn open(&self, req: &Request, ino: Ino) -> Result<()> {
+        // the handles are counted so a file being written is kept in the overlay
+        // until all its handles are released
+        if let Some(ref overlay) = self.overlay {
+            overlay.open(ino);
+        }
+
+        Ok(req.reply(OpenOut::default())?)
+    }
+
+    async fn release(&self, req: &Request, ino: Ino) -> Result<()> {
+        if let Some(ref overlay) = self.overlay {
+            if let Err(err) = overlay.release(&self.cache, &self.meta, ino).await {
+                error!("failed to sync inode {} on release: {:#}", ino, err);
+                self.counters.error();
+                return Ok(req.reply_error(libc::EIO)?);
+            }
+        }
+
+        Ok(req.reply(())?)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> control::Handler for Filesystem<S>
+where
+    S: Store,
+{
+    fn status(&self) -> control::Status {
+        let (flist, digest) = match self.control {
+            Some(ref ctl) => (ctl.flist.clone(), ctl.digest.clone()),
+            None => Default::default(),
+        };
+
+        control::Status {
+            flist,
+            digest,
+            mountpoint: self
+                .mounted
+                .as_ref()
+                .map(|(_, mountpoint)| mountpoint.clone())
+                .unwrap_or_default(),
+            uptime: self.activity.start.elapsed().as_secs(),
+            reads: self.counters.reads.load(Ordering::Relaxed),
+            bytes_read: self.counters.bytes_read.load(Ordering::Relaxed),
+            errors: self.counters.errors.load(Ordering::Relaxed),
+            inflight: self.activity.inflight.load(Ordering::SeqCst),
+            cache: self.cache.stats(),
+        }
+    }
+
+    async fn unmount(&self) -> Result<()> {
+        let (fusermount, mountpoint) = self.mounted.clone().context("filesystem is not mounted")?;
+
+        info!("unmounting {} on request", mountpoint.display());
+        unmount(fusermount, mountpoint).await
+    }
+}
+
+// reply_xattr answers an xattr request with the value, a request of size 0 only
+// asks for the size of the value
+fn reply_xattr(req: &Request, size: u32, value: &[u8]) -> Result<()> {
+    if size == 0 {
+        let mut out = XattrOut::default();
+        out.size(value.len() as u32);
+        return Ok(req.reply(out)?);
+    }
+
+    if value.len() > size as usize {
+        return Ok(req.reply_error(libc::ERANGE)?);
+    }
+
+    Ok(req.reply(value)?)
+}
+
+// confine returns a link target that can't point outside of the mount, parents are the
+// directories from the root of the flist down to the link. The target is resolved like
+// the kernel does: the links it goes through are followed with their confined target
+// (which is what the kernel gets when it reads them), so a `..` after a link like
+// `x -> .` is counted from where the link leads. Targets that stay inside the mount are
+// returned as is. Other targets are rewritten relative to the root of the mount and a
+// `..` above the root is dropped, like it's done by chroot. Names that are missing or
+// not directories are kept as is, the kernel fails to resolve them anyway
+async fn confine(
+    meta: &Reader,
+    parents: &[(Ino, String)],
+    link: &Inode,
+    target: &[u8],
+) -> Result<Vec<u8>> {
+    let path = Path::new(OsStr::from_bytes(target));
+    let mut escapes = path.is_absolute();
+    // the resolved directories, the inode is None for names that can't be looked up
+    let mut resolved: Vec<(Option<Ino>, Vec<u8>)> = Vec::default();
+    if !escapes {
+        resolved.extend(
+            parents
+                .iter()
+                .map(|(ino, name)| (Some(*ino), name.as_bytes().to_vec())),
+        );
+    }
+
+    // the names left to resolve, the flag is set for the names of the target itself and
+    // not set for the names of the links it goes through
+    let mut pending: Vec<(Vec<u8>, bool)> = components(path)
+        .into_iter()
+        .rev()
+        .map(|name| (name, true))
+        .collect();
+
+    let mut links = 0;
+    while let Some((name, own)) = pending.pop() {
+        if name == b".." {
+            // the confined target of a link already dropped its `..` above the root
+            if resolved.pop().is_none() && own {
+                escapes = true;
+            }
+            continue;
+        }
+
+        let dir = match resolved.last() {
+            Some((dir, _)) => *dir,
+            None => Some(1),
+        };
+        let node = match dir {
+            Some(dir) => meta.lookup(dir, String::from_utf8_lossy(&name)).await?,
+            None => None,
+        };
+
+        let node = match node {
+            Some(node) if node.mode.is(FileType::Link) => node,
+            Some(node) if node.mode.is(FileType::Dir) => {
+                resolved.push((Some(node.ino), name));
+                continue;
+            }
+            _ => {
+                resolved.push((None, name));
+                continue;
+            }
+        };
+
+        links += 1;
+        if links > MAX_LINKS {
+            // a link to itself, the kernel fails to follow it with ELOOP
+            return Ok(link.name.as_bytes().to_vec());
+        }
+
+        let inner = node.data.unwrap_or_default();
+        let inner = Path::new(OsStr::from_bytes(&inner));
+        if inner.is_absolute() {
+            resolved.clear();
+        }
+        pending.extend(
+            components(inner)
+                .into_iter()
+                .rev()
+                .map(|name| (name, false)),
+        );
+    }
+
+    if !escapes {
+        return Ok(target.into());
+    }
+
+    let mut confined = PathBuf::new();
+    for _ in parents {
+        confined.push("..");
+    }
+    for (_, name) in resolved {
+        confined.push(OsStr::from_bytes(&name));
+    }
+    if confined.as_os_str().is_empty() {
+        confined.push(".");
+    }
+
+    Ok(confined.into_os_string().into_vec())
+}
+
+// unmount_on_idle unmounts the filesystem once it's idle for the given timeout. The
+// unmount is not forced so it fails (and retried later) if files are still open
+async fn unmount_on_idle(
+    activity: Arc<Activity>,
+    timeout: Duration,
+    fusermount: PathBuf,
+    mountpoint: PathBuf,
+) {
+    let interval = timeout.min(Duration::from_secs(1));
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match activity.idle() {
+            Some(idle) if idle >= timeout => {}
+            _ => continue,
+        }
+
+        info!("filesystem is idle, unmounting {}", mountpoint.display());
+        match unmount(fusermount.clone(), mountpoint.clone()).await {
+            Ok(_) => return,
+            Err(_) => {
+                warn!("failed to unmount idle filesystem, will retry later");
+                activity.touch();
+            }
+        }
+    }
+}
+
+/// MountHandle is a filesystem mounted with spawn. Awaiting the handle waits until
+/// the filesystem is unmounted (by unmount, fusermount or the idle timeout), dropping
+/// it leaves the filesystem mounted
+pub struct MountHandle {
+    fusermount: PathBuf,
+    mountpoint: PathBuf,
+    task: JoinHandle<Result<()>>,
+}
+
+impl MountHandle {
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// unmount unmounts the filesystem and waits until it's released
+    pub async fn unmount(self) -> Result<()> {
+        unmount(self.fusermount.clone(), self.mountpoint.clone()).await?;
+        self.await
+    }
+
+    /// lazy_unmount detaches the filesystem even if files are still open and waits
+    /// until it's released
+    pub async fn lazy_unmount(self) -> Result<()> {
+        lazy_unmount(self.fusermount.clone(), self.mountpoint.clone()).await?;
+        self.await
+    }
+}
+
+impl std::future::Future for MountHandle {
+    type Output = Result<()>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        std::pin::Pin::new(&mut self.task)
+            .poll(cx)
+            .map(|result| result.context("filesystem task failed")?)
+    }
+}
+
+/// unmount runs a non forced unmount of the mountpoint, it fails if files are still open
+pub async fn unmount(fusermount: PathBuf, mountpoint: PathBuf) -> Result<()> {
+    run_fusermount(fusermount, mountpoint, "-u").await
+}
+
+/// lazy_unmount detaches the mountpoint even if files are still open, the filesystem
+/// is released (and the rfs process exits) once it's not used anymore
+pub async fn lazy_unmount(fusermount: PathBuf, mountpoint: PathBuf) -> Result<()> {
+    run_fusermount(fusermount, mountpoint, "-uz").await
+}
+
+async fn run_fusermount(
+    fusermount: PathBuf,
+    mountpoint: PathBuf,
+    flags: &'static str,
+) -> Result<()> {
+    let status = task::spawn_blocking(move || {
+        std::process::Command::new(fusermount)
+            .arg(flags)
+            .arg(mountpoint)
+            .status()
+    })
+    .await
+    .context("failed to spawn fusermount")?
+    .context("failed to run fusermount")?;
+
+    ensure!(status.success(), "fusermount exited with {}", status);
+    Ok(())
 }
 
 // ==== AsyncSession ====
@@ -405,3 +1594,172 @@ impl AttributeFiller for Inode {
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{confine, AbortOnDrop, Activity, Filesystem};
+    use crate::cache::Cache;
+    use crate::fungi::{
+        meta::{Block, FileType, Ino, Inode, Mode},
+        Reader, Writer,
+    };
+    use crate::store::mem::MemStore;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_activity() {
+        let activity = Arc::new(Activity::default());
+        let guard = activity.begin();
+        assert!(activity.idle().is_none());
+        drop(guard);
+        assert!(activity.idle().is_some());
+
+        // a request that panics is not left in progress
+        let result = std::panic::catch_unwind(|| {
+            let _guard = activity.begin();
+            panic!("request failed");
+        });
+        assert!(result.is_err());
+        assert!(activity.idle().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_abort_on_drop() {
+        let alive = Arc::new(());
+        let task = {
+            let alive = Arc::clone(&alive);
+            AbortOnDrop(tokio::spawn(async move {
+                let _alive = alive;
+                futures::future::pending::<()>().await
+            }))
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&alive), 2);
+
+        drop(task);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::strong_count(&alive) > 1 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("task is not aborted");
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        const PATH: &str = "/tmp/prefetch.fl";
+        Writer::new(PATH, true).await.unwrap();
+        let meta = Reader::new(PATH).await.unwrap();
+        let cache = Cache::new("/tmp/prefetch-cache", MemStore::default());
+        let fs = Filesystem::new(meta, cache)
+            .with_readahead(2)
+            .with_max_inflight(1);
+
+        let blocks: Vec<Block> = (1..=4)
+            .map(|i| Block {
+                id: [i; 32],
+                key: [i; 32],
+            })
+            .collect();
+
+        // the only permit is taken by a foreground read, the prefetch of the 2 next
+        // blocks waits for it but the mount is busy meanwhile
+        let permit = fs.inflight.as_ref().unwrap().acquire().await.unwrap();
+        fs.prefetch(2, &blocks, 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(fs.activity.idle().is_none());
+        assert_eq!(fs.activity.inflight.load(super::Ordering::SeqCst), 2);
+
+        // the blocks are not in the store, the prefetch fails but it's done
+        drop(permit);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while fs.activity.idle().is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("prefetch is not done");
+    }
+
+    #[tokio::test]
+    async fn test_confine() {
+        // parents of the link, link target, expected confined target
+        type Case<'a> = (&'a [(Ino, String)], &'a str, &'a str);
+
+        const PATH: &str = "/tmp/confine.fl";
+        let writer = Writer::new(PATH, true).await.unwrap();
+        let node = |parent, name: &str, typ, data: Option<&str>| Inode {
+            parent,
+            name: name.into(),
+            mode: Mode::new(typ, 0o755),
+            data: data.map(|data| data.into()),
+            ..Inode::default()
+        };
+
+        let root = writer
+            .inode(node(0, "/", FileType::Dir, None))
+            .await
+            .unwrap();
+        let a = writer
+            .inode(node(root, "a", FileType::Dir, None))
+            .await
+            .unwrap();
+        let b = writer
+            .inode(node(a, "b", FileType::Dir, None))
+            .await
+            .unwrap();
+        for (name, target) in [
+            ("dot", "."),
+            ("top", "/"),
+            ("up", "../../.."),
+            ("loop1", "loop2"),
+            ("loop2", "loop1"),
+        ] {
+            writer
+                .inode(node(b, name, FileType::Link, Some(target)))
+                .await
+                .unwrap();
+        }
+
+        let meta = Reader::new(PATH).await.unwrap();
+        let link = meta.lookup(b, "dot").await.unwrap().unwrap();
+        let parents: Vec<(Ino, String)> = vec![(a, "a".into()), (b, "b".into())];
+        let cases: &[Case] = &[
+            // targets inside the mount are kept
+            (&parents, "file", "file"),
+            (&parents, "../c/file", "../c/file"),
+            (&parents, "../../file", "../../file"),
+            (&parents, "dot/../../file", "dot/../../file"),
+            (&parents, "up/a/b", "up/a/b"),
+            // absolute targets are resolved from the root of the mount
+            (&parents, "/etc/passwd", "../../etc/passwd"),
+            (&[], "/etc/passwd", "etc/passwd"),
+            (&parents, "/", "../.."),
+            (&[], "/", "."),
+            // going up from the root stays at the root
+            (&parents, "../../../etc/passwd", "../../etc/passwd"),
+            (&parents, "../../../../", "../.."),
+            (&[], "../../etc/../etc/passwd", "etc/passwd"),
+            // a `..` after a link is counted from where the link leads
+            (&parents, "dot/../../../etc/passwd", "../../etc/passwd"),
+            (&parents, "top/../etc/passwd", "../../etc/passwd"),
+            (&parents, "up/../etc", "../../etc"),
+            // links that can't be resolved make a link to itself
+            (&parents, "loop1/..", "dot"),
+        ];
+
+        for (parents, target, expected) in cases {
+            let confined = confine(&meta, parents, &link, target.as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(confined).unwrap(),
+                *expected,
+                "target {}",
+                target
+            );
+        }
+    }
+}
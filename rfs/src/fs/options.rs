@@ -0,0 +1,149 @@
+use anyhow::{bail, Context, Error, Result};
+use std::str::FromStr;
+
+/// options that are passed as is to the fuse mount
+const KERNEL_OPTIONS: &[&str] = &[
+    "allow_other",
+    "allow_root",
+    "default_permissions",
+    "nodev",
+    "nosuid",
+    "noexec",
+    "noatime",
+];
+
+/// options with a value that are passed as is to the fuse mount
+const KERNEL_VALUE_OPTIONS: &[&str] = &["fsname"];
+
+/// options that can't be used together, setting one of them replaces the others
+const EXCLUSIVE_OPTIONS: &[&[&str]] = &[&["allow_other", "allow_root"]];
+
+/// MountOption is an option of the mount given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountOption {
+    /// a fuse mount option like allow_other or fsname=<name>
+    Kernel(String),
+    /// report all entries as owned by this user instead of the flist owner
+    Uid(u32),
+    /// report all entries as owned by this group instead of the flist group
+    Gid(u32),
+}
+
+impl FromStr for MountOption {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (name, arg) = match value.split_once('=') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (value, None),
+        };
+
+        let option = match (name, arg) {
+            ("uid", Some(arg)) => Self::Uid(
+                arg.parse()
+                    .with_context(|| format!("invalid uid '{}'", arg))?,
+            ),
+            ("gid", Some(arg)) => Self::Gid(
+                arg.parse()
+                    .with_context(|| format!("invalid gid '{}'", arg))?,
+            ),
+            // unmount only knows the mounts of rfs by their subtype
+            ("subtype", _) => bail!("the subtype of the mount can't be changed, it's always rfs"),
+            (name, None) if KERNEL_OPTIONS.contains(&name) => Self::Kernel(name.into()),
+            (name, Some(arg)) if KERNEL_VALUE_OPTIONS.contains(&name) && !arg.is_empty() => {
+                // the options are joined with commas when the filesystem is mounted
+                if arg.contains(',') {
+                    bail!("invalid mount option '{}', value can't have a comma", value);
+                }
+                Self::Kernel(value.into())
+            }
+            _ => bail!(
+                "unsupported mount option '{}', supported options are {}, {}=<value>, uid and gid",
+                value,
+                KERNEL_OPTIONS.join(", "),
+                KERNEL_VALUE_OPTIONS.join("=<value>, "),
+            ),
+        };
+
+        Ok(option)
+    }
+}
+
+/// join returns the fuse mount options, the given options are added to the default
+/// ones and replace the default options with the same name or that are exclusive
+/// with them (allow_root replaces allow_other)
+pub fn join(defaults: &[String], options: &[MountOption]) -> String {
+    let mut joined: Vec<&str> = defaults.iter().map(|option| option.as_str()).collect();
+    for option in options {
+        let option = match option {
+            MountOption::Kernel(option) => option.as_str(),
+            _ => continue,
+        };
+
+        let name = option.split('=').next().unwrap_or_default();
+        let replaced = EXCLUSIVE_OPTIONS
+            .iter()
+            .find(|group| group.contains(&name))
+            .copied()
+            .unwrap_or(&[]);
+        joined.retain(|current| {
+            let current = current.split('=').next().unwrap_or_default();
+            current != name && !replaced.contains(&current)
+        });
+        joined.push(option);
+    }
+
+    joined.join(",")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "allow_other".parse::<MountOption>().unwrap(),
+            MountOption::Kernel("allow_other".into())
+        );
+        assert_eq!(
+            "fsname=myflist".parse::<MountOption>().unwrap(),
+            MountOption::Kernel("fsname=myflist".into())
+        );
+        assert_eq!(
+            "uid=1000".parse::<MountOption>().unwrap(),
+            MountOption::Uid(1000)
+        );
+        assert_eq!("gid=0".parse::<MountOption>().unwrap(), MountOption::Gid(0));
+
+        for invalid in [
+            "uid=root",
+            "uid",
+            "allow_other=1",
+            "fsname=",
+            "fsname=a,suid",
+            "subtype=other",
+            "suid",
+            "dev",
+        ] {
+            assert!(invalid.parse::<MountOption>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_join() {
+        let defaults = vec!["ro".to_string(), "fsname=1".to_string()];
+        let options: Vec<MountOption> = ["fsname=flist", "uid=1000", "noatime", "noatime"]
+            .iter()
+            .map(|option| option.parse().unwrap())
+            .collect();
+
+        assert_eq!(join(&defaults, &options), "ro,fsname=flist,noatime");
+
+        let defaults = vec!["ro".to_string(), "allow_other".to_string()];
+        let options = vec!["allow_root".parse().unwrap()];
+        assert_eq!(join(&defaults, &options), "ro,allow_root");
+        let options = vec!["allow_other".parse().unwrap()];
+        assert_eq!(join(&defaults, &options), "ro,allow_other");
+    }
+}
@@ -0,0 +1,389 @@
+use crate::cache::Cache;
+use crate::fungi::{
+    meta::{Block, Ino, Inode},
+    Reader, Writer,
+};
+use crate::pack::BLOB_SIZE;
+use crate::store::Store;
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Dirty is the local copy of a file that is being written
+struct Dirty {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    // set if the file changed since the last sync
+    changed: bool,
+}
+
+// Slot holds the local copy of a file, it's empty until the file is downloaded. Each
+// file has its own lock so a download only holds the requests of its own file
+type Slot = Arc<Mutex<Option<Dirty>>>;
+
+/// Overlay makes the mount writable. A written file is copied to a local file
+/// where the writes are applied (so a write of part of a block never needs to
+/// upload the block), the file is then synced on fsync or once it's closed: its
+/// content is chunked, the blocks are uploaded to the store and the flist is
+/// updated. New inodes are allocated by the flist database so they never collide
+/// with the inodes of the flist.
+pub struct Overlay {
+    meta: Writer,
+    root: PathBuf,
+    dirty: Mutex<HashMap<Ino, Slot>>,
+    // number of open handles of each file, the local copy of a file is kept until
+    // its last handle is released
+    handles: std::sync::Mutex<HashMap<Ino, usize>>,
+}
+
+impl Overlay {
+    /// new creates an overlay that updates the flist with the given writer and keeps
+    /// the files being written under root. Leftovers of a previous mount are removed
+    pub async fn new<P: Into<PathBuf>>(meta: Writer, root: P) -> Result<Self> {
+        let root = root.into();
+        let _ = fs::remove_dir_all(&root).await;
+        fs::create_dir_all(&root)
+            .await
+            .with_context(|| format!("failed to create overlay directory {}", root.display()))?;
+
+        Ok(Self {
+            meta,
+            root,
+            dirty: Mutex::default(),
+            handles: std::sync::Mutex::default(),
+        })
+    }
+
+    /// meta returns the writer of the flist
+    pub fn meta(&self) -> &Writer {
+        &self.meta
+    }
+
+    /// available returns the free space in bytes and the free inodes of the filesystem
+    /// of the overlay directory, that's where written files are kept until they're synced
+    pub fn available(&self) -> Result<(u64, u64)> {
+        let stat = nix::sys::statvfs::statvfs(&self.root)
+            .with_context(|| format!("failed to stat overlay directory {}", self.root.display()))?;
+
+        Ok((
+            stat.blocks_available() as u64 * stat.fragment_size() as u64,
+            stat.files_available() as u64,
+        ))
+    }
+
+    /// size returns the size of a file that is being written
+    pub async fn size(&self, ino: Ino) -> Option<u64> {
+        let slot = self.slot(ino).await?;
+        let size = slot.lock().await.as_ref()?.size;
+        Some(size)
+    }
+
+    /// open counts an open handle of a file
+    pub fn open(&self, ino: Ino) {
+        *self
+            .handles
+            .lock()
+            .expect("failed to lock handles")
+            .entry(ino)
+            .or_default() += 1;
+    }
+
+    // close counts a released handle of a file, true is returned if it was the last
+    // handle of the file
+    fn close(&self, ino: Ino) -> bool {
+        let mut handles = self.handles.lock().expect("failed to lock handles");
+        match handles.get_mut(&ino) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                handles.remove(&ino);
+                true
+            }
+        }
+    }
+
+    async fn slot(&self, ino: Ino) -> Option<Slot> {
+        self.dirty.lock().await.get(&ino).cloned()
+    }
+
+    // load returns the locked local copy of a file, the file content is downloaded
+    // on the first write
+    async fn load<S: Store>(
+        &self,
+        cache: &Cache<S>,
+        reader: &Reader,
+        ino: Ino,
+    ) -> Result<OwnedMutexGuard<Option<Dirty>>> {
+        let slot = Arc::clone(self.dirty.lock().await.entry(ino).or_default());
+        // the other requests of the file wait for the download, so the same file
+        // is never downloaded twice
+        let mut slot = slot.lock_owned().await;
+        if slot.is_some() {
+            return Ok(slot);
+        }
+
+        let node = reader.inode(ino).await?;
+        let path = self.root.join(ino.to_string());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await?;
+
+        if node.size > 0 {
+            let blocks = reader.blocks(ino).await?;
+            cache
+                .direct(&blocks, &mut file)
+                .await
+                .with_context(|| format!("failed to download file '{}'", node.name))?;
+            file.set_len(node.size).await?;
+        }
+
+        *slot = Some(Dirty {
+            file,
+            path,
+            size: node.size,
+            changed: false,
+        });
+
+        Ok(slot)
+    }
+
+    /// write writes data at the given offset of a file
+    pub async fn write<S: Store>(
+        &self,
+        cache: &Cache<S>,
+        reader: &Reader,
+        ino: Ino,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut slot = self.load(cache, reader, ino).await?;
+        let dirty = slot.as_mut().expect("file is loaded");
+
+        dirty.file.seek(SeekFrom::Start(offset)).await?;
+        dirty.file.write_all(data).await?;
+        dirty.size = dirty.size.max(offset + data.len() as u64);
+        dirty.changed = true;
+
+        Ok(())
+    }
+
+    /// truncate changes the size of a file
+    pub async fn truncate<S: Store>(
+        &self,
+        cache: &Cache<S>,
+        reader: &Reader,
+        ino: Ino,
+        size: u64,
+    ) -> Result<()> {
+        let mut slot = self.load(cache, reader, ino).await?;
+        let dirty = slot.as_mut().expect("file is loaded");
+
+        dirty.file.set_len(size).await?;
+        dirty.size = size;
+        dirty.changed = true;
+
+        Ok(())
+    }
+
+    /// read reads from the local copy of a file, None is returned if the file is
+    /// not being written
+    pub async fn read(&self, ino: Ino, offset: u64, size: usize) -> Result<Option<Vec<u8>>> {
+        let slot = match self.slot(ino).await {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+        let mut slot = slot.lock().await;
+        let dirty = match slot.as_mut() {
+            Some(dirty) => dirty,
+            None => return Ok(None),
+        };
+
+        let size = size.min(dirty.size.saturating_sub(offset) as usize);
+        let mut buf = vec![0; size];
+        dirty.file.seek(SeekFrom::Start(offset)).await?;
+        dirty.file.read_exact(&mut buf).await?;
+
+        Ok(Some(buf))
+    }
+
+    /// sync uploads the blocks of a changed file to the store and records them and
+    /// the new file size in the flist
+    pub async fn sync<S: Store>(&self, cache: &Cache<S>, reader: &Reader, ino: Ino) -> Result<()> {
+        let slot = match self.slot(ino).await {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        let mut slot = slot.lock().await;
+        let dirty = match slot.as_mut() {
+            Some(dirty) if dirty.changed => dirty,
+            _ => return Ok(()),
+        };
+
+        let mut blocks = Vec::default();
+        let mut buf = vec![0; BLOB_SIZE];
+        let mut remaining = dirty.size;
+        dirty.file.rewind().await?;
+        while remaining > 0 {
+            let size = (remaining as usize).min(BLOB_SIZE);
+            dirty.file.read_exact(&mut buf[..size]).await?;
+            remaining -= size as u64;
+
+            // full blocks of zeros are recorded as holes like in sparse packing.
+            // blocks are content addressed so the unchanged blocks are stored
+            // again under the same key
+            let block = if size == BLOB_SIZE && buf.iter().all(|b| *b == 0) {
+                Block::hole()
+            } else {
+                cache.store().set(&buf[..size]).await?
+            };
+            blocks.push(block);
+        }
+
+        let node = reader.inode(ino).await?;
+        self.meta.set_blocks(ino, &blocks).await?;
+        self.meta
+            .update_inode(&Inode {
+                size: dirty.size,
+                mtime: now(),
+                ..node
+            })
+            .await?;
+        dirty.changed = false;
+
+        Ok(())
+    }
+
+    /// release syncs a file when one of its handles is released, the local copy of
+    /// the file is removed once its last handle is released
+    pub async fn release<S: Store>(
+        &self,
+        cache: &Cache<S>,
+        reader: &Reader,
+        ino: Ino,
+    ) -> Result<()> {
+        self.sync(cache, reader, ino).await?;
+        if self.close(ino) {
+            self.forget(ino).await;
+        }
+        Ok(())
+    }
+
+    /// forget drops the local copy of a file without syncing it
+    pub async fn forget(&self, ino: Ino) {
+        let slot = match self.dirty.lock().await.remove(&ino) {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let slot = slot.lock().await;
+        if let Some(ref dirty) = *slot {
+            if let Err(err) = fs::remove_file(&dirty.path).await {
+                warn!("failed to remove {}: {}", dirty.path.display(), err);
+            }
+        }
+    }
+}
+
+/// now returns the current time as a unix timestamp
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::meta::{FileType, Mode};
+    use crate::store::mem::MemStore;
+
+    #[tokio::test]
+    async fn test_overlay() {
+        const ROOT: &str = "/tmp/overlay-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+        let root = PathBuf::from(ROOT);
+        fs::create_dir_all(&root).await.unwrap();
+
+        let writer = Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let dir = writer
+            .inode(Inode {
+                name: "/".into(),
+                mode: Mode::new(FileType::Dir, 0o755),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        let ino = writer
+            .inode(Inode {
+                parent: dir,
+                name: "file".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+
+        let reader = Reader::new(root.join("meta.fl")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), MemStore::default());
+        let overlay = Overlay::new(writer, root.join("overlay")).await.unwrap();
+
+        // the writes are kept in the local copy until the file is synced
+        overlay.open(ino);
+        overlay
+            .write(&cache, &reader, ino, 0, b"hello")
+            .await
+            .unwrap();
+        overlay
+            .write(&cache, &reader, ino, 5, b" world")
+            .await
+            .unwrap();
+        assert_eq!(overlay.size(ino).await, Some(11));
+        assert_eq!(
+            overlay.read(ino, 6, 100).await.unwrap(),
+            Some(b"world".to_vec())
+        );
+        assert_eq!(reader.inode(ino).await.unwrap().size, 0);
+
+        // fsync uploads the blocks and records them in the flist
+        overlay.sync(&cache, &reader, ino).await.unwrap();
+        assert_eq!(reader.inode(ino).await.unwrap().size, 11);
+        let blocks = reader.blocks(ino).await.unwrap();
+        assert_eq!(blocks.len(), 1);
+        let (size, file) = cache.get(&blocks[0]).await.unwrap();
+        let mut content = Vec::default();
+        file.take(size).read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, b"hello world");
+
+        // the local copy is kept until the last handle is released
+        overlay.open(ino);
+        overlay.release(&cache, &reader, ino).await.unwrap();
+        assert_eq!(overlay.size(ino).await, Some(11));
+        overlay.truncate(&cache, &reader, ino, 5).await.unwrap();
+        overlay.release(&cache, &reader, ino).await.unwrap();
+        assert_eq!(overlay.size(ino).await, None);
+        assert_eq!(reader.inode(ino).await.unwrap().size, 5);
+        assert!(!root.join("overlay").join(ino.to_string()).exists());
+
+        // a released file is downloaded again on the next write
+        overlay.write(&cache, &reader, ino, 5, b"!").await.unwrap();
+        assert_eq!(
+            overlay.read(ino, 0, 100).await.unwrap(),
+            Some(b"hello!".to_vec())
+        );
+    }
+}
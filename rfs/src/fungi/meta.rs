@@ -1,8 +1,10 @@
 use std::{
-    collections::LinkedList,
+    collections::{BTreeMap, LinkedList},
     path::{Path, PathBuf},
 };
 
+use futures::{StreamExt, TryStreamExt};
+
 use sqlx::{
     sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow},
     FromRow, Row, SqlitePool,
@@ -58,6 +60,9 @@ pub enum Error {
     #[error("io error: {0:#}")]
     IO(#[from] std::io::Error),
 
+    #[error("unsupported flist format: {0}")]
+    UnsupportedFormat(String),
+
     #[error("store error: {0}")]
     Store(#[from] store::Error),
 
@@ -122,7 +127,7 @@ impl FromRow<'_, SqliteRow> for Inode {
             name: row.get("name"),
             size: row.get::<i64, &str>("size") as u64,
             uid: row.get("uid"),
-            gid: row.get("uid"),
+            gid: row.get("gid"),
             mode: row.get::<u32, &str>("mode").into(),
             rdev: row.get::<i64, &str>("rdev") as u64,
             ctime: row.get("ctime"),
@@ -140,6 +145,19 @@ pub struct Block {
     pub key: [u8; KEY_LEN],
 }
 
+impl Block {
+    /// a hole block is a block of zeros that is not stored in the store. It's
+    /// used to represent holes of sparse files. A hole block is always
+    /// a full block of zeros.
+    pub fn hole() -> Self {
+        Self::default()
+    }
+
+    pub fn is_hole(&self) -> bool {
+        self.id == [0; ID_LEN] && self.key == [0; KEY_LEN]
+    }
+}
+
 impl FromRow<'_, SqliteRow> for Block {
     fn from_row(row: &'_ SqliteRow) -> std::result::Result<Self, sqlx::Error> {
         let hash: &[u8] = row.get("id");
@@ -149,7 +167,7 @@ impl FromRow<'_, SqliteRow> for Block {
 
         let key: &[u8] = row.get("key");
 
-        if hash.len() != KEY_LEN {
+        if key.len() != KEY_LEN {
             return Err(sqlx::Error::Decode(Box::new(Error::InvalidKey)));
         }
 
@@ -209,18 +227,98 @@ pub trait WalkVisitor {
 
 struct WalkItem(PathBuf, Inode);
 
+/// MetaReader is the read interface of the flist metadata. The sqlite Reader is the
+/// default implementation, other backends (for example a shared database used by a
+/// server to index flists) can implement it to be used in place of the sqlite file.
+#[async_trait::async_trait]
+pub trait MetaReader: Send + Sync {
+    async fn inode(&self, ino: Ino) -> Result<Inode>;
+    async fn children(&self, parent: Ino, limit: u32, offset: u64) -> Result<Vec<Inode>>;
+    async fn lookup(&self, parent: Ino, name: &str) -> Result<Option<Inode>>;
+    async fn blocks(&self, ino: Ino) -> Result<Vec<Block>>;
+    async fn block_sizes(&self, ino: Ino) -> Result<Vec<Option<u64>>>;
+    async fn all_blocks(&self, limit: u32, offset: u64) -> Result<Vec<Block>>;
+    async fn tag(&self, tag: Tag<'_>) -> Result<Option<String>>;
+    async fn tags(&self) -> Result<Vec<(String, String)>>;
+    async fn routes(&self) -> Result<Vec<Route>>;
+}
+
+/// default size of the memory map of the database, flists are usually smaller
+/// so they are fully mapped
+pub const DEFAULT_MMAP_SIZE: u64 = 256 * 1024 * 1024;
+/// default size of the page cache of a connection in KiB
+pub const DEFAULT_CACHE_SIZE: u64 = 64 * 1024;
+/// default number of connections of a reader, the same as the sqlx default
+pub const DEFAULT_CONNECTIONS: u32 = 10;
+/// default number of directories that walk_concurrent lists at the same time
+pub const DEFAULT_WALK_CONCURRENCY: usize = 8;
+
+/// ReaderOptions are the options of the sqlite connections of a Reader. The database
+/// is always opened read only (so a Reader works on a read only file), the journal
+/// mode is kept as delete since a reader never writes, and temp tables are kept in
+/// memory
+#[derive(Debug, Clone)]
+pub struct ReaderOptions {
+    /// the flist is never changed while it's open, sqlite then skips the file locks
+    /// and the change detection on each query. It must not be set if the flist is
+    /// written by a Writer at the same time (a writable mount)
+    pub immutable: bool,
+    /// bytes of the database that are memory mapped instead of read with syscalls
+    pub mmap_size: u64,
+    /// size of the page cache of each connection in KiB
+    pub cache_size: u64,
+    /// maximum number of connections of the pool, which is also the maximum number
+    /// of queries that run at the same time
+    pub connections: u32,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        Self {
+            immutable: false,
+            mmap_size: DEFAULT_MMAP_SIZE,
+            cache_size: DEFAULT_CACHE_SIZE,
+            connections: DEFAULT_CONNECTIONS,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Reader {
     pool: SqlitePool,
 }
 
+/// the 0-fs flist archive (produced by the v1 rfs/0-flist tools and the hub v1) is a
+/// gzip compressed tar file of a sqlite database with capnp encoded directories
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
 impl Reader {
     pub async fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::with_options(path, ReaderOptions::default()).await
+    }
+
+    /// with_options opens the flist with the given connection options, use an
+    /// immutable reader for flists that are only read like in a read only mount
+    pub async fn with_options<P: AsRef<Path>>(path: P, options: ReaderOptions) -> Result<Self> {
+        let path = path.as_ref();
+        check_format(path).await?;
+
         let opts = SqliteConnectOptions::new()
+            .read_only(true)
+            .immutable(options.immutable)
             .journal_mode(SqliteJournalMode::Delete)
+            .pragma("mmap_size", options.mmap_size.to_string())
+            // a negative cache size is in KiB instead of pages
+            .pragma("cache_size", format!("-{}", options.cache_size))
+            .pragma("temp_store", "memory")
             .filename(path);
 
-        let pool = SqlitePool::connect_with(opts).await?;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(options.connections.max(1))
+            .connect_with(opts)
+            .await?;
+        check_schema(&pool).await?;
 
         Ok(Self { pool })
     }
@@ -268,6 +366,26 @@ impl Reader {
         Ok(results)
     }
 
+    /// block_sizes returns the size of each block of the file in the same order as blocks.
+    /// the size is None for fixed size blocks
+    pub async fn block_sizes(&self, ino: Ino) -> Result<Vec<Option<u64>>> {
+        let rows = sqlx::query("select * from block where ino = ?;")
+            .bind(ino as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        // flists created by older versions don't have the size column
+        Ok(rows
+            .iter()
+            .map(|row| {
+                row.try_get::<Option<i64>, _>("size")
+                    .ok()
+                    .flatten()
+                    .map(|size| size as u64)
+            })
+            .collect())
+    }
+
     pub async fn all_blocks(&self, limit: u32, offset: u64) -> Result<Vec<Block>> {
         let results: Vec<Block> = sqlx::query_as("select id, key from block limit ? offset ?;")
             .bind(limit)
@@ -278,6 +396,112 @@ impl Reader {
         Ok(results)
     }
 
+    /// xattrs returns the extended attributes of an inode
+    pub async fn xattrs(&self, ino: Ino) -> Result<Vec<(String, Vec<u8>)>> {
+        let result = sqlx::query_as("select key, value from xattr where ino = ? order by key;")
+            .bind(ino as i64)
+            .fetch_all(&self.pool)
+            .await;
+
+        match result {
+            Ok(xattrs) => Ok(xattrs),
+            Err(err) if is_missing_table(&err) => Ok(Vec::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// xattr returns the value of an extended attribute of an inode
+    pub async fn xattr<K: AsRef<str>>(&self, ino: Ino, key: K) -> Result<Option<Vec<u8>>> {
+        let result: std::result::Result<Option<(Vec<u8>,)>, _> =
+            sqlx::query_as("select value from xattr where ino = ? and key = ?;")
+                .bind(ino as i64)
+                .bind(key.as_ref())
+                .fetch_optional(&self.pool)
+                .await;
+
+        match result {
+            Ok(value) => Ok(value.map(|v| v.0)),
+            Err(err) if is_missing_table(&err) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn blocks_count(&self) -> Result<u64> {
+        let (count,): (i64,) = sqlx::query_as("select count(*) from block;")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u64)
+    }
+
+    pub async fn inodes_count(&self) -> Result<u64> {
+        let (count,): (i64,) = sqlx::query_as("select count(*) from inode;")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count as u64)
+    }
+
+    /// unique_blocks_count returns the number of distinct blocks, hole blocks are
+    /// not counted since they are not stored
+    pub async fn unique_blocks_count(&self) -> Result<u64> {
+        let (count,): (i64,) =
+            sqlx::query_as("select count(distinct id) from block where id != ?;")
+                .bind(&Block::hole().id[..])
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(count as u64)
+    }
+
+    /// block_size_histogram returns the number of blocks per size range, a range is
+    /// keyed by its upper bound (a power of 2). The size of a fixed size block is
+    /// derived from the file size since only the last block of a file is smaller
+    pub async fn block_size_histogram(&self) -> Result<BTreeMap<u64, u64>> {
+        let mut rows = sqlx::query(
+            r#"select block.*, inode.size as file_size
+                from block join inode on block.ino = inode.ino
+                order by block.ino, block.rowid;"#,
+        )
+        .fetch(&self.pool);
+
+        let mut histogram = BTreeMap::default();
+        let (mut current, mut offset) = (None, 0);
+        while let Some(row) = rows.try_next().await? {
+            let ino: i64 = row.get("ino");
+            if current != Some(ino) {
+                current = Some(ino);
+                offset = 0;
+            }
+
+            let file_size = row.get::<i64, &str>("file_size") as u64;
+            // flists created by older versions don't have the size column
+            let size = match row.try_get::<Option<i64>, _>("size").ok().flatten() {
+                Some(size) => size as u64,
+                None => file_size
+                    .saturating_sub(offset)
+                    .min(crate::pack::BLOB_SIZE as u64),
+            };
+            offset += size;
+
+            *histogram.entry(size.next_power_of_two()).or_default() += 1;
+        }
+
+        Ok(histogram)
+    }
+
+    /// files_size returns the total size of all regular files
+    pub async fn files_size(&self) -> Result<u64> {
+        let (size,): (i64,) =
+            sqlx::query_as("select coalesce(sum(size), 0) from inode where (mode & ?) = ?;")
+                .bind(TYPE_MASK)
+                .bind(FileType::Regular as u32)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(size as u64)
+    }
+
     pub async fn tag(&self, tag: Tag<'_>) -> Result<Option<String>> {
         let value: Option<(String,)> = sqlx::query_as("select value from tag where key = ?;")
             .bind(tag.key())
@@ -316,6 +540,93 @@ impl Reader {
         Ok(())
     }
 
+    /// walk_concurrent visits the same entries as walk, but the children of up to
+    /// `concurrency` directories are read at the same time over the connections of
+    /// the pool. The tree is walked level by level: the visitor is still called for
+    /// one entry at a time and a directory is always visited before its children,
+    /// but the entries of a level are visited before the entries of the next level.
+    /// A Walk::Break on a directory skips its content, and on a file skips the rest
+    /// of its directory, as with walk
+    pub async fn walk_concurrent<W: WalkVisitor + Send>(
+        &self,
+        visitor: &mut W,
+        concurrency: usize,
+    ) -> Result<()> {
+        let root = self.inode(1).await?;
+        let path: PathBuf = "/".into();
+        if visitor.visit(&path, &root).await? == Walk::Break {
+            return Ok(());
+        }
+
+        let concurrency = concurrency.max(1);
+        let mut level = vec![WalkItem(path, root)];
+        while !level.is_empty() {
+            // the listings are read by a task so they go on while the visitor runs, they
+            // are sent in order so the walk is the same on each run
+            let (tx, mut rx) = tokio::sync::mpsc::channel(concurrency);
+            let reader = self.clone();
+            let lister = tokio::spawn(async move {
+                let reader = &reader;
+                let mut listings = futures::stream::iter(level)
+                    .map(|WalkItem(path, node)| async move {
+                        reader
+                            .all_children(node.ino)
+                            .await
+                            .map(|children| (path, children))
+                    })
+                    .buffered(concurrency);
+
+                while let Some(listing) = listings.next().await {
+                    // the walk stopped on an error
+                    if tx.send(listing).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let mut next = Vec::default();
+            while let Some(listing) = rx.recv().await {
+                let (path, children) = listing?;
+                for child in children {
+                    let child_path = path.join(&child.name);
+                    if visitor.visit(&child_path, &child).await? == Walk::Break {
+                        if child.mode.is(FileType::Dir) {
+                            continue;
+                        }
+                        break;
+                    }
+
+                    if child.mode.is(FileType::Dir) {
+                        next.push(WalkItem(child_path, child));
+                    }
+                }
+            }
+
+            // a lister that panicked would otherwise end the level early
+            lister
+                .await
+                .map_err(|err| anyhow::anyhow!("failed to list directories: {}", err))?;
+            level = next;
+        }
+
+        Ok(())
+    }
+
+    // all_children returns all the children of a directory with a single query, a
+    // paged listing costs a scan of the skipped rows on each page
+    async fn all_children(&self, parent: Ino) -> Result<Vec<Inode>> {
+        let results: Vec<Inode> = sqlx::query_as(
+            r#"select inode.*, extra.data
+                                from inode left join extra on inode.ino = extra.ino
+                                where inode.parent = ?;"#,
+        )
+        .bind(parent as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(results)
+    }
+
     async fn walk_node<W: WalkVisitor + Send>(
         &self,
         list: &mut LinkedList<WalkItem>,
@@ -351,6 +662,100 @@ impl Reader {
     }
 }
 
+#[async_trait::async_trait]
+impl MetaReader for Reader {
+    async fn inode(&self, ino: Ino) -> Result<Inode> {
+        Reader::inode(self, ino).await
+    }
+
+    async fn children(&self, parent: Ino, limit: u32, offset: u64) -> Result<Vec<Inode>> {
+        Reader::children(self, parent, limit, offset).await
+    }
+
+    async fn lookup(&self, parent: Ino, name: &str) -> Result<Option<Inode>> {
+        Reader::lookup(self, parent, name).await
+    }
+
+    async fn blocks(&self, ino: Ino) -> Result<Vec<Block>> {
+        Reader::blocks(self, ino).await
+    }
+
+    async fn block_sizes(&self, ino: Ino) -> Result<Vec<Option<u64>>> {
+        Reader::block_sizes(self, ino).await
+    }
+
+    async fn all_blocks(&self, limit: u32, offset: u64) -> Result<Vec<Block>> {
+        Reader::all_blocks(self, limit, offset).await
+    }
+
+    async fn tag(&self, tag: Tag<'_>) -> Result<Option<String>> {
+        Reader::tag(self, tag).await
+    }
+
+    async fn tags(&self) -> Result<Vec<(String, String)>> {
+        Reader::tags(self).await
+    }
+
+    async fn routes(&self) -> Result<Vec<Route>> {
+        Reader::routes(self).await
+    }
+}
+
+// check_format makes sure the file is a sqlite database and detects the legacy
+// flist archives to give a useful error instead of a generic sqlite error
+async fn check_format(path: &Path) -> Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut magic = vec![0; SQLITE_MAGIC.len()];
+    let read = file.read(&mut magic).await?;
+    let magic = &magic[..read];
+
+    if magic.starts_with(GZIP_MAGIC) {
+        return Err(Error::UnsupportedFormat(
+            "legacy 0-fs flist archive (tar.gz) produced by rfs v1/0-flist, it must be converted to the fl format first".into(),
+        ));
+    }
+
+    // an empty file is a new database
+    if !magic.is_empty() && magic != SQLITE_MAGIC {
+        return Err(Error::UnsupportedFormat("file is not an fl".into()));
+    }
+
+    Ok(())
+}
+
+// check_schema detects an extracted legacy flist database (entries table with capnp
+// encoded directories) which has no inode table
+async fn check_schema(pool: &SqlitePool) -> Result<()> {
+    let tables: Vec<(String,)> =
+        sqlx::query_as("select name from sqlite_master where type = 'table';")
+            .fetch_all(pool)
+            .await?;
+
+    let has = |name: &str| tables.iter().any(|(table,)| table == name);
+    if has("inode") {
+        return Ok(());
+    }
+
+    if has("entries") {
+        return Err(Error::UnsupportedFormat(
+            "legacy 0-fs flist database (capnp entries) produced by rfs v1/0-flist, it must be converted to the fl format first".into(),
+        ));
+    }
+
+    Err(Error::UnsupportedFormat("missing inode table".into()))
+}
+
+// is_missing_table is true if the query failed because one of its tables doesn't
+// exist, the optional tables are missing in the flists created by older versions
+fn is_missing_table(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(err) => err.message().starts_with("no such table"),
+        _ => false,
+    }
+}
+
 #[derive(Clone)]
 pub struct Writer {
     pool: SqlitePool,
@@ -418,6 +823,97 @@ impl Writer {
         Ok(())
     }
 
+    /// sized_block adds a block with an explicit size, this is needed for blocks that
+    /// are not of the fixed block size (content defined chunks)
+    pub async fn sized_block(
+        &self,
+        ino: Ino,
+        id: &[u8; ID_LEN],
+        key: &[u8; KEY_LEN],
+        size: u64,
+    ) -> Result<()> {
+        sqlx::query("insert into block (ino, id, key, size) values (?, ?, ?, ?)")
+            .bind(ino as i64)
+            .bind(&id[..])
+            .bind(&key[..])
+            .bind(size as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// update_inode updates the attributes of an existing inode, the name and
+    /// parent of the inode are not changed
+    pub async fn update_inode(&self, inode: &Inode) -> Result<()> {
+        sqlx::query(
+            r#"update inode set size = ?, uid = ?, gid = ?, mode = ?, rdev = ?, ctime = ?, mtime = ?
+                where ino = ?;"#,
+        )
+        .bind(inode.size as i64)
+        .bind(inode.uid)
+        .bind(inode.gid)
+        .bind(inode.mode.0)
+        .bind(inode.rdev as i64)
+        .bind(inode.ctime)
+        .bind(inode.mtime)
+        .bind(inode.ino as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// set_blocks replaces all the blocks of an inode with the given (fixed size) blocks
+    pub async fn set_blocks(&self, ino: Ino, blocks: &[Block]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("delete from block where ino = ?;")
+            .bind(ino as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        for block in blocks {
+            sqlx::query("insert into block (ino, id, key) values (?, ?, ?)")
+                .bind(ino as i64)
+                .bind(&block.id[..])
+                .bind(&block.key[..])
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// delete_inode removes an inode with its blocks, extra data and xattrs. children of
+    /// a directory are not removed
+    pub async fn delete_inode(&self, ino: Ino) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for query in [
+            "delete from block where ino = ?;",
+            "delete from extra where ino = ?;",
+            "delete from xattr where ino = ?;",
+            "delete from inode where ino = ?;",
+        ] {
+            sqlx::query(query)
+                .bind(ino as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// xattr sets an extended attribute of an inode
+    pub async fn xattr<K: AsRef<str>>(&self, ino: Ino, key: K, value: &[u8]) -> Result<()> {
+        sqlx::query("insert or replace into xattr (ino, key, value) values (?, ?, ?);")
+            .bind(ino as i64)
+            .bind(key.as_ref())
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn route<U: AsRef<str>>(&self, start: u8, end: u8, url: U) -> Result<()> {
         sqlx::query("insert into route (start, end, url) values (?, ?, ?)")
             .bind(start)
@@ -529,6 +1025,37 @@ mod test {
         assert!(child.is_none());
     }
 
+    #[tokio::test]
+    async fn test_files_size() {
+        const PATH: &str = "/tmp/files-size.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        let root = meta
+            .inode(Inode {
+                name: "/".into(),
+                mode: Mode::new(FileType::Dir, 0o755),
+                size: 4096,
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+
+        for (name, size) in [("a", 100), ("b", 1000)] {
+            meta.inode(Inode {
+                parent: root,
+                name: name.into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                size,
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        }
+
+        let meta = Reader::new(PATH).await.unwrap();
+        assert_eq!(meta.files_size().await.unwrap(), 1100);
+    }
+
     #[tokio::test]
     async fn test_get_block() {
         const PATH: &str = "/tmp/block.fl";
@@ -552,6 +1079,187 @@ mod test {
         assert_eq!(blocks[1].key, key2);
     }
 
+    #[tokio::test]
+    async fn test_update() {
+        const PATH: &str = "/tmp/update.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        let ino = meta
+            .inode(Inode {
+                name: "file".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                size: 10,
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        meta.block(ino, &[1; ID_LEN], &[1; KEY_LEN]).await.unwrap();
+
+        meta.update_inode(&Inode {
+            ino,
+            size: 20,
+            mode: Mode::new(FileType::Regular, 0o600),
+            mtime: 100,
+            ..Inode::default()
+        })
+        .await
+        .unwrap();
+        meta.set_blocks(ino, &[Block::hole(), Block::hole()])
+            .await
+            .unwrap();
+
+        let reader = Reader::new(PATH).await.unwrap();
+        let inode = reader.inode(ino).await.unwrap();
+        assert_eq!(inode.name, "file");
+        assert_eq!(inode.size, 20);
+        assert_eq!(inode.mode.permissions(), 0o600);
+        assert_eq!(inode.mtime, 100);
+
+        let blocks = reader.blocks(ino).await.unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|block| block.is_hole()));
+
+        meta.delete_inode(ino).await.unwrap();
+        assert!(reader.inode(ino).await.is_err());
+        assert!(reader.blocks(ino).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_xattrs() {
+        const PATH: &str = "/tmp/xattrs.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        meta.xattr(1, "user.b", b"2").await.unwrap();
+        meta.xattr(1, "user.a", b"1").await.unwrap();
+        meta.xattr(1, "user.a", b"3").await.unwrap();
+        meta.xattr(2, "security.capability", &[1, 0, 0, 2])
+            .await
+            .unwrap();
+
+        let reader = Reader::new(PATH).await.unwrap();
+        assert_eq!(
+            reader.xattrs(1).await.unwrap(),
+            vec![
+                ("user.a".into(), b"3".to_vec()),
+                ("user.b".into(), b"2".to_vec())
+            ]
+        );
+        assert_eq!(
+            reader.xattr(1, "user.b").await.unwrap(),
+            Some(b"2".to_vec())
+        );
+        assert_eq!(reader.xattr(1, "user.c").await.unwrap(), None);
+        assert!(reader.xattrs(3).await.unwrap().is_empty());
+
+        // flists created before the xattr table was added have no xattrs
+        sqlx::query("drop table xattr;")
+            .execute(&meta.pool)
+            .await
+            .unwrap();
+        assert!(reader.xattrs(1).await.unwrap().is_empty());
+        assert_eq!(reader.xattr(1, "user.a").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_counts() {
+        const PATH: &str = "/tmp/counts.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        let root = meta
+            .inode(Inode {
+                name: "/".into(),
+                mode: Mode::new(FileType::Dir, 0o755),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+
+        // a file of 2 fixed size blocks (the first one is a hole) and a file of
+        // 2 sized blocks
+        let fixed = meta
+            .inode(Inode {
+                parent: root,
+                name: "fixed".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                size: 512 * 1024 + 100,
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        meta.block(fixed, &Block::hole().id, &Block::hole().key)
+            .await
+            .unwrap();
+        meta.block(fixed, &[2; ID_LEN], &[2; KEY_LEN])
+            .await
+            .unwrap();
+
+        let sized = meta
+            .inode(Inode {
+                parent: root,
+                name: "sized".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                size: 3000,
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        meta.sized_block(sized, &[1; ID_LEN], &[1; KEY_LEN], 1000)
+            .await
+            .unwrap();
+        meta.sized_block(sized, &[3; ID_LEN], &[3; KEY_LEN], 2000)
+            .await
+            .unwrap();
+
+        let reader = Reader::new(PATH).await.unwrap();
+        assert_eq!(reader.inodes_count().await.unwrap(), 3);
+        assert_eq!(reader.blocks_count().await.unwrap(), 4);
+        assert_eq!(reader.unique_blocks_count().await.unwrap(), 3);
+
+        let histogram = reader.block_size_histogram().await.unwrap();
+        assert_eq!(
+            histogram.into_iter().collect::<Vec<_>>(),
+            vec![(128, 1), (1024, 1), (2048, 1), (512 * 1024, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_sizes() {
+        const PATH: &str = "/tmp/block-sizes.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+        let hash: [u8; ID_LEN] = [1; ID_LEN];
+        let key: [u8; KEY_LEN] = [2; KEY_LEN];
+
+        meta.block(1, &hash, &key).await.unwrap();
+        meta.sized_block(2, &hash, &key, 1000).await.unwrap();
+        meta.sized_block(2, &hash, &key, 2000).await.unwrap();
+
+        let meta = Reader::new(PATH).await.unwrap();
+        assert_eq!(meta.block_sizes(1).await.unwrap(), vec![None]);
+        assert_eq!(
+            meta.block_sizes(2).await.unwrap(),
+            vec![Some(1000), Some(2000)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_block_invalid() {
+        const PATH: &str = "/tmp/block-invalid.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        // a malformed flist with a short block key must not crash the reader
+        sqlx::query("insert into block (ino, id, key) values (?, ?, ?)")
+            .bind(1)
+            .bind(&[1u8; ID_LEN][..])
+            .bind(&[1u8; 10][..])
+            .execute(&meta.pool)
+            .await
+            .unwrap();
+
+        let meta = Reader::new(PATH).await.unwrap();
+        let err = meta.blocks(1).await;
+        assert!(err.is_err());
+    }
+
     #[tokio::test]
     async fn test_get_tag() {
         const PATH: &str = "/tmp/tag.fl";
@@ -595,6 +1303,103 @@ mod test {
         assert_eq!(routes[0].url, "zdb://hub1.grid.tf");
     }
 
+    #[tokio::test]
+    async fn test_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const PATH: &str = "/tmp/read-only.fl";
+        let _ = tokio::fs::remove_file(PATH).await;
+        let meta = Writer::new(PATH, true).await.unwrap();
+        meta.inode(Inode {
+            name: "/".into(),
+            mode: Mode::new(FileType::Dir, 0o755),
+            ..Inode::default()
+        })
+        .await
+        .unwrap();
+        meta.tag(Tag::Version, "0.1").await.unwrap();
+        drop(meta);
+
+        // an flist on a read only file or file system can still be opened
+        tokio::fs::set_permissions(PATH, std::fs::Permissions::from_mode(0o444))
+            .await
+            .unwrap();
+
+        for immutable in [false, true] {
+            let options = ReaderOptions {
+                immutable,
+                ..ReaderOptions::default()
+            };
+            let reader = Reader::with_options(PATH, options).await.unwrap();
+            assert_eq!(reader.inode(1).await.unwrap().name, "/");
+            assert_eq!(
+                reader.tag(Tag::Version).await.unwrap().as_deref(),
+                Some("0.1")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_legacy_archive() {
+        const PATH: &str = "/tmp/legacy.flist";
+        tokio::fs::write(PATH, [0x1f, 0x8b, 0x08, 0x00])
+            .await
+            .unwrap();
+
+        let err = Reader::new(PATH).await.err().unwrap();
+        assert!(matches!(err, Error::UnsupportedFormat(_)));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_database() {
+        const PATH: &str = "/tmp/legacy-db.flist";
+        let _ = tokio::fs::remove_file(PATH).await;
+
+        let opts = SqliteConnectOptions::new()
+            .create_if_missing(true)
+            .filename(PATH);
+        let pool = SqlitePool::connect_with(opts).await.unwrap();
+        sqlx::query("create table entries (key VARCHAR(64) PRIMARY KEY, value BLOB);")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let err = Reader::new(PATH).await.err().unwrap();
+        assert!(matches!(err, Error::UnsupportedFormat(_)));
+    }
+
+    #[tokio::test]
+    async fn test_meta_reader() {
+        const PATH: &str = "/tmp/meta-reader.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        let root = meta
+            .inode(Inode {
+                name: "/".into(),
+                mode: Mode::new(FileType::Dir, 0o755),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        meta.inode(Inode {
+            parent: root,
+            name: "file".into(),
+            mode: Mode::new(FileType::Regular, 0o644),
+            ..Inode::default()
+        })
+        .await
+        .unwrap();
+        meta.route(0, 255, "dir:///tmp/store").await.unwrap();
+
+        let reader: Box<dyn MetaReader> = Box::new(Reader::new(PATH).await.unwrap());
+        assert_eq!(reader.inode(root).await.unwrap().name, "/");
+        assert_eq!(reader.children(root, 10, 0).await.unwrap().len(), 1);
+        assert!(reader.lookup(root, "file").await.unwrap().is_some());
+        assert!(reader.lookup(root, "missing").await.unwrap().is_none());
+        assert_eq!(reader.routes().await.unwrap().len(), 1);
+    }
+
     #[test]
     fn test_mode() {
         let m = Mode::new(FileType::Regular, 0754);
@@ -632,6 +1437,133 @@ mod test {
         meta.walk(&mut WalkTest).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_walk_concurrent() {
+        const PATH: &str = "/tmp/walk-concurrent.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        let dir = |parent, name: &str| Inode {
+            parent,
+            name: name.into(),
+            mode: Mode::new(FileType::Dir, 0o755),
+            ..Inode::default()
+        };
+        let root = meta.inode(dir(0, "/")).await.unwrap();
+        for name in ["bin", "etc", "usr"] {
+            let parent = meta.inode(dir(root, name)).await.unwrap();
+            let sub = meta.inode(dir(parent, "sub")).await.unwrap();
+            for file in ["a", "b", "c"] {
+                for parent in [parent, sub] {
+                    meta.inode(Inode {
+                        parent,
+                        name: file.into(),
+                        mode: Mode::new(FileType::Regular, 0o644),
+                        ..Inode::default()
+                    })
+                    .await
+                    .unwrap();
+                }
+            }
+        }
+
+        let meta = Reader::new(PATH).await.unwrap();
+        let mut serial = PathsVisitor::default();
+        meta.walk(&mut serial).await.unwrap();
+        let mut concurrent = PathsVisitor::default();
+        meta.walk_concurrent(&mut concurrent, 2).await.unwrap();
+
+        assert_eq!(concurrent.0.len(), 1 + 3 * 2 + 3 * 2 * 3);
+        // a directory is visited before its content
+        for (index, path) in concurrent.0.iter().enumerate() {
+            if let Some(parent) = path.parent() {
+                assert!(concurrent.0[..index].iter().any(|p| p == parent));
+            }
+        }
+
+        let mut sorted = concurrent.0.clone();
+        sorted.sort();
+        serial.0.sort();
+        assert_eq!(sorted, serial.0);
+    }
+
+    // bench_walk compares walk and walk_concurrent on a tree of 100 directories of 20
+    // files, the visitor spends about a millisecond on each file like unpack does to
+    // copy it. Run it with `cargo test --release -- --ignored bench_walk --nocapture`
+    #[ignore]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bench_walk() {
+        const PATH: &str = "/tmp/bench-walk.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        let node = |parent, name: String, typ| Inode {
+            parent,
+            name,
+            mode: Mode::new(typ, 0o755),
+            ..Inode::default()
+        };
+        let root = meta
+            .inode(node(0, "/".into(), FileType::Dir))
+            .await
+            .unwrap();
+        for dir in 0..10 {
+            let parent = meta
+                .inode(node(root, format!("dir-{}", dir), FileType::Dir))
+                .await
+                .unwrap();
+            for sub in 0..10 {
+                let sub = meta
+                    .inode(node(parent, format!("sub-{}", sub), FileType::Dir))
+                    .await
+                    .unwrap();
+                for file in 0..20 {
+                    meta.inode(node(sub, format!("file-{}", file), FileType::Regular))
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+
+        struct SlowVisitor(usize);
+
+        #[async_trait::async_trait]
+        impl WalkVisitor for SlowVisitor {
+            async fn visit(&mut self, _path: &Path, node: &Inode) -> Result<Walk> {
+                self.0 += 1;
+                if node.mode.is(FileType::Regular) {
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                }
+                Ok(Walk::Continue)
+            }
+        }
+
+        let meta = Reader::new(PATH).await.unwrap();
+        for concurrency in [0, 1, DEFAULT_WALK_CONCURRENCY] {
+            let mut visitor = SlowVisitor(0);
+            let start = std::time::Instant::now();
+            match concurrency {
+                0 => meta.walk(&mut visitor).await.unwrap(),
+                n => meta.walk_concurrent(&mut visitor, n).await.unwrap(),
+            }
+            println!(
+                "concurrency {}: {} entries in {:?}",
+                concurrency,
+                visitor.0,
+                start.elapsed()
+            );
+        }
+    }
+
+    #[derive(Default)]
+    struct PathsVisitor(Vec<PathBuf>);
+
+    #[async_trait::async_trait]
+    impl WalkVisitor for PathsVisitor {
+        async fn visit(&mut self, path: &Path, _node: &Inode) -> Result<Walk> {
+            self.0.push(path.into());
+            Ok(Walk::Continue)
+        }
+    }
+
     struct WalkTest;
 
     #[async_trait::async_trait]
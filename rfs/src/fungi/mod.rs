@@ -1,3 +1,4 @@
 pub mod meta;
+pub mod sign;
 
-pub use meta::{Error, Reader, Result, Writer};
+pub use meta::{Error, MetaReader, Reader, Result, Writer};
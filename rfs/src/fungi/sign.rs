@@ -0,0 +1,144 @@
+// Integrity and authenticity of an fl file.
+//
+// Any change to the sqlite file would break a signature kept inside of it, so both
+// the checksum and the signature are kept in sidecar files next to the fl:
+// - `<fl>.sha256` holds the hex encoded sha256 digest of the whole fl file
+// - `<fl>.sig` holds the hex encoded ed25519 signature of that same digest (the 32
+//   raw bytes, not the hex string)
+//
+// The private key is a PKCS#8 (DER) ed25519 key like the ones created with
+// `openssl genpkey -algorithm ed25519 -outform DER`, the public key is the hex
+// encoding of the 32 bytes of the ed25519 public key.
+use anyhow::{Context, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+pub const CHECKSUM_EXT: &str = "sha256";
+pub const SIGNATURE_EXT: &str = "sig";
+
+/// sidecar returns the path of a sidecar file of the fl, the extension is appended so
+/// `image.fl` has the sidecar `image.fl.sig`
+pub fn sidecar(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    name.into()
+}
+
+/// digest returns the sha256 digest of the fl file
+pub async fn digest<P: AsRef<Path>>(path: P) -> Result<[u8; 32]> {
+    let content = fs::read(path.as_ref())
+        .await
+        .with_context(|| format!("failed to read {}", path.as_ref().display()))?;
+    Ok(Sha256::digest(&content).into())
+}
+
+/// generate_key creates a new private key and returns it in PKCS#8 (DER) format
+pub fn generate_key() -> Result<Vec<u8>> {
+    let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| anyhow::anyhow!("failed to generate key"))?;
+    Ok(document.as_ref().to_vec())
+}
+
+fn key_pair(private_key: &[u8]) -> Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(private_key)
+        .map_err(|err| anyhow::anyhow!("invalid ed25519 private key: {}", err))
+}
+
+/// public_key returns the hex encoded public key of a PKCS#8 private key
+pub fn public_key(private_key: &[u8]) -> Result<String> {
+    Ok(hex::encode(key_pair(private_key)?.public_key().as_ref()))
+}
+
+/// checksum writes the checksum sidecar of the fl and returns the digest
+pub async fn checksum<P: AsRef<Path>>(path: P) -> Result<[u8; 32]> {
+    let path = path.as_ref();
+    let digest = digest(path).await?;
+    fs::write(sidecar(path, CHECKSUM_EXT), hex::encode(digest))
+        .await
+        .context("failed to write checksum")?;
+
+    Ok(digest)
+}
+
+/// sign writes the checksum and the signature sidecars of the fl. The fl must not
+/// be changed afterwards, a writable mount of a signed fl breaks the signature
+pub async fn sign<P: AsRef<Path>>(path: P, private_key: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let key = key_pair(private_key)?;
+    let digest = checksum(path).await?;
+    let signature = hex::encode(key.sign(&digest).as_ref());
+    fs::write(sidecar(path, SIGNATURE_EXT), signature)
+        .await
+        .context("failed to write signature")?;
+
+    Ok(())
+}
+
+/// read_signature reads the signature sidecar of the fl
+pub async fn read_signature<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = sidecar(path.as_ref(), SIGNATURE_EXT);
+    fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("failed to read signature {}", path.display()))
+}
+
+/// verify_checksum checks the fl against its checksum sidecar
+pub async fn verify_checksum<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let checksum = sidecar(path, CHECKSUM_EXT);
+    let expected = fs::read_to_string(&checksum)
+        .await
+        .with_context(|| format!("failed to read checksum {}", checksum.display()))?;
+    let actual = hex::encode(digest(path).await?);
+
+    anyhow::ensure!(
+        expected.trim().eq_ignore_ascii_case(&actual),
+        "checksum mismatch expected '{}' got '{}'",
+        expected.trim(),
+        actual
+    );
+
+    Ok(())
+}
+
+/// verify checks the hex encoded signature of the fl against the hex encoded public key
+pub async fn verify<P: AsRef<Path>>(path: P, public_key: &str, signature: &str) -> Result<()> {
+    let public_key = hex::decode(public_key.trim()).context("invalid public key")?;
+    let signature = hex::decode(signature.trim()).context("invalid signature")?;
+    let digest = digest(path).await?;
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(&digest, &signature)
+        .map_err(|_| anyhow::anyhow!("signature does not match the fl"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sign() {
+        const PATH: &str = "/tmp/sign-test.fl";
+        fs::write(PATH, b"some flist content").await.unwrap();
+
+        let private_key = generate_key().unwrap();
+        let public = public_key(&private_key).unwrap();
+        sign(PATH, &private_key).await.unwrap();
+
+        verify_checksum(PATH).await.unwrap();
+        let signature = read_signature(PATH).await.unwrap();
+        verify(PATH, &public, &signature).await.unwrap();
+
+        // another key or a changed fl are refused
+        let other = public_key(&generate_key().unwrap()).unwrap();
+        assert!(verify(PATH, &other, &signature).await.is_err());
+
+        fs::write(PATH, b"some tampered content").await.unwrap();
+        assert!(verify(PATH, &public, &signature).await.is_err());
+        assert!(verify_checksum(PATH).await.is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::fungi::{Reader, Result};
+use crate::store::{
+    self,
+    zdb::{Keys, ZdbStore},
+    Store,
+};
+use anyhow::Context;
+
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+/// Referenced is the set of block ids that are referenced by a set of flists.
+/// It's implemented as a bloom filter so huge stores can be scanned without
+/// keeping all referenced ids in memory (~10 bits per block).
+///
+/// A false positive means an orphan key is considered referenced, which only means
+/// it's not collected. A referenced block is never reported as orphan.
+pub struct Referenced {
+    bits: Vec<u64>,
+    len: u64,
+}
+
+impl Referenced {
+    /// create a set that can hold `expected` ids with ~1% false positive rate
+    pub fn new(expected: usize) -> Self {
+        let words = (expected.max(1) * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: vec![0; words],
+            len: (words * 64) as u64,
+        }
+    }
+
+    /// build the referenced set from all the blocks of the given flists. blocks
+    /// are read from the flists in batches of `batch` size
+    pub async fn from_flists(flists: &[Reader], batch: u32) -> Result<Self> {
+        let mut expected = 0;
+        for flist in flists {
+            expected += flist.blocks_count().await?;
+        }
+
+        let mut referenced = Self::new(expected as usize);
+        for flist in flists {
+            let mut offset = 0;
+            loop {
+                let blocks = flist.all_blocks(batch, offset).await?;
+                if blocks.is_empty() {
+                    break;
+                }
+
+                offset += blocks.len() as u64;
+                for block in blocks {
+                    referenced.insert(&block.id);
+                }
+            }
+        }
+
+        Ok(referenced)
+    }
+
+    pub fn insert(&mut self, id: &[u8]) {
+        for bit in self.positions(id) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.positions(id)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing of the key, keys are hashed first since store keys
+    // are not guaranteed to be uniformly distributed
+    fn positions(&self, id: &[u8]) -> impl Iterator<Item = u64> {
+        let hash = blake2b_simd::Params::new().hash_length(16).hash(id);
+        let hash = hash.as_bytes();
+        let mut h1 = [0; 8];
+        let mut h2 = [0; 8];
+        h1.copy_from_slice(&hash[..8]);
+        h2.copy_from_slice(&hash[8..16]);
+        let h1 = u64::from_le_bytes(h1);
+        let h2 = u64::from_le_bytes(h2) | 1;
+
+        let len = self.len;
+        (0..HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+}
+
+/// Scan is implemented by the stores that can list all their keys
+#[async_trait::async_trait]
+pub trait Scan: Store {
+    /// scan returns the next set of keys (with the size of their blob) after cursor
+    /// (or from the start if cursor is None) and the cursor for the next call. None
+    /// is returned once all the keys are scanned
+    async fn scan(&self, cursor: Option<&[u8]>) -> store::Result<Option<(Vec<u8>, Keys)>>;
+}
+
+#[async_trait::async_trait]
+impl Scan for ZdbStore {
+    async fn scan(&self, cursor: Option<&[u8]>) -> store::Result<Option<(Vec<u8>, Keys)>> {
+        ZdbStore::scan(self, cursor).await
+    }
+}
+
+/// OrphansScanner walks over all the keys of a store using scan cursors
+/// and returns the keys that are not referenced in batches. Only one batch
+/// of keys is kept in memory at any time.
+pub struct OrphansScanner<'a, S: Scan> {
+    store: &'a S,
+    referenced: &'a Referenced,
+    batch: usize,
+    cursor: Option<Vec<u8>>,
+    done: bool,
+    /// number of keys scanned so far
+    pub scanned: u64,
+    /// number of orphan keys found so far
+    pub orphans: u64,
+    /// size of the blobs of the orphan keys found so far
+    pub reclaimable: u64,
+}
+
+impl<'a, S: Scan> OrphansScanner<'a, S> {
+    pub fn new(store: &'a S, referenced: &'a Referenced, batch: usize) -> Self {
+        Self {
+            store,
+            referenced,
+            batch: batch.max(1),
+            cursor: None,
+            done: false,
+            scanned: 0,
+            orphans: 0,
+            reclaimable: 0,
+        }
+    }
+
+    /// next returns the next batch of orphan keys with the size of their blob, or None
+    /// once the scan is complete
+    pub async fn next_batch(&mut self) -> Result<Option<Keys>> {
+        let mut orphans = Vec::default();
+        while !self.done && orphans.len() < self.batch {
+            let (cursor, keys) = match self.store.scan(self.cursor.as_deref()).await? {
+                Some(result) => result,
+                None => {
+                    self.done = true;
+                    break;
+                }
+            };
+
+            self.cursor = Some(cursor);
+            self.scanned += keys.len() as u64;
+            orphans.extend(
+                keys.into_iter()
+                    .filter(|(key, _)| !self.referenced.contains(key)),
+            );
+        }
+
+        if orphans.is_empty() && self.done {
+            return Ok(None);
+        }
+
+        self.orphans += orphans.len() as u64;
+        self.reclaimable += orphans.iter().map(|(_, size)| size).sum::<u64>();
+        info!(
+            "scanned {} keys, found {} orphan keys ({} bytes)",
+            self.scanned, self.orphans, self.reclaimable
+        );
+
+        Ok(Some(orphans))
+    }
+}
+
+/// Stats of a garbage collection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// number of keys in the store
+    pub scanned: u64,
+    /// number of keys that are not referenced by the live flists
+    pub orphans: u64,
+    /// size of the blobs of the orphan keys
+    pub reclaimable: u64,
+    /// number of orphan keys deleted from the store, none on a dry run
+    pub deleted: u64,
+}
+
+/// collect deletes the keys of the store that are not referenced, one batch at a time
+/// as they are found. Nothing is deleted on a dry run, the stats then only report what
+/// would be reclaimed.
+///
+/// Blocks uploaded by a pack that is still running are not referenced yet, so the
+/// store must not be written to while it's collected
+pub async fn collect<S: Scan>(
+    store: &S,
+    referenced: &Referenced,
+    batch: usize,
+    dry_run: bool,
+) -> Result<Stats> {
+    let mut scanner = OrphansScanner::new(store, referenced, batch);
+    let mut deleted = 0;
+    while let Some(orphans) = scanner.next_batch().await? {
+        if dry_run {
+            continue;
+        }
+
+        for (key, _) in orphans {
+            store
+                .delete(&key)
+                .await
+                .context("failed to delete orphan key")?;
+            deleted += 1;
+        }
+        info!("deleted {} orphan keys", deleted);
+    }
+
+    Ok(Stats {
+        scanned: scanner.scanned,
+        orphans: scanner.orphans,
+        reclaimable: scanner.reclaimable,
+        deleted,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::{
+        meta::{FileType, Inode, Mode},
+        Writer,
+    };
+    use crate::store::{mem::MemStore, BlockStore};
+
+    #[test]
+    fn test_referenced() {
+        let mut referenced = Referenced::new(1000);
+        for i in 0..1000u32 {
+            referenced.insert(&i.to_le_bytes());
+        }
+
+        for i in 0..1000u32 {
+            assert!(referenced.contains(&i.to_le_bytes()));
+        }
+
+        let false_positives = (1000..11000u32)
+            .filter(|i| referenced.contains(&i.to_le_bytes()))
+            .count();
+
+        // expected rate is ~1%
+        assert!(false_positives < 300, "false positives {}", false_positives);
+    }
+
+    #[tokio::test]
+    async fn test_collect() {
+        const PATH: &str = "/tmp/gc.fl";
+        let store = MemStore::default();
+        let blocks = BlockStore::from(store.clone());
+
+        let kept = blocks.set(b"kept").await.unwrap();
+        let orphan = blocks.set(b"orphan").await.unwrap();
+        let orphan_size = store.get(&orphan.id).await.unwrap().len() as u64;
+
+        let meta = Writer::new(PATH, true).await.unwrap();
+        let ino = meta
+            .inode(Inode {
+                name: "file".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        meta.block(ino, &kept.id, &kept.key).await.unwrap();
+
+        let flists = [Reader::new(PATH).await.unwrap()];
+        let referenced = Referenced::from_flists(&flists, 10).await.unwrap();
+
+        let stats = collect(&store, &referenced, 1, true).await.unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                scanned: 2,
+                orphans: 1,
+                reclaimable: orphan_size,
+                deleted: 0,
+            }
+        );
+        assert!(store.exists(&orphan.id).await.unwrap());
+
+        let stats = collect(&store, &referenced, 1, false).await.unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                scanned: 2,
+                orphans: 1,
+                reclaimable: orphan_size,
+                deleted: 1,
+            }
+        );
+        assert!(store.exists(&kept.id).await.unwrap());
+        assert!(!store.exists(&orphan.id).await.unwrap());
+    }
+}
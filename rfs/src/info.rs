@@ -0,0 +1,136 @@
+use crate::fungi::{Reader, Result};
+use std::fmt;
+
+/// Info is a summary of the content of an flist, it's built from the metadata only
+/// so the stores don't need to be reachable
+#[derive(Debug, serde::Serialize)]
+pub struct Info {
+    /// number of inodes (files, directories, links, ...)
+    pub inodes: u64,
+    /// total size of the regular files in bytes
+    pub size: u64,
+    /// number of blocks of all files
+    pub blocks: u64,
+    /// number of distinct blocks, this is what the stores hold
+    pub unique_blocks: u64,
+    pub routes: Vec<RouteInfo>,
+    /// number of blocks per size range
+    pub block_sizes: Vec<SizeRange>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RouteInfo {
+    pub start: u8,
+    pub end: u8,
+    pub url: String,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub struct SizeRange {
+    /// upper bound of the range in bytes, the lower bound is the upper
+    /// bound of the previous range
+    pub max: u64,
+    pub count: u64,
+}
+
+impl Info {
+    pub async fn new(meta: &Reader) -> Result<Self> {
+        let routes = meta
+            .routes()
+            .await?
+            .into_iter()
+            .map(|route| RouteInfo {
+                start: route.start,
+                end: route.end,
+                url: route.url,
+            })
+            .collect();
+
+        let block_sizes = meta
+            .block_size_histogram()
+            .await?
+            .into_iter()
+            .map(|(max, count)| SizeRange { max, count })
+            .collect();
+
+        Ok(Info {
+            inodes: meta.inodes_count().await?,
+            size: meta.files_size().await?,
+            blocks: meta.blocks_count().await?,
+            unique_blocks: meta.unique_blocks_count().await?,
+            routes,
+            block_sizes,
+        })
+    }
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "inodes: {}", self.inodes)?;
+        writeln!(f, "size: {} bytes", self.size)?;
+        writeln!(f, "blocks: {} ({} unique)", self.blocks, self.unique_blocks)?;
+
+        writeln!(f, "routes:")?;
+        for route in self.routes.iter() {
+            writeln!(
+                f,
+                "\trange:[{}-{}] store:{}",
+                route.start, route.end, route.url
+            )?;
+        }
+
+        writeln!(f, "block sizes:")?;
+        for range in self.block_sizes.iter() {
+            writeln!(f, "\t<= {} bytes: {}", range.max, range.count)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::{
+        meta::{FileType, Inode, Mode},
+        Writer,
+    };
+
+    #[tokio::test]
+    async fn test_info() {
+        const PATH: &str = "/tmp/info.fl";
+        let meta = Writer::new(PATH, true).await.unwrap();
+
+        let root = meta
+            .inode(Inode {
+                name: "/".into(),
+                mode: Mode::new(FileType::Dir, 0o755),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        let file = meta
+            .inode(Inode {
+                parent: root,
+                name: "file".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                size: 100,
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        meta.block(file, &[1; 32], &[1; 32]).await.unwrap();
+        meta.route(0, 255, "dir:///tmp/store").await.unwrap();
+
+        let info = Info::new(&Reader::new(PATH).await.unwrap()).await.unwrap();
+        assert_eq!(info.inodes, 2);
+        assert_eq!(info.size, 100);
+        assert_eq!(info.blocks, 1);
+        assert_eq!(info.unique_blocks, 1);
+        assert_eq!(info.block_sizes, vec![SizeRange { max: 128, count: 1 }]);
+
+        let text = info.to_string();
+        assert!(text.contains("\trange:[0-255] store:dir:///tmp/store\n"));
+        assert!(text.contains("\t<= 128 bytes: 1\n"));
+    }
+}
@@ -5,13 +5,30 @@ pub mod cache;
 pub mod fungi;
 pub mod store;
 
+pub mod chunker;
+pub use chunker::Chunker;
 mod pack;
-pub use pack::pack;
+pub use pack::{pack, pack_with, PackOptions};
 mod unpack;
 pub use unpack::unpack;
 mod clone;
 pub use clone::clone;
+mod export;
+pub use export::export;
+mod builder;
+pub use builder::{Attributes, FlistBuilder};
+pub mod bench;
 pub mod config;
+pub mod content;
+pub mod diff;
+#[cfg(feature = "fuse")]
+pub mod fs;
+pub mod gc;
+pub mod info;
+pub mod logger;
+pub mod migrate;
+pub mod sync;
+pub mod verify;
 
 const PARALLEL_UPLOAD: usize = 10; // number of files we can upload in parallel
 
@@ -67,7 +84,9 @@ mod test {
         store.add(0x00, 0x7f, store0);
         store.add(0x80, 0xff, store1);
 
-        pack(writer, store, &source, false, None).await.unwrap();
+        pack(writer, store, &source, false, false, Chunker::Fixed, None)
+            .await
+            .unwrap();
 
         println!("packing complete");
         // recreate the stores for reading.
@@ -104,4 +123,279 @@ mod test {
 
         assert!(status.success());
     }
+
+    #[tokio::test]
+    async fn pack_unpack_concurrent() {
+        const ROOT: &str = "/tmp/pack-unpack-concurrent-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let root: PathBuf = ROOT.into();
+        let source = root.join("source");
+        fs::create_dir_all(&source).await.unwrap();
+
+        let mut urandom = fs::File::open("/dev/urandom")
+            .await
+            .unwrap()
+            .take(10 * 1024 * 1024 + 10);
+        let mut file = fs::File::create(source.join("file.rnd")).await.unwrap();
+        tokio::io::copy(&mut urandom, &mut file).await.unwrap();
+
+        let writer = meta::Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let options = PackOptions {
+            upload_concurrency: 8,
+            ..Default::default()
+        };
+        pack_with(writer, store, &source, options, None)
+            .await
+            .unwrap();
+
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), store);
+        let reader = meta::Reader::new(root.join("meta.fl")).await.unwrap();
+
+        // blocks are uploaded out of order but must be recorded in the file order
+        unpack(&reader, &cache, root.join("destination"), false)
+            .await
+            .unwrap();
+
+        let status = std::process::Command::new("diff")
+            .arg(root.join("source"))
+            .arg(root.join("destination"))
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn pack_unpack_sparse() {
+        use std::io::SeekFrom;
+        use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+        const ROOT: &str = "/tmp/pack-unpack-sparse-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let root: PathBuf = ROOT.into();
+        let source = root.join("source");
+        fs::create_dir_all(&source).await.unwrap();
+
+        // a file of 4 blocks where only the 3rd block has data
+        let block = pack::BLOB_SIZE as u64;
+        let mut file = fs::File::create(source.join("sparse")).await.unwrap();
+        file.set_len(block * 4).await.unwrap();
+        file.seek(SeekFrom::Start(block * 2 + 10)).await.unwrap();
+        file.write_all("some data".as_bytes()).await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        let writer = meta::Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        pack(writer, store, &source, false, true, Chunker::Fixed, None)
+            .await
+            .unwrap();
+
+        let reader = meta::Reader::new(root.join("meta.fl")).await.unwrap();
+        let node = reader.lookup(1, "sparse").await.unwrap().unwrap();
+        let blocks = reader.blocks(node.ino).await.unwrap();
+        assert_eq!(blocks.len(), 4);
+        let holes: Vec<bool> = blocks.iter().map(|b| b.is_hole()).collect();
+        assert_eq!(holes, vec![true, true, false, true]);
+
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), store);
+        unpack(&reader, &cache, root.join("destination"), false)
+            .await
+            .unwrap();
+
+        let status = std::process::Command::new("diff")
+            .arg(root.join("source"))
+            .arg(root.join("destination"))
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn pack_unpack_empty() {
+        const ROOT: &str = "/tmp/pack-unpack-empty-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let root: PathBuf = ROOT.into();
+        let source = root.join("source");
+        fs::create_dir_all(&source).await.unwrap();
+        fs::File::create(source.join("empty")).await.unwrap();
+
+        let writer = meta::Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        pack(writer, store, &source, false, false, Chunker::Fixed, None)
+            .await
+            .unwrap();
+
+        // empty files are stored as files without blocks
+        let reader = meta::Reader::new(root.join("meta.fl")).await.unwrap();
+        let node = reader.lookup(1, "empty").await.unwrap().unwrap();
+        assert_eq!(node.size, 0);
+        assert!(reader.blocks(node.ino).await.unwrap().is_empty());
+
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), store);
+        unpack(&reader, &cache, root.join("destination"), false)
+            .await
+            .unwrap();
+
+        let meta = fs::metadata(root.join("destination").join("empty"))
+            .await
+            .unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn pack_unpack_cdc() {
+        const ROOT: &str = "/tmp/pack-unpack-cdc-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let root: PathBuf = ROOT.into();
+        let source = root.join("source");
+        fs::create_dir_all(&source).await.unwrap();
+
+        let mut urandom = fs::OpenOptions::default()
+            .read(true)
+            .open("/dev/urandom")
+            .await
+            .unwrap()
+            .take(3 * 1024 * 1024 + 100);
+        let mut file = fs::File::create(source.join("file")).await.unwrap();
+        tokio::io::copy(&mut urandom, &mut file).await.unwrap();
+
+        let chunker = Chunker::cdc(64 * 1024, 256 * 1024, 1024 * 1024).unwrap();
+        let writer = meta::Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        pack(writer, store, &source, false, false, chunker, None)
+            .await
+            .unwrap();
+
+        let reader = meta::Reader::new(root.join("meta.fl")).await.unwrap();
+        let node = reader.lookup(1, "file").await.unwrap().unwrap();
+        let sizes = reader.block_sizes(node.ino).await.unwrap();
+        let total: u64 = sizes.iter().map(|size| size.unwrap()).sum();
+        assert_eq!(total, node.size);
+        assert_eq!(
+            reader.tag(meta::Tag::Custom("chunker")).await.unwrap(),
+            Some(chunker.to_string())
+        );
+
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), store);
+        unpack(&reader, &cache, root.join("destination"), false)
+            .await
+            .unwrap();
+
+        let status = std::process::Command::new("diff")
+            .arg(root.join("source"))
+            .arg(root.join("destination"))
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn pack_too_many_blocks() {
+        const ROOT: &str = "/tmp/pack-too-many-blocks-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let root: PathBuf = ROOT.into();
+        let source = root.join("source");
+        fs::create_dir_all(&source).await.unwrap();
+
+        // a sparse file, nothing is read since it's refused before the upload
+        let file = fs::File::create(source.join("big")).await.unwrap();
+        file.set_len(16 << 30).await.unwrap();
+
+        let chunker = Chunker::sized(chunker::MIN_BLOCK_SIZE).unwrap();
+        let writer = meta::Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let err = pack(writer, store, &source, false, false, chunker, None)
+            .await
+            .unwrap_err()
+            .to_string();
+
+        let path = source.join("big").display().to_string();
+        assert!(err.contains(&path), "{}", err);
+        assert!(err.contains("at least 16384"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn pack_export() {
+        const ROOT: &str = "/tmp/pack-export-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let root: PathBuf = ROOT.into();
+        let source = root.join("source");
+        let long = "d".repeat(120);
+        fs::create_dir_all(source.join("dir").join(&long))
+            .await
+            .unwrap();
+
+        let mut big = vec![0u8; 3 * 1024 * 1024 + 7];
+        fs::File::open("/dev/urandom")
+            .await
+            .unwrap()
+            .read_exact(&mut big)
+            .await
+            .unwrap();
+        fs::write(source.join("big"), &big).await.unwrap();
+        fs::write(source.join("dir").join(&long).join("small"), "hello")
+            .await
+            .unwrap();
+        fs::symlink("../big", source.join("dir").join("link"))
+            .await
+            .unwrap();
+
+        let writer = meta::Writer::new(root.join("meta.fl"), true).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        pack(writer, store, &source, false, false, Chunker::Fixed, None)
+            .await
+            .unwrap();
+
+        let reader = meta::Reader::new(root.join("meta.fl")).await.unwrap();
+        let store = DirStore::new(root.join("store")).await.unwrap();
+        let cache = Cache::new(root.join("cache"), store);
+        let mut out = Vec::default();
+        export(&reader, &cache, &mut out).await.unwrap();
+
+        let mut archive = tar::Archive::new(out.as_slice());
+        let mut entries = std::collections::HashMap::new();
+        for (index, entry) in archive.entries().unwrap().enumerate() {
+            let mut entry = entry.unwrap();
+            let name = String::from_utf8(entry.path_bytes().into_owned()).unwrap();
+            if index == 0 {
+                assert_eq!(name, "./");
+            }
+
+            let mut content = Vec::default();
+            std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+            let target = entry
+                .link_name()
+                .unwrap()
+                .map(|target| target.display().to_string());
+            entries.insert(name, (entry.header().entry_type(), content, target));
+        }
+
+        let small = format!("./dir/{}/small", long);
+        assert_eq!(entries.len(), 6);
+        assert_eq!(entries["./"].0, tar::EntryType::Directory);
+        assert_eq!(entries["./big"].1, big);
+        assert_eq!(entries["./dir/"].0, tar::EntryType::Directory);
+        assert_eq!(
+            entries[&format!("./dir/{}/", long)].0,
+            tar::EntryType::Directory
+        );
+        assert_eq!(entries[&small].1, b"hello");
+        assert_eq!(entries["./dir/link"].0, tar::EntryType::Symlink);
+        assert_eq!(entries["./dir/link"].2.as_deref(), Some("../big"));
+    }
 }
@@ -0,0 +1,147 @@
+use log::{kv, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Value};
+use std::io::Write;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// JsonLogger writes the logs to stdout as one json object per line with the
+/// fields timestamp, level, module and message. The key values of a record
+/// (like `job_id` or `store_url`) are added as extra fields
+pub struct JsonLogger {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for JsonLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonLogger {
+    pub fn new() -> Self {
+        JsonLogger {
+            level: LevelFilter::Info,
+            modules: Vec::default(),
+        }
+    }
+
+    /// with_level sets the level of all the modules without their own level
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// with_module_level sets the level of the module and its sub modules
+    pub fn with_module_level(mut self, module: &str, level: LevelFilter) -> Self {
+        self.modules.push((module.into(), level));
+        // the longest (most specific) module is matched first
+        self.modules
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        self
+    }
+
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max = self
+            .modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, std::cmp::max);
+        log::set_max_level(max);
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module.as_str() || target.starts_with(&format!("{}::", module))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn format(&self, record: &Record) -> Value {
+        let mut fields = Map::new();
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        fields.insert("timestamp".into(), timestamp.into());
+        fields.insert("level".into(), record.level().as_str().into());
+        fields.insert(
+            "module".into(),
+            record.module_path().unwrap_or(record.target()).into(),
+        );
+        fields.insert("message".into(), record.args().to_string().into());
+
+        let mut visitor = Fields(&mut fields);
+        let _ = record.key_values().visit(&mut visitor);
+
+        Value::Object(fields)
+    }
+}
+
+// Fields adds the key values of a record to the json fields, the standard
+// fields are never overridden
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'a, 'kvs> kv::VisitSource<'kvs> for Fields<'a> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .entry(key.as_str())
+            .or_insert_with(|| value.to_string().into());
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = self.format(record);
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let logger = JsonLogger::new()
+            .with_level(LevelFilter::Debug)
+            .with_module_level("sqlx", LevelFilter::Error);
+
+        assert_eq!(logger.level("rfs::store"), LevelFilter::Debug);
+        assert_eq!(logger.level("sqlx::query"), LevelFilter::Error);
+        assert_eq!(logger.level("sqlxx"), LevelFilter::Debug);
+
+        let kvs = [("job_id", "1234"), ("level", "overridden")];
+        let line = logger.format(
+            &Record::builder()
+                .args(format_args!("flist is created"))
+                .level(log::Level::Info)
+                .target("fl_server::handlers")
+                .module_path(Some("fl_server::handlers"))
+                .key_values(&kvs)
+                .build(),
+        );
+
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["module"], "fl_server::handlers");
+        assert_eq!(line["message"], "flist is created");
+        assert_eq!(line["job_id"], "1234");
+        assert!(line["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+}
@@ -8,11 +8,11 @@ use std::io::Read;
 use anyhow::{Context, Result};
 use clap::{ArgAction, Args, Parser, Subcommand};
 
+use rfs::fs;
 use rfs::fungi;
 use rfs::store::{self};
-use rfs::{cache, config};
+use rfs::{bench, cache, chunker, config};
 
-mod fs;
 /// mount flists
 #[derive(Parser, Debug)]
 #[clap(name ="rfs", author, version = env!("GIT_VERSION"), about, long_about = None)]
@@ -21,6 +21,10 @@ struct Options {
     #[clap(long, action=ArgAction::Count)]
     debug: u8,
 
+    /// format of the logs, json writes one json object per line
+    #[clap(long, default_value = "text", value_parser = ["text", "json"])]
+    log_format: String,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -29,7 +33,10 @@ struct Options {
 enum Commands {
     /// mount an FL
     Mount(MountOptions),
+    /// unmount an FL mounted by rfs and wait for the rfs process to exit
+    Unmount(UnmountOptions),
     /// create an FL and upload blocks to provided storage
+    #[clap(visible_alias = "build")]
     Pack(PackOptions),
     /// unpack (downloads) content of an FL the provided location
     Unpack(UnpackOptions),
@@ -37,23 +44,306 @@ enum Commands {
     Clone(CloneOptions),
     /// list or modify FL metadata and stores
     Config(ConfigOptions),
+    /// benchmark a store by writing, reading back and deleting blocks
+    #[clap(visible_alias = "store-bench")]
+    BenchStore(BenchStoreOptions),
+    /// check that all the blocks of an FL can be read from its stores
+    Verify(VerifyOptions),
+    /// print a summary of the content of an FL, the stores are not used
+    Info(InfoOptions),
+    /// list the paths that are added, removed or modified between two FLs
+    Diff(DiffOptions),
+    /// copy all the blocks of an FL from a store to another, blocks that are already
+    /// in the destination are skipped
+    Sync(SyncOptions),
+    /// delete the blocks of a zdb store that are not used by any of the given FLs
+    Gc(GcOptions),
+    /// copy an FL created by an older version to a new FL with the current schema
+    Migrate(MigrateOptions),
+    /// write the checksum of an FL, and its signature if a key is given, next to the FL
+    Sign(SignOptions),
+    /// generate an ed25519 private key to sign FLs and print its public key
+    Keygen(KeygenOptions),
 }
 
 #[derive(Args, Debug)]
-struct MountOptions {
+struct SignOptions {
+    /// path to metadata file (flist)
+    #[clap(short, long)]
+    meta: String,
+
+    /// path to the ed25519 private key (PKCS#8 DER), only the checksum is written if not set
+    #[clap(short, long)]
+    key: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct KeygenOptions {
+    /// path of the private key file to create
+    path: String,
+}
+
+#[derive(Args, Debug)]
+struct MigrateOptions {
+    /// path to the old metadata file (flist)
+    old: String,
+
+    /// path to the new metadata file (flist), it's replaced if it exists
+    new: String,
+}
+
+#[derive(Args, Debug)]
+struct SyncOptions {
+    /// path to metadata file (flist)
+    #[clap(short, long)]
+    meta: String,
+
+    /// source store url in the format [xx-xx=]<url>, defaults to the stores of the FL.
+    /// use @<path> to load routes with per route options from a toml file
+    #[clap(long, action=ArgAction::Append)]
+    from: Vec<String>,
+
+    /// destination store url in the format [xx-xx=]<url>. the range xx-xx is optional and
+    /// used for sharding. use @<path> to load routes with per route options from a toml file
+    #[clap(long, required = true, action=ArgAction::Append)]
+    to: Vec<String>,
+
+    /// number of blocks copied at the same time
+    #[clap(short, long, default_value_t = 16)]
+    concurrency: usize,
+}
+
+#[derive(Args, Debug)]
+struct GcOptions {
+    /// path to a metadata file (flist) whose blocks are kept, can be repeated
+    #[clap(short, long, required = true, action=ArgAction::Append)]
+    meta: Vec<String>,
+
+    /// url of the zdb store (namespace) to collect
+    #[clap(short, long)]
+    store: String,
+
+    /// delete the blocks that are not used, required unless --dry-run is set
+    #[clap(long, default_value_t = false)]
+    confirm: bool,
+
+    /// only report the unused blocks and the bytes that would be reclaimed
+    #[clap(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// number of unused blocks found before they are deleted
+    #[clap(long, default_value_t = 1000)]
+    batch: usize,
+}
+
+#[derive(Args, Debug)]
+struct DiffOptions {
+    /// path to the old metadata file (flist)
+    old: String,
+
+    /// path to the new metadata file (flist)
+    new: String,
+
+    /// print the changes as json
+    #[clap(long, default_value_t = false)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct InfoOptions {
     /// path to metadata file (flist)
     #[clap(short, long)]
     meta: String,
 
+    /// print the summary as json
+    #[clap(long, default_value_t = false)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct VerifyOptions {
+    /// path to metadata file (flist)
+    #[clap(short, long)]
+    meta: String,
+
+    /// download each block and check it against its hash, by default only the
+    /// presence of the blocks in the store is checked
+    #[clap(long, default_value_t = false)]
+    deep: bool,
+
+    /// number of blocks checked at the same time
+    #[clap(short, long, default_value_t = 16)]
+    concurrency: usize,
+}
+
+#[derive(Args, Debug)]
+struct BenchStoreOptions {
+    /// number of blocks to write and read
+    #[clap(short, long, default_value_t = 100)]
+    blocks: usize,
+
+    /// size of each block in bytes
+    #[clap(short, long, default_value_t = 512 * 1024)]
+    size: usize,
+
+    /// number of operations in flight at the same time
+    #[clap(short, long, default_value_t = 10)]
+    concurrency: usize,
+
+    /// only benchmark reads of the blocks of an existing fl, no blocks are written
+    #[clap(long, requires = "meta")]
+    read_only: bool,
+
+    /// leave the benchmark blocks in the store instead of deleting them at the end
+    #[clap(long, default_value_t = false)]
+    keep: bool,
+
+    /// fl to read the blocks from in read-only mode
+    #[clap(short, long)]
+    meta: Option<String>,
+
+    /// store url, the URL is per store type, please check docs for more information
+    url: String,
+}
+
+#[derive(Args, Debug)]
+struct MountOptions {
+    /// path to metadata file (flist) or an http(s) url to download it from. the url can
+    /// end with #sha256=<hex> to verify the downloaded flist
+    #[clap(short, long)]
+    meta: String,
+
+    /// download the metadata again even if it's already in the cache
+    #[clap(long, default_value_t = false)]
+    refresh_meta: bool,
+
     /// directory used as cache for downloaded file chuncks
     #[clap(short, long, default_value_t = String::from("/tmp/cache"))]
     cache: String,
 
+    /// use a cache directory dedicated to the mounted flist (named after its digest)
+    /// under the cache directory
+    #[clap(long, default_value_t = false)]
+    isolated_cache: bool,
+
+    /// like isolated-cache but the dedicated cache directory is removed once
+    /// the filesystem is unmounted or the process is terminated
+    #[clap(long, default_value_t = false)]
+    ephemeral_cache: bool,
+
+    /// maximum size of the cache directory in bytes, the cache is unbounded if not set
+    #[clap(long)]
+    cache_size: Option<u64>,
+
+    /// cache eviction policy used once the cache size is exceeded [lru, lfu, fifo]
+    #[clap(long, default_value_t = cache::Policy::Lru)]
+    cache_policy: cache::Policy,
+
+    /// check cached blocks against their hash on each read, corrupted blocks
+    /// are downloaded again
+    #[clap(long, default_value_t = false)]
+    verify_chunks: bool,
+
+    /// check the FL against its checksum file (<fl>.sha256) before mounting
+    #[clap(long, default_value_t = false)]
+    verify_checksum: bool,
+
+    /// check the FL against its signature file (<fl>.sig) before mounting, the value is
+    /// the hex encoded ed25519 public key or a file that holds it
+    #[clap(long)]
+    verify_signature: Option<String>,
+
+    /// number of replicas queried at the same time for a block, the fastest answer wins.
+    /// only useful if the flist has replicated routes, it multiplies the read load on the stores
+    #[clap(long, default_value_t = 1)]
+    race: usize,
+
+    /// store to use instead of the flist stores in the format [xx-xx=]<url>, can be repeated.
+    /// it replaces the flist routes for the key prefixes in its range (all of them by default),
+    /// for example to read a published flist from a mirror. use @<path> to load routes from a
+    /// toml file
+    #[clap(long, action=ArgAction::Append)]
+    store_override: Vec<String>,
+
+    /// fail to mount if any of the flist stores is not reachable, otherwise
+    /// unreachable stores are only reported
+    #[clap(long, default_value_t = false)]
+    strict_stores: bool,
+
+    /// make the mount writable. written files are uploaded to the flist stores and the
+    /// flist is updated in place, so the flist must be a local file
+    #[clap(long, default_value_t = false)]
+    rw: bool,
+
+    /// expose a read-only virtual file /.rfs/info with information about the mounted flist
+    #[clap(long, default_value_t = false)]
+    expose_info: bool,
+
+    /// maximum number of reads processed at the same time, extra reads are queued.
+    /// it should not be much higher than the connection pool size of the stores
+    #[clap(long)]
+    max_inflight: Option<usize>,
+
+    /// number of blocks downloaded in the background ahead of a file that is read
+    /// sequentially. 0 disables the readahead
+    #[clap(long, default_value_t = 0)]
+    readahead: usize,
+
+    /// number of seconds the attributes of the flist entries are cached (in memory and
+    /// by the kernel). by default they are cached forever for a read-only mount and for
+    /// a second for a writable mount
+    #[clap(long)]
+    attr_cache_ttl: Option<u64>,
+
+    /// maximum number of entries kept in the attributes cache
+    #[clap(long, default_value_t = fs::ATTR_CACHE_CAP)]
+    attr_cache_size: usize,
+
+    /// keep the links of the flist inside the mount, absolute link targets are resolved
+    /// from the root of the mount instead of the root of the system. use it for untrusted flists
+    #[clap(long, default_value_t = false)]
+    no_follow_escape: bool,
+
+    /// fuse mount option, can be repeated. supported options are allow_other, allow_root,
+    /// default_permissions, nodev, nosuid, noexec, noatime, fsname=<name>, uid=<uid> and
+    /// gid=<gid>. uid and gid set the owner of all the entries of the mount
+    #[clap(short = 'o', long = "mount-option", action = ArgAction::Append)]
+    mount_options: Vec<fs::options::MountOption>,
+
+    /// source of the mount shown by `mount` and in /proc/mounts, defaults to the path
+    /// (or url) of the flist. the mount type is always fuse.rfs
+    #[clap(long)]
+    fsname: Option<String>,
+
+    /// report all the entries of the mount as owned by the given user instead of the owner
+    /// recorded in the flist, the user running rfs if no uid is given. same as -o uid=<uid>
+    #[clap(long)]
+    squash_uid: Option<Option<u32>>,
+
+    /// report all the entries of the mount as owned by the given group instead of the group
+    /// recorded in the flist, the group running rfs if no gid is given. same as -o gid=<gid>
+    #[clap(long)]
+    squash_gid: Option<Option<u32>>,
+
+    /// unmount automatically after the given number of seconds without any filesystem activity
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+
+    /// serve the mount status as json on a unix socket at the given path. the socket
+    /// also accepts an `unmount` command. access is limited to the owner of the process
+    #[clap(long)]
+    control_socket: Option<String>,
+
     /// run in the background.
     #[clap(short, long)]
     daemon: bool,
 
-    /// log file only used with daemon mode
+    /// run in the foreground, it's the default
+    #[clap(long, conflicts_with = "daemon")]
+    foreground: bool,
+
+    /// log file only used with daemon mode. defaults to a file named after the
+    /// mountpoint in the runtime directory of rfs
     #[clap(short, long)]
     log: Option<String>,
 
@@ -61,6 +351,21 @@ struct MountOptions {
     target: String,
 }
 
+#[derive(Args, Debug)]
+struct UnmountOptions {
+    /// detach the mount even if files are still open (fusermount -uz), the rfs
+    /// process exits once the files are closed
+    #[clap(long, default_value_t = false)]
+    lazy: bool,
+
+    /// number of seconds to wait for the rfs process to exit
+    #[clap(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// mountpoint of the FL
+    target: String,
+}
+
 #[derive(Args, Debug)]
 struct PackOptions {
     /// path to metadata file (flist)
@@ -68,14 +373,51 @@ struct PackOptions {
     meta: String,
 
     /// store url in the format [xx-xx=]<url>. the range xx-xx is optional and used for
-    /// sharding. the URL is per store type, please check docs for more information
+    /// sharding. the URL is per store type, please check docs for more information.
+    /// use @<path> to load routes with per route options from a toml file
     #[clap(short, long, action=ArgAction::Append)]
     store: Vec<String>,
 
+    /// how the blocks are spread over the stores: range (by the key range of each store)
+    /// or ring (consistent hashing, adding a store only moves a fraction of the blocks)
+    #[clap(long, default_value_t = store::Sharding::Range)]
+    sharding: store::Sharding,
+
     /// no_strip_password disable automatic password stripping from store url, otherwise password will be stored in the fl.
     #[clap(long, default_value_t = false)]
     no_strip_password: bool,
 
+    /// do not upload blocks that are completely inside a hole of a sparse file. holes are read back as zeros
+    /// but the fl can't be mounted by older rfs versions
+    #[clap(long, default_value_t = false)]
+    sparse: bool,
+
+    /// record the extended attributes (xattrs) of the files, they are exposed by the mount
+    #[clap(long, default_value_t = false)]
+    xattrs: bool,
+
+    /// chunking algorithm used to split files into blocks. cdc (content defined chunking) gives
+    /// better dedup between versions of the same files but the fl can't be mounted by older rfs versions
+    #[clap(long, default_value = "fixed", value_parser = ["fixed", "cdc"])]
+    chunker: String,
+
+    /// minimum block size for cdc chunker
+    #[clap(long, default_value_t = chunker::CDC_MIN_SIZE)]
+    cdc_min: usize,
+
+    /// average block size for cdc chunker, must be a power of 2
+    #[clap(long, default_value_t = chunker::CDC_AVG_SIZE)]
+    cdc_avg: usize,
+
+    /// maximum block size for cdc chunker
+    #[clap(long, default_value_t = chunker::CDC_MAX_SIZE)]
+    cdc_max: usize,
+
+    /// sign the FL with the ed25519 private key (PKCS#8 DER) at this path, the checksum
+    /// and the signature are written next to the FL (<fl>.sha256 and <fl>.sig)
+    #[clap(long)]
+    sign_key: Option<String>,
+
     /// target directory to upload
     target: String,
 }
@@ -95,8 +437,20 @@ struct UnpackOptions {
     #[clap(short, long, default_value_t = false)]
     preserve_ownership: bool,
 
+    /// store to use instead of the flist stores in the format [xx-xx=]<url>, can be repeated.
+    /// it replaces the flist routes for the key prefixes in its range (all of them by default).
+    /// use @<path> to load routes from a toml file
+    #[clap(long, action=ArgAction::Append)]
+    store: Vec<String>,
+
+    /// write the content of the FL to a tar archive at this path instead of a directory,
+    /// the ownership is always kept in the archive
+    #[clap(short, long, conflicts_with = "target")]
+    output: Option<String>,
+
     /// target directory for unpacking
-    target: String,
+    #[clap(required_unless_present = "output")]
+    target: Option<String>,
 }
 
 #[derive(Args, Debug)]
@@ -106,7 +460,8 @@ struct CloneOptions {
     meta: String,
 
     /// store url in the format [xx-xx=]<url>. the range xx-xx is optional and used for
-    /// sharding. the URL is per store type, please check docs for more information
+    /// sharding. the URL is per store type, please check docs for more information.
+    /// use @<path> to load routes with per route options from a toml file
     #[clap(short, long, action=ArgAction::Append)]
     store: Vec<String>,
 
@@ -167,7 +522,8 @@ enum StoreOperation {
 #[derive(Args, Debug)]
 struct StoreAddOptions {
     /// store url in the format [xx-xx=]<url>. the range xx-xx is optional and used for
-    /// sharding. the URL is per store type, please check docs for more information
+    /// sharding. the URL is per store type, please check docs for more information.
+    /// use @<path> to load routes with per route options from a toml file
     #[clap(short, long, action=ArgAction::Append)]
     store: Vec<String>,
 }
@@ -197,38 +553,214 @@ where
 }
 
 fn main() -> Result<()> {
+    // no thread is started yet
+    unsafe { rfs::store::tls::install_system_ca() };
     let opts = Options::parse();
 
-    simple_logger::SimpleLogger::new()
-        .with_utc_timestamps()
-        .with_level({
-            match opts.debug {
-                0 => log::LevelFilter::Info,
-                1 => log::LevelFilter::Debug,
-                _ => log::LevelFilter::Trace,
-            }
-        })
-        .with_module_level("sqlx", log::Level::Error.to_level_filter())
-        .init()?;
+    let level = match opts.debug {
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    match opts.log_format.as_str() {
+        "json" => rfs::logger::JsonLogger::new()
+            .with_level(level)
+            .with_module_level("sqlx", log::Level::Error.to_level_filter())
+            .init()?,
+        _ => simple_logger::SimpleLogger::new()
+            .with_utc_timestamps()
+            .with_level(level)
+            .with_module_level("sqlx", log::Level::Error.to_level_filter())
+            .init()?,
+    }
 
     log::debug!("options: {:#?}", opts);
 
     match opts.command {
         Commands::Mount(opts) => mount(opts),
+        Commands::Unmount(opts) => unmount(opts),
         Commands::Pack(opts) => pack(opts),
         Commands::Unpack(opts) => unpack(opts),
         Commands::Clone(opts) => clone(opts),
         Commands::Config(opts) => config(opts),
+        Commands::BenchStore(opts) => bench_store(opts),
+        Commands::Verify(opts) => verify(opts),
+        Commands::Info(opts) => info(opts),
+        Commands::Diff(opts) => diff(opts),
+        Commands::Sync(opts) => sync(opts),
+        Commands::Gc(opts) => gc(opts),
+        Commands::Migrate(opts) => migrate(opts),
+        Commands::Sign(opts) => sign(opts),
+        Commands::Keygen(opts) => keygen(opts),
     }
 }
 
+fn verify(opts: VerifyOptions) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        let meta = fungi::Reader::new(opts.meta)
+            .await
+            .context("failed to initialize metadata database")?;
+
+        let router = store::get_router(&meta).await?;
+        let gaps = router.gaps();
+        for gap in gaps.iter() {
+            warn!(
+                "key prefixes {:02x}-{:02x} are not covered by any route",
+                gap.start(),
+                gap.end()
+            );
+        }
+
+        if !gaps.is_empty() {
+            const PAGE: u32 = 1000;
+            let mut offset = 0;
+            let mut uncovered = 0;
+            loop {
+                let blocks = meta.all_blocks(PAGE, offset).await?;
+                for block in blocks.iter().filter(|block| !block.is_hole()) {
+                    if gaps.iter().any(|gap| gap.contains(&block.id[0])) {
+                        uncovered += 1;
+                    }
+                }
+
+                if blocks.len() < PAGE as usize {
+                    break;
+                }
+                offset += PAGE as u64;
+            }
+
+            anyhow::ensure!(
+                uncovered == 0,
+                "{} blocks can't be read because their key prefix is not covered by any route",
+                uncovered
+            );
+        }
+
+        info!("all blocks are covered by the routes");
+
+        let report = rfs::verify::verify(&meta, router, opts.concurrency, opts.deep).await?;
+        info!(
+            "checked {} blocks: {} missing, {} corrupted, {} failed",
+            report.checked, report.missing, report.corrupted, report.failed
+        );
+
+        anyhow::ensure!(
+            report.is_ok(),
+            "{} blocks are missing or corrupted and {} could not be checked",
+            report.missing + report.corrupted,
+            report.failed
+        );
+
+        Ok(())
+    })
+}
+
+fn info(opts: InfoOptions) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        let meta = fungi::Reader::new(opts.meta)
+            .await
+            .context("failed to initialize metadata database")?;
+
+        let info = rfs::info::Info::new(&meta).await?;
+        if opts.json {
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        } else {
+            print!("{}", info);
+        }
+
+        Ok(())
+    })
+}
+
+fn bench_store(opts: BenchStoreOptions) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        let store = store::make(&opts.url).await?;
+
+        let keys = match opts.meta {
+            Some(ref meta) if opts.read_only => {
+                let meta = fungi::Reader::new(meta)
+                    .await
+                    .context("failed to initialize metadata database")?;
+                let blocks = meta.all_blocks(opts.blocks as u32, 0).await?;
+                blocks
+                    .into_iter()
+                    .filter(|block| !block.is_hole())
+                    .map(|block| block.id.to_vec())
+                    .collect()
+            }
+            _ => bench::keys(opts.blocks),
+        };
+
+        if !opts.read_only {
+            let stats = bench::set(&store, &keys, opts.size, opts.concurrency).await;
+            println!("set: {}", stats);
+        }
+
+        let stats = bench::get(&store, &keys, opts.concurrency).await;
+        println!("get: {}", stats);
+
+        if opts.read_only {
+            return Ok(());
+        }
+
+        if opts.keep {
+            warn!(
+                "{} benchmark blocks of {} bytes are left in the store",
+                keys.len(),
+                opts.size
+            );
+            return Ok(());
+        }
+
+        let stats = bench::delete(&store, &keys, opts.concurrency).await;
+        println!("delete: {}", stats);
+        if stats.errors > 0 {
+            // stores that can't delete (like http) leave all the blocks behind
+            warn!(
+                "{} benchmark blocks of {} bytes could not be deleted and are left in the store",
+                stats.errors, opts.size
+            );
+        }
+
+        Ok(())
+    })
+}
+
 fn pack(opts: PackOptions) -> Result<()> {
     let rt = tokio::runtime::Runtime::new()?;
 
+    let chunker = match opts.chunker.as_str() {
+        "cdc" => rfs::Chunker::cdc(opts.cdc_min, opts.cdc_avg, opts.cdc_max)
+            .map_err(anyhow::Error::msg)?,
+        _ => rfs::Chunker::Fixed,
+    };
+
+    if opts.sparse && chunker != rfs::Chunker::Fixed {
+        warn!("sparse is only supported with the fixed chunker, holes will be uploaded");
+    }
+
     rt.block_on(async move {
-        let store = store::parse_router(opts.store.as_slice()).await?;
-        let meta = fungi::Writer::new(opts.meta, true).await?;
-        rfs::pack(meta, store, opts.target, !opts.no_strip_password, None).await?;
+        let store = store::parse_router_with(opts.store.as_slice(), opts.sharding).await?;
+        let meta = fungi::Writer::new(&opts.meta, true).await?;
+        let options = rfs::PackOptions {
+            strip_password: !opts.no_strip_password,
+            sparse: opts.sparse,
+            chunker,
+            xattrs: opts.xattrs,
+            ..Default::default()
+        };
+        rfs::pack_with(meta, store, opts.target, options, None).await?;
+
+        if let Some(ref key) = opts.sign_key {
+            let key = std::fs::read(key).with_context(|| format!("failed to read {}", key))?;
+            fungi::sign::sign(&opts.meta, &key).await?;
+        }
 
         Ok(())
     })
@@ -242,10 +774,22 @@ fn unpack(opts: UnpackOptions) -> Result<()> {
             .await
             .context("failed to initialize metadata database")?;
 
-        let router = store::get_router(&meta).await?;
+        let router = store::get_router_with_overrides(&meta, &opts.store).await?;
 
         let cache = cache::Cache::new(opts.cache, router);
-        rfs::unpack(&meta, &cache, opts.target, opts.preserve_ownership).await?;
+        match (opts.output, opts.target) {
+            (Some(output), _) => {
+                let file = tokio::fs::File::create(&output)
+                    .await
+                    .with_context(|| format!("failed to create {}", output))?;
+                rfs::export(&meta, &cache, tokio::io::BufWriter::new(file)).await?;
+            }
+            (None, Some(target)) => {
+                rfs::unpack(&meta, &cache, target, opts.preserve_ownership).await?;
+            }
+            (None, None) => unreachable!("target is required without output"),
+        }
+
         Ok(())
     })
 }
@@ -259,19 +803,30 @@ fn mount(opts: MountOptions) -> Result<()> {
     if opts.daemon {
         let pid_file = tempfile::NamedTempFile::new()?;
         let target = opts.target.clone();
-        let mut daemon = daemonize::Daemonize::new()
+        // the logs of the daemon are always kept, they are the only way to know
+        // why a mount failed
+        let (log, out) = match opts.log {
+            Some(ref log) => (std::path::PathBuf::from(log), std::fs::File::create(log)),
+            None => {
+                let log = default_log(&opts.target)?;
+                eprintln!("logs are written to {}", log.display());
+                let out = create_private(&log);
+                (log, out)
+            }
+        };
+
+        let out = out.with_context(|| format!("failed to create log file {}", log.display()))?;
+        let err = out.try_clone()?;
+        let daemon = daemonize::Daemonize::new()
             .working_directory(std::env::current_dir()?)
-            .pid_file(pid_file.path());
-        if let Some(ref log) = opts.log {
-            let out = std::fs::File::create(log)?;
-            let err = out.try_clone()?;
-            daemon = daemon.stdout(out).stderr(err);
-        }
+            .pid_file(pid_file.path())
+            .stdout(out)
+            .stderr(err);
 
         match daemon.execute() {
             daemonize::Outcome::Parent(result) => {
                 result.context("daemonize")?;
-                wait_child(target, pid_file);
+                wait_child(target, pid_file, &log);
                 return Ok(());
             }
             _ => {}
@@ -295,7 +850,199 @@ fn is_mountpoint<S: AsRef<str>>(target: S) -> Result<bool> {
     Ok(output.status.success())
 }
 
-fn wait_child(target: String, mut pid_file: tempfile::NamedTempFile) {
+fn unmount(opts: UnmountOptions) -> Result<()> {
+    if !is_mountpoint(&opts.target)? {
+        anyhow::bail!("target {} is not a mount point", opts.target);
+    }
+
+    let pid = rfs_mount_pid(&opts.target)?;
+    let fusermount = which::which("fusermount").context("looking up 'fusermount' in PATH")?;
+
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        if opts.lazy {
+            fs::lazy_unmount(fusermount, (&opts.target).into()).await
+        } else {
+            fs::unmount(fusermount, (&opts.target).into()).await
+        }
+    })
+    .with_context(|| format!("failed to unmount {}", opts.target))?;
+
+    let pid = match pid {
+        Some(pid) => pid,
+        None => {
+            eprintln!(
+                "the rfs process of {} is not known, not waiting for it to exit",
+                opts.target
+            );
+            return Ok(());
+        }
+    };
+
+    // the rfs process exits once the kernel releases the filesystem
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(opts.timeout);
+    while signal::kill(Pid::from_raw(pid), None).is_ok() {
+        if std::time::Instant::now() >= deadline {
+            if opts.lazy {
+                eprintln!(
+                    "{} is detached, rfs (pid {}) exits once its files are closed",
+                    opts.target, pid
+                );
+                return Ok(());
+            }
+
+            anyhow::bail!("rfs (pid {}) did not exit after the unmount", pid);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    Ok(())
+}
+
+// rfs_mount_pid returns the pid of the rfs process that serves the mount at target if
+// it's known. rfs mounts have the rfs subtype and their pid is kept in the pid file of
+// the mountpoint, mounts of older versions have the g8ufs subtype and the pid as source
+fn rfs_mount_pid<S: AsRef<str>>(target: S) -> Result<Option<i32>> {
+    let target = std::fs::canonicalize(target.as_ref())
+        .with_context(|| format!("failed to resolve {}", target.as_ref()))?;
+    let mounts = std::fs::read_to_string("/proc/self/mounts").context("failed to read mounts")?;
+
+    // the last mount on the target is the one that is visible
+    let mount = mounts
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .filter(|fields| fields.len() > 2)
+        .rfind(|fields| std::path::Path::new(&unescape_mount(fields[1])) == target);
+
+    match mount {
+        Some(fields) if fields[2] == format!("fuse.{}", fs::SUBTYPE) => Ok(pid_file(&target)
+            .ok()
+            .and_then(|pid_file| std::fs::read_to_string(pid_file).ok())
+            .and_then(|pid| pid.trim().parse().ok())),
+        Some(fields) if fields[2] == "fuse.g8ufs" => fields[0]
+            .parse()
+            .map(Some)
+            .with_context(|| format!("invalid rfs mount source '{}'", fields[0])),
+        _ => anyhow::bail!("{} is not an rfs mount", target.display()),
+    }
+}
+
+// unescape_mount decodes the octal escapes (like \040 for a space) of a path in /proc/mounts
+fn unescape_mount(path: &str) -> String {
+    let mut out = Vec::with_capacity(path.len());
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match (bytes[i], code) {
+            (b'\\', Some(code)) => {
+                out.push(code);
+                i += 4;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// default_log returns the log file of a daemon mount without --log, it's named after
+// the mountpoint so each mount has its own log
+fn default_log(target: &str) -> Result<std::path::PathBuf> {
+    mount_file(target, "log")
+}
+
+// pid_file returns the file that has the pid of the rfs process serving the mount at
+// target, it's used by unmount to wait for the process to exit
+fn pid_file<P: AsRef<std::path::Path>>(target: P) -> Result<std::path::PathBuf> {
+    mount_file(target, "pid")
+}
+
+// mount_file returns a file in the runtime directory named after the mountpoint
+fn mount_file<P: AsRef<std::path::Path>>(target: P, ext: &str) -> Result<std::path::PathBuf> {
+    let target = target.as_ref();
+    let target = std::fs::canonicalize(target).unwrap_or_else(|_| target.into());
+    let name: String = target
+        .to_string_lossy()
+        .trim_matches('/')
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+
+    Ok(runtime_dir()?.join(format!("rfs-{}.{}", name, ext)))
+}
+
+// runtime_dir returns the directory of the files of the mounts, /run/rfs for root. The
+// daemon runs as root, so the directory must only be writable by its owner, otherwise
+// another user could replace a file in it with a link to any file of the system
+fn runtime_dir() -> Result<std::path::PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    let uid = nix::unistd::geteuid();
+    let dir = if uid.is_root() {
+        std::path::PathBuf::from("/run/rfs")
+    } else {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => std::path::PathBuf::from(dir).join("rfs"),
+            None => std::env::temp_dir().join(format!("rfs-{}", uid)),
+        }
+    };
+
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => {
+            return Err(err).with_context(|| format!("failed to create {}", dir.display()));
+        }
+        _ => {}
+    }
+
+    let meta = std::fs::symlink_metadata(&dir)
+        .with_context(|| format!("failed to stat {}", dir.display()))?;
+    if !meta.is_dir() || meta.uid() != uid.as_raw() || meta.permissions().mode() & 0o022 != 0 {
+        anyhow::bail!(
+            "{} is not a directory owned and only writable by uid {}",
+            dir.display(),
+            uid
+        );
+    }
+
+    Ok(dir)
+}
+
+// create_private creates or truncates a file of the runtime directory that only its
+// owner can read, a link in place of the file is not followed
+fn create_private(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+// tail returns the last lines of the file, a file that can't be read has no lines
+fn tail(path: &std::path::Path, count: usize) -> Vec<String> {
+    let content = std::fs::read(path).unwrap_or_default();
+    let content = String::from_utf8_lossy(&content);
+    let lines: Vec<&str> = content.lines().collect();
+    lines[lines.len().saturating_sub(count)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn wait_child(target: String, mut pid_file: tempfile::NamedTempFile, log: &std::path::Path) {
     for _ in 0..5 {
         if is_mountpoint(&target).unwrap() {
             return;
@@ -315,21 +1062,224 @@ fn wait_child(target: String, mut pid_file: tempfile::NamedTempFile) {
     }
     // cleanup is not performed if the process is terminated with exit(2)
     drop(pid_file);
-    eprintln!("failed to mount in under 5 seconds, please check logs for more information");
+    eprintln!(
+        "failed to mount in under 5 seconds, last lines of the log {}:",
+        log.display()
+    );
+    for line in tail(log, 20) {
+        eprintln!("  {}", line);
+    }
     std::process::exit(1);
 }
 
-async fn fuse(opts: MountOptions) -> Result<()> {
-    let meta = fungi::Reader::new(opts.meta)
+async fn fuse(mut opts: MountOptions) -> Result<()> {
+    // the mount is named after the flist as given, not the cached copy of a remote flist
+    let fsname = match opts.fsname.take() {
+        Some(fsname) => fsname,
+        None if cache::meta::is_remote(&opts.meta) => opts.meta.clone(),
+        None => std::fs::canonicalize(&opts.meta)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| opts.meta.clone()),
+    };
+
+    if cache::meta::is_remote(&opts.meta) {
+        // a downloaded flist is only a cached copy, changes to it would be lost
+        anyhow::ensure!(!opts.rw, "a writable mount needs a local flist");
+
+        let path = cache::meta::fetch(&opts.cache, &opts.meta, opts.refresh_meta)
+            .await
+            .context("failed to fetch metadata")?;
+        // the checksum and signature are downloaded from the same url with their extension
+        if opts.verify_checksum {
+            cache::meta::fetch_sidecar(&opts.meta, &path, fungi::sign::CHECKSUM_EXT)
+                .await
+                .context("failed to fetch metadata checksum")?;
+        }
+        if opts.verify_signature.is_some() {
+            cache::meta::fetch_sidecar(&opts.meta, &path, fungi::sign::SIGNATURE_EXT)
+                .await
+                .context("failed to fetch metadata signature")?;
+        }
+        opts.meta = path.to_string_lossy().into_owned();
+    }
+
+    if opts.verify_checksum {
+        fungi::sign::verify_checksum(&opts.meta)
+            .await
+            .context("failed to verify metadata checksum")?;
+    }
+
+    if let Some(ref public_key) = opts.verify_signature {
+        let public_key = std::fs::read_to_string(public_key).unwrap_or_else(|_| public_key.clone());
+        let signature = fungi::sign::read_signature(&opts.meta).await?;
+        fungi::sign::verify(&opts.meta, &public_key, &signature)
+            .await
+            .context("failed to verify metadata signature")?;
+    }
+
+    // a writable mount changes the flist while it's mounted
+    let reader_options = fungi::meta::ReaderOptions {
+        immutable: !opts.rw,
+        ..Default::default()
+    };
+    let meta = fungi::Reader::with_options(&opts.meta, reader_options)
         .await
         .context("failed to initialize metadata database")?;
 
-    let router = store::get_router(&meta).await?;
+    let mut router = store::get_router_with_overrides(&meta, &opts.store_override).await?;
+    router.set_race(opts.race);
+    for gap in router.gaps() {
+        warn!(
+            "key prefixes {:02x}-{:02x} are not covered by any route, blocks in this range can't be read",
+            gap.start(),
+            gap.end()
+        );
+    }
 
-    let cache = cache::Cache::new(opts.cache, router);
-    let filesystem = fs::Filesystem::new(meta, cache);
+    let unhealthy = router.unhealthy().await;
+    for (url, err) in unhealthy.iter() {
+        warn!(store_url = url.as_str(); "store '{}' is not reachable: {:#}", url, err);
+    }
+
+    if opts.strict_stores && !unhealthy.is_empty() {
+        anyhow::bail!("{} of the flist stores are not reachable", unhealthy.len());
+    }
+
+    let cache_dir = if opts.isolated_cache || opts.ephemeral_cache {
+        let digest = fs::info::digest(&opts.meta).await?;
+        std::path::Path::new(&opts.cache).join(digest)
+    } else {
+        std::path::PathBuf::from(&opts.cache)
+    };
+
+    let cache = match opts.cache_size {
+        Some(size) => cache::Cache::with_policy(cache_dir.clone(), router, size, opts.cache_policy),
+        None => cache::Cache::new(cache_dir.clone(), router),
+    }
+    .with_verification(opts.verify_chunks);
+    let mut filesystem = fs::Filesystem::new(meta.clone(), cache).with_fsname(fsname);
+    if opts.expose_info {
+        let info = fs::info::build(&opts.meta, &meta).await?;
+        filesystem = filesystem.with_info(info).await?;
+    }
+
+    if opts.rw {
+        let writer = fungi::Writer::new(&opts.meta, false)
+            .await
+            .context("failed to open metadata database for writing")?;
+        filesystem = filesystem
+            .with_overlay(writer, cache_dir.join("overlay"))
+            .await?;
+    }
+
+    if let Some(max) = opts.max_inflight {
+        filesystem = filesystem.with_max_inflight(max);
+    }
+
+    if opts.readahead > 0 {
+        filesystem = filesystem.with_readahead(opts.readahead);
+    }
+
+    filesystem = filesystem.with_attr_cache(
+        opts.attr_cache_ttl.map(std::time::Duration::from_secs),
+        opts.attr_cache_size,
+    );
+
+    if let Some(uid) = opts.squash_uid {
+        let uid = uid.unwrap_or_else(|| nix::unistd::getuid().as_raw());
+        opts.mount_options.push(fs::options::MountOption::Uid(uid));
+    }
+
+    if let Some(gid) = opts.squash_gid {
+        let gid = gid.unwrap_or_else(|| nix::unistd::getgid().as_raw());
+        opts.mount_options.push(fs::options::MountOption::Gid(gid));
+    }
+
+    if !opts.mount_options.is_empty() {
+        filesystem = filesystem.with_mount_options(std::mem::take(&mut opts.mount_options));
+    }
 
-    filesystem.mount(opts.target).await
+    if opts.no_follow_escape {
+        filesystem = filesystem.with_confined_links();
+    }
+
+    if let Some(timeout) = opts.idle_timeout {
+        filesystem = filesystem.with_idle_timeout(std::time::Duration::from_secs(timeout));
+    }
+
+    if let Some(ref path) = opts.control_socket {
+        let digest = fs::info::digest(&opts.meta).await?;
+        filesystem = filesystem.with_control_socket(path, opts.meta.clone(), digest);
+    }
+
+    // this process is the daemon in daemon mode, so the pid is the one serving the mount
+    let pid_file = match pid_file(&opts.target) {
+        Ok(pid_file) => {
+            let written = create_private(&pid_file).and_then(|mut file| {
+                use std::io::Write;
+                file.write_all(std::process::id().to_string().as_bytes())
+            });
+            if let Err(err) = written {
+                warn!("failed to write pid file {}: {}", pid_file.display(), err);
+            }
+            Some(pid_file)
+        }
+        Err(err) => {
+            warn!("failed to write pid file: {:#}", err);
+            None
+        }
+    };
+
+    // the filesystem is unmounted if the process is terminated so the ephemeral
+    // cache can be removed
+    let result = tokio::select! {
+        result = filesystem.mount(&opts.target) => result,
+        signal = terminated() => {
+            info!("received {}, unmounting {}", signal, opts.target);
+            info!("cache: {}", filesystem.cache_stats());
+            let fusermount =
+                which::which("fusermount").context("looking up 'fusermount' in PATH")?;
+            if let Err(err) = fs::unmount(fusermount, (&opts.target).into()).await {
+                warn!("failed to unmount {}: {:#}", opts.target, err);
+            }
+            Ok(())
+        }
+    };
+    if let Some(pid_file) = pid_file {
+        let _ = std::fs::remove_file(pid_file);
+    }
+
+    if !opts.ephemeral_cache {
+        return result;
+    }
+
+    info!("removing ephemeral cache {}", cache_dir.display());
+    if let Err(err) = tokio::fs::remove_dir_all(&cache_dir).await {
+        warn!("failed to remove cache {}: {}", cache_dir.display(), err);
+    }
+
+    result
+}
+
+// terminated waits for a termination signal and returns its name
+async fn terminated() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (mut term, mut int) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(term), Ok(int)) => (term, int),
+        _ => {
+            error!("failed to install signal handlers");
+            return futures::future::pending().await;
+        }
+    };
+
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = int.recv() => "SIGINT",
+    }
 }
 
 fn clone(opts: CloneOptions) -> Result<()> {
@@ -381,3 +1331,195 @@ fn config(opts: ConfigOptions) -> Result<()> {
         Ok(())
     })
 }
+
+fn diff(opts: DiffOptions) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        let old = fungi::Reader::new(&opts.old)
+            .await
+            .with_context(|| format!("failed to open {}", opts.old))?;
+        let new = fungi::Reader::new(&opts.new)
+            .await
+            .with_context(|| format!("failed to open {}", opts.new))?;
+
+        let changes = rfs::diff::diff(&old, &new).await?;
+        if opts.json {
+            println!("{}", serde_json::to_string_pretty(&changes)?);
+        } else {
+            for change in changes.iter() {
+                println!("{}", change);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+fn migrate(opts: MigrateOptions) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        let reader = fungi::Reader::new(&opts.old)
+            .await
+            .with_context(|| format!("failed to open {}", opts.old))?;
+        let writer = fungi::Writer::new(&opts.new, true)
+            .await
+            .with_context(|| format!("failed to create {}", opts.new))?;
+
+        let stats = rfs::migrate::migrate(&reader, &writer).await?;
+        info!(
+            "migrated {} inodes and {} blocks to {}",
+            stats.inodes, stats.blocks, opts.new
+        );
+
+        Ok(())
+    })
+}
+
+fn sign(opts: SignOptions) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        match opts.key {
+            Some(ref key) => {
+                let key = std::fs::read(key).with_context(|| format!("failed to read {}", key))?;
+                fungi::sign::sign(&opts.meta, &key).await?;
+            }
+            None => {
+                fungi::sign::checksum(&opts.meta).await?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+fn keygen(opts: KeygenOptions) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let key = fungi::sign::generate_key()?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&opts.path)
+        .with_context(|| format!("failed to create {}", opts.path))?;
+    file.write_all(&key)?;
+
+    println!("{}", fungi::sign::public_key(&key)?);
+    Ok(())
+}
+
+fn sync(opts: SyncOptions) -> Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        let meta = fungi::Reader::new(opts.meta)
+            .await
+            .context("failed to initialize metadata database")?;
+
+        let from = if opts.from.is_empty() {
+            store::get_router(&meta).await?
+        } else {
+            store::parse_router(&opts.from).await?
+        };
+        let to = store::parse_router(&opts.to).await?;
+
+        let total = meta.unique_blocks_count().await?;
+        let (tx, rx) = std::sync::mpsc::channel::<rfs::sync::Report>();
+        // the progress is printed on a single line of stderr until the sender is dropped
+        let progress = std::thread::spawn(move || {
+            use std::io::Write;
+            const MIB: f64 = 1024.0 * 1024.0;
+
+            let mut stderr = std::io::stderr();
+            for report in rx {
+                let _ = write!(
+                    stderr,
+                    "\r\x1b[2Ksynced {}/{} blocks: {} copied ({:.1} MiB), {} skipped, {} missing",
+                    report.synced,
+                    total,
+                    report.copied,
+                    report.bytes as f64 / MIB,
+                    report.skipped,
+                    report.missing
+                );
+                let _ = stderr.flush();
+            }
+            eprintln!();
+        });
+
+        let report = rfs::sync::sync(&meta, from, to, opts.concurrency, Some(tx)).await;
+        let _ = progress.join();
+        let report = report?;
+
+        info!(
+            "synced {} blocks: {} copied ({} bytes), {} skipped, {} missing, {} failed",
+            report.synced,
+            report.copied,
+            report.bytes,
+            report.skipped,
+            report.missing,
+            report.failed
+        );
+
+        if report.missing > 0 {
+            warn!(
+                "{} blocks are missing from the source store and were not copied",
+                report.missing
+            );
+        }
+
+        anyhow::ensure!(
+            report.failed == 0,
+            "{} blocks could not be copied",
+            report.failed
+        );
+
+        Ok(())
+    })
+}
+
+fn gc(opts: GcOptions) -> Result<()> {
+    anyhow::ensure!(
+        opts.confirm || opts.dry_run,
+        "gc deletes all the blocks of the store that are not used by the given flists, \
+         use --confirm to delete them or --dry-run to only report them"
+    );
+
+    let rt = tokio::runtime::Runtime::new()?;
+
+    rt.block_on(async move {
+        let mut flists = Vec::with_capacity(opts.meta.len());
+        for meta in opts.meta.iter() {
+            let flist = fungi::Reader::new(meta)
+                .await
+                .with_context(|| format!("failed to initialize metadata database '{}'", meta))?;
+            flists.push(flist);
+        }
+
+        let store = store::zdb::ZdbStore::make(&opts.store)
+            .await
+            .context("failed to initialize store")?;
+
+        const PAGE: u32 = 1000;
+        let referenced = rfs::gc::Referenced::from_flists(&flists, PAGE).await?;
+        let stats = rfs::gc::collect(&store, &referenced, opts.batch, opts.dry_run).await?;
+
+        if opts.dry_run {
+            info!(
+                "scanned {} blocks: {} unused blocks ({} bytes) can be deleted",
+                stats.scanned, stats.orphans, stats.reclaimable
+            );
+        } else {
+            info!(
+                "scanned {} blocks: deleted {} unused blocks ({} bytes)",
+                stats.scanned, stats.deleted, stats.reclaimable
+            );
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,181 @@
+use crate::fungi::{
+    meta::{Ino, Inode, Tag, Walk, WalkVisitor},
+    Reader, Result, Writer,
+};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Stats of a migrated flist
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub inodes: u64,
+    pub blocks: u64,
+}
+
+struct Migrator<'a> {
+    reader: &'a Reader,
+    writer: &'a Writer,
+    // the inode numbers of the new flist are allocated on insert, this maps the
+    // inode numbers of the old flist to the new ones to keep the parents right
+    inodes: HashMap<Ino, Ino>,
+    stats: Stats,
+}
+
+#[async_trait::async_trait]
+impl<'a> WalkVisitor for Migrator<'a> {
+    async fn visit(&mut self, _path: &Path, node: &Inode) -> Result<Walk> {
+        // the walk visits a directory before its children, only the parent of the
+        // root is not in the map
+        let parent = self
+            .inodes
+            .get(&node.parent)
+            .copied()
+            .unwrap_or(node.parent);
+
+        let ino = self
+            .writer
+            .inode(Inode {
+                parent,
+                ..node.clone()
+            })
+            .await?;
+        self.inodes.insert(node.ino, ino);
+        self.stats.inodes += 1;
+
+        let blocks = self.reader.blocks(node.ino).await?;
+        let sizes = self.reader.block_sizes(node.ino).await?;
+        for (block, size) in blocks.iter().zip(sizes) {
+            match size {
+                Some(size) => {
+                    self.writer
+                        .sized_block(ino, &block.id, &block.key, size)
+                        .await?
+                }
+                None => self.writer.block(ino, &block.id, &block.key).await?,
+            }
+            self.stats.blocks += 1;
+        }
+
+        for (key, value) in self.reader.xattrs(node.ino).await? {
+            self.writer.xattr(ino, key, &value).await?;
+        }
+
+        Ok(Walk::Continue)
+    }
+}
+
+/// migrate copies the content of an flist to a new flist with the current schema.
+/// The flists created by older versions miss some tables and columns (like the
+/// xattr table and the size of the blocks) that the reader has to work around on
+/// every read, the new flist has all of them. All the entries with their blocks,
+/// link targets and xattrs are kept, with the tags and routes of the flist. The
+/// inode numbers may change, the paths don't
+pub async fn migrate(reader: &Reader, writer: &Writer) -> Result<Stats> {
+    let mut migrator = Migrator {
+        reader,
+        writer,
+        inodes: HashMap::default(),
+        stats: Stats::default(),
+    };
+    reader.walk(&mut migrator).await?;
+
+    for (key, value) in reader.tags().await? {
+        writer.tag(Tag::Custom(key.as_str()), value).await?;
+    }
+
+    for route in reader.routes().await? {
+        writer.route(route.start, route.end, route.url).await?;
+    }
+
+    Ok(migrator.stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::meta::{FileType, Mode};
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+    #[tokio::test]
+    async fn test_migrate() {
+        const OLD: &str = "/tmp/migrate-old.fl";
+        const NEW: &str = "/tmp/migrate-new.fl";
+        let _ = tokio::fs::remove_file(OLD).await;
+
+        // an flist of an older version, blocks have no size and there is no xattr table
+        let opts = SqliteConnectOptions::new()
+            .create_if_missing(true)
+            .filename(OLD);
+        let pool = SqlitePool::connect_with(opts).await.unwrap();
+        for query in [
+            "create table inode (ino integer primary key autoincrement, parent integer,
+                name varchar(255), size integer, uid integer, gid integer, mode integer,
+                rdev integer, ctime integer, mtime integer);",
+            "create table extra (ino integer primary key, data varchar(4096));",
+            "create table block (ino integer, id varchar(32), key varchar(32));",
+            "create table tag (key varchar(10) primary key, value varchar(255));",
+            "create table route (start integer, end integer, url varchar(2048));",
+        ] {
+            sqlx::query(query).execute(&pool).await.unwrap();
+        }
+
+        let dir = Mode::new(FileType::Dir, 0o755).mode();
+        let file = Mode::new(FileType::Regular, 0o644).mode();
+        let link = Mode::new(FileType::Link, 0o777).mode();
+        for (parent, name, size, gid, mode) in [
+            (0, "/", 0, 0, dir),
+            (1, "etc", 0, 0, dir),
+            (2, "passwd", 100, 42, file),
+            (1, "link", 0, 0, link),
+        ] {
+            sqlx::query(
+                "insert into inode (parent, name, size, uid, gid, mode, rdev, ctime, mtime)
+                    values (?, ?, ?, 1000, ?, ?, 0, 0, 0);",
+            )
+            .bind(parent)
+            .bind(name)
+            .bind(size)
+            .bind(gid)
+            .bind(mode)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        for query in [
+            "insert into extra (ino, data) values (4, cast('etc/passwd' as blob));",
+            "insert into block (ino, id, key) values (3, randomblob(32), randomblob(32));",
+            "insert into tag (key, value) values ('version', '0.1');",
+            "insert into route (start, end, url) values (0, 255, 'dir:///tmp/store');",
+        ] {
+            sqlx::query(query).execute(&pool).await.unwrap();
+        }
+        pool.close().await;
+
+        let reader = Reader::new(OLD).await.unwrap();
+        let writer = Writer::new(NEW, true).await.unwrap();
+        let stats = migrate(&reader, &writer).await.unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                inodes: 4,
+                blocks: 1
+            }
+        );
+
+        let migrated = Reader::new(NEW).await.unwrap();
+        let etc = migrated.lookup(1, "etc").await.unwrap().unwrap();
+        let passwd = migrated.lookup(etc.ino, "passwd").await.unwrap().unwrap();
+        assert_eq!(passwd.size, 100);
+        assert_eq!((passwd.uid, passwd.gid), (1000, 42));
+        assert_eq!(migrated.blocks(passwd.ino).await.unwrap().len(), 1);
+        assert_eq!(migrated.block_sizes(passwd.ino).await.unwrap(), vec![None]);
+
+        let link = migrated.lookup(1, "link").await.unwrap().unwrap();
+        assert_eq!(link.data.as_deref(), Some("etc/passwd".as_bytes()));
+        assert_eq!(
+            migrated.tag(Tag::Version).await.unwrap().as_deref(),
+            Some("0.1")
+        );
+        assert_eq!(migrated.routes().await.unwrap().len(), 1);
+    }
+}
@@ -1,8 +1,10 @@
-use crate::fungi::meta::{Ino, Inode};
+use crate::chunker::Chunker;
+use crate::fungi::meta::{Block, Ino, Inode, Tag};
 use crate::fungi::{Error, Result, Writer};
 use crate::store::{BlockStore, Store};
 use anyhow::Context;
 use futures::lock::Mutex;
+use futures::stream::{FuturesOrdered, StreamExt};
 use std::collections::LinkedList;
 use std::ffi::OsString;
 use std::fs::Metadata;
@@ -10,47 +12,93 @@ use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use workers::WorkerPool;
 
-const BLOB_SIZE: usize = 512 * 1024; // 512K
+pub(crate) const BLOB_SIZE: usize = 512 * 1024; // 512K
 
 type FailuresList = Arc<Mutex<Vec<(PathBuf, Error)>>>;
 
 #[derive(Debug)]
 struct Item(Ino, PathBuf, OsString, Metadata);
+
+/// Options of pack_with
+#[derive(Debug, Clone, Copy)]
+pub struct PackOptions {
+    /// strip the passwords from the store urls before they are written to the FL
+    pub strip_password: bool,
+    /// record the chunks that are completely inside a file hole as hole blocks
+    pub sparse: bool,
+    pub chunker: Chunker,
+    /// number of blocks of a file that are uploaded at the same time
+    pub upload_concurrency: usize,
+    /// record the extended attributes of the files
+    pub xattrs: bool,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions {
+            strip_password: false,
+            sparse: false,
+            chunker: Chunker::Fixed,
+            upload_concurrency: 1,
+            xattrs: false,
+        }
+    }
+}
+
 /// creates an FL from the given root location. It takes ownership of the writer because
 /// it's logically incorrect to store multiple filessytem in the same FL.
 /// All file chunks will then be uploaded to the provided store
 ///
+/// If sparse is set, chunks of a file that are completely inside a hole are not uploaded
+/// and recorded as hole blocks instead. Sparse is only supported with the fixed chunker.
 pub async fn pack<P: Into<PathBuf>, S: Store>(
     writer: Writer,
     store: S,
     root: P,
     strip_password: bool,
+    sparse: bool,
+    chunker: Chunker,
     sender: Option<Sender<u32>>,
 ) -> Result<()> {
-    use tokio::fs;
+    let options = PackOptions {
+        strip_password,
+        sparse,
+        chunker,
+        ..Default::default()
+    };
+
+    pack_with(writer, store, root, options, sender).await
+}
 
-    // building routing table from store information
-    for route in store.routes() {
-        let mut store_url = route.url;
+/// pack_with is the same as pack but takes the pack options. Blocks of the same file
+/// are uploaded concurrently up to the upload concurrency, they are still recorded
+/// in the FL in the file order
+pub async fn pack_with<P: Into<PathBuf>, S: Store>(
+    writer: Writer,
+    store: S,
+    root: P,
+    options: PackOptions,
+    sender: Option<Sender<u32>>,
+) -> Result<()> {
+    use tokio::fs;
 
-        if strip_password {
-            let mut url = url::Url::parse(&store_url).context("failed to parse store url")?;
-            if url.password().is_some() {
-                url.set_password(None)
-                    .map_err(|_| anyhow::anyhow!("failed to strip password"))?;
+    let PackOptions {
+        strip_password,
+        sparse,
+        chunker,
+        upload_concurrency,
+        xattrs,
+    } = options;
 
-                store_url = url.to_string();
-            }
-        }
+    // building routing table from store information
+    write_routes(&writer, &store, strip_password).await?;
 
+    if chunker != Chunker::Fixed {
         writer
-            .route(
-                route.start.unwrap_or(u8::MIN),
-                route.end.unwrap_or(u8::MAX),
-                store_url,
-            )
+            .tag(Tag::Custom("chunker"), chunker.to_string())
             .await?;
     }
 
@@ -64,7 +112,14 @@ pub async fn pack<P: Into<PathBuf>, S: Store>(
     let mut list = LinkedList::default();
 
     let failures = FailuresList::default();
-    let uploader = Uploader::new(store, writer.clone(), Arc::clone(&failures));
+    let uploader = Uploader::new(
+        store,
+        writer.clone(),
+        Arc::clone(&failures),
+        sparse && chunker == Chunker::Fixed,
+        chunker,
+        upload_concurrency,
+    );
     let mut pool = workers::WorkerPool::new(uploader.clone(), super::PARALLEL_UPLOAD);
 
     pack_one(
@@ -72,17 +127,37 @@ pub async fn pack<P: Into<PathBuf>, S: Store>(
         &writer,
         &mut pool,
         Item(0, root, OsString::from("/"), meta),
+        xattrs,
+        chunker,
         sender.as_ref(),
     )
     .await?;
 
     while !list.is_empty() {
         let dir = list.pop_back().unwrap();
-        pack_one(&mut list, &writer, &mut pool, dir, sender.as_ref()).await?;
+        pack_one(
+            &mut list,
+            &writer,
+            &mut pool,
+            dir,
+            xattrs,
+            chunker,
+            sender.as_ref(),
+        )
+        .await?;
     }
 
     pool.close().await;
 
+    // stores can buffer the blocks, they must all be written before the fl is
+    // considered complete
+    uploader
+        .store
+        .get_ref()
+        .flush()
+        .await
+        .context("failed to flush blocks to store")?;
+
     let failures = failures.lock().await;
     if failures.is_empty() {
         return Ok(());
@@ -99,12 +174,52 @@ pub async fn pack<P: Into<PathBuf>, S: Store>(
     )))
 }
 
+/// write the store routes to the flist, optionally stripping the passwords
+/// from the store urls
+pub(crate) async fn write_routes<S: Store>(
+    writer: &Writer,
+    store: &S,
+    strip_password: bool,
+) -> Result<()> {
+    for route in store.routes() {
+        let mut store_url = route.url;
+
+        if strip_password {
+            let mut url = url::Url::parse(&store_url).context("failed to parse store url")?;
+            // the urls of a fallback store and of the members of a ring can also
+            // have a password
+            let fallback = crate::store::tier::strip_fallback_password(&mut url)?;
+            let members = crate::store::ring::strip_member_passwords(&mut url)?;
+            if url.password().is_some() {
+                url.set_password(None)
+                    .map_err(|_| anyhow::anyhow!("failed to strip password"))?;
+
+                store_url = url.to_string();
+            } else if fallback || members {
+                store_url = url.to_string();
+            }
+        }
+
+        writer
+            .route(
+                route.start.unwrap_or(u8::MIN),
+                route.end.unwrap_or(u8::MAX),
+                store_url,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
 /// pack_one is called for each dir
 async fn pack_one<S: Store>(
     list: &mut LinkedList<Item>,
     writer: &Writer,
     pool: &mut WorkerPool<Uploader<S>>,
     Item(parent, path, name, meta): Item,
+    xattrs: bool,
+    chunker: Chunker,
     sender: Option<&Sender<u32>>,
 ) -> Result<()> {
     use std::os::unix::fs::MetadataExt;
@@ -126,6 +241,10 @@ async fn pack_one<S: Store>(
         })
         .await?;
 
+    if xattrs {
+        write_xattrs(writer, current, &path).await?;
+    }
+
     let mut children = fs::read_dir(&path)
         .await
         .context("failed to list dir children")?;
@@ -174,10 +293,19 @@ async fn pack_one<S: Store>(
             })
             .await?;
 
+        if xattrs {
+            write_xattrs(writer, child_ino, &child_path).await?;
+        }
+
         if !meta.is_file() {
             continue;
         }
 
+        // a file that the mount can't serve fails the pack before it's uploaded
+        chunker
+            .check_size(meta.size())
+            .map_err(|err| anyhow::anyhow!("{}: {}", child_path.display(), err))?;
+
         let worker = pool.get().await;
         worker
             .send((child_ino, child_path))
@@ -193,7 +321,10 @@ where
     store: Arc<BlockStore<S>>,
     failures: FailuresList,
     writer: Writer,
-    buffer: [u8; BLOB_SIZE],
+    sparse: bool,
+    chunker: Chunker,
+    concurrency: usize,
+    buffer: Vec<u8>,
 }
 
 impl<S> Clone for Uploader<S>
@@ -205,7 +336,10 @@ where
             store: Arc::clone(&self.store),
             failures: Arc::clone(&self.failures),
             writer: self.writer.clone(),
-            buffer: [0; BLOB_SIZE],
+            sparse: self.sparse,
+            chunker: self.chunker,
+            concurrency: self.concurrency,
+            buffer: vec![0; self.chunker.max_size()],
         }
     }
 }
@@ -214,39 +348,259 @@ impl<S> Uploader<S>
 where
     S: Store,
 {
-    fn new(store: BlockStore<S>, writer: Writer, failures: FailuresList) -> Self {
+    fn new(
+        store: BlockStore<S>,
+        writer: Writer,
+        failures: FailuresList,
+        sparse: bool,
+        chunker: Chunker,
+        concurrency: usize,
+    ) -> Self {
         Self {
             store: Arc::new(store),
             failures,
             writer,
-            buffer: [0; BLOB_SIZE],
+            sparse,
+            chunker,
+            concurrency: concurrency.max(1),
+            buffer: vec![0; chunker.max_size()],
         }
     }
 
     async fn upload(&mut self, ino: Ino, path: &Path) -> Result<()> {
+        if self.chunker != Chunker::Fixed {
+            return self.upload_chunked(ino, path).await;
+        }
+
+        use std::io::SeekFrom;
+        use std::os::unix::io::AsRawFd;
         use tokio::fs;
-        use tokio::io::AsyncReadExt;
-        use tokio::io::BufReader;
+        use tokio::io::AsyncSeekExt;
 
         // create file blocks
-        let fd = fs::OpenOptions::default().read(true).open(path).await?;
+        let mut fd = fs::OpenOptions::default().read(true).open(path).await?;
+        let size = fd.metadata().await?.len();
 
-        let mut reader = BufReader::new(fd);
+        // blocks that are being uploaded, in the file order
+        let mut pending = FuturesOrdered::new();
+        let mut offset: u64 = 0;
         loop {
-            let size = reader.read(&mut self.buffer).await?;
-            if size == 0 {
-                break;
-            }
+            let data = if self.sparse
+                && offset + BLOB_SIZE as u64 <= size
+                && is_hole(fd.as_raw_fd(), offset, BLOB_SIZE as u64)
+            {
+                offset += BLOB_SIZE as u64;
+                None
+            } else {
+                // is_hole can move the file cursor so we always seek to the
+                // block offset before reading
+                fd.seek(SeekFrom::Start(offset)).await?;
+                let size = read_full(&mut fd, &mut self.buffer).await?;
+                if size == 0 {
+                    break;
+                }
+                offset += size as u64;
+                Some(self.buffer[..size].to_vec())
+            };
 
             // write block to remote store
-            let block = self.store.set(&self.buffer[..size]).await?;
+            pending.push_back(set_block(Arc::clone(&self.store), data));
+            if pending.len() < self.concurrency {
+                continue;
+            }
 
             // write block info to meta
+            if let Some(result) = pending.next().await {
+                let (block, _) = result?;
+                self.writer.block(ino, &block.id, &block.key).await?;
+            }
+        }
+
+        while let Some(result) = pending.next().await {
+            let (block, _) = result?;
             self.writer.block(ino, &block.id, &block.key).await?;
         }
 
         Ok(())
     }
+
+    // upload_chunked uploads a file split into blocks by the chunker, the size of
+    // each block is recorded since it is not the fixed block size
+    async fn upload_chunked(&mut self, ino: Ino, path: &Path) -> Result<()> {
+        let mut fd = tokio::fs::File::open(path).await?;
+
+        let mut pending = FuturesOrdered::new();
+        let mut filled = 0;
+        let mut eof = false;
+        loop {
+            if !eof {
+                filled += read_full(&mut fd, &mut self.buffer[filled..]).await?;
+                eof = filled < self.buffer.len();
+            }
+
+            let size = match self.chunker.cut(&self.buffer[..filled], eof) {
+                Some(size) => size,
+                None => break,
+            };
+
+            let data = self.buffer[..size].to_vec();
+            pending.push_back(set_block(Arc::clone(&self.store), Some(data)));
+
+            // keep the rest of the buffer for the next block
+            self.buffer.copy_within(size..filled, 0);
+            filled -= size;
+
+            if pending.len() < self.concurrency {
+                continue;
+            }
+
+            if let Some(result) = pending.next().await {
+                let (block, size) = result?;
+                self.writer
+                    .sized_block(ino, &block.id, &block.key, size)
+                    .await?;
+            }
+        }
+
+        while let Some(result) = pending.next().await {
+            let (block, size) = result?;
+            self.writer
+                .sized_block(ino, &block.id, &block.key, size)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// set_block uploads the block data and returns the block with its size, no data
+// is a sparse file hole
+async fn set_block<S: Store>(
+    store: Arc<BlockStore<S>>,
+    data: Option<Vec<u8>>,
+) -> Result<(Block, u64)> {
+    match data {
+        Some(data) => Ok((store.set(&data).await?, data.len() as u64)),
+        None => Ok((Block::hole(), BLOB_SIZE as u64)),
+    }
+}
+
+/// is_hole checks if the range [offset, offset+len) of the file has no data
+/// using SEEK_DATA. Filesystems that doesn't support holes will always report data
+fn is_hole(fd: std::os::unix::io::RawFd, offset: u64, len: u64) -> bool {
+    use nix::unistd::{lseek, Whence};
+
+    match lseek(fd, offset as i64, Whence::SeekData) {
+        Ok(data) => data as u64 >= offset + len,
+        // no more data after offset
+        Err(nix::errno::Errno::ENXIO) => true,
+        Err(_) => false,
+    }
+}
+
+// write_xattrs records the extended attributes of the file at path
+async fn write_xattrs(writer: &Writer, ino: Ino, path: &Path) -> Result<()> {
+    let xattrs = read_xattrs(path)
+        .with_context(|| format!("failed to read xattrs of {}", path.display()))?;
+
+    for (key, value) in xattrs {
+        writer.xattr(ino, key, &value).await?;
+    }
+
+    Ok(())
+}
+
+/// read_xattrs returns the extended attributes of a file without following links.
+/// A file on a filesystem that doesn't support xattrs has none
+fn read_xattrs(path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    use std::ffi::CString;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let names = match xattr_call(|buf, size| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::default()),
+        Err(err) => return Err(err),
+    };
+
+    let mut xattrs = Vec::default();
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        let key = match std::str::from_utf8(name) {
+            Ok(key) => key.to_owned(),
+            Err(_) => {
+                log::warn!(
+                    "skipping xattr {} of {}, the name is not valid utf8",
+                    String::from_utf8_lossy(name),
+                    path.display()
+                );
+                continue;
+            }
+        };
+
+        let name = CString::new(name)?;
+        let value = match xattr_call(|buf, size| unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                name.as_ptr(),
+                buf as *mut libc::c_void,
+                size,
+            )
+        }) {
+            Ok(value) => value,
+            // removed after the names were listed
+            Err(err) if err.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(err) => return Err(err),
+        };
+
+        xattrs.push((key, value));
+    }
+
+    Ok(xattrs)
+}
+
+// xattr_call runs an xattr syscall that fills a buffer, the size of the buffer is
+// queried first and the call is tried again if the value grew in between
+fn xattr_call<F>(call: F) -> std::io::Result<Vec<u8>>
+where
+    F: Fn(*mut u8, usize) -> isize,
+{
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0; size as usize];
+        let read = call(buf.as_mut_ptr(), buf.len());
+        if read < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(err);
+        }
+
+        buf.truncate(read as usize);
+        return Ok(buf);
+    }
+}
+
+// read_full fills the buffer unless EOF is reached
+pub(crate) async fn read_full<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut [u8],
+) -> Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        let read = reader.read(&mut buffer[total..]).await?;
+        if read == 0 {
+            break;
+        }
+        total += read;
+    }
+
+    Ok(total)
 }
 
 #[async_trait::async_trait]
@@ -0,0 +1,184 @@
+use super::{Result, Route, Store};
+use std::sync::Mutex;
+
+/// default number of blobs written in one batch
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+/// a batch is written once its blobs reach this size even if it's not full
+const MAX_BATCH_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Default)]
+struct Buffer {
+    blobs: Vec<(Vec<u8>, Vec<u8>)>,
+    bytes: usize,
+}
+
+impl Buffer {
+    fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.blobs
+            .iter()
+            .find(|(buffered, _)| buffered == key)
+            .map(|(_, blob)| blob)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        while let Some(at) = self.blobs.iter().position(|(buffered, _)| buffered == key) {
+            let (_, blob) = self.blobs.remove(at);
+            self.bytes -= blob.len();
+        }
+    }
+
+    fn take(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.bytes = 0;
+        std::mem::take(&mut self.blobs)
+    }
+}
+
+/// BatchStore buffers the written blobs and writes them to the wrapped store in
+/// batches with set_many, which saves a round trip per blob on stores like zdb that
+/// pipeline the batch. The buffered blobs can be read back before they are written.
+/// flush must be called once all the blobs are set, the blobs still buffered when
+/// the store is dropped are lost. A failed batch fails the set (or the flush) that
+/// writes it, not necessarily the set of the failed blobs
+pub struct BatchStore<S> {
+    store: S,
+    size: usize,
+    buffer: Mutex<Buffer>,
+}
+
+impl<S> BatchStore<S>
+where
+    S: Store,
+{
+    pub fn new(store: S, size: usize) -> Self {
+        Self {
+            store,
+            size: size.max(1),
+            buffer: Mutex::default(),
+        }
+    }
+
+    async fn write(&self, blobs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        if blobs.is_empty() {
+            return Ok(());
+        }
+
+        let batch: Vec<(&[u8], &[u8])> = blobs
+            .iter()
+            .map(|(key, blob)| (key.as_slice(), blob.as_slice()))
+            .collect();
+
+        self.store.set_many(&batch).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for BatchStore<S>
+where
+    S: Store,
+{
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let buffered = self
+            .buffer
+            .lock()
+            .expect("failed to lock buffer")
+            .get(key)
+            .cloned();
+
+        match buffered {
+            Some(blob) => Ok(blob),
+            None => self.store.get(key).await,
+        }
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+        let batch = {
+            let mut buffer = self.buffer.lock().expect("failed to lock buffer");
+            buffer.blobs.push((key.into(), blob.into()));
+            buffer.bytes += blob.len();
+            if buffer.blobs.len() < self.size && buffer.bytes < MAX_BATCH_BYTES {
+                return Ok(());
+            }
+
+            buffer.take()
+        };
+
+        self.write(batch).await
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        let buffered = self
+            .buffer
+            .lock()
+            .expect("failed to lock buffer")
+            .get(key)
+            .is_some();
+
+        if buffered {
+            return Ok(true);
+        }
+
+        self.store.exists(key).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        // a buffered blob would be written after the delete
+        self.buffer
+            .lock()
+            .expect("failed to lock buffer")
+            .remove(key);
+
+        self.store.delete(key).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let batch = self.buffer.lock().expect("failed to lock buffer").take();
+        self.write(batch).await?;
+        self.store.flush().await
+    }
+
+    async fn health(&self) -> Result<()> {
+        self.store.health().await
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        self.store.routes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::mem::MemStore;
+
+    #[tokio::test]
+    async fn test_batch_store() {
+        let inner = MemStore::default();
+        let store = BatchStore::new(inner.clone(), 3);
+
+        store.set(b"a", b"blob a").await.unwrap();
+        store.set(b"b", b"blob b").await.unwrap();
+        // buffered blobs are not written yet but can be read back
+        assert!(!inner.exists(b"a").await.unwrap());
+        assert!(store.exists(b"a").await.unwrap());
+        assert_eq!(store.get(b"b").await.unwrap(), b"blob b");
+
+        // the batch is written once it's full
+        store.set(b"c", b"blob c").await.unwrap();
+        for key in [b"a", b"b", b"c"] {
+            assert!(inner.exists(key).await.unwrap());
+        }
+
+        store.set(b"d", b"blob d").await.unwrap();
+        assert!(!inner.exists(b"d").await.unwrap());
+        store.flush().await.unwrap();
+        assert_eq!(inner.get(b"d").await.unwrap(), b"blob d");
+
+        // a deleted blob that is still buffered is never written
+        store.set(b"e", b"blob e").await.unwrap();
+        store.delete(b"e").await.unwrap();
+        store.delete(b"d").await.unwrap();
+        store.flush().await.unwrap();
+        assert!(!inner.exists(b"d").await.unwrap());
+        assert!(!inner.exists(b"e").await.unwrap());
+    }
+}
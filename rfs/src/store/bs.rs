@@ -13,7 +13,16 @@ fn hash(input: &[u8]) -> GenericArray<u8, generic_array::typenum::U32> {
     GenericArray::from_slice(hash.as_bytes()).to_owned()
 }
 
-/// The block store builds on top of a store and adds encryption and compression
+/// verify_block checks that the plain content of a block matches the block, the
+/// key of a block is the hash of its plain content
+pub fn verify_block(block: &Block, plain: &[u8]) -> bool {
+    hash(plain).as_slice() == block.key
+}
+
+/// The block store builds on top of a store and adds encryption and compression.
+/// A block is snap compressed then encrypted with aes-256-gcm, the key is the blake2b
+/// hash of the plain content and the nonce is the first 12 bytes of the key. The id
+/// of the block in the store is the blake2b hash of the encrypted blob
 #[derive(Clone, Debug)]
 pub struct BlockStore<S: Store> {
     store: S,
@@ -36,8 +45,18 @@ where
         self.store
     }
 
+    /// get_ref returns the store the blocks are written to
+    pub fn get_ref(&self) -> &S {
+        &self.store
+    }
+
     pub async fn get(&self, block: &Block) -> Result<Vec<u8>> {
         let encrypted = self.store.get(&block.id).await?;
+        // an encrypted blob always has the auth tag even if the content is empty
+        // (empty files have no blocks at all) so an empty blob is always corrupt
+        if encrypted.is_empty() {
+            return Err(Error::InvalidBlob);
+        }
 
         let cipher = Aes256Gcm::new_from_slice(&block.key).map_err(|_| Error::InvalidKey)?;
         let nonce = Nonce::from_slice(&block.key[..12]);
@@ -130,4 +149,57 @@ mod test {
 
         assert_eq!(blob.as_bytes(), received.as_slice());
     }
+
+    #[tokio::test]
+    async fn test_empty_blob() {
+        let store = InMemoryStore::default();
+        let map = Arc::clone(&store.map);
+        let block_store = BlockStore::from(store);
+
+        // empty content is still a valid block
+        let block = block_store.set(&[]).await.unwrap();
+        let received = block_store.get(&block).await.unwrap();
+        assert!(received.is_empty());
+
+        // but an empty blob in the store is not
+        map.lock().await.insert(block.id.to_vec(), vec![]);
+        let err = block_store.get(&block).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidBlob));
+    }
+
+    #[tokio::test]
+    async fn test_encryption() {
+        let store = InMemoryStore::default();
+        let map = Arc::clone(&store.map);
+        let block_store = BlockStore::from(store);
+
+        let blob = b"some random data to store, some random data to store";
+        let block = block_store.set(blob).await.unwrap();
+
+        // the store only has the encrypted blob keyed by its hash
+        let encrypted = map.lock().await.get(block.id.as_slice()).cloned().unwrap();
+        assert_ne!(encrypted.as_slice(), blob.as_slice());
+        assert_eq!(hash(&encrypted).as_slice(), block.id);
+        assert!(verify_block(&block, blob));
+
+        // the same content is always the same block
+        let again = block_store.set(blob).await.unwrap();
+        assert_eq!((again.id, again.key), (block.id, block.key));
+
+        // the block can't be read with another key
+        let other = block_store.set(b"other data").await.unwrap();
+        let wrong = Block {
+            id: block.id,
+            key: other.key,
+        };
+        let err = block_store.get(&wrong).await.unwrap_err();
+        assert!(matches!(err, Error::EncryptionError));
+
+        // and a corrupted blob fails the authentication
+        let mut corrupted = encrypted.clone();
+        corrupted[0] ^= 0xff;
+        map.lock().await.insert(block.id.to_vec(), corrupted);
+        let err = block_store.get(&block).await.unwrap_err();
+        assert!(matches!(err, Error::EncryptionError));
+    }
 }
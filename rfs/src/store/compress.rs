@@ -0,0 +1,245 @@
+use super::{Error, Result, Route, Store};
+use url::Url;
+
+/// query parameter of the compression of a store, the value is `zstd` or
+/// `zstd:<level>`
+pub const PARAM_COMPRESS: &str = "compress";
+
+const DEFAULT_LEVEL: i32 = 3;
+// compressed blobs start with this header followed by the zstd frame (which starts
+// with the zstd magic number), blobs without it are stored as is
+const MAGIC: &[u8] = b"rfsz";
+
+/// Compression settings of a CompressedStore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// zstd compression level
+    pub level: i32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            level: DEFAULT_LEVEL,
+        }
+    }
+}
+
+impl Compression {
+    /// for_url returns the compression set by the url query parameter, the parameter
+    /// is removed from the url. None is returned if the url has no compression
+    pub fn for_url(u: &mut Url) -> Result<Option<Self>> {
+        let mut compression = None;
+
+        let mut query = Vec::default();
+        for (key, value) in u.query_pairs() {
+            if key != PARAM_COMPRESS {
+                query.push((key.to_string(), value.to_string()));
+                continue;
+            }
+
+            compression = Some(value.parse()?);
+        }
+
+        if query.is_empty() {
+            u.set_query(None);
+        } else {
+            u.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        Ok(compression)
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Other(anyhow::anyhow!("invalid compression '{}'", s));
+
+        let level = match s.split_once(':') {
+            None if s == "zstd" => DEFAULT_LEVEL,
+            Some(("zstd", level)) => level.parse().map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+
+        if !zstd::compression_level_range().contains(&level) {
+            return Err(invalid());
+        }
+
+        Ok(Compression { level })
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "zstd:{}", self.level)
+    }
+}
+
+/// CompressedStore wraps a store and compresses the blobs with zstd. A blob is only
+/// stored compressed if that makes it smaller, so incompressible blobs are stored as
+/// is and blobs written without compression can still be read.
+///
+/// Note that blocks written by a BlockStore are already compressed before they are
+/// encrypted so they are (almost always) stored as is.
+pub struct CompressedStore<S: Store> {
+    store: S,
+    compression: Compression,
+}
+
+impl<S: Store> CompressedStore<S> {
+    pub fn new(store: S, compression: Compression) -> Self {
+        Self { store, compression }
+    }
+}
+
+fn compress(blob: &[u8], level: i32) -> Result<Option<Vec<u8>>> {
+    let compressed = zstd::bulk::compress(blob, level)?;
+    if MAGIC.len() + compressed.len() >= blob.len() {
+        return Ok(None);
+    }
+
+    let mut data = Vec::with_capacity(MAGIC.len() + compressed.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&compressed);
+
+    Ok(Some(data))
+}
+
+fn decompress(blob: Vec<u8>) -> Vec<u8> {
+    // a blob stored as is can start with the header by chance (an encrypted blob
+    // is random data), it's then only taken as compressed if it's a valid frame
+    if let Some(frame) = blob.strip_prefix(MAGIC) {
+        if let Ok(data) = zstd::stream::decode_all(frame) {
+            return data;
+        }
+    }
+
+    blob
+}
+
+#[async_trait::async_trait]
+impl<S: Store> Store for CompressedStore<S> {
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let blob = self.store.get(key).await?;
+        Ok(decompress(blob))
+    }
+
+    async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let blobs = self.store.get_many(keys).await?;
+        Ok(blobs.into_iter().map(|blob| blob.map(decompress)).collect())
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+        match compress(blob, self.compression.level)? {
+            Some(compressed) => self.store.set(key, &compressed).await,
+            None => self.store.set(key, blob).await,
+        }
+    }
+
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        let compressed = blobs
+            .iter()
+            .map(|(_, blob)| compress(blob, self.compression.level))
+            .collect::<Result<Vec<_>>>()?;
+
+        let batch: Vec<(&[u8], &[u8])> = blobs
+            .iter()
+            .zip(&compressed)
+            .map(|((key, blob), compressed)| (*key, compressed.as_deref().unwrap_or(*blob)))
+            .collect();
+
+        self.store.set_many(&batch).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.store.flush().await
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        self.store.exists(key).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.store.delete(key).await
+    }
+
+    async fn health(&self) -> Result<()> {
+        self.store.health().await
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        // the compression is kept in the routes so the stores built from an fl
+        // can read the compressed blobs
+        self.store
+            .routes()
+            .into_iter()
+            .map(|mut route| {
+                if let Ok(mut u) = Url::parse(&route.url) {
+                    u.query_pairs_mut()
+                        .append_pair(PARAM_COMPRESS, &self.compression.to_string());
+                    route.url = u.into();
+                }
+                route
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::mem::MemStore;
+    use rand::RngCore;
+
+    #[tokio::test]
+    async fn test_compressed_store() {
+        let inner = MemStore::default();
+        let store = CompressedStore::new(inner.clone(), Compression { level: 9 });
+
+        // compressible
+        let text = "hello world ".repeat(1000).into_bytes();
+        store.set(b"text", &text).await.unwrap();
+        let stored = inner.get(b"text").await.unwrap();
+        assert!(stored.starts_with(MAGIC));
+        assert!(stored.len() < text.len());
+        assert_eq!(store.get(b"text").await.unwrap(), text);
+
+        // incompressible
+        let mut random = vec![0u8; 4096];
+        rand::thread_rng().fill_bytes(&mut random);
+        store.set(b"random", &random).await.unwrap();
+        assert_eq!(inner.get(b"random").await.unwrap(), random);
+        assert_eq!(store.get(b"random").await.unwrap(), random);
+
+        // blobs written without compression, even if they look like a compressed blob
+        let legacy = [MAGIC, b"not a zstd frame".as_slice()].concat();
+        inner.set(b"legacy", &legacy).await.unwrap();
+        assert_eq!(store.get(b"legacy").await.unwrap(), legacy);
+
+        // empty blobs are returned as is so they are still rejected as invalid blobs
+        store.set(b"empty", b"").await.unwrap();
+        assert!(store.get(b"empty").await.unwrap().is_empty());
+
+        assert!(matches!(
+            store.get(b"missing").await,
+            Err(Error::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut u = Url::parse("zdb://hub.grid.tf/ns?compress=zstd:9&pool=10").unwrap();
+        let compression = Compression::for_url(&mut u).unwrap();
+        assert_eq!(compression, Some(Compression { level: 9 }));
+        assert_eq!(u.as_str(), "zdb://hub.grid.tf/ns?pool=10");
+
+        assert_eq!(
+            "zstd".parse::<Compression>().unwrap(),
+            Compression::default()
+        );
+        assert!("gzip".parse::<Compression>().is_err());
+        assert!("zstd:100".parse::<Compression>().is_err());
+    }
+}
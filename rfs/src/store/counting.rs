@@ -0,0 +1,84 @@
+use super::{Error, Result, Route, Store};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// CountingStore is a store that drops all the blobs written to it and only
+/// keeps count of them. It's used for dry runs to know how many blocks (and bytes)
+/// would have been uploaded to a real store.
+#[derive(Clone, Default)]
+pub struct CountingStore {
+    inner: Arc<Mutex<Counter>>,
+}
+
+#[derive(Default)]
+struct Counter {
+    keys: HashSet<Vec<u8>>,
+    stats: Stats,
+}
+
+/// Stats of the blobs written to a CountingStore
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// number of blobs written
+    pub blocks: u64,
+    /// number of distinct blobs written
+    pub unique_blocks: u64,
+    /// size of all blobs written
+    pub bytes: u64,
+    /// size of distinct blobs, this is what is actually uploaded to a store
+    pub unique_bytes: u64,
+}
+
+impl CountingStore {
+    pub fn stats(&self) -> Stats {
+        self.inner.lock().unwrap().stats.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for CountingStore {
+    async fn get(&self, _key: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::KeyNotFound)
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+        let mut counter = self.inner.lock().unwrap();
+        let size = blob.len() as u64;
+        counter.stats.blocks += 1;
+        counter.stats.bytes += size;
+        if counter.keys.insert(key.into()) {
+            counter.stats.unique_blocks += 1;
+            counter.stats.unique_bytes += size;
+        }
+
+        Ok(())
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::url("counting://")]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counting() {
+        let store = CountingStore::default();
+        store.set(b"a", b"hello").await.unwrap();
+        store.set(b"b", b"world!").await.unwrap();
+        store.set(b"a", b"hello").await.unwrap();
+
+        assert_eq!(
+            store.stats(),
+            Stats {
+                blocks: 3,
+                unique_blocks: 2,
+                bytes: 16,
+                unique_bytes: 11,
+            }
+        );
+        assert!(matches!(store.get(b"a").await, Err(Error::KeyNotFound)));
+    }
+}
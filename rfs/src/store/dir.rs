@@ -6,43 +6,83 @@ use tokio::fs;
 use url;
 
 pub const SCHEME: &str = "dir";
+/// schemes of the dir store, `file` is an alias of `dir`
+pub const SCHEMES: [&str; 2] = [SCHEME, "file"];
 
-/// DirStore is a simple store that store blobs on the filesystem
-/// and is mainly used for testing
+/// query parameter of the number of directory levels the blobs are sharded into
+pub const PARAM_DEPTH: &str = "depth";
+const DEFAULT_DEPTH: usize = 1;
+const MAX_DEPTH: usize = 4;
 
+/// DirStore is a simple store that store blobs on the filesystem
+/// and is mainly used for testing and air-gapped deployments.
+/// Blobs are sharded by the first byte of the key `{root}/{ab}/{hexkey}`,
+/// the same layout the http store reads. Bigger stores can use one directory
+/// level per byte with the `depth` param, `?depth=2` gives `{root}/{ab}/{cd}/{hexkey}`
 #[derive(Clone)]
 pub struct DirStore {
     root: PathBuf,
+    depth: usize,
 }
 
 impl DirStore {
     pub async fn make<U: AsRef<str>>(url: &U) -> Result<DirStore> {
         let u = url::Url::parse(url.as_ref())?;
-        if u.scheme() != SCHEME {
+        if !SCHEMES.contains(&u.scheme()) {
             return Err(Error::InvalidScheme(u.scheme().into(), SCHEME.into()));
         }
 
-        Ok(DirStore::new(u.path()).await?)
+        let depth = match u.query_pairs().find(|(key, _)| key == PARAM_DEPTH) {
+            Some((_, value)) => match value.parse() {
+                Ok(depth) if (1..=MAX_DEPTH).contains(&depth) => depth,
+                _ => {
+                    return Err(Error::Other(anyhow::anyhow!(
+                        "invalid dir store depth '{}' expected 1 to {}",
+                        value,
+                        MAX_DEPTH
+                    )))
+                }
+            },
+            None => DEFAULT_DEPTH,
+        };
+
+        Ok(DirStore::new(u.path()).await?.with_depth(depth))
     }
+
     pub async fn new<P: Into<PathBuf>>(root: P) -> Result<Self> {
         let root = root.into();
         fs::create_dir_all(&root).await?;
-        Ok(Self { root })
+        Ok(Self {
+            root,
+            depth: DEFAULT_DEPTH,
+        })
+    }
+
+    /// with_depth sets the number of directory levels the blobs are sharded into
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth.clamp(1, MAX_DEPTH);
+        self
+    }
+
+    fn path(&self, key: &[u8]) -> PathBuf {
+        let mut path = self.root.clone();
+        for b in key.iter().take(self.depth) {
+            path.push(hex::encode([*b]));
+        }
+
+        path.join(hex::encode(key))
     }
 }
 
 #[async_trait::async_trait]
 impl Store for DirStore {
     async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
-        let file_name = hex::encode(key);
-        let dir_path = self.root.join(&file_name[0..2]);
-
-        let mut path = dir_path.join(&file_name);
-        let data = match fs::read(&path).await {
+        let data = match fs::read(self.path(key)).await {
             Ok(data) => data,
             Err(err) if err.kind() == ErrorKind::NotFound => {
-                path = self.root.join(file_name);
-                let data = match fs::read(&path).await {
+                // fallback to the old flat layout
+                let path = self.root.join(hex::encode(key));
+                match fs::read(&path).await {
                     Ok(data) => data,
                     Err(err) if err.kind() == ErrorKind::NotFound => {
                         return Err(Error::KeyNotFound);
@@ -50,8 +90,7 @@ impl Store for DirStore {
                     Err(err) => {
                         return Err(Error::IO(err));
                     }
-                };
-                data
+                }
             }
             Err(err) => {
                 return Err(Error::IO(err));
@@ -62,22 +101,137 @@ impl Store for DirStore {
     }
 
     async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
-        let file_name = hex::encode(key);
-        let dir_path = self.root.join(&file_name[0..2]);
+        let file_path = self.path(key);
+        if let Some(dir_path) = file_path.parent() {
+            fs::create_dir_all(dir_path).await?;
+        }
+
+        // the blob is written to a temporary file first so a failed write never
+        // leaves a partial blob behind, each write has its own temporary file so
+        // concurrent sets of the same key never write to the same file
+        let tmp_path = file_path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        let result = match fs::write(&tmp_path, blob).await {
+            Ok(_) => fs::rename(&tmp_path, &file_path).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(Error::IO(err));
+        }
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        // the old flat layout is checked too, same as get
+        for path in [self.path(key), self.root.join(hex::encode(key))] {
+            match fs::metadata(&path).await {
+                Ok(_) => return Ok(true),
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(Error::IO(err)),
+            }
+        }
 
-        fs::create_dir_all(&dir_path).await?;
+        Ok(false)
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        // the blob is removed from the old flat layout too, same as get
+        for path in [self.path(key), self.root.join(hex::encode(key))] {
+            match fs::remove_file(&path).await {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(Error::IO(err)),
+            }
+        }
 
-        let file_path = dir_path.join(file_name);
-        fs::write(file_path, blob).await?;
         Ok(())
     }
 
     fn routes(&self) -> Vec<Route> {
-        let r = Route::url(format!(
+        let mut url = format!(
             "dir://{}",
             String::from_utf8_lossy(self.root.as_os_str().as_bytes())
+        );
+        if self.depth != DEFAULT_DEPTH {
+            url = format!("{}?{}={}", url, PARAM_DEPTH, self.depth);
+        }
+
+        vec![Route::url(url)]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dir_store() {
+        const ROOT: &str = "/tmp/dir-store-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let store = DirStore::make(&format!("file://{}?depth=2", ROOT))
+            .await
+            .unwrap();
+        store.set(&[0xab, 0xcd, 0xef], b"blob").await.unwrap();
+
+        let path = PathBuf::from(ROOT).join("ab/cd/abcdef");
+        assert_eq!(fs::read(path).await.unwrap(), b"blob");
+        assert_eq!(store.get(&[0xab, 0xcd, 0xef]).await.unwrap(), b"blob");
+        assert!(matches!(
+            store.get(&[0xab, 0xcd, 0x00]).await,
+            Err(Error::KeyNotFound)
         ));
+        assert!(store.exists(&[0xab, 0xcd, 0xef]).await.unwrap());
+        assert!(!store.exists(&[0xab, 0xcd, 0x00]).await.unwrap());
 
-        vec![r]
+        let routes = store.routes();
+        assert_eq!(routes[0].url, format!("dir://{}?depth=2", ROOT));
+
+        // a store made from the route reads the same blobs
+        let store = DirStore::make(&routes[0].url).await.unwrap();
+        assert_eq!(store.get(&[0xab, 0xcd, 0xef]).await.unwrap(), b"blob");
+
+        // blobs of the old flat layout are still readable
+        fs::write(PathBuf::from(ROOT).join("0102"), b"old")
+            .await
+            .unwrap();
+        assert_eq!(store.get(&[0x01, 0x02]).await.unwrap(), b"old");
+        assert!(store.exists(&[0x01, 0x02]).await.unwrap());
+
+        // deleting removes the blobs of both layouts, a missing blob is not an error
+        store.delete(&[0xab, 0xcd, 0xef]).await.unwrap();
+        store.delete(&[0x01, 0x02]).await.unwrap();
+        store.delete(&[0x01, 0x02]).await.unwrap();
+        assert!(!store.exists(&[0xab, 0xcd, 0xef]).await.unwrap());
+        assert!(!store.exists(&[0x01, 0x02]).await.unwrap());
+
+        assert!(DirStore::make(&format!("dir://{}?depth=9", ROOT))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dir_store_concurrent_set() {
+        const ROOT: &str = "/tmp/dir-store-concurrent-test";
+        let _ = fs::remove_dir_all(ROOT).await;
+
+        let store = DirStore::new(ROOT).await.unwrap();
+        let blob = vec![1u8; 512 * 1024];
+        let sets = (0..20).map(|_| store.set(&[0xab, 0xcd], &blob));
+        for result in futures::future::join_all(sets).await {
+            result.unwrap();
+        }
+
+        assert_eq!(store.get(&[0xab, 0xcd]).await.unwrap(), blob);
+
+        // no temporary file is left behind
+        let mut entries = fs::read_dir(PathBuf::from(ROOT).join("ab")).await.unwrap();
+        let mut names = Vec::default();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, vec!["abcd"]);
     }
 }
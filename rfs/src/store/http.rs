@@ -1,68 +1,173 @@
-use super::{Error, Result, Route, Store};
-use reqwest::{self, StatusCode};
+use super::{tls::Tls, Error, Result, Route, Store};
+use reqwest::{self, Client, StatusCode};
+use std::time::Duration;
 use url::Url;
 
+/// query parameter of the request timeout in seconds
+pub const PARAM_TIMEOUT: &str = "timeout";
+/// query parameter of the blob path template, the `{key}` placeholder is replaced
+/// by the hex key and `{prefix}` by its first 2 characters
+pub const PARAM_TEMPLATE: &str = "template";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTPStore is a read only store that fetches blobs from a web server. By default
+/// the blobs are expected in the same layout as the dir store `{prefix}/{key}` with
+/// a fallback to the legacy flat layout `{key}`
 #[derive(Clone)]
 pub struct HTTPStore {
     url: Url,
+    base: Url,
+    template: Option<String>,
+    client: Client,
 }
 
 impl HTTPStore {
     pub async fn make<U: AsRef<str>>(url: &U) -> Result<HTTPStore> {
-        let u = Url::parse(url.as_ref())?;
+        let mut u = Url::parse(url.as_ref())?;
         if u.scheme() != "http" && u.scheme() != "https" {
             return Err(Error::Other(anyhow::Error::msg("invalid scheme")));
         }
 
-        Ok(HTTPStore::new(u).await?)
+        let tls = Tls::for_url(&mut u)?;
+        let builder = match u.scheme() {
+            "https" => tls.builder()?,
+            _ => Client::builder(),
+        };
+
+        let timeout = match u.query_pairs().find(|(key, _)| key == PARAM_TIMEOUT) {
+            Some((_, value)) => Duration::from_secs(value.parse().map_err(|_| {
+                Error::Other(anyhow::anyhow!("invalid http store timeout '{}'", value))
+            })?),
+            None => DEFAULT_TIMEOUT,
+        };
+
+        let client = builder
+            .timeout(timeout)
+            .build()
+            .map_err(|err| Error::Other(err.into()))?;
+
+        HTTPStore::new(u, client).await
     }
-    pub async fn new<U: Into<Url>>(url: U) -> Result<Self> {
+
+    pub async fn new<U: Into<Url>>(url: U, client: Client) -> Result<Self> {
         let url = url.into();
-        Ok(Self { url })
+        if url.cannot_be_a_base() {
+            return Err(Error::Other(anyhow::Error::msg("cannot be base")));
+        }
+
+        let template = url
+            .query_pairs()
+            .find(|(key, _)| key == PARAM_TEMPLATE)
+            .map(|(_, value)| value.into_owned());
+
+        if let Some(ref template) = template {
+            if !template.contains("{key}") {
+                return Err(Error::Other(anyhow::anyhow!(
+                    "http store template '{}' is missing the {{key}} placeholder",
+                    template
+                )));
+            }
+        }
+
+        // the store options are kept in the url so they are recorded in the route
+        // but they are never sent with the requests
+        let mut base = url.clone();
+        base.set_query(None);
+
+        Ok(Self {
+            url,
+            base,
+            template,
+            client,
+        })
+    }
+
+    // paths returns the urls where the blob of the given key is looked up in order
+    fn paths(&self, key: &[u8]) -> Vec<Url> {
+        let file = hex::encode(key);
+        let join = |path: &str| {
+            let mut u = self.base.clone();
+            u.path_segments_mut()
+                .expect("url is a base")
+                .pop_if_empty()
+                .extend(path.split('/').filter(|s| !s.is_empty()));
+            u
+        };
+
+        match self.template {
+            Some(ref template) => vec![join(
+                &template
+                    .replace("{prefix}", &file[0..2])
+                    .replace("{key}", &file),
+            )],
+            None => vec![join(&format!("{}/{}", &file[0..2], file)), join(&file)],
+        }
+    }
+}
+
+// request_error reports tls handshake failures (reqwest returns them as connection
+// errors) as tls errors so a misconfigured certificate is easy to spot
+fn request_error(err: reqwest::Error) -> Error {
+    use std::error::Error as _;
+
+    if err.is_timeout() {
+        return Error::Unavailable;
+    }
+
+    if err.is_connect() {
+        let mut source = err.source();
+        while let Some(cause) = source {
+            let msg = cause.to_string().to_lowercase();
+            if msg.contains("certificate") || msg.contains("handshake") {
+                return Error::Tls(format!("{}: {}", err, cause));
+            }
+            source = cause.source();
+        }
+    }
+
+    Error::Other(err.into())
+}
+
+// status_error maps an unexpected response status to a store error
+fn status_error(url: &Url, status: StatusCode) -> Error {
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => Error::KeyNotFound,
+        StatusCode::TOO_MANY_REQUESTS => Error::Unavailable,
+        status if status.is_server_error() => Error::Unavailable,
+        status => Error::Other(anyhow::anyhow!("get {}: unexpected status {}", url, status)),
     }
 }
 
 #[async_trait::async_trait]
 impl Store for HTTPStore {
     async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
-        let file = hex::encode(key);
-        let mut file_path = self.url.clone();
-        file_path
-            .path_segments_mut()
-            .map_err(|_| Error::Other(anyhow::Error::msg("cannot be base")))?
-            .push(&file[0..2])
-            .push(&file);
-        let mut legacy_path = self.url.clone();
-
-        legacy_path
-            .path_segments_mut()
-            .map_err(|_| Error::Other(anyhow::Error::msg("cannot be base")))?
-            .push(&file);
-
-        let data = match reqwest::get(file_path).await {
-            Ok(mut response) => {
-                if response.status() == StatusCode::NOT_FOUND {
-                    response = reqwest::get(legacy_path)
-                        .await
-                        .map_err(|_| Error::KeyNotFound)?;
-                    if response.status() != StatusCode::OK {
-                        return Err(Error::KeyNotFound);
-                    }
-                }
-                if response.status() != StatusCode::OK {
-                    return Err(Error::Unavailable);
-                }
-                response.bytes().await.map_err(|e| Error::Other(e.into()))?
+        let mut result = Err(Error::KeyNotFound);
+        for path in self.paths(key) {
+            let response = self
+                .client
+                .get(path.clone())
+                .send()
+                .await
+                .map_err(request_error)?;
+
+            if response.status() == StatusCode::OK {
+                let data = response.bytes().await.map_err(request_error)?;
+                return Ok(data.into());
             }
-            Err(err) => return Err(Error::Other(err.into())),
-        };
-        Ok(data.into())
+
+            result = Err(status_error(&path, response.status()));
+            // only a missing blob is looked up in the next location
+            if !matches!(result, Err(Error::KeyNotFound)) {
+                break;
+            }
+        }
+
+        result
     }
 
     async fn set(&self, _key: &[u8], _blob: &[u8]) -> Result<()> {
-        Err(Error::Other(anyhow::Error::msg(
-            "http store doesn't support uploading",
-        )))
+        Err(Error::Unsupported("uploading to an http store"))
     }
 
     fn routes(&self) -> Vec<Route> {
@@ -71,3 +176,60 @@ impl Store for HTTPStore {
         vec![r]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_paths() {
+        let store = HTTPStore::make(&"http://localhost:9000/store?timeout=5")
+            .await
+            .unwrap();
+        let paths: Vec<String> = store
+            .paths(&[0xab, 0xcd])
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "http://localhost:9000/store/ab/abcd",
+                "http://localhost:9000/store/abcd"
+            ]
+        );
+        assert_eq!(
+            store.routes()[0].url,
+            "http://localhost:9000/store?timeout=5"
+        );
+
+        let url = "http://localhost:9000/store/?template=blobs/{prefix}/{key}.bin";
+        let store = HTTPStore::make(&url).await.unwrap();
+        let paths: Vec<String> = store
+            .paths(&[0xab, 0xcd])
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(paths, vec!["http://localhost:9000/store/blobs/ab/abcd.bin"]);
+
+        assert!(
+            HTTPStore::make(&"http://localhost:9000/store?template=blobs")
+                .await
+                .is_err()
+        );
+        assert!(HTTPStore::make(&"http://localhost:9000/store?timeout=soon")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_unsupported() {
+        let store = HTTPStore::make(&"http://localhost:9000/store")
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.set(&[1], b"data").await,
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
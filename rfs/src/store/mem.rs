@@ -0,0 +1,77 @@
+use super::{zdb::Keys, Error, Result, Route, Store};
+use crate::gc::Scan;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const SCHEME: &str = "mem";
+
+// max number of keys returned by a scan
+const SCAN_PAGE: usize = 100;
+
+/// MemStore keeps all blobs in memory, the data is lost once the store is dropped.
+/// It's mainly useful for testing and benchmarking
+#[derive(Clone, Default)]
+pub struct MemStore {
+    map: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemStore {
+    pub async fn make<U: AsRef<str>>(url: &U) -> Result<MemStore> {
+        let u = url::Url::parse(url.as_ref())?;
+        if u.scheme() != SCHEME {
+            return Err(Error::InvalidScheme(u.scheme().into(), SCHEME.into()));
+        }
+
+        Ok(MemStore::default())
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for MemStore {
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let map = self.map.lock().unwrap();
+        map.get(key).cloned().ok_or(Error::KeyNotFound)
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+        let mut map = self.map.lock().unwrap();
+        map.insert(key.into(), blob.into());
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.map.lock().unwrap().contains_key(key))
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.map.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::url(format!("{}://", SCHEME))]
+    }
+}
+
+// keys are scanned in order, the cursor is the last key of the previous scan
+#[async_trait::async_trait]
+impl Scan for MemStore {
+    async fn scan(&self, cursor: Option<&[u8]>) -> Result<Option<(Vec<u8>, Keys)>> {
+        let map = self.map.lock().unwrap();
+        let mut keys: Keys = map
+            .iter()
+            .filter(|(key, _)| cursor.map(|c| key.as_slice() > c).unwrap_or(true))
+            .map(|(key, blob)| (key.clone(), blob.len() as u64))
+            .collect();
+        keys.sort();
+        keys.truncate(SCAN_PAGE);
+
+        let cursor = match keys.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(None),
+        };
+
+        Ok(Some((cursor, keys)))
+    }
+}
@@ -1,14 +1,25 @@
+pub mod batch;
 mod bs;
+pub mod compress;
+pub mod counting;
 pub mod dir;
 pub mod http;
+pub mod mem;
+pub mod retry;
+pub mod ring;
 mod router;
 pub mod s3store;
+pub mod sftp;
+pub mod tier;
+pub mod tls;
 pub mod zdb;
 
 use anyhow::Context;
+use futures::stream::{FuturesUnordered, StreamExt};
 use rand::seq::SliceRandom;
+use std::collections::BTreeMap;
 
-pub use bs::BlockStore;
+pub use bs::{verify_block, BlockStore};
 use regex::Regex;
 
 use crate::fungi;
@@ -16,17 +27,71 @@ use crate::fungi;
 pub use self::router::Router;
 
 pub async fn make<U: AsRef<str>>(u: U) -> Result<Stores> {
+    let mut parsed = url::Url::parse(u.as_ref())?;
+    let fallback = tier::fallback_for_url(&mut parsed)?;
+
+    let store = make_decorated(parsed.as_str()).await?;
+    let fallback = match fallback {
+        Some(fallback) => make_decorated(fallback).await?,
+        None => return Ok(store),
+    };
+
+    let tiered = tier::TieredStore::new(store, fallback);
+    Ok(Stores::Tiered(Box::new(tiered)))
+}
+
+async fn make_decorated<U: AsRef<str>>(u: U) -> Result<Stores> {
+    let mut parsed = url::Url::parse(u.as_ref())?;
+    let policy = retry::Policy::for_url(&mut parsed)?;
+    let compression = compress::Compression::for_url(&mut parsed)?;
+
+    let mut store = make_store(parsed.as_str()).await?;
+    // store decorators set by the url, a retry wraps all the other decorators
+    // so a failed operation is retried as a whole
+    if let Some(compression) = compression {
+        let compressed = compress::CompressedStore::new(store, compression);
+        store = Stores::Compressed(Box::new(compressed));
+    }
+
+    if let Some(policy) = policy {
+        store = Stores::Retry(Box::new(retry::RetryStore::new(store, policy)));
+    }
+
+    Ok(store)
+}
+
+async fn make_store<U: AsRef<str>>(u: U) -> Result<Stores> {
     let parsed = url::Url::parse(u.as_ref())?;
 
     match parsed.scheme() {
-        dir::SCHEME => return Ok(Stores::Dir(dir::DirStore::make(&u).await?)),
-        "s3" | "s3s" | "s3s+tls" => return Ok(Stores::S3(s3store::S3Store::make(&u).await?)),
-        "zdb" => return Ok(Stores::ZDB(zdb::ZdbStore::make(&u).await?)),
+        scheme if dir::SCHEMES.contains(&scheme) => {
+            return Ok(Stores::Dir(dir::DirStore::make(&u).await?))
+        }
+        scheme if s3store::SCHEMES.contains(&scheme) => {
+            return Ok(Stores::S3(s3store::S3Store::make(&u).await?))
+        }
+        "zdb" | "zdbs" => return Ok(Stores::ZDB(zdb::ZdbStore::make(&u).await?)),
         "http" | "https" => return Ok(Stores::HTTP(http::HTTPStore::make(&u).await?)),
+        mem::SCHEME => return Ok(Stores::Mem(mem::MemStore::make(&u).await?)),
+        sftp::SCHEME => return Ok(Stores::SFTP(sftp::SftpStore::make(&u).await?)),
+        ring::SCHEME => {
+            let mut members = Vec::default();
+            for (url, weight) in ring::members_for_url(&parsed)? {
+                let store = make_member(url.clone()).await?;
+                members.push((url, weight, store));
+            }
+            Ok(Stores::Ring(Box::new(ring::RingStore::new(members))))
+        }
         _ => return Err(Error::UnknownStore(parsed.scheme().into())),
     }
 }
 
+// make_member makes a member store of a ring like any other store, the future is
+// boxed since make is called again from make
+fn make_member(u: String) -> futures::future::BoxFuture<'static, Result<Stores>> {
+    Box::pin(async move { make(u).await })
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("key not found")]
@@ -35,10 +100,12 @@ pub enum Error {
     InvalidKey,
     #[error("invalid blob")]
     InvalidBlob,
-    #[error("key is not routable")]
-    KeyNotRoutable,
+    #[error("no route for key prefix {0:02x}")]
+    KeyNotRoutable(u8),
     #[error("store is not available")]
     Unavailable,
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
 
     #[error("compression error: {0}")]
     Compression(#[from] snap::Error),
@@ -46,6 +113,9 @@ pub enum Error {
     #[error("encryption error")]
     EncryptionError,
 
+    #[error("tls error: {0}")]
+    Tls(String),
+
     // TODO: better display for the Box<Vec<Self>>
     #[error("multiple error: {0:?}")]
     Multiple(Box<Vec<Self>>),
@@ -89,8 +159,70 @@ pub trait Store: Send + Sync + 'static {
     async fn get(&self, key: &[u8]) -> Result<Vec<u8>>;
     async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()>;
     fn routes(&self) -> Vec<Route>;
+
+    /// exists checks if a blob is in the store without downloading it. The default
+    /// implementation gets the blob, stores with a cheaper check override it
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        match self.get(key).await {
+            Ok(_) => Ok(true),
+            Err(Error::KeyNotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// health checks that the store is reachable. The default implementation looks
+    /// up a key that doesn't exist, a missing key means the store is answering
+    async fn health(&self) -> Result<()> {
+        match self.get(&HEALTH_KEY).await {
+            Ok(_) | Err(Error::KeyNotFound) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// get_many gets a batch of blobs, the blobs are returned in the order of the
+    /// keys and a missing key is None. The default implementation gets the blobs
+    /// one after the other, stores that can get many blobs in one round trip
+    /// override it
+    async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut blobs = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.get(key).await {
+                Ok(blob) => blobs.push(Some(blob)),
+                Err(Error::KeyNotFound) => blobs.push(None),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    /// set_many sets a batch of blobs. The default implementation sets the blobs
+    /// one after the other, stores that can set many blobs in one round trip
+    /// override it
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        for (key, blob) in blobs {
+            self.set(key, blob).await?;
+        }
+
+        Ok(())
+    }
+
+    /// flush writes the blobs the store still keeps in memory. The default
+    /// implementation does nothing, stores that buffer writes override it
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// delete removes a blob from the store, a missing blob is not an error. The
+    /// default implementation fails, stores that can remove blobs override it
+    async fn delete(&self, _key: &[u8]) -> Result<()> {
+        Err(Error::Unsupported("deleting blobs"))
+    }
 }
 
+// key used to probe the stores health
+const HEALTH_KEY: [u8; 32] = [0; 32];
+
 #[async_trait::async_trait]
 impl<S> Store for Router<S>
 where
@@ -104,17 +236,62 @@ where
 
         // to make it fare we shuffle the list of matching routers randomly everytime
         // before we do a get
-        let mut routers: Vec<&S> = self.route(key[0]).collect();
+        let mut routers: Vec<(usize, &S)> = self.route_indexed(key[0]).collect();
         routers.shuffle(&mut rand::thread_rng());
-        for store in routers {
-            match store.get(key).await {
+
+        let race = self.race().min(routers.len());
+        if race > 1 {
+            // query the fastest replicas at the same time, first one to answer wins.
+            // the sort is stable so replicas with the same latency stay shuffled
+            routers.sort_by_key(|(index, _)| self.latency(*index));
+            let start = std::time::Instant::now();
+            let mut racers: FuturesUnordered<_> = routers[..race]
+                .iter()
+                .map(|&(index, store)| async move {
+                    let result = self.timed_get(index, store, key).await;
+                    (index, result)
+                })
+                .collect();
+
+            // the losers are dropped as soon as a winner is found, stores must
+            // make sure a dropped get does not leave a connection in a bad state
+            let mut done = Vec::with_capacity(race);
+            while let Some((index, result)) = racers.next().await {
+                done.push(index);
+                match result {
+                    Ok(object) => {
+                        // the losers were at least as slow as the winner, without a
+                        // sample they would keep their latency and be picked forever
+                        for (index, _) in routers[..race].iter() {
+                            if !done.contains(index) {
+                                self.observe(*index, start.elapsed());
+                            }
+                        }
+                        return Ok(object);
+                    }
+                    Err(err) => errors.push(err),
+                }
+            }
+
+            drop(racers);
+            routers.drain(..race);
+        }
+
+        for (index, store) in routers {
+            match self.timed_get(index, store, key).await {
                 Ok(object) => return Ok(object),
                 Err(err) => errors.push(err),
             };
         }
 
         if errors.is_empty() {
-            return Err(Error::KeyNotRoutable);
+            return Err(Error::KeyNotRoutable(key[0]));
+        }
+
+        // a key is only missing if all the replicas are missing it, otherwise one
+        // of the failed replicas may still have it
+        if errors.iter().all(|err| matches!(err, Error::KeyNotFound)) {
+            return Err(Error::KeyNotFound);
         }
 
         // return aggregated errors
@@ -133,12 +310,165 @@ where
         }
 
         if !b {
-            return Err(Error::KeyNotRoutable);
+            return Err(Error::KeyNotRoutable(key[0]));
+        }
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        if key.is_empty() {
+            return Err(Error::InvalidKey);
+        }
+
+        // a set writes to all the matching stores, so a blob only exists if all of
+        // them have it
+        let mut b = false;
+        for store in self.route(key[0]) {
+            b = true;
+            if !store.exists(key).await? {
+                return Ok(false);
+            }
+        }
+
+        if !b {
+            return Err(Error::KeyNotRoutable(key[0]));
+        }
+
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            return Err(Error::InvalidKey);
+        }
+
+        // the blob is removed from all the stores a set writes it to
+        let mut b = false;
+        for store in self.route(key[0]) {
+            b = true;
+            store.delete(key).await?;
+        }
+
+        if !b {
+            return Err(Error::KeyNotRoutable(key[0]));
+        }
+
+        Ok(())
+    }
+
+    async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        // the keys are batched per store, each key is asked to one of its matching
+        // stores picked at random. The keys a store fails to return are read again
+        // with get which tries all the matching stores
+        let mut batches: BTreeMap<usize, (&S, Vec<usize>)> = BTreeMap::new();
+        for (at, key) in keys.iter().enumerate() {
+            if key.is_empty() {
+                return Err(Error::InvalidKey);
+            }
+
+            let routers: Vec<(usize, &S)> = self.route_indexed(key[0]).collect();
+            let &(index, store) = routers
+                .choose(&mut rand::thread_rng())
+                .ok_or(Error::KeyNotRoutable(key[0]))?;
+            let (_, ats) = batches.entry(index).or_insert_with(|| (store, Vec::new()));
+            ats.push(at);
+        }
+
+        let gets = batches.into_iter().map(|(index, (store, ats))| async move {
+            let batch: Vec<&[u8]> = ats.iter().map(|at| keys[*at]).collect();
+            let start = std::time::Instant::now();
+            let result = store.get_many(&batch).await;
+            match result {
+                Ok(_) => self.observe(index, start.elapsed()),
+                Err(_) => self.observe(index, FAILURE_LATENCY),
+            }
+            (ats, result)
+        });
+
+        let mut blobs = vec![None; keys.len()];
+        let mut missing = Vec::new();
+        for (ats, result) in futures::future::join_all(gets).await {
+            match result {
+                Ok(batch) if batch.len() == ats.len() => {
+                    for (at, blob) in ats.into_iter().zip(batch) {
+                        match blob {
+                            Some(blob) => blobs[at] = Some(blob),
+                            // another replica can still have it
+                            None if self.route(keys[at][0]).nth(1).is_some() => missing.push(at),
+                            None => {}
+                        }
+                    }
+                }
+                _ => missing.extend(ats),
+            }
+        }
+
+        for at in missing {
+            match self.get(keys[at]).await {
+                Ok(blob) => blobs[at] = Some(blob),
+                Err(Error::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        // like set, a blob is written to all the matching stores. The blobs are
+        // batched per store and the batches are written at the same time
+        let mut batches: BTreeMap<usize, (&S, Vec<(&[u8], &[u8])>)> = BTreeMap::new();
+        for (key, blob) in blobs {
+            if key.is_empty() {
+                return Err(Error::InvalidKey);
+            }
+
+            let mut b = false;
+            for (index, store) in self.route_indexed(key[0]) {
+                b = true;
+                let (_, batch) = batches.entry(index).or_insert_with(|| (store, Vec::new()));
+                batch.push((*key, *blob));
+            }
+
+            if !b {
+                return Err(Error::KeyNotRoutable(key[0]));
+            }
+        }
+
+        let sets = batches
+            .into_values()
+            .map(|(store, batch)| async move { store.set_many(&batch).await });
+        for result in futures::future::join_all(sets).await {
+            result?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        for (_, store) in self.routes.iter() {
+            store.flush().await?;
         }
 
         Ok(())
     }
 
+    async fn health(&self) -> Result<()> {
+        let errors: Vec<Error> = self
+            .unhealthy()
+            .await
+            .into_iter()
+            .map(|(_, err)| err)
+            .collect();
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(Error::Multiple(Box::new(errors)))
+    }
+
     fn routes(&self) -> Vec<Route> {
         let mut routes = Vec::default();
         for (key, value) in self.routes.iter() {
@@ -156,59 +486,328 @@ where
     }
 }
 
-pub async fn get_router(meta: &fungi::Reader) -> Result<Router<Stores>> {
+/// latency recorded for a route when a get fails
+const FAILURE_LATENCY: std::time::Duration = std::time::Duration::from_secs(1);
+
+impl<S> Router<S>
+where
+    S: Store,
+{
+    // timed_get records the latency of the get on the route. A missing key is an
+    // answer like any other, but a failed store counts as a very slow one so it's not
+    // the first one picked by a race just because it fails fast
+    async fn timed_get(&self, index: usize, store: &S, key: &[u8]) -> Result<Vec<u8>> {
+        let start = std::time::Instant::now();
+        let result = store.get(key).await;
+        match result {
+            Ok(_) | Err(Error::KeyNotFound) => self.observe(index, start.elapsed()),
+            Err(_) => self.observe(index, FAILURE_LATENCY),
+        }
+
+        result
+    }
+
+    /// unhealthy checks the health of all the stores of the router at the same time
+    /// and returns the urls of the stores that failed with their errors
+    pub async fn unhealthy(&self) -> Vec<(String, Error)> {
+        let checks = self.routes.iter().map(|(_, store)| async move {
+            store.health().await.map_err(|err| {
+                let urls: Vec<String> = store.routes().into_iter().map(|r| r.url).collect();
+                (urls.join(", "), err)
+            })
+        });
+
+        futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .filter_map(|result| result.err())
+            .collect()
+    }
+}
+
+pub async fn get_router<R: fungi::MetaReader>(meta: &R) -> Result<Router<Stores>> {
+    get_router_with_overrides(meta, &[]).await
+}
+
+/// get_router_with_overrides builds the router of the flist routes, overrides are store
+/// specs (like the ones of parse_router) that win over the flist routes for the key
+/// prefixes they cover. A flist route that is partly covered keeps the rest of its range
+pub async fn get_router_with_overrides<R: fungi::MetaReader>(
+    meta: &R,
+    overrides: &[String],
+) -> Result<Router<Stores>> {
     let mut router = Router::new();
 
+    let mut covered = Vec::default();
+    for spec in overrides {
+        let routes = match spec.strip_prefix('@') {
+            Some(path) => parse_routes_file(path)?,
+            None => vec![parse_route(spec)?],
+        };
+
+        for ((start, end), url) in routes {
+            let store = make(&url)
+                .await
+                .with_context(|| format!("failed to initialize store '{}'", url))?;
+            router.add(start, end, store);
+            covered.push((start, end));
+        }
+    }
+
     for route in meta.routes().await.context("failed to get store routes")? {
-        let store = make(&route.url)
-            .await
-            .with_context(|| format!("failed to initialize store '{}'", route.url))?;
-        router.add(route.start, route.end, store);
+        let ranges = uncovered((route.start, route.end), &covered);
+        if ranges.is_empty() {
+            log::info!("store '{}' is overridden", route.url);
+            continue;
+        }
+
+        for (start, end) in ranges {
+            let store = make(&route.url)
+                .await
+                .with_context(|| format!("failed to initialize store '{}'", route.url))?;
+            router.add(start, end, store);
+        }
     }
 
     Ok(router)
 }
 
+// uncovered returns the parts of the range that are not in any of the covered ranges
+fn uncovered(range: (u8, u8), covered: &[(u8, u8)]) -> Vec<(u8, u8)> {
+    let mut ranges = Vec::default();
+    let mut start = None;
+    for prefix in range.0..=range.1 {
+        let is_covered = covered
+            .iter()
+            .any(|(low, high)| (*low..=*high).contains(&prefix));
+        match (is_covered, start) {
+            (false, None) => start = Some(prefix),
+            (true, Some(from)) => {
+                ranges.push((from, prefix - 1));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(from) = start {
+        ranges.push((from, range.1));
+    }
+
+    ranges
+}
+
+/// parse_router builds a router from a list of store specs. A spec is either of the
+/// form `[xx-xx=]<url>` or `@<path>` where path is a toml file with a list of routes.
+/// Each route in the file can carry extra options that are applied to the store url
+///
+/// ```toml
+/// [[route]]
+/// range = "00-7f" # optional, defaults to 00-ff
+/// url = "zdb://hub.grid.tf/namespace"
+///
+/// [route.options]
+/// password = "secret"
+/// ```
 pub async fn parse_router(urls: &[String]) -> anyhow::Result<Router<Stores>> {
+    parse_router_with(urls, Sharding::Range).await
+}
+
+/// Sharding is how the blocks are spread over the stores of a router
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Sharding {
+    /// each store keeps the blocks of its key prefix range, stores with the same
+    /// range are replicas
+    #[default]
+    Range,
+    /// all the stores are members of a single consistent hash ring, see
+    /// ring::RingStore. The stores can't have a range but can have a `weight`
+    Ring,
+}
+
+impl std::str::FromStr for Sharding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "range" => Ok(Sharding::Range),
+            "ring" => Ok(Sharding::Ring),
+            _ => anyhow::bail!("unknown sharding '{}' expected 'range' or 'ring'", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Sharding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sharding::Range => write!(f, "range"),
+            Sharding::Ring => write!(f, "ring"),
+        }
+    }
+}
+
+/// parse_router_with builds a router from a list of store specs (see parse_router)
+/// with the given sharding. With the ring sharding the router has a single ring
+/// store that covers all the key prefixes, and it's kept in the flist as a single
+/// route with a `ring://` url so the flist is read with the same ring
+pub async fn parse_router_with(
+    urls: &[String],
+    sharding: Sharding,
+) -> anyhow::Result<Router<Stores>> {
     let mut router = Router::new();
-    let pattern = r"^(?P<range>[0-9a-f]{2}-[0-9a-f]{2})=(?P<url>.+)$";
-    let re = Regex::new(pattern)?;
 
-    for u in urls {
-        let ((start, end), store) = match re.captures(u) {
-            None => ((0x00, 0xff), make(u).await?),
-            Some(captures) => {
-                let url = captures.name("url").context("missing url group")?.as_str();
-                let rng = captures
-                    .name("range")
-                    .context("missing range group")?
-                    .as_str();
-
-                let store = make(url).await?;
-                let range = match rng.split_once('-') {
-                    None => anyhow::bail!("invalid range format"),
-                    Some((low, high)) => (
-                        u8::from_str_radix(low, 16)
-                            .with_context(|| format!("failed to parse low range '{}'", low))?,
-                        u8::from_str_radix(high, 16)
-                            .with_context(|| format!("failed to parse high range '{}'", high))?,
-                    ),
-                };
-                (range, store)
+    if sharding == Sharding::Ring {
+        let mut members = Vec::default();
+        for u in urls {
+            let routes = match u.strip_prefix('@') {
+                Some(path) => parse_routes_file(path)?,
+                None => vec![parse_route(u)?],
+            };
+
+            for (range, url) in routes {
+                if range != (0x00, 0xff) {
+                    anyhow::bail!("store '{}' of a ring can't have a range", url);
+                }
+                members.push(url);
             }
+        }
+
+        let store = make(ring::url_for(&members))
+            .await
+            .context("failed to initialize ring")?;
+        router.add(0x00, 0xff, store);
+        return Ok(router);
+    }
+
+    for u in urls {
+        let routes = match u.strip_prefix('@') {
+            Some(path) => parse_routes_file(path)?,
+            None => vec![parse_route(u)?],
         };
 
-        router.add(start, end, store);
+        for ((start, end), url) in routes {
+            let store = make(&url).await?;
+            router.add(start, end, store);
+        }
     }
 
     Ok(router)
 }
 
+fn parse_range(rng: &str) -> anyhow::Result<(u8, u8)> {
+    match rng.split_once('-') {
+        None => anyhow::bail!("invalid range format"),
+        Some((low, high)) => Ok((
+            u8::from_str_radix(low, 16)
+                .with_context(|| format!("failed to parse low range '{}'", low))?,
+            u8::from_str_radix(high, 16)
+                .with_context(|| format!("failed to parse high range '{}'", high))?,
+        )),
+    }
+}
+
+// parse_route parses a route in the form [xx-xx=]<url>
+fn parse_route(u: &str) -> anyhow::Result<((u8, u8), String)> {
+    let pattern = r"^(?P<range>[0-9a-f]{2}-[0-9a-f]{2})=(?P<url>.+)$";
+    let re = Regex::new(pattern)?;
+
+    match re.captures(u) {
+        None => Ok(((0x00, 0xff), u.into())),
+        Some(captures) => {
+            let url = captures.name("url").context("missing url group")?.as_str();
+            let rng = captures
+                .name("range")
+                .context("missing range group")?
+                .as_str();
+
+            Ok((parse_range(rng)?, url.into()))
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RoutesFile {
+    #[serde(default)]
+    route: Vec<RouteSpec>,
+}
+
+#[derive(serde::Deserialize)]
+struct RouteSpec {
+    range: Option<String>,
+    url: String,
+    #[serde(default)]
+    options: BTreeMap<String, toml::Value>,
+}
+
+// parse_routes_file loads the routes from a toml routes file
+fn parse_routes_file(path: &str) -> anyhow::Result<Vec<((u8, u8), String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read routes file '{}'", path))?;
+    let file: RoutesFile = toml::from_str(&content)
+        .with_context(|| format!("failed to parse routes file '{}'", path))?;
+
+    let mut routes = Vec::default();
+    for spec in file.route {
+        let range = match spec.range {
+            Some(ref rng) => parse_range(rng)?,
+            None => (0x00, 0xff),
+        };
+
+        routes.push((range, with_options(&spec.url, &spec.options)?));
+    }
+
+    Ok(routes)
+}
+
+// with_options applies the route options to the store url. username and password
+// are set as the url credentials, all other options are set as query parameters
+// and override the ones that are already in the url
+fn with_options(url: &str, options: &BTreeMap<String, toml::Value>) -> anyhow::Result<String> {
+    let mut u = url::Url::parse(url).with_context(|| format!("invalid store url '{}'", url))?;
+
+    let mut query: Vec<(String, String)> = u
+        .query_pairs()
+        .filter(|(key, _)| !options.contains_key(key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+
+    for (key, value) in options {
+        let value = match value {
+            toml::Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+
+        match key.as_str() {
+            "username" => u
+                .set_username(&value)
+                .map_err(|_| anyhow::anyhow!("failed to set username of '{}'", url))?,
+            "password" => u
+                .set_password(Some(&value))
+                .map_err(|_| anyhow::anyhow!("failed to set password of '{}'", url))?,
+            _ => query.push((key.clone(), value)),
+        }
+    }
+
+    if query.is_empty() {
+        u.set_query(None);
+    } else {
+        u.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    Ok(u.to_string())
+}
+
 pub enum Stores {
     S3(s3store::S3Store),
     Dir(dir::DirStore),
     ZDB(zdb::ZdbStore),
     HTTP(http::HTTPStore),
+    Mem(mem::MemStore),
+    SFTP(sftp::SftpStore),
+    Compressed(Box<compress::CompressedStore<Stores>>),
+    Retry(Box<retry::RetryStore<Stores>>),
+    Tiered(Box<tier::TieredStore<Stores, Stores>>),
+    Ring(Box<ring::RingStore<Stores>>),
 }
 
 #[async_trait::async_trait]
@@ -219,6 +818,12 @@ impl Store for Stores {
             self::Stores::Dir(dir_store) => dir_store.get(key).await,
             self::Stores::ZDB(zdb_store) => zdb_store.get(key).await,
             self::Stores::HTTP(http_store) => http_store.get(key).await,
+            self::Stores::Mem(mem_store) => mem_store.get(key).await,
+            self::Stores::SFTP(sftp_store) => sftp_store.get(key).await,
+            self::Stores::Compressed(compressed_store) => compressed_store.get(key).await,
+            self::Stores::Retry(retry_store) => retry_store.get(key).await,
+            self::Stores::Tiered(tiered_store) => tiered_store.get(key).await,
+            self::Stores::Ring(ring_store) => ring_store.get(key).await,
         }
     }
     async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
@@ -227,6 +832,12 @@ impl Store for Stores {
             self::Stores::Dir(dir_store) => dir_store.set(key, blob).await,
             self::Stores::ZDB(zdb_store) => zdb_store.set(key, blob).await,
             self::Stores::HTTP(http_store) => http_store.set(key, blob).await,
+            self::Stores::Mem(mem_store) => mem_store.set(key, blob).await,
+            self::Stores::SFTP(sftp_store) => sftp_store.set(key, blob).await,
+            self::Stores::Compressed(compressed_store) => compressed_store.set(key, blob).await,
+            self::Stores::Retry(retry_store) => retry_store.set(key, blob).await,
+            self::Stores::Tiered(tiered_store) => tiered_store.set(key, blob).await,
+            self::Stores::Ring(ring_store) => ring_store.set(key, blob).await,
         }
     }
     fn routes(&self) -> Vec<Route> {
@@ -235,6 +846,440 @@ impl Store for Stores {
             self::Stores::Dir(dir_store) => dir_store.routes(),
             self::Stores::ZDB(zdb_store) => zdb_store.routes(),
             self::Stores::HTTP(http_store) => http_store.routes(),
+            self::Stores::Mem(mem_store) => mem_store.routes(),
+            self::Stores::SFTP(sftp_store) => sftp_store.routes(),
+            self::Stores::Compressed(compressed_store) => compressed_store.routes(),
+            self::Stores::Retry(retry_store) => retry_store.routes(),
+            self::Stores::Tiered(tiered_store) => tiered_store.routes(),
+            self::Stores::Ring(ring_store) => ring_store.routes(),
+        }
+    }
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        match self {
+            self::Stores::S3(s3_store) => s3_store.exists(key).await,
+            self::Stores::Dir(dir_store) => dir_store.exists(key).await,
+            self::Stores::ZDB(zdb_store) => zdb_store.exists(key).await,
+            self::Stores::HTTP(http_store) => http_store.exists(key).await,
+            self::Stores::Mem(mem_store) => mem_store.exists(key).await,
+            self::Stores::SFTP(sftp_store) => sftp_store.exists(key).await,
+            self::Stores::Compressed(compressed_store) => compressed_store.exists(key).await,
+            self::Stores::Retry(retry_store) => retry_store.exists(key).await,
+            self::Stores::Tiered(tiered_store) => tiered_store.exists(key).await,
+            self::Stores::Ring(ring_store) => ring_store.exists(key).await,
+        }
+    }
+    async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        match self {
+            self::Stores::S3(s3_store) => s3_store.get_many(keys).await,
+            self::Stores::Dir(dir_store) => dir_store.get_many(keys).await,
+            self::Stores::ZDB(zdb_store) => zdb_store.get_many(keys).await,
+            self::Stores::HTTP(http_store) => http_store.get_many(keys).await,
+            self::Stores::Mem(mem_store) => mem_store.get_many(keys).await,
+            self::Stores::SFTP(sftp_store) => sftp_store.get_many(keys).await,
+            self::Stores::Compressed(compressed_store) => compressed_store.get_many(keys).await,
+            self::Stores::Retry(retry_store) => retry_store.get_many(keys).await,
+            self::Stores::Tiered(tiered_store) => tiered_store.get_many(keys).await,
+            self::Stores::Ring(ring_store) => ring_store.get_many(keys).await,
+        }
+    }
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        match self {
+            self::Stores::S3(s3_store) => s3_store.set_many(blobs).await,
+            self::Stores::Dir(dir_store) => dir_store.set_many(blobs).await,
+            self::Stores::ZDB(zdb_store) => zdb_store.set_many(blobs).await,
+            self::Stores::HTTP(http_store) => http_store.set_many(blobs).await,
+            self::Stores::Mem(mem_store) => mem_store.set_many(blobs).await,
+            self::Stores::SFTP(sftp_store) => sftp_store.set_many(blobs).await,
+            self::Stores::Compressed(compressed_store) => compressed_store.set_many(blobs).await,
+            self::Stores::Retry(retry_store) => retry_store.set_many(blobs).await,
+            self::Stores::Tiered(tiered_store) => tiered_store.set_many(blobs).await,
+            self::Stores::Ring(ring_store) => ring_store.set_many(blobs).await,
+        }
+    }
+    async fn flush(&self) -> Result<()> {
+        match self {
+            self::Stores::S3(s3_store) => s3_store.flush().await,
+            self::Stores::Dir(dir_store) => dir_store.flush().await,
+            self::Stores::ZDB(zdb_store) => zdb_store.flush().await,
+            self::Stores::HTTP(http_store) => http_store.flush().await,
+            self::Stores::Mem(mem_store) => mem_store.flush().await,
+            self::Stores::SFTP(sftp_store) => sftp_store.flush().await,
+            self::Stores::Compressed(compressed_store) => compressed_store.flush().await,
+            self::Stores::Retry(retry_store) => retry_store.flush().await,
+            self::Stores::Tiered(tiered_store) => tiered_store.flush().await,
+            self::Stores::Ring(ring_store) => ring_store.flush().await,
         }
     }
+    async fn health(&self) -> Result<()> {
+        match self {
+            self::Stores::S3(s3_store) => s3_store.health().await,
+            self::Stores::Dir(dir_store) => dir_store.health().await,
+            self::Stores::ZDB(zdb_store) => zdb_store.health().await,
+            self::Stores::HTTP(http_store) => http_store.health().await,
+            self::Stores::Mem(mem_store) => mem_store.health().await,
+            self::Stores::SFTP(sftp_store) => sftp_store.health().await,
+            self::Stores::Compressed(compressed_store) => compressed_store.health().await,
+            self::Stores::Retry(retry_store) => retry_store.health().await,
+            self::Stores::Tiered(tiered_store) => tiered_store.health().await,
+            self::Stores::Ring(ring_store) => ring_store.health().await,
+        }
+    }
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        match self {
+            self::Stores::S3(s3_store) => s3_store.delete(key).await,
+            self::Stores::Dir(dir_store) => dir_store.delete(key).await,
+            self::Stores::ZDB(zdb_store) => zdb_store.delete(key).await,
+            self::Stores::HTTP(http_store) => http_store.delete(key).await,
+            self::Stores::Mem(mem_store) => mem_store.delete(key).await,
+            self::Stores::SFTP(sftp_store) => sftp_store.delete(key).await,
+            self::Stores::Compressed(compressed_store) => compressed_store.delete(key).await,
+            self::Stores::Retry(retry_store) => retry_store.delete(key).await,
+            self::Stores::Tiered(tiered_store) => tiered_store.delete(key).await,
+            self::Stores::Ring(ring_store) => ring_store.delete(key).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    struct DelayStore(Duration);
+
+    #[async_trait::async_trait]
+    impl Store for DelayStore {
+        async fn get(&self, _key: &[u8]) -> Result<Vec<u8>> {
+            tokio::time::sleep(self.0).await;
+            Ok(vec![1])
+        }
+
+        async fn set(&self, _key: &[u8], _blob: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn routes(&self) -> Vec<Route> {
+            vec![Route::url("delay://")]
+        }
+    }
+
+    async fn p99(router: &Router<DelayStore>) -> Duration {
+        let mut samples = Vec::default();
+        for _ in 0..50 {
+            let start = Instant::now();
+            router.get(&[0]).await.unwrap();
+            samples.push(start.elapsed());
+        }
+
+        samples.sort();
+        samples[samples.len() * 99 / 100]
+    }
+
+    #[test]
+    fn test_uncovered() {
+        assert_eq!(uncovered((0x00, 0xff), &[]), vec![(0x00, 0xff)]);
+        assert!(uncovered((0x00, 0xff), &[(0x00, 0xff)]).is_empty());
+        assert_eq!(
+            uncovered((0x00, 0xff), &[(0x10, 0x1f), (0x80, 0xff)]),
+            vec![(0x00, 0x0f), (0x20, 0x7f)]
+        );
+        assert_eq!(uncovered((0x20, 0x3f), &[(0x00, 0x2f)]), vec![(0x30, 0x3f)]);
+    }
+
+    #[tokio::test]
+    async fn test_get_router_with_overrides() {
+        const PATH: &str = "/tmp/overrides.fl";
+        let meta = fungi::Writer::new(PATH, true).await.unwrap();
+        meta.route(0x00, 0xff, "dir:///tmp/flist").await.unwrap();
+        let meta = fungi::Reader::new(PATH).await.unwrap();
+
+        let overrides = vec!["80-ff=dir:///tmp/mirror".to_string()];
+        let router = get_router_with_overrides(&meta, &overrides).await.unwrap();
+        let routes: Vec<(u8, u8, String)> = router
+            .routes()
+            .into_iter()
+            .map(|route| (route.start.unwrap(), route.end.unwrap(), route.url))
+            .collect();
+
+        assert_eq!(
+            routes,
+            vec![
+                (0x80, 0xff, "dir:///tmp/mirror".to_string()),
+                (0x00, 0x7f, "dir:///tmp/flist".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let (range, url) = parse_route("zdb://hub.grid.tf/ns").unwrap();
+        assert_eq!(range, (0x00, 0xff));
+        assert_eq!(url, "zdb://hub.grid.tf/ns");
+
+        let (range, url) = parse_route("00-7f=dir:///tmp/store").unwrap();
+        assert_eq!(range, (0x00, 0x7f));
+        assert_eq!(url, "dir:///tmp/store");
+    }
+
+    #[test]
+    fn test_parse_routes_file() {
+        const PATH: &str = "/tmp/routes.toml";
+        std::fs::write(
+            PATH,
+            r#"
+[[route]]
+url = "dir:///tmp/store0"
+
+[[route]]
+range = "80-ff"
+url = "zdb://hub.grid.tf/ns?pool=10"
+
+[route.options]
+username = "user"
+password = "p@ss:word"
+pool = 50
+"#,
+        )
+        .unwrap();
+
+        let routes = parse_routes_file(PATH).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0], ((0x00, 0xff), "dir:///tmp/store0".into()));
+        assert_eq!(routes[1].0, (0x80, 0xff));
+
+        let u = url::Url::parse(&routes[1].1).unwrap();
+        assert_eq!(u.username(), "user");
+        assert_eq!(u.password(), Some("p%40ss%3Aword"));
+        let query: Vec<(String, String)> = u.query_pairs().into_owned().collect();
+        assert_eq!(query, vec![("pool".into(), "50".into())]);
+    }
+
+    #[tokio::test]
+    async fn test_not_routable() {
+        let mut router = Router::new();
+        router.add(0x00, 0x7f, mem::MemStore::default());
+        router.add(0xa0, 0xff, mem::MemStore::default());
+
+        router.set(&[0x10], b"data").await.unwrap();
+        assert_eq!(router.get(&[0x10]).await.unwrap(), b"data");
+
+        assert!(matches!(
+            router.get(&[0x90]).await,
+            Err(Error::KeyNotRoutable(0x90))
+        ));
+        assert!(matches!(
+            router.set(&[0x80], b"data").await,
+            Err(Error::KeyNotRoutable(0x80))
+        ));
+    }
+
+    // HealthStore is a store that is either up (but empty) or down
+    struct HealthStore(bool);
+
+    #[async_trait::async_trait]
+    impl Store for HealthStore {
+        async fn get(&self, _key: &[u8]) -> Result<Vec<u8>> {
+            match self.0 {
+                true => Err(Error::KeyNotFound),
+                false => Err(Error::Unavailable),
+            }
+        }
+
+        async fn set(&self, _key: &[u8], _blob: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn routes(&self) -> Vec<Route> {
+            vec![Route::url(format!("health://{}", self.0))]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        assert!(HealthStore(true).health().await.is_ok());
+        assert!(matches!(
+            HealthStore(false).health().await,
+            Err(Error::Unavailable)
+        ));
+
+        let mut router = Router::new();
+        router.add(0x00, 0x7f, HealthStore(true));
+        router.add(0x80, 0xff, HealthStore(false));
+
+        let unhealthy = router.unhealthy().await;
+        assert_eq!(unhealthy.len(), 1);
+        assert_eq!(unhealthy[0].0, "health://false");
+        assert!(matches!(router.health().await, Err(Error::Multiple(_))));
+    }
+
+    #[tokio::test]
+    async fn test_make_retry() {
+        let store = make("mem://?retry=2").await.unwrap();
+        assert!(matches!(store, Stores::Retry(_)));
+        assert_eq!(store.routes()[0].url, "mem://?retry=2&retry_delay=100");
+
+        let store = make("mem://").await.unwrap();
+        assert!(matches!(store, Stores::Mem(_)));
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        let mut router = Router::new();
+        router.add(0x00, 0xff, DelayStore(Duration::from_millis(1)));
+        router.add(0x00, 0xff, DelayStore(Duration::from_millis(50)));
+
+        let sequential = p99(&router).await;
+        router.set_race(2);
+        let raced = p99(&router).await;
+
+        assert!(sequential >= Duration::from_millis(50));
+        assert!(raced < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_race_latency() {
+        let mut router = Router::new();
+        router.add(0x00, 0xff, DelayStore(Duration::from_millis(1)));
+        router.add(0x00, 0xff, DelayStore(Duration::from_millis(200)));
+        router.set_race(2);
+
+        // the loser gets a sample even if it never answers
+        router.get(&[0]).await.unwrap();
+        assert!(router.latency(0) > Duration::default());
+        assert!(router.latency(1) >= router.latency(0));
+    }
+
+    // ReplicaStore is a mem store that can be killed
+    #[derive(Clone, Default)]
+    struct ReplicaStore {
+        store: mem::MemStore,
+        down: Arc<AtomicBool>,
+    }
+
+    impl ReplicaStore {
+        fn check(&self) -> Result<()> {
+            match self.down.load(Ordering::SeqCst) {
+                true => Err(Error::Unavailable),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Store for ReplicaStore {
+        async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+            self.check()?;
+            self.store.get(key).await
+        }
+
+        async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+            self.check()?;
+            self.store.set(key, blob).await
+        }
+
+        fn routes(&self) -> Vec<Route> {
+            vec![Route::url("replica://")]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicas() {
+        let replicas = vec![ReplicaStore::default(), ReplicaStore::default()];
+        let mut router = Router::new();
+        router.add_replicas(0x00, 0xff, replicas.clone());
+
+        router.set(&[0x10], b"data").await.unwrap();
+        for replica in replicas.iter() {
+            assert_eq!(replica.get(&[0x10]).await.unwrap(), b"data");
+        }
+
+        assert!(matches!(router.get(&[0x20]).await, Err(Error::KeyNotFound)));
+
+        // kill a replica in the middle of the reads
+        for race in [1, 2] {
+            router.set_race(race);
+            replicas[0].down.store(false, Ordering::SeqCst);
+            for i in 0..20 {
+                if i == 10 {
+                    replicas[0].down.store(true, Ordering::SeqCst);
+                }
+                assert_eq!(router.get(&[0x10]).await.unwrap(), b"data");
+            }
+
+            // the dead replica may have the key
+            assert!(matches!(router.get(&[0x20]).await, Err(Error::Multiple(_))));
+
+            // the dead replica fails fast but it's not the fastest one
+            assert!(router.latency(0) > router.latency(1));
+        }
+
+        // writes go to all replicas
+        assert!(router.set(&[0x30], b"data").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exists() {
+        let replicas = vec![mem::MemStore::default(), mem::MemStore::default()];
+        let mut router = Router::new();
+        router.add_replicas(0x00, 0x7f, replicas.clone());
+
+        router.set(&[0x10], b"data").await.unwrap();
+        assert!(router.exists(&[0x10]).await.unwrap());
+        assert!(!router.exists(&[0x20]).await.unwrap());
+
+        // a blob that is missing from a replica is uploaded again
+        replicas[0].set(&[0x30], b"data").await.unwrap();
+        assert!(!router.exists(&[0x30]).await.unwrap());
+
+        assert!(matches!(
+            router.exists(&[0x80]).await,
+            Err(Error::KeyNotRoutable(0x80))
+        ));
+
+        // the default implementation gets the blob
+        assert!(!HealthStore(true).exists(&[0x10]).await.unwrap());
+        assert!(HealthStore(false).exists(&[0x10]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_many() {
+        let replicas = vec![mem::MemStore::default(), mem::MemStore::default()];
+        let other = mem::MemStore::default();
+        let mut router = Router::new();
+        router.add_replicas(0x00, 0x7f, replicas.clone());
+        router.add(0x80, 0xff, other.clone());
+
+        router.set(&[0x10], b"a").await.unwrap();
+        router.set(&[0x90], b"b").await.unwrap();
+        // only one of the replicas has the key, it's still found
+        replicas[1].set(&[0x20], b"c").await.unwrap();
+
+        let keys: [&[u8]; 4] = [&[0x10], &[0x20], &[0x30], &[0x90]];
+        let blobs = router.get_many(&keys).await.unwrap();
+        assert_eq!(
+            blobs,
+            vec![
+                Some(b"a".to_vec()),
+                Some(b"c".to_vec()),
+                None,
+                Some(b"b".to_vec())
+            ]
+        );
+
+        // the default implementation gets the blobs one by one
+        assert_eq!(
+            other.get_many(&[&[0x90], &[0x91]]).await.unwrap(),
+            vec![Some(b"b".to_vec()), None]
+        );
+
+        let keys: [&[u8]; 0] = [];
+        assert!(router.get_many(&keys).await.unwrap().is_empty());
+
+        assert!(matches!(
+            router.get_many(&[&[0x10], &[]]).await,
+            Err(Error::InvalidKey)
+        ));
+    }
 }
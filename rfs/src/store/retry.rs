@@ -0,0 +1,304 @@
+use super::{Error, Result, Route, Store};
+use rand::Rng;
+use std::time::Duration;
+use url::Url;
+
+/// query parameter of the maximum number of attempts of each operation
+pub const PARAM_RETRY: &str = "retry";
+/// query parameter of the delay before the first retry in milliseconds
+pub const PARAM_RETRY_DELAY: &str = "retry_delay";
+
+/// Policy of a RetryStore
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// maximum number of attempts, including the first one
+    pub attempts: u32,
+    /// delay before the first retry
+    pub delay: Duration,
+    /// the delay is multiplied by this factor after each retry
+    pub multiplier: f64,
+    /// upper bound of the delay between attempts
+    pub max_delay: Duration,
+    /// fraction of the delay (0 to 1) that is randomly taken off each wait so
+    /// clients that failed together don't retry together
+    pub jitter: f64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            attempts: 3,
+            delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Policy {
+    /// for_url returns the retry policy set by the url query parameters, the
+    /// parameters are removed from the url. None is returned if the url has no
+    /// retry parameter
+    pub fn for_url(u: &mut Url) -> Result<Option<Self>> {
+        let mut policy = Policy::default();
+        let mut enabled = false;
+
+        let mut query = Vec::default();
+        for (key, value) in u.query_pairs() {
+            let invalid = || Error::Other(anyhow::anyhow!("invalid {} '{}'", key, value));
+            match key.as_ref() {
+                PARAM_RETRY => {
+                    policy.attempts = value.parse().map_err(|_| invalid())?;
+                    enabled = true;
+                }
+                PARAM_RETRY_DELAY => {
+                    policy.delay = Duration::from_millis(value.parse().map_err(|_| invalid())?);
+                }
+                _ => query.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        if query.is_empty() {
+            u.set_query(None);
+        } else {
+            u.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        Ok(enabled.then_some(policy))
+    }
+
+    // backoff returns the wait before the given retry (starting at 1)
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .delay
+            .mul_f64(self.multiplier.max(1.0).powi(retry as i32 - 1))
+            .min(self.max_delay);
+
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+        delay.mul_f64(1.0 - jitter)
+    }
+}
+
+/// is_transient returns false for the errors that will not go away by trying again
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::KeyNotFound
+        | Error::InvalidKey
+        | Error::InvalidBlob
+        | Error::KeyNotRoutable(_)
+        | Error::Unsupported(_)
+        | Error::Compression(_)
+        | Error::EncryptionError
+        | Error::Tls(_)
+        | Error::Url(_)
+        | Error::UnknownStore(_)
+        | Error::InvalidScheme(_, _) => false,
+        Error::Multiple(errors) => errors.iter().any(is_transient),
+        Error::Unavailable | Error::IO(_) | Error::Other(_) => true,
+    }
+}
+
+/// RetryStore wraps a store and retries the failed operations with an exponential
+/// backoff. Deterministic errors (like a missing key or an invalid blob) are returned
+/// right away
+pub struct RetryStore<S: Store> {
+    store: S,
+    policy: Policy,
+}
+
+impl<S: Store> RetryStore<S> {
+    pub fn new(store: S, policy: Policy) -> Self {
+        Self { store, policy }
+    }
+
+    async fn retry<'a, T, F, Fut>(&'a self, op: &str, f: F) -> Result<T>
+    where
+        F: Fn(&'a S) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f(&self.store).await {
+                Err(err) if attempt < self.policy.attempts && is_transient(&err) => {
+                    let wait = self.policy.backoff(attempt);
+                    debug!(
+                        "store {} failed (attempt {}/{}), retrying in {:?}: {:#}",
+                        op, attempt, self.policy.attempts, wait, err
+                    );
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Store> Store for RetryStore<S> {
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.retry("get", |store| store.get(key)).await
+    }
+
+    async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.retry("get_many", |store| store.get_many(keys)).await
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+        self.retry("set", |store| store.set(key, blob)).await
+    }
+
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        self.retry("set_many", |store| store.set_many(blobs)).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        // a failed flush can't be retried, the buffered blobs are gone
+        self.store.flush().await
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        self.retry("exists", |store| store.exists(key)).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.retry("delete", |store| store.delete(key)).await
+    }
+
+    async fn health(&self) -> Result<()> {
+        self.retry("health", |store| store.health()).await
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        // the retry options are kept in the routes so the stores built from
+        // an fl retry the same way
+        self.store
+            .routes()
+            .into_iter()
+            .map(|mut route| {
+                if let Ok(mut u) = Url::parse(&route.url) {
+                    u.query_pairs_mut()
+                        .append_pair(PARAM_RETRY, &self.policy.attempts.to_string())
+                        .append_pair(
+                            PARAM_RETRY_DELAY,
+                            &self.policy.delay.as_millis().to_string(),
+                        );
+                    route.url = u.into();
+                }
+                route
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // FlakyStore fails with the given error until it was called `failures` times
+    struct FlakyStore {
+        failures: u32,
+        calls: AtomicU32,
+        error: fn() -> Error,
+    }
+
+    impl FlakyStore {
+        fn new(failures: u32, error: fn() -> Error) -> Self {
+            FlakyStore {
+                failures,
+                calls: AtomicU32::default(),
+                error,
+            }
+        }
+
+        fn call(&self) -> Result<()> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err((self.error)());
+            }
+
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Store for FlakyStore {
+        async fn get(&self, _key: &[u8]) -> Result<Vec<u8>> {
+            self.call().map(|_| vec![1])
+        }
+
+        async fn set(&self, _key: &[u8], _blob: &[u8]) -> Result<()> {
+            self.call()
+        }
+
+        fn routes(&self) -> Vec<Route> {
+            vec![Route::url("flaky://host/path?x=1")]
+        }
+    }
+
+    fn policy(attempts: u32) -> Policy {
+        Policy {
+            attempts,
+            delay: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let store = RetryStore::new(FlakyStore::new(2, || Error::Unavailable), policy(3));
+        assert_eq!(store.get(b"key").await.unwrap(), vec![1]);
+        assert_eq!(store.store.calls.load(Ordering::SeqCst), 3);
+
+        let store = RetryStore::new(FlakyStore::new(2, || Error::Unavailable), policy(3));
+        store.set(b"key", b"blob").await.unwrap();
+
+        let store = RetryStore::new(FlakyStore::new(3, || Error::Unavailable), policy(3));
+        assert!(matches!(store.get(b"key").await, Err(Error::Unavailable)));
+        assert_eq!(store.store.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry() {
+        let store = RetryStore::new(FlakyStore::new(1, || Error::KeyNotFound), policy(3));
+        assert!(matches!(store.get(b"key").await, Err(Error::KeyNotFound)));
+        assert_eq!(store.store.calls.load(Ordering::SeqCst), 1);
+
+        let store = RetryStore::new(FlakyStore::new(1, || Error::InvalidBlob), policy(3));
+        assert!(matches!(store.get(b"key").await, Err(Error::InvalidBlob)));
+        assert_eq!(store.store.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut u = Url::parse("zdb://hub.grid.tf/ns?retry=5&retry_delay=20&pool=10").unwrap();
+        let policy = Policy::for_url(&mut u).unwrap().unwrap();
+        assert_eq!(policy.attempts, 5);
+        assert_eq!(policy.delay, Duration::from_millis(20));
+        assert_eq!(u.as_str(), "zdb://hub.grid.tf/ns?pool=10");
+
+        let mut u = Url::parse("zdb://hub.grid.tf/ns").unwrap();
+        assert_eq!(Policy::for_url(&mut u).unwrap(), None);
+
+        let mut u = Url::parse("zdb://hub.grid.tf/ns?retry=many").unwrap();
+        assert!(Policy::for_url(&mut u).is_err());
+
+        let policy = Policy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(20), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_routes() {
+        let store = RetryStore::new(FlakyStore::new(0, || Error::Unavailable), policy(4));
+        assert_eq!(
+            store.routes()[0].url,
+            "flaky://host/path?x=1&retry=4&retry_delay=1"
+        );
+    }
+}
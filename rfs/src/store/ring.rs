@@ -0,0 +1,404 @@
+use super::{Error, Result, Route, Store};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use url::Url;
+
+pub const SCHEME: &str = "ring";
+
+/// query parameter of the ring url that holds the url of a member store
+pub const PARAM_STORE: &str = "store";
+/// query parameter of a member store url that sets its weight on the ring
+pub const PARAM_WEIGHT: &str = "weight";
+
+// number of points a member with a weight of 1 has on the ring, more points spread
+// the keys more evenly over the members
+const POINTS: u32 = 128;
+
+/// maximum weight of a member store, a member has `weight * 128` points on the ring
+/// so the weight is bounded to keep the ring small
+pub const MAX_WEIGHT: u32 = 1000;
+
+/// members_for_url returns the url and the weight of the member stores of a ring
+/// url of the form `ring://?store=<url>&store=<url>`. The weight of a member is
+/// set by the `weight` query parameter of its url which is removed from the url
+pub fn members_for_url(u: &Url) -> Result<Vec<(String, u32)>> {
+    if u.scheme() != SCHEME {
+        return Err(Error::InvalidScheme(u.scheme().into(), SCHEME.into()));
+    }
+
+    let mut members = Vec::default();
+    for (key, value) in u.query_pairs() {
+        if key != PARAM_STORE {
+            continue;
+        }
+
+        let mut member = Url::parse(&value)?;
+        let mut weight = 1;
+        let mut query = Vec::default();
+        for (key, value) in member.query_pairs() {
+            match key.as_ref() {
+                PARAM_WEIGHT => {
+                    weight = match value.parse() {
+                        Ok(weight) if (1..=MAX_WEIGHT).contains(&weight) => weight,
+                        _ => {
+                            return Err(Error::Other(anyhow::anyhow!(
+                                "invalid store weight '{}', expected 1 to {}",
+                                value,
+                                MAX_WEIGHT
+                            )))
+                        }
+                    }
+                }
+                _ => query.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        if query.is_empty() {
+            member.set_query(None);
+        } else {
+            member.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        members.push((member.to_string(), weight));
+    }
+
+    if members.is_empty() {
+        return Err(Error::Other(anyhow::anyhow!("a ring has no stores")));
+    }
+
+    Ok(members)
+}
+
+/// url_for returns the ring url of the member store urls, the inverse of
+/// members_for_url
+pub fn url_for<S: AsRef<str>>(members: &[S]) -> String {
+    let mut u = Url::parse(&format!("{}://", SCHEME)).expect("ring url is valid");
+    u.query_pairs_mut()
+        .extend_pairs(members.iter().map(|m| (PARAM_STORE, m.as_ref())));
+    u.to_string()
+}
+
+/// strip_member_passwords removes the passwords of the member store urls (and of
+/// their fallback stores) that are kept in the ring url query. It returns true if a
+/// password was removed
+pub(crate) fn strip_member_passwords(u: &mut Url) -> anyhow::Result<bool> {
+    if u.scheme() != SCHEME {
+        return Ok(false);
+    }
+
+    let mut stripped = false;
+    let mut query = Vec::default();
+    for (key, value) in u.query_pairs() {
+        let mut value = value.to_string();
+        if key == PARAM_STORE {
+            let mut member = Url::parse(&value)?;
+            let mut changed = super::tier::strip_fallback_password(&mut member)?;
+            if member.password().is_some() {
+                member
+                    .set_password(None)
+                    .map_err(|_| anyhow::anyhow!("failed to strip password"))?;
+                changed = true;
+            }
+            if changed {
+                value = member.into();
+                stripped = true;
+            }
+        }
+        query.push((key.to_string(), value));
+    }
+
+    if stripped {
+        u.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    Ok(stripped)
+}
+
+/// RingStore spreads the blocks over its member stores with consistent hashing.
+///
+/// Each member is placed on a ring of 64 bit positions at a number of points
+/// (proportional to its weight) that are computed from the sha256 hash of the
+/// member address. The block key is the blake2b hash of the block content, so its
+/// first 8 bytes (big endian) are used as is as the position of the block on the
+/// ring, and the block is kept by the member of the first point at or after that
+/// position, wrapping around at the end of the ring.
+///
+/// Unlike the key ranges of a router, adding a member to a ring of N members only
+/// moves about 1/(N+1) of the blocks to the new member, and removing one only moves
+/// the blocks of the removed member. The member address used for the points is the
+/// url without credentials and query, so changing a password or a store option
+/// doesn't move any block
+pub struct RingStore<S> {
+    url: String,
+    members: Vec<S>,
+    // points of the ring sorted by position, with the index of their member
+    points: Vec<(u64, usize)>,
+}
+
+impl<S> RingStore<S>
+where
+    S: Store,
+{
+    /// new creates a ring of the member stores, each member is given with its
+    /// url (with the weight query parameter if any) and its weight, the weight is
+    /// bounded to 1..=MAX_WEIGHT
+    pub fn new<I: IntoIterator<Item = (String, u32, S)>>(members: I) -> Self {
+        let mut urls = Vec::default();
+        let mut stores = Vec::default();
+        let mut points = Vec::default();
+        for (index, (url, weight, store)) in members.into_iter().enumerate() {
+            let weight = weight.clamp(1, MAX_WEIGHT);
+            let address = address(&url);
+            for point in 0..weight * POINTS {
+                let hash = Sha256::digest(format!("{}#{}", address, point).as_bytes());
+                points.push((position(&hash), index));
+            }
+
+            urls.push(match weight {
+                1 => url,
+                weight => with_weight(&url, weight),
+            });
+            stores.push(store);
+        }
+        points.sort_unstable();
+
+        Self {
+            url: url_for(&urls),
+            members: stores,
+            points,
+        }
+    }
+
+    /// member returns the index of the member that keeps the key
+    pub fn member(&self, key: &[u8]) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        let position = position(key);
+        let index = self.points.partition_point(|(point, _)| *point < position);
+        let (_, member) = self.points[index % self.points.len()];
+        Some(member)
+    }
+
+    fn store(&self, key: &[u8]) -> Result<&S> {
+        self.member(key)
+            .map(|index| &self.members[index])
+            .ok_or_else(|| Error::Other(anyhow::anyhow!("a ring has no stores")))
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for RingStore<S>
+where
+    S: Store,
+{
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.store(key)?.get(key).await
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+        self.store(key)?.set(key, blob).await
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        self.store(key)?.exists(key).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.store(key)?.delete(key).await
+    }
+
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        // the blobs are grouped by member so each member gets a single batch
+        let mut batches: HashMap<usize, Vec<(&[u8], &[u8])>> = HashMap::default();
+        for &(key, blob) in blobs {
+            let member = self
+                .member(key)
+                .ok_or_else(|| Error::Other(anyhow::anyhow!("a ring has no stores")))?;
+            batches.entry(member).or_default().push((key, blob));
+        }
+
+        let sets = batches
+            .into_iter()
+            .map(|(member, batch)| async move { self.members[member].set_many(&batch).await });
+        for result in futures::future::join_all(sets).await {
+            result?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        for store in self.members.iter() {
+            store.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn health(&self) -> Result<()> {
+        let checks = self.members.iter().map(|store| store.health());
+        let errors: Vec<Error> = futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .filter_map(|result| result.err())
+            .collect();
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(Error::Multiple(Box::new(errors)))
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        vec![Route::url(&self.url)]
+    }
+}
+
+// position returns the position on the ring of a key (or a point hash), a key
+// shorter than 8 bytes is padded with zeros
+fn position(key: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    let len = key.len().min(8);
+    bytes[..len].copy_from_slice(&key[..len]);
+    u64::from_be_bytes(bytes)
+}
+
+// address returns the part of the store url that identifies the store on the ring
+fn address(url: &str) -> String {
+    let mut u = match Url::parse(url) {
+        Ok(u) => u,
+        Err(_) => return url.into(),
+    };
+
+    let _ = u.set_username("");
+    let _ = u.set_password(None);
+    u.set_query(None);
+    u.set_fragment(None);
+    u.to_string()
+}
+
+fn with_weight(url: &str, weight: u32) -> String {
+    match Url::parse(url) {
+        Ok(mut u) => {
+            u.query_pairs_mut()
+                .append_pair(PARAM_WEIGHT, &weight.to_string());
+            u.to_string()
+        }
+        Err(_) => url.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::mem::MemStore;
+
+    fn ring(urls: &[&str]) -> RingStore<MemStore> {
+        RingStore::new(urls.iter().map(|u| (u.to_string(), 1, MemStore::default())))
+    }
+
+    fn keys() -> Vec<Vec<u8>> {
+        (0..4096u32)
+            .map(|i| Sha256::digest(i.to_be_bytes()).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_members_for_url() {
+        let u = url_for(&[
+            "zdb://hub.grid.tf:9900/ns?password=secret",
+            "dir:///tmp/store?weight=3",
+        ]);
+        let members = members_for_url(&Url::parse(&u).unwrap()).unwrap();
+        assert_eq!(
+            members,
+            vec![
+                ("zdb://hub.grid.tf:9900/ns?password=secret".into(), 1),
+                ("dir:///tmp/store".into(), 3),
+            ]
+        );
+
+        assert!(members_for_url(&Url::parse("ring://").unwrap()).is_err());
+        for weight in ["0", "1001", "4294967295", "-1", "a"] {
+            let u = url_for(&[&format!("dir:///tmp/store?weight={}", weight)]);
+            assert!(
+                members_for_url(&Url::parse(&u).unwrap()).is_err(),
+                "{}",
+                weight
+            );
+        }
+    }
+
+    #[test]
+    fn test_ring_remap() {
+        let before = ring(&["zdb://a:9900/ns", "zdb://b:9900/ns", "zdb://c:9900/ns"]);
+        let after = ring(&[
+            "zdb://a:9900/ns",
+            "zdb://b:9900/ns",
+            "zdb://c:9900/ns",
+            "zdb://d:9900/ns",
+        ]);
+
+        let keys = keys();
+        let mut counts = [0; 4];
+        let mut moved = 0;
+        for key in keys.iter() {
+            let old = before.member(key).unwrap();
+            let new = after.member(key).unwrap();
+            counts[new] += 1;
+            // a key only moves to the new member
+            if old != new {
+                assert_eq!(new, 3);
+                moved += 1;
+            }
+        }
+
+        // about a quarter of the keys move to the new member
+        assert!(moved > keys.len() / 8 && moved < keys.len() / 2);
+        for count in counts {
+            assert!(count > keys.len() / 8);
+        }
+
+        // credentials and options don't move the keys
+        let other = ring(&[
+            "zdb://user:pass@a:9900/ns?compress=zstd",
+            "zdb://b:9900/ns",
+            "zdb://c:9900/ns",
+        ]);
+        for key in keys.iter() {
+            assert_eq!(before.member(key), other.member(key));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ring_store() {
+        let store = RingStore::new(vec![
+            ("mem://".to_string(), 1, MemStore::default()),
+            ("mem://b".to_string(), 2, MemStore::default()),
+        ]);
+        assert_eq!(
+            store.routes()[0].url,
+            url_for(&["mem://", "mem://b?weight=2"])
+        );
+
+        let keys = keys();
+        let blobs: Vec<(&[u8], &[u8])> = keys
+            .iter()
+            .map(|key| (key.as_slice(), key.as_slice()))
+            .collect();
+        store.set_many(&blobs).await.unwrap();
+
+        let mut counts = [0; 2];
+        for key in keys.iter() {
+            let member = store.member(key).unwrap();
+            counts[member] += 1;
+            assert_eq!(store.members[member].get(key).await.unwrap(), *key);
+            assert_eq!(store.get(key).await.unwrap(), *key);
+        }
+        // the heavier member keeps more keys
+        assert!(counts[1] > counts[0]);
+    }
+}
@@ -1,22 +1,52 @@
 use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// route implements a naive prefix router by going through the complete set of
 /// available routers and find that ones that matches this given prefix
 #[derive(Default, Clone)]
 pub struct Router<T> {
     pub(crate) routes: Vec<(RangeInclusive<u8>, T)>,
+    // observed get latency per route (same index as routes)
+    latency: Vec<Arc<Latency>>,
+    race: usize,
 }
 
 impl<T> Router<T> {
     pub fn new() -> Self {
         Self {
             routes: Vec::default(),
+            latency: Vec::default(),
+            race: 0,
         }
     }
 
     /// add a range
     pub fn add(&mut self, start: u8, end: u8, route: T) {
         self.routes.push((start..=end, route));
+        self.latency.push(Arc::default());
+    }
+
+    /// add a set of replicas that hold the same data for a range. a get is served
+    /// by any of the replicas and a set is written to all of them
+    pub fn add_replicas<I: IntoIterator<Item = T>>(&mut self, start: u8, end: u8, replicas: I) {
+        for replica in replicas {
+            self.add(start, end, replica);
+        }
+    }
+
+    /// set the number of replicas that are queried at the same time on get. The
+    /// replicas with the lowest observed latency are picked and the first one to
+    /// answer wins. A value of 0 or 1 means replicas are tried one after the other.
+    ///
+    /// racing multiplies the read load on the stores so it should be used with care
+    pub fn set_race(&mut self, race: usize) {
+        self.race = race;
+    }
+
+    pub fn race(&self) -> usize {
+        self.race
     }
 
     /// return all stores that matches a certain key
@@ -28,6 +58,76 @@ impl<T> Router<T> {
             .filter(move |f| f.0.contains(&i))
             .map(|v| &v.1)
     }
+
+    /// gaps returns the ranges of key prefixes that are not covered by any route,
+    /// keys in these ranges can't be read or written
+    pub fn gaps(&self) -> Vec<RangeInclusive<u8>> {
+        let mut covered = [false; 256];
+        for (range, _) in self.routes.iter() {
+            for prefix in range.clone() {
+                covered[prefix as usize] = true;
+            }
+        }
+
+        let mut gaps = Vec::default();
+        let mut start = None;
+        for prefix in 0..=255u8 {
+            match (covered[prefix as usize], start) {
+                (false, None) => start = Some(prefix),
+                (true, Some(from)) => {
+                    gaps.push(from..=prefix - 1);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(from) = start {
+            gaps.push(from..=255);
+        }
+
+        gaps
+    }
+
+    /// same as route but also returns the index of the route
+    pub(crate) fn route_indexed(&self, i: u8) -> impl Iterator<Item = (usize, &T)> {
+        self.routes
+            .iter()
+            .enumerate()
+            .filter(move |(_, f)| f.0.contains(&i))
+            .map(|(index, v)| (index, &v.1))
+    }
+
+    /// latency returns the observed latency of the route at index
+    pub(crate) fn latency(&self, index: usize) -> Duration {
+        self.latency.get(index).map(|l| l.get()).unwrap_or_default()
+    }
+
+    pub(crate) fn observe(&self, index: usize, sample: Duration) {
+        if let Some(latency) = self.latency.get(index) {
+            latency.observe(sample);
+        }
+    }
+}
+
+/// Latency keeps a moving average of the route latency in microseconds
+#[derive(Default)]
+struct Latency(AtomicU64);
+
+impl Latency {
+    fn get(&self) -> Duration {
+        Duration::from_micros(self.0.load(Ordering::Relaxed))
+    }
+
+    fn observe(&self, sample: Duration) {
+        let sample = sample.as_micros() as u64;
+        let old = self.0.load(Ordering::Relaxed);
+        let value = match old {
+            0 => sample,
+            _ => (old * 7 + sample) / 8,
+        };
+        self.0.store(value, Ordering::Relaxed);
+    }
 }
 
 #[cfg(test)]
@@ -53,4 +153,30 @@ mod test {
         assert_eq!(paths[1], "b");
         assert_eq!(paths[2], "c");
     }
+
+    #[test]
+    fn test_gaps() {
+        let mut router = Router::default();
+        assert_eq!(router.gaps(), vec![0..=255]);
+
+        router.add(0x00, 0x7f, "a");
+        router.add(0xa0, 0xef, "b");
+        router.add(0xc0, 0xf0, "c");
+        assert_eq!(router.gaps(), vec![0x80..=0x9f, 0xf1..=0xff]);
+
+        router.add(0x00, 0xff, "d");
+        assert!(router.gaps().is_empty());
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut router = Router::default();
+        router.add(0, 255, "a");
+
+        router.observe(0, Duration::from_millis(8));
+        assert_eq!(router.latency(0), Duration::from_millis(8));
+        router.observe(0, Duration::from_millis(16));
+        assert_eq!(router.latency(0), Duration::from_millis(9));
+        assert_eq!(router.latency(1), Duration::default());
+    }
 }
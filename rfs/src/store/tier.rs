@@ -0,0 +1,281 @@
+use super::{Error, Result, Route, Store};
+use url::Url;
+
+/// query parameter of the url of the fallback store
+pub const PARAM_FALLBACK: &str = "fallback";
+
+/// fallback_for_url returns the url of the fallback store set by the url query
+/// parameters, the parameter is removed from the url
+pub fn fallback_for_url(u: &mut Url) -> Result<Option<String>> {
+    let mut fallback = None;
+    let mut query = Vec::default();
+    for (key, value) in u.query_pairs() {
+        match key.as_ref() {
+            PARAM_FALLBACK => fallback = Some(value.to_string()),
+            _ => query.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    let fallback = match fallback {
+        Some(fallback) => fallback,
+        None => return Ok(None),
+    };
+
+    let parsed = Url::parse(&fallback)?;
+    if parsed.query_pairs().any(|(key, _)| key == PARAM_FALLBACK) {
+        return Err(Error::Other(anyhow::anyhow!(
+            "a fallback store can't have a fallback store"
+        )));
+    }
+
+    if query.is_empty() {
+        u.set_query(None);
+    } else {
+        u.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    Ok(Some(fallback))
+}
+
+/// strip_fallback_password removes the password of the fallback store url that is
+/// kept in the url query. It returns true if a password was removed
+pub(crate) fn strip_fallback_password(u: &mut Url) -> anyhow::Result<bool> {
+    let mut stripped = false;
+    let mut query = Vec::default();
+    for (key, value) in u.query_pairs() {
+        let mut value = value.to_string();
+        if key == PARAM_FALLBACK {
+            let mut fallback = Url::parse(&value)?;
+            if fallback.password().is_some() {
+                fallback
+                    .set_password(None)
+                    .map_err(|_| anyhow::anyhow!("failed to strip password"))?;
+                value = fallback.into();
+                stripped = true;
+            }
+        }
+        query.push((key.to_string(), value));
+    }
+
+    if stripped {
+        u.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    Ok(stripped)
+}
+
+/// TieredStore reads a block from the fallback store if it's missing from the
+/// primary store, and writes it back to the primary store so the next read is
+/// served by the primary. It's meant for a fast local store in front of a slow
+/// remote one. Writes go to both stores
+pub struct TieredStore<P, F> {
+    primary: P,
+    fallback: F,
+}
+
+impl<P, F> TieredStore<P, F>
+where
+    P: Store,
+    F: Store,
+{
+    pub fn new(primary: P, fallback: F) -> Self {
+        Self { primary, fallback }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, F> Store for TieredStore<P, F>
+where
+    P: Store,
+    F: Store,
+{
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let missing = match self.primary.get(key).await {
+            Ok(blob) => return Ok(blob),
+            Err(Error::KeyNotFound) => true,
+            // the fallback is still tried, but the block is not written back to
+            // a primary that is failing
+            Err(err) => {
+                log::warn!("primary store failed, using fallback store: {:#}", err);
+                false
+            }
+        };
+
+        let blob = self.fallback.get(key).await?;
+        if missing {
+            if let Err(err) = self.primary.set(key, &blob).await {
+                log::warn!("failed to write back block to primary store: {:#}", err);
+            }
+        }
+
+        Ok(blob)
+    }
+
+    async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        let (mut blobs, write_back) = match self.primary.get_many(keys).await {
+            Ok(blobs) => (blobs, true),
+            Err(err) => {
+                log::warn!("primary store failed, using fallback store: {:#}", err);
+                (vec![None; keys.len()], false)
+            }
+        };
+
+        let missing: Vec<usize> = (0..keys.len()).filter(|at| blobs[*at].is_none()).collect();
+        if missing.is_empty() {
+            return Ok(blobs);
+        }
+
+        let batch: Vec<&[u8]> = missing.iter().map(|at| keys[*at]).collect();
+        let found = self.fallback.get_many(&batch).await?;
+        for (at, blob) in missing.into_iter().zip(found) {
+            let blob = match blob {
+                Some(blob) => blob,
+                None => continue,
+            };
+
+            if write_back {
+                if let Err(err) = self.primary.set(keys[at], &blob).await {
+                    log::warn!("failed to write back block to primary store: {:#}", err);
+                }
+            }
+            blobs[at] = Some(blob);
+        }
+
+        Ok(blobs)
+    }
+
+    async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+        self.fallback.set(key, blob).await?;
+        self.primary.set(key, blob).await
+    }
+
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        self.fallback.set_many(blobs).await?;
+        self.primary.set_many(blobs).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.fallback.flush().await?;
+        self.primary.flush().await
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        if self.primary.exists(key).await? {
+            return Ok(true);
+        }
+
+        self.fallback.exists(key).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        // the primary is emptied first so a failure never leaves a blob that is
+        // only in the primary
+        self.primary.delete(key).await?;
+        self.fallback.delete(key).await
+    }
+
+    async fn health(&self) -> Result<()> {
+        // blocks can still be read if only one of the stores is down
+        match (self.primary.health().await, self.fallback.health().await) {
+            (Ok(_), _) | (_, Ok(_)) => Ok(()),
+            (Err(primary), Err(fallback)) => {
+                Err(Error::Multiple(Box::new(vec![primary, fallback])))
+            }
+        }
+    }
+
+    fn routes(&self) -> Vec<Route> {
+        // the fallback is kept in the routes so the stores built from an fl use
+        // the same tiers
+        let fallback = match self.fallback.routes().into_iter().next() {
+            Some(route) => route.url,
+            None => return self.primary.routes(),
+        };
+
+        self.primary
+            .routes()
+            .into_iter()
+            .map(|mut route| {
+                if let Ok(mut u) = Url::parse(&route.url) {
+                    u.query_pairs_mut().append_pair(PARAM_FALLBACK, &fallback);
+                    route.url = u.into();
+                }
+                route
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::mem::MemStore;
+
+    #[tokio::test]
+    async fn test_tiered() {
+        let primary = MemStore::default();
+        let fallback = MemStore::default();
+        fallback.set(b"remote", b"remote blob").await.unwrap();
+        primary.set(b"local", b"local blob").await.unwrap();
+
+        let store = TieredStore::new(primary.clone(), fallback.clone());
+        assert_eq!(store.get(b"local").await.unwrap(), b"local blob");
+        assert!(!fallback.exists(b"local").await.unwrap());
+
+        // a block missing from the primary is written back to it
+        assert!(!primary.exists(b"remote").await.unwrap());
+        assert_eq!(store.get(b"remote").await.unwrap(), b"remote blob");
+        assert_eq!(primary.get(b"remote").await.unwrap(), b"remote blob");
+
+        assert!(matches!(store.get(b"none").await, Err(Error::KeyNotFound)));
+
+        store.set(b"new", b"new blob").await.unwrap();
+        assert!(primary.exists(b"new").await.unwrap());
+        assert!(fallback.exists(b"new").await.unwrap());
+    }
+
+    #[test]
+    fn test_fallback_for_url() {
+        let mut u =
+            Url::parse("zdb://localhost/ns?retry=3&fallback=https%3A%2F%2Fu%3Ap%40hub%2Fstore")
+                .unwrap();
+        let fallback = fallback_for_url(&mut u).unwrap();
+        assert_eq!(fallback.as_deref(), Some("https://u:p@hub/store"));
+        assert_eq!(u.as_str(), "zdb://localhost/ns?retry=3");
+
+        let mut u = Url::parse("zdb://localhost:9900/ns").unwrap();
+        assert_eq!(fallback_for_url(&mut u).unwrap(), None);
+
+        let mut u = Url::parse(
+            "zdb://localhost/ns?fallback=zdb%3A%2F%2Fremote%2Fns%3Ffallback%3Dmem%3A%2F%2F",
+        )
+        .unwrap();
+        assert!(fallback_for_url(&mut u).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let mut u = Url::parse("mem://?fallback=dir%3A%2F%2F%2Ftmp%2Ftiered").unwrap();
+        let fallback = fallback_for_url(&mut u).unwrap().unwrap();
+        let store = TieredStore::new(
+            MemStore::default(),
+            crate::store::make(fallback).await.unwrap(),
+        );
+
+        let routes = store.routes();
+        assert_eq!(routes.len(), 1);
+        let mut route = Url::parse(&routes[0].url).unwrap();
+        assert_eq!(
+            fallback_for_url(&mut route).unwrap().as_deref(),
+            Some("dir:///tmp/tiered")
+        );
+
+        let mut u =
+            Url::parse("mem://?fallback=https%3A%2F%2Fu%3Apass%40hub.grid.tf%2Fstore").unwrap();
+        assert!(strip_fallback_password(&mut u).unwrap());
+        assert_eq!(
+            fallback_for_url(&mut u).unwrap().as_deref(),
+            Some("https://u@hub.grid.tf/store")
+        );
+    }
+}
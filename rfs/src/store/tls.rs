@@ -0,0 +1,258 @@
+use super::{Error, Result};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// environment variables of the global tls settings
+pub const ENV_CA: &str = "RFS_TLS_CA";
+pub const ENV_CERT: &str = "RFS_TLS_CERT";
+pub const ENV_KEY: &str = "RFS_TLS_KEY";
+
+/// store url query parameters that override the global tls settings
+pub const PARAM_CA: &str = "tls_ca";
+pub const PARAM_CERT: &str = "tls_cert";
+pub const PARAM_KEY: &str = "tls_key";
+
+// environment variable of the ca bundle of the system tls library
+const ENV_SYSTEM_CA: &str = "SSL_CERT_FILE";
+
+/// install_system_ca makes the ca bundle of the environment (RFS_TLS_CA) the one of
+/// the system tls library that s3s stores use. The library reads it from the
+/// environment, so it's set once when the process starts
+///
+/// # Safety
+///
+/// It changes the environment of the process, so it must be called before any other
+/// thread is started
+pub unsafe fn install_system_ca() {
+    if let Some(ca) = std::env::var_os(ENV_CA) {
+        std::env::set_var(ENV_SYSTEM_CA, ca);
+    }
+}
+
+/// Tls settings of the stores that connect over tls (https, s3s and zdbs). The
+/// global settings are taken from the environment and each store url can override
+/// them with query parameters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tls {
+    /// pem bundle of the trusted certificate authorities
+    pub ca: Option<PathBuf>,
+    /// pem client certificate used for mutual tls
+    pub cert: Option<PathBuf>,
+    /// pem private key of the client certificate
+    pub key: Option<PathBuf>,
+}
+
+impl Tls {
+    /// from_env returns the global tls settings
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var_os(name).map(PathBuf::from);
+        Tls {
+            ca: var(ENV_CA),
+            cert: var(ENV_CERT),
+            key: var(ENV_KEY),
+        }
+    }
+
+    /// for_url returns the tls settings of a store, the query parameters of the url
+    /// take precedence over the global settings. The tls parameters are removed
+    /// from the url
+    pub fn for_url(u: &mut Url) -> Result<Self> {
+        let mut tls = Self::from_env();
+
+        let mut query = Vec::default();
+        for (key, value) in u.query_pairs() {
+            match key.as_ref() {
+                PARAM_CA => tls.ca = Some(value.as_ref().into()),
+                PARAM_CERT => tls.cert = Some(value.as_ref().into()),
+                PARAM_KEY => tls.key = Some(value.as_ref().into()),
+                _ => query.push((key.into_owned(), value.into_owned())),
+            }
+        }
+
+        if query.is_empty() {
+            u.set_query(None);
+        } else {
+            u.query_pairs_mut().clear().extend_pairs(query);
+        }
+
+        if tls.cert.is_some() != tls.key.is_some() {
+            return Err(Error::Tls(
+                "client certificate and key must be set together".into(),
+            ));
+        }
+
+        Ok(tls)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ca.is_none() && self.cert.is_none() && self.key.is_none()
+    }
+
+    /// client builds an http client with the tls settings
+    pub fn client(&self) -> Result<reqwest::Client> {
+        self.builder()?
+            .build()
+            .map_err(|err| Error::Tls(format!("failed to build tls client: {}", err)))
+    }
+
+    /// builder returns an http client builder with the tls settings applied
+    pub fn builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(ref ca) = self.ca {
+            let pem = read(ca, "ca bundle")?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem).map_err(|err| {
+                Error::Tls(format!("invalid ca bundle '{}': {}", ca.display(), err))
+            })?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            let identity = reqwest::Identity::from_pkcs8_pem(
+                &read(cert, "client certificate")?,
+                &read(key, "client key")?,
+            )
+            .map_err(|err| {
+                Error::Tls(format!(
+                    "invalid client certificate '{}' or key '{}': {}",
+                    cert.display(),
+                    key.display(),
+                    err
+                ))
+            })?;
+            builder = builder.identity(identity);
+        }
+
+        Ok(builder)
+    }
+
+    /// connector builds a tls connector with the settings, it's used by the stores
+    /// that open their tls connections themselves (zdbs)
+    pub fn connector(&self) -> Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if let Some(ref ca) = self.ca {
+            let invalid =
+                |err| Error::Tls(format!("invalid ca bundle '{}': {}", ca.display(), err));
+            let certs = openssl::x509::X509::stack_from_pem(&read(ca, "ca bundle")?)
+                .map_err(|err| invalid(err.to_string()))?;
+            if certs.is_empty() {
+                return Err(invalid("no certificates".into()));
+            }
+            for cert in certs {
+                let der = cert.to_der().map_err(|err| invalid(err.to_string()))?;
+                let cert = native_tls::Certificate::from_der(&der)
+                    .map_err(|err| invalid(err.to_string()))?;
+                builder.add_root_certificate(cert);
+            }
+        }
+
+        if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+            let identity = native_tls::Identity::from_pkcs8(
+                &read(cert, "client certificate")?,
+                &read(key, "client key")?,
+            )
+            .map_err(|err| {
+                Error::Tls(format!(
+                    "invalid client certificate '{}' or key '{}': {}",
+                    cert.display(),
+                    key.display(),
+                    err
+                ))
+            })?;
+            builder.identity(identity);
+        }
+
+        builder
+            .build()
+            .map_err(|err| Error::Tls(format!("failed to build tls connector: {}", err)))
+    }
+
+    /// system checks the settings of the stores that use the system tls library (s3s).
+    /// These stores only support the ca bundle of the system library, which is the
+    /// one of the environment if install_system_ca was called
+    pub fn system(&self, store: &str) -> Result<()> {
+        if self.cert.is_some() {
+            return Err(Error::Tls(format!(
+                "client certificates are not supported by {} stores",
+                store
+            )));
+        }
+
+        let ca = match self.ca {
+            Some(ref ca) => ca,
+            None => return Ok(()),
+        };
+
+        // fail early with a clear error instead of a failed handshake
+        read(ca, "ca bundle")?;
+
+        match std::env::var_os(ENV_SYSTEM_CA) {
+            Some(ref current) if Path::new(current) == ca => Ok(()),
+            _ => Err(Error::Tls(format!(
+                "{} stores only use the ca bundle of the system, set '{}' with {} or {} instead",
+                store,
+                ca.display(),
+                ENV_CA,
+                ENV_SYSTEM_CA
+            ))),
+        }
+    }
+}
+
+fn read(path: &Path, what: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|err| {
+        Error::Tls(format!(
+            "failed to read {} '{}': {}",
+            what,
+            path.display(),
+            err
+        ))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_for_url() {
+        let mut u = Url::parse(
+            "https://hub.grid.tf/store?tls_ca=/etc/ca.pem&tls_cert=/etc/cert.pem&tls_key=/etc/key.pem&region=x",
+        )
+        .unwrap();
+
+        let tls = Tls::for_url(&mut u).unwrap();
+        assert_eq!(tls.ca, Some("/etc/ca.pem".into()));
+        assert_eq!(tls.cert, Some("/etc/cert.pem".into()));
+        assert_eq!(tls.key, Some("/etc/key.pem".into()));
+        assert_eq!(u.as_str(), "https://hub.grid.tf/store?region=x");
+
+        let mut u = Url::parse("https://hub.grid.tf/store?tls_cert=/etc/cert.pem").unwrap();
+        assert!(matches!(Tls::for_url(&mut u), Err(Error::Tls(_))));
+    }
+
+    #[test]
+    fn test_invalid_ca() {
+        let tls = Tls {
+            ca: Some("/tmp/missing-ca-bundle.pem".into()),
+            ..Default::default()
+        };
+
+        let err = tls.client().unwrap_err();
+        assert!(matches!(err, Error::Tls(_)));
+        assert!(err.to_string().contains("ca bundle"));
+        assert!(matches!(tls.connector(), Err(Error::Tls(_))));
+        assert!(matches!(tls.system("s3s"), Err(Error::Tls(_))));
+
+        let tls = Tls {
+            cert: Some("/tmp/cert.pem".into()),
+            key: Some("/tmp/key.pem".into()),
+            ..Default::default()
+        };
+        assert!(matches!(tls.system("s3s"), Err(Error::Tls(_))));
+        assert!(matches!(tls.connector(), Err(Error::Tls(_))));
+    }
+}
@@ -1,15 +1,206 @@
-use super::{Error, Result, Route, Store};
+use super::{tls::Tls, Error, Result, Route, Store};
 use anyhow::Context;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
 
 use bb8_redis::{
-    bb8::{CustomizeConnection, Pool},
+    bb8::{CustomizeConnection, ManageConnection, Pool, PooledConnection},
     redis::{
-        aio::Connection, cmd, AsyncCommands, ConnectionAddr, ConnectionInfo, RedisConnectionInfo,
-        RedisError,
+        aio::{AsyncStream, Connection},
+        cmd, pipe, AsyncCommands, ConnectionAddr, ConnectionInfo, RedisConnectionInfo, RedisError,
+        RedisResult, Value,
     },
     RedisConnectionManager,
 };
 
+/// query parameter of the maximum number of connections of the pool
+pub const PARAM_POOL: &str = "pool";
+/// query parameter of the timeout in seconds to open a connection
+pub const PARAM_CONNECT_TIMEOUT: &str = "connect_timeout";
+/// query parameter of the timeout in seconds to get a connection from the pool
+pub const PARAM_ACQUIRE_TIMEOUT: &str = "acquire_timeout";
+/// query parameter to create the namespace if it doesn't exist
+pub const PARAM_CREATE: &str = "create";
+
+// an operation that failed because the connection was lost (like on a zdb restart)
+// is attempted up to this number of times, on a new connection each time
+const RECONNECT_ATTEMPTS: u32 = 4;
+// delay before the first reconnect, it's doubled after each one
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// Options of the connection pool of a zdb store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolOptions {
+    pub max_size: u32,
+    /// no timeout if not set, the connection is given up by the operating system
+    pub connect_timeout: Option<Duration>,
+    /// it includes the time to open a new connection if none is idle
+    pub acquire_timeout: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_size: 20,
+            connect_timeout: None,
+            acquire_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl PoolOptions {
+    /// for_url returns the pool options set by the url query parameters. The
+    /// parameters are kept in the url so a mount uses the same options
+    pub fn for_url(u: &url::Url) -> Result<Self> {
+        let mut options = PoolOptions::default();
+        for (key, value) in u.query_pairs() {
+            let invalid = || Error::Other(anyhow::anyhow!("invalid {} '{}'", key, value));
+            let seconds = || match value.parse() {
+                Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+                _ => Err(invalid()),
+            };
+
+            match key.as_ref() {
+                PARAM_POOL => {
+                    options.max_size = match value.parse() {
+                        Ok(size) if size > 0 => size,
+                        _ => return Err(invalid()),
+                    }
+                }
+                PARAM_CONNECT_TIMEOUT => options.connect_timeout = Some(seconds()?),
+                PARAM_ACQUIRE_TIMEOUT => options.acquire_timeout = seconds()?,
+                _ => {}
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+// TimeoutManager is a redis connection manager that gives up opening a connection
+// after a timeout. The tls connections (zdbs) are opened with the tls connector of
+// the store, the redis client only knows the trusted certificates of the system
+struct TimeoutManager {
+    manager: RedisConnectionManager,
+    info: ConnectionInfo,
+    tls: Option<native_tls::TlsConnector>,
+    timeout: Option<Duration>,
+}
+
+impl TimeoutManager {
+    async fn open(&self) -> RedisResult<Connection> {
+        match (&self.tls, &self.info.addr) {
+            (Some(tls), ConnectionAddr::TcpTls { host, port, .. }) => {
+                let stream = tokio::net::TcpStream::connect((host.as_str(), *port)).await?;
+                let stream = tokio_native_tls::TlsConnector::from(tls.clone())
+                    .connect(host, stream)
+                    .await
+                    .map_err(std::io::Error::other)?;
+                let stream: Pin<Box<dyn AsyncStream + Send + Sync>> = Box::pin(stream);
+                Connection::new(&self.info.redis, stream).await
+            }
+            _ => self.manager.connect().await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ManageConnection for TimeoutManager {
+    type Connection = Connection;
+    type Error = RedisError;
+
+    async fn connect(&self) -> std::result::Result<Self::Connection, Self::Error> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return self.open().await,
+        };
+
+        tokio::time::timeout(timeout, self.open())
+            .await
+            .map_err(|_| {
+                RedisError::from(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out opening connection",
+                ))
+            })?
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> std::result::Result<(), Self::Error> {
+        self.manager.is_valid(conn).await
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        self.manager.has_broken(conn)
+    }
+}
+
+// take_create strips the create parameter from the url and returns its value. It's
+// never kept in the url so the routes of an fl can't create namespaces on mount
+fn take_create(u: &mut url::Url) -> Result<bool> {
+    let mut create = false;
+    let mut query = Vec::default();
+    for (key, value) in u.query_pairs() {
+        if key != PARAM_CREATE {
+            query.push((key.to_string(), value.to_string()));
+            continue;
+        }
+
+        create = match value.as_ref() {
+            "1" | "true" => true,
+            "0" | "false" => false,
+            _ => return Err(Error::Other(anyhow::anyhow!("invalid {} '{}'", key, value))),
+        };
+    }
+
+    if query.is_empty() {
+        u.set_query(None);
+    } else {
+        u.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    Ok(create)
+}
+
+// create_namespace creates the namespace and sets its password. A namespace that
+// already exists is left as is, its password is only changed by its owner
+async fn create_namespace(
+    connection: &mut Connection,
+    namespace: &str,
+    password: Option<&str>,
+) -> Result<()> {
+    let result: std::result::Result<(), RedisError> =
+        cmd("NSNEW").arg(namespace).query_async(connection).await;
+    match result {
+        Ok(_) => log::info!("created namespace {}", namespace),
+        // zdb reports an existing namespace as not available
+        Err(err)
+            if err.to_string().contains("already exists")
+                || err.to_string().contains("not available") =>
+        {
+            log::debug!("namespace {} already exists", namespace);
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(anyhow::Error::from(err)
+                .context(format!("failed to create namespace {}", namespace))
+                .into())
+        }
+    }
+
+    if let Some(password) = password {
+        cmd("NSSET")
+            .arg(namespace)
+            .arg("password")
+            .arg(password)
+            .query_async::<_, ()>(connection)
+            .await
+            .with_context(|| format!("failed to set password of namespace {}", namespace))?;
+    }
+
+    Ok(())
+}
+
 #[derive(Debug)]
 struct WithNamespace {
     namespace: Option<String>,
@@ -48,13 +239,25 @@ fn get_connection_info<U: AsRef<str>>(u: U) -> Result<(ConnectionInfo, Option<St
             let addr = match host {
                 url::Host::Domain(domain) => domain.to_owned(),
                 url::Host::Ipv4(ipv4) => ipv4.to_string(),
+                // the address is used without the brackets of the url
                 url::Host::Ipv6(ipv6) => ipv6.to_string(),
             };
 
-            let addr = ConnectionAddr::Tcp(addr, u.port().unwrap_or(9900));
+            let port = u.port().unwrap_or(9900);
+            let addr = match u.scheme() {
+                "zdbs" => ConnectionAddr::TcpTls {
+                    host: addr,
+                    port,
+                    insecure: false,
+                },
+                _ => ConnectionAddr::Tcp(addr, port),
+            };
+            // an empty last segment (like a trailing slash) is no namespace
             let ns: Option<String> = u
                 .path_segments()
-                .and_then(|s| s.last().map(|s| s.to_owned()));
+                .and_then(|mut s| s.next_back())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_owned());
             (addr, ns)
         }
         None => (ConnectionAddr::Unix(u.path().into()), None),
@@ -80,12 +283,21 @@ fn get_connection_info<U: AsRef<str>>(u: U) -> Result<(ConnectionInfo, Option<St
 #[derive(Clone)]
 pub struct ZdbStore {
     url: String,
-    pool: Pool<RedisConnectionManager>,
+    pool: Pool<TimeoutManager>,
 }
 
 impl ZdbStore {
     pub async fn make<U: AsRef<str>>(url: &U) -> Result<ZdbStore> {
-        let (mut info, namespace) = get_connection_info(url.as_ref())?;
+        let mut u = url::Url::parse(url.as_ref())?;
+        let tls = Tls::for_url(&mut u)?;
+        let create = take_create(&mut u)?;
+        let tls = match u.scheme() {
+            "zdbs" => Some(tls.connector()?),
+            _ => None,
+        };
+
+        let (mut info, namespace) = get_connection_info(u.as_str())?;
+        let options = PoolOptions::for_url(&u)?;
 
         let namespace = WithNamespace {
             namespace,
@@ -95,50 +307,271 @@ impl ZdbStore {
         log::debug!("connection {:#?}", info);
         log::debug!("switching namespace to: {:?}", namespace.namespace);
 
-        let mgr = RedisConnectionManager::new(info)
+        let mgr = RedisConnectionManager::new(info.clone())
             .context("failed to create redis connection manager")?;
+        let mgr = TimeoutManager {
+            manager: mgr,
+            info,
+            tls,
+            timeout: options.connect_timeout,
+        };
+
+        match namespace.namespace {
+            Some(ref ns) if create && ns != "default" => {
+                let mut connection = mgr
+                    .connect()
+                    .await
+                    .context("failed to connect to create namespace")?;
+                create_namespace(&mut connection, ns, namespace.password.as_deref()).await?;
+            }
+            _ => {}
+        }
 
         let pool = Pool::builder()
-            .max_size(20)
+            .max_size(options.max_size)
+            .connection_timeout(options.acquire_timeout)
             .connection_customizer(Box::new(namespace))
             .build(mgr)
             .await
             .context("failed to create connection pool")?;
 
         Ok(ZdbStore {
-            url: url.as_ref().to_string(),
+            url: u.to_string(),
             pool,
         })
     }
 }
 
+impl ZdbStore {
+    // run runs the operation on a connection of the pool. If the connection is lost
+    // the operation is attempted again on another connection, the pool drops the dead
+    // connections on checkout and opens new ones that select the namespace again
+    async fn run<T, F, Fut>(&self, what: &'static str, operation: F) -> Result<T>
+    where
+        F: Fn(PooledConnection<'static, TimeoutManager>) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let con = self
+                .pool
+                .get_owned()
+                .await
+                .context("failed to get connection")?;
+
+            match operation(con).await {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt < RECONNECT_ATTEMPTS && is_connection_error(&err) => {
+                    let delay = RECONNECT_DELAY * 2u32.pow(attempt - 1);
+                    log::warn!(
+                        store_url = self.url.as_str();
+                        "lost connection to {} ({}), reconnecting in {:?}",
+                        self.url,
+                        err,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(anyhow::Error::from(err).context(what).into()),
+            }
+        }
+    }
+
+    /// scan returns the next set of keys (with the size of their blob) in the namespace
+    /// starting after cursor (or from the start of the namespace if cursor is None) and
+    /// the cursor for the next call. None is returned once the end of the namespace is
+    /// reached
+    pub async fn scan(&self, cursor: Option<&[u8]>) -> Result<Option<(Vec<u8>, Keys)>> {
+        let mut con = self.pool.get().await.context("failed to get connection")?;
+
+        let mut c = cmd("SCAN");
+        if let Some(cursor) = cursor {
+            c.arg(cursor);
+        }
+
+        let value: Value = match c.query_async(&mut *con).await {
+            Ok(value) => value,
+            // zdb returns an error once there are no more keys to scan
+            Err(err) if err.to_string().contains("No more data") => return Ok(None),
+            Err(err) => return Err(anyhow::Error::from(err).context("failed to scan").into()),
+        };
+
+        parse_scan(value).map(Some)
+    }
+}
+
+// is_connection_error is true if the error is caused by the connection and not by
+// the command, the command can then be sent again on another connection
+fn is_connection_error(err: &RedisError) -> bool {
+    err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.is_io_error()
+        || err.is_timeout()
+}
+
+/// keys returned by a scan with the size of their blob
+pub type Keys = Vec<(Vec<u8>, u64)>;
+
+// parse_scan parses zdb scan response in the form [cursor, [[key, size, timestamp], ...]]
+fn parse_scan(value: Value) -> Result<(Vec<u8>, Keys)> {
+    let invalid = || Error::Other(anyhow::anyhow!("invalid scan response"));
+
+    let mut parts = match value {
+        Value::Bulk(parts) if parts.len() == 2 => parts.into_iter(),
+        _ => return Err(invalid()),
+    };
+
+    let cursor = match parts.next() {
+        Some(Value::Data(cursor)) => cursor,
+        _ => return Err(invalid()),
+    };
+
+    let entries = match parts.next() {
+        Some(Value::Bulk(entries)) => entries,
+        _ => return Err(invalid()),
+    };
+
+    let mut keys = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let mut fields = match entry {
+            Value::Bulk(fields) => fields.into_iter(),
+            _ => return Err(invalid()),
+        };
+
+        match (fields.next(), fields.next()) {
+            (Some(Value::Data(key)), Some(Value::Int(size))) => keys.push((key, size as u64)),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok((cursor, keys))
+}
+
 #[async_trait::async_trait]
 impl Store for ZdbStore {
     async fn get(&self, key: &[u8]) -> super::Result<Vec<u8>> {
-        let mut con = self.pool.get().await.context("failed to get connection")?;
+        // the query runs on its own task so if this future is dropped (for example
+        // when racing multiple replicas) the request still completes and the connection
+        // goes back to the pool in a clean state instead of having a pending reply
+        let store = self.clone();
+        let key = key.to_vec();
+        let result: Option<Vec<u8>> = tokio::spawn(async move {
+            let key = &key;
+            store
+                .run("failed to get blob", move |mut con| async move {
+                    con.get(key).await
+                })
+                .await
+        })
+        .await
+        .context("failed to join get task")??;
+
+        // an empty value is returned as is, it's up to the block store to decide
+        // if an empty blob is valid or not
+        result.ok_or(Error::KeyNotFound)
+    }
 
-        let result: Option<Vec<u8>> = con.get(key).await.context("failed to get blob")?;
-        let result = result.ok_or(Error::KeyNotFound)?;
+    async fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        if keys.is_empty() {
+            return Ok(Vec::default());
+        }
 
-        if result.is_empty() {
-            return Err(Error::InvalidBlob);
+        // zdb has no MGET, the GETs are pipelined instead so the batch is still sent
+        // in one round trip. Like get, the query runs on its own task
+        let store = self.clone();
+        let mut pipeline = pipe();
+        for key in keys {
+            pipeline.cmd("GET").arg(*key);
         }
 
-        Ok(result)
+        let blobs: Vec<Option<Vec<u8>>> = tokio::spawn(async move {
+            let pipeline = &pipeline;
+            store
+                .run("failed to get blobs", move |mut con| async move {
+                    pipeline.query_async(&mut *con).await
+                })
+                .await
+        })
+        .await
+        .context("failed to join get task")??;
+
+        Ok(blobs)
     }
 
     async fn set(&self, key: &[u8], blob: &[u8]) -> Result<()> {
-        let mut con = self.pool.get().await.context("failed to get connection")?;
+        self.run("failed to set blob", move |mut con| async move {
+            if con.exists(key).await? {
+                return Ok(());
+            }
 
-        if con
-            .exists(key)
-            .await
-            .context("failed to check if blob exists")?
-        {
+            con.set(key, blob).await
+        })
+        .await
+    }
+
+    async fn set_many(&self, blobs: &[(&[u8], &[u8])]) -> Result<()> {
+        if blobs.is_empty() {
             return Ok(());
-        };
+        }
+
+        // like set, the blobs that are already in the namespace are not written
+        // again. The EXISTS and then the SETs are pipelined, two round trips for
+        // the whole batch
+        let mut exists = pipe();
+        for (key, _) in blobs {
+            exists.cmd("EXISTS").arg(*key);
+        }
+
+        let exists = &exists;
+        self.run("failed to set blobs", move |mut con| async move {
+            let found: Vec<bool> = exists.query_async(&mut *con).await?;
+
+            let mut sets = pipe();
+            let mut count = 0;
+            for ((key, blob), found) in blobs.iter().zip(found) {
+                if !found {
+                    sets.cmd("SET").arg(*key).arg(*blob).ignore();
+                    count += 1;
+                }
+            }
+
+            if count > 0 {
+                sets.query_async::<_, ()>(&mut *con).await?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn exists(&self, key: &[u8]) -> Result<bool> {
+        self.run(
+            "failed to check if blob exists",
+            move |mut con| async move { con.exists(key).await },
+        )
+        .await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        // zdb fails to DEL a missing key, so it's checked first like set does
+        self.run("failed to delete blob", move |mut con| async move {
+            let found: bool = con.exists(key).await?;
+            if !found {
+                return Ok(());
+            }
+
+            cmd("DEL").arg(key).query_async(&mut *con).await
+        })
+        .await
+    }
 
-        con.set(key, blob).await.context("failed to set blob")?;
+    async fn health(&self) -> Result<()> {
+        let mut con = self.pool.get().await.context("failed to get connection")?;
+        let _: String = cmd("PING")
+            .query_async(&mut *con)
+            .await
+            .context("failed to ping zdb")?;
 
         Ok(())
     }
@@ -159,6 +592,20 @@ mod test {
         assert_eq!(info.addr, ConnectionAddr::Tcp("hub.grid.tf".into(), 9900));
     }
 
+    #[test]
+    fn test_connection_info_tls() {
+        let (info, ns) = get_connection_info("zdbs://hub.grid.tf:9901/custom").unwrap();
+        assert_eq!(ns, Some("custom".into()));
+        assert_eq!(
+            info.addr,
+            ConnectionAddr::TcpTls {
+                host: "hub.grid.tf".into(),
+                port: 9901,
+                insecure: false
+            }
+        );
+    }
+
     #[test]
     fn test_connection_info_ns() {
         let (info, ns) = get_connection_info("zdb://username@hub.grid.tf/custom").unwrap();
@@ -167,6 +614,110 @@ mod test {
         assert_eq!(info.redis.username, Some("username".into()));
     }
 
+    #[test]
+    fn test_connection_info_ipv6() {
+        let (info, ns) = get_connection_info("zdb://[::1]:9900/ns").unwrap();
+        assert_eq!(ns, Some("ns".into()));
+        assert_eq!(info.addr, ConnectionAddr::Tcp("::1".into(), 9900));
+    }
+
+    #[test]
+    fn test_connection_info_no_ns() {
+        let (info, ns) = get_connection_info("zdb://hub.grid.tf:9900/").unwrap();
+        assert_eq!(ns, None);
+        assert_eq!(info.addr, ConnectionAddr::Tcp("hub.grid.tf".into(), 9900));
+
+        let (info, ns) = get_connection_info("zdb://hub.grid.tf").unwrap();
+        assert_eq!(ns, None);
+        assert_eq!(info.addr, ConnectionAddr::Tcp("hub.grid.tf".into(), 9900));
+    }
+
+    #[test]
+    fn test_connection_error() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(is_connection_error(&RedisError::from(reset)));
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(is_connection_error(&RedisError::from(refused)));
+
+        let response = RedisError::from((
+            bb8_redis::redis::ErrorKind::ResponseError,
+            "namespace not found",
+        ));
+        assert!(!is_connection_error(&response));
+    }
+
+    #[test]
+    fn test_parse_scan() {
+        let value = Value::Bulk(vec![
+            Value::Data(b"cursor".to_vec()),
+            Value::Bulk(vec![
+                Value::Bulk(vec![
+                    Value::Data(b"a".to_vec()),
+                    Value::Int(10),
+                    Value::Int(0),
+                ]),
+                Value::Bulk(vec![
+                    Value::Data(b"b".to_vec()),
+                    Value::Int(20),
+                    Value::Int(0),
+                ]),
+            ]),
+        ]);
+
+        let (cursor, keys) = parse_scan(value).unwrap();
+        assert_eq!(cursor, b"cursor");
+        assert_eq!(keys, vec![(b"a".to_vec(), 10), (b"b".to_vec(), 20)]);
+
+        assert!(parse_scan(Value::Nil).is_err());
+    }
+
+    #[test]
+    fn test_pool_options() {
+        let u = url::Url::parse("zdb://hub.grid.tf/custom").unwrap();
+        assert_eq!(PoolOptions::for_url(&u).unwrap(), PoolOptions::default());
+
+        let u = url::Url::parse(
+            "zdb://hub.grid.tf/custom?pool=50&connect_timeout=5&acquire_timeout=10&retry=3",
+        )
+        .unwrap();
+        assert_eq!(
+            PoolOptions::for_url(&u).unwrap(),
+            PoolOptions {
+                max_size: 50,
+                connect_timeout: Some(Duration::from_secs(5)),
+                acquire_timeout: Duration::from_secs(10),
+            }
+        );
+
+        for query in [
+            "pool=0",
+            "pool=many",
+            "connect_timeout=0",
+            "acquire_timeout=-1",
+        ] {
+            let u = url::Url::parse(&format!("zdb://hub.grid.tf/custom?{}", query)).unwrap();
+            assert!(PoolOptions::for_url(&u).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_take_create() {
+        let mut u = url::Url::parse("zdb://hub.grid.tf/custom?create=1&pool=5").unwrap();
+        assert!(take_create(&mut u).unwrap());
+        assert_eq!(u.as_str(), "zdb://hub.grid.tf/custom?pool=5");
+
+        let mut u = url::Url::parse("zdb://hub.grid.tf/custom?create=false").unwrap();
+        assert!(!take_create(&mut u).unwrap());
+        assert_eq!(u.as_str(), "zdb://hub.grid.tf/custom");
+
+        let mut u = url::Url::parse("zdb://hub.grid.tf/custom").unwrap();
+        assert!(!take_create(&mut u).unwrap());
+
+        let mut u = url::Url::parse("zdb://hub.grid.tf/custom?create=yes").unwrap();
+        assert!(take_create(&mut u).is_err());
+    }
+
     #[test]
     fn test_connection_info_unix() {
         let (info, ns) = get_connection_info("zdb:///path/to/socket").unwrap();
@@ -0,0 +1,181 @@
+use crate::clone::blocks;
+use crate::fungi::{meta::Block, Reader, Result};
+use crate::store::{self, Store};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hex::ToHex;
+use std::sync::mpsc::Sender;
+
+/// Report of the sync of the blocks of an flist from a store to another
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// number of distinct blocks that were synced
+    pub synced: u64,
+    /// blocks copied to the destination store
+    pub copied: u64,
+    /// blocks that are already in the destination store
+    pub skipped: u64,
+    /// blocks that are not found in the source store
+    pub missing: u64,
+    /// blocks that could not be copied because of a store error
+    pub failed: u64,
+    /// size of the copied blobs in bytes
+    pub bytes: u64,
+}
+
+impl Report {
+    fn add(&mut self, block: &Block, status: Status) {
+        let id: String = block.id.encode_hex();
+        self.synced += 1;
+        match status {
+            Status::Copied(size) => {
+                self.copied += 1;
+                self.bytes += size;
+            }
+            Status::Skipped => self.skipped += 1,
+            Status::Missing => {
+                log::warn!("block {} is missing from the source store", id);
+                self.missing += 1;
+            }
+            Status::Failed(err) => {
+                log::error!("failed to copy block {}: {:#}", id, err);
+                self.failed += 1;
+            }
+        }
+    }
+}
+
+enum Status {
+    Copied(u64),
+    Skipped,
+    Missing,
+    Failed(store::Error),
+}
+
+/// sync copies every block of the flist from the `from` store to the `to` store, up to
+/// `concurrency` blocks are copied at the same time. The blobs are copied as is, and
+/// blocks that are already in the destination are skipped. A block that is missing
+/// from the source is only reported, the sync goes on with the other blocks. If a
+/// sender is given, the report so far is sent to it after each block
+pub async fn sync<F: Store, T: Store>(
+    reader: &Reader,
+    from: F,
+    to: T,
+    concurrency: usize,
+    sender: Option<Sender<Report>>,
+) -> Result<Report> {
+    let concurrency = concurrency.max(1);
+
+    let mut report = Report::default();
+    let mut pending = FuturesUnordered::new();
+    let blocks = blocks(reader);
+    futures::pin_mut!(blocks);
+    while let Some(block) = blocks.next().await {
+        let block = block?;
+        if pending.len() >= concurrency {
+            if let Some((block, status)) = pending.next().await {
+                report.add(&block, status);
+                notify(sender.as_ref(), &report);
+            }
+        }
+
+        pending.push(copy(&from, &to, block));
+    }
+
+    while let Some((block, status)) = pending.next().await {
+        report.add(&block, status);
+        notify(sender.as_ref(), &report);
+    }
+
+    Ok(report)
+}
+
+fn notify(sender: Option<&Sender<Report>>, report: &Report) {
+    if let Some(sender) = sender {
+        // a closed receiver means nobody is interested in the progress anymore
+        let _ = sender.send(report.clone());
+    }
+}
+
+async fn copy<F: Store, T: Store>(from: &F, to: &T, block: Block) -> (Block, Status) {
+    match to.exists(&block.id).await {
+        Ok(true) => return (block, Status::Skipped),
+        Ok(false) => {}
+        Err(err) => return (block, Status::Failed(err)),
+    }
+
+    let blob = match from.get(&block.id).await {
+        Ok(blob) => blob,
+        Err(store::Error::KeyNotFound) => return (block, Status::Missing),
+        Err(err) => return (block, Status::Failed(err)),
+    };
+
+    let status = match to.set(&block.id, &blob).await {
+        Ok(_) => Status::Copied(blob.len() as u64),
+        Err(err) => Status::Failed(err),
+    };
+
+    (block, status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::{
+        meta::{FileType, Inode, Mode},
+        Writer,
+    };
+    use crate::store::{mem::MemStore, BlockStore};
+
+    #[tokio::test]
+    async fn test_sync() {
+        const PATH: &str = "/tmp/sync.fl";
+        let from = MemStore::default();
+        let to = MemStore::default();
+        let blocks = BlockStore::from(from.clone());
+
+        let copied = blocks.set(b"copied").await.unwrap();
+        let skipped = blocks.set(b"skipped").await.unwrap();
+        to.set(&skipped.id, b"skipped").await.unwrap();
+        let missing = BlockStore::from(MemStore::default())
+            .set(b"missing")
+            .await
+            .unwrap();
+
+        let meta = Writer::new(PATH, true).await.unwrap();
+        let ino = meta
+            .inode(Inode {
+                name: "file".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        for block in [&copied, &copied, &skipped, &missing, &Block::hole()] {
+            meta.block(ino, &block.id, &block.key).await.unwrap();
+        }
+
+        let reader = Reader::new(PATH).await.unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let report = sync(&reader, from.clone(), to.clone(), 2, Some(tx))
+            .await
+            .unwrap();
+
+        let size = from.get(&copied.id).await.unwrap().len() as u64;
+        assert_eq!(
+            report,
+            Report {
+                synced: 3,
+                copied: 1,
+                skipped: 1,
+                missing: 1,
+                failed: 0,
+                bytes: size,
+            }
+        );
+        assert_eq!(rx.iter().last(), Some(report));
+
+        // the copied blocks can be read from the destination
+        let received = BlockStore::from(to).get(&copied).await.unwrap();
+        assert_eq!(received, b"copied");
+    }
+}
@@ -1,6 +1,6 @@
 use crate::cache::Cache;
 use crate::fungi::{
-    meta::{FileType, Inode, Result, Walk, WalkVisitor},
+    meta::{FileType, Inode, Result, Walk, WalkVisitor, DEFAULT_WALK_CONCURRENCY},
     Reader,
 };
 use crate::store::Store;
@@ -23,7 +23,8 @@ pub async fn unpack<P: AsRef<Path>, S: Store>(
 ) -> Result<()> {
     let mut visitor = CopyVisitor::new(meta, cache, root.as_ref(), preserve);
 
-    meta.walk(&mut visitor).await
+    meta.walk_concurrent(&mut visitor, DEFAULT_WALK_CONCURRENCY)
+        .await
 }
 
 struct CopyVisitor<'a, S>
@@ -78,6 +79,9 @@ where
                     .await
                     .with_context(|| format!("failed to download file '{:?}'", rooted))?;
 
+                // files ending with a hole are not extended by the copy
+                fd.set_len(node.size).await?;
+
                 fd.set_permissions(Permissions::from_mode(node.mode.mode()))
                     .await?;
             }
@@ -0,0 +1,225 @@
+use crate::fungi::{meta::Block, Reader, Result};
+use crate::store::{self, verify_block, BlockStore, Store};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hex::ToHex;
+use std::collections::HashSet;
+
+const PAGE: u32 = 1000;
+
+/// Report of the verification of the blocks of an flist
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// number of distinct blocks that were checked
+    pub checked: u64,
+    /// blocks that are not found in the stores
+    pub missing: u64,
+    /// blocks that are found but their content is invalid
+    pub corrupted: u64,
+    /// blocks that could not be checked because of a store error
+    pub failed: u64,
+}
+
+impl Report {
+    /// is_ok is true if all the blocks are found (and valid)
+    pub fn is_ok(&self) -> bool {
+        self.missing == 0 && self.corrupted == 0 && self.failed == 0
+    }
+
+    fn add(&mut self, block: &Block, status: Status) {
+        let id: String = block.id.encode_hex();
+        self.checked += 1;
+        match status {
+            Status::Ok => {}
+            Status::Missing => {
+                log::error!("block {} is missing", id);
+                self.missing += 1;
+            }
+            Status::Corrupted => {
+                log::error!("block {} is corrupted", id);
+                self.corrupted += 1;
+            }
+            Status::Failed(err) => {
+                log::error!("failed to check block {}: {:#}", id, err);
+                self.failed += 1;
+            }
+        }
+    }
+}
+
+enum Status {
+    Ok,
+    Missing,
+    Corrupted,
+    Failed(store::Error),
+}
+
+/// verify checks that every block of the flist exists in the store, up to
+/// `concurrency` blocks are checked at the same time. With `deep` each blob is
+/// fetched and decrypted instead and its content is checked against the block
+/// hash to detect corrupted blocks.
+pub async fn verify<S: Store>(
+    reader: &Reader,
+    store: S,
+    concurrency: usize,
+    deep: bool,
+) -> Result<Report> {
+    let store: BlockStore<S> = store.into();
+    let concurrency = concurrency.max(1);
+
+    let mut report = Report::default();
+    let mut seen = HashSet::new();
+    let mut pending = FuturesUnordered::new();
+    let mut offset = 0;
+    loop {
+        let blocks = reader.all_blocks(PAGE, offset).await?;
+        offset += blocks.len() as u64;
+        let last = blocks.len() < PAGE as usize;
+
+        for block in blocks {
+            // holes are not stored and blocks shared by files are checked once
+            if block.is_hole() || !seen.insert(block.id) {
+                continue;
+            }
+
+            if pending.len() >= concurrency {
+                if let Some((block, status)) = pending.next().await {
+                    report.add(&block, status);
+                }
+            }
+
+            pending.push(check(&store, block, deep));
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    while let Some((block, status)) = pending.next().await {
+        report.add(&block, status);
+    }
+
+    Ok(report)
+}
+
+async fn check<S: Store>(store: &BlockStore<S>, block: Block, deep: bool) -> (Block, Status) {
+    let result = if deep {
+        store
+            .get(&block)
+            .await
+            .map(|plain| match verify_block(&block, &plain) {
+                true => Status::Ok,
+                false => Status::Corrupted,
+            })
+    } else {
+        store
+            .get_ref()
+            .exists(&block.id)
+            .await
+            .map(|exists| match exists {
+                true => Status::Ok,
+                false => Status::Missing,
+            })
+    };
+
+    let status = match result {
+        Ok(status) => status,
+        Err(store::Error::KeyNotFound) => Status::Missing,
+        Err(
+            store::Error::InvalidBlob
+            | store::Error::InvalidKey
+            | store::Error::EncryptionError
+            | store::Error::Compression(_),
+        ) => Status::Corrupted,
+        Err(err) => Status::Failed(err),
+    };
+
+    (block, status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fungi::{
+        meta::{FileType, Inode, Mode},
+        Writer,
+    };
+    use crate::store::{mem::MemStore, Route};
+
+    // ExistsStore only answers existence checks, blobs can't be downloaded
+    #[derive(Clone)]
+    struct ExistsStore(MemStore);
+
+    #[async_trait::async_trait]
+    impl Store for ExistsStore {
+        async fn get(&self, _key: &[u8]) -> store::Result<Vec<u8>> {
+            Err(store::Error::Unsupported("getting blobs"))
+        }
+
+        async fn set(&self, key: &[u8], blob: &[u8]) -> store::Result<()> {
+            self.0.set(key, blob).await
+        }
+
+        async fn exists(&self, key: &[u8]) -> store::Result<bool> {
+            self.0.exists(key).await
+        }
+
+        fn routes(&self) -> Vec<Route> {
+            self.0.routes()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        const PATH: &str = "/tmp/verify.fl";
+        let store = MemStore::default();
+        let blocks = BlockStore::from(store.clone());
+
+        let ok = blocks.set(b"ok").await.unwrap();
+        let corrupted = blocks.set(b"corrupted").await.unwrap();
+        store.set(&corrupted.id, b"garbage").await.unwrap();
+        let missing = BlockStore::from(MemStore::default())
+            .set(b"missing")
+            .await
+            .unwrap();
+
+        let meta = Writer::new(PATH, true).await.unwrap();
+        let ino = meta
+            .inode(Inode {
+                name: "file".into(),
+                mode: Mode::new(FileType::Regular, 0o644),
+                ..Inode::default()
+            })
+            .await
+            .unwrap();
+        for block in [&ok, &ok, &corrupted, &missing, &Block::hole()] {
+            meta.block(ino, &block.id, &block.key).await.unwrap();
+        }
+
+        // without deep only the existence of the blocks is checked
+        let reader = Reader::new(PATH).await.unwrap();
+        let report = verify(&reader, ExistsStore(store.clone()), 2, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            Report {
+                checked: 3,
+                missing: 1,
+                ..Report::default()
+            }
+        );
+        assert!(!report.is_ok());
+
+        let report = verify(&reader, store, 2, true).await.unwrap();
+        assert_eq!(
+            report,
+            Report {
+                checked: 3,
+                missing: 1,
+                corrupted: 1,
+                failed: 0,
+            }
+        );
+    }
+}